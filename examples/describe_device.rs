@@ -0,0 +1,92 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::collections::HashMap;
+
+use ar_drivers::{describe_device, DeviceDescription};
+use clap::Parser;
+use tinyjson::JsonValue;
+
+/// Print the USB interface layout of a device as JSON, for bug reports
+#[derive(clap::Parser, Debug)]
+struct CliArgs {
+    /// USB Vendor ID, in hex (e.g. 3318)
+    #[arg(value_parser = parse_hex)]
+    vid: u16,
+    /// USB Product ID, in hex (e.g. 0424)
+    #[arg(value_parser = parse_hex)]
+    pid: u16,
+}
+
+fn parse_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+fn to_json(desc: &DeviceDescription) -> JsonValue {
+    let number = |n: u8| JsonValue::Number(n as f64);
+    let interfaces = desc
+        .interfaces
+        .iter()
+        .map(|interface| {
+            let endpoints = interface
+                .endpoints
+                .iter()
+                .map(|endpoint| {
+                    JsonValue::Object(HashMap::from([
+                        ("address".to_string(), number(endpoint.address)),
+                        (
+                            "transfer_type".to_string(),
+                            format!("{:?}", endpoint.transfer_type).into(),
+                        ),
+                        (
+                            "max_packet_size".to_string(),
+                            (endpoint.max_packet_size as f64).into(),
+                        ),
+                    ]))
+                })
+                .collect::<Vec<_>>();
+            JsonValue::Object(HashMap::from([
+                ("number".to_string(), number(interface.number)),
+                (
+                    "alternate_setting".to_string(),
+                    number(interface.alternate_setting),
+                ),
+                ("class".to_string(), number(interface.class)),
+                ("subclass".to_string(), number(interface.subclass)),
+                ("protocol".to_string(), number(interface.protocol)),
+                (
+                    "name".to_string(),
+                    interface.name.clone().map_or(JsonValue::Null, Into::into),
+                ),
+                ("endpoints".to_string(), endpoints.into()),
+                (
+                    "hid_report_descriptor".to_string(),
+                    interface
+                        .hid_report_descriptor
+                        .as_ref()
+                        .map_or(JsonValue::Null, |bytes| {
+                            bytes
+                                .iter()
+                                .map(|b| format!("{b:02x}"))
+                                .collect::<String>()
+                                .into()
+                        }),
+                ),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    JsonValue::Object(HashMap::from([
+        ("vid".to_string(), format!("{:04x}", desc.vid).into()),
+        ("pid".to_string(), format!("{:04x}", desc.pid).into()),
+        ("bus".to_string(), number(desc.bus)),
+        ("address".to_string(), number(desc.address)),
+        ("interfaces".to_string(), interfaces.into()),
+    ]))
+}
+
+fn main() {
+    let args = CliArgs::parse();
+    let desc = describe_device(args.vid, args.pid).unwrap();
+    println!("{}", to_json(&desc).format().unwrap());
+}
@@ -0,0 +1,14 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use ar_drivers::preflight;
+
+/// Check the connection to the glasses step by step, and print which step fails
+fn main() {
+    let report = preflight(None);
+    print!("{report}");
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::{fs::File, io::BufWriter, path::PathBuf, time::Duration};
+
+use ar_drivers::{any_glasses, EventLogFormat, EventLogReader, EventLogger};
+use clap::Parser;
+
+/// Record the events of the AR glasses into a file, or print a binary recording
+#[derive(clap::Parser, Debug)]
+enum CliArgs {
+    /// Record events until the limits are reached (or forever)
+    Record {
+        /// Output file
+        output: PathBuf,
+        /// Output format
+        #[arg(long, short, value_enum, default_value_t = CliFormat::Binary)]
+        format: CliFormat,
+        /// Stop after this many seconds
+        #[arg(long)]
+        seconds: Option<u64>,
+        /// Stop after this many bytes
+        #[arg(long)]
+        max_bytes: Option<u64>,
+    },
+    /// Print the records of a binary recording
+    Replay {
+        /// Input file
+        input: PathBuf,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+enum CliFormat {
+    /// One JSON object per event
+    Jsonl,
+    /// Accelerometer and gyroscope samples only
+    Csv,
+    /// Compact binary, can be replayed
+    Binary,
+}
+
+fn main() {
+    match CliArgs::parse() {
+        CliArgs::Record {
+            output,
+            format,
+            seconds,
+            max_bytes,
+        } => {
+            let format = match format {
+                CliFormat::Jsonl => EventLogFormat::Jsonl,
+                CliFormat::Csv => EventLogFormat::ImuCsv,
+                CliFormat::Binary => EventLogFormat::Binary,
+            };
+            let mut glasses = any_glasses().unwrap();
+            println!("Got glasses, serial={}", glasses.serial().unwrap());
+            let writer = BufWriter::new(File::create(output).unwrap());
+            let mut logger = EventLogger::new(glasses.as_mut(), writer, format)
+                .unwrap()
+                .with_flush_interval(Duration::from_secs(1))
+                .with_limits(max_bytes, seconds.map(Duration::from_secs));
+            while !logger.is_full() {
+                logger.read_event().unwrap();
+            }
+        }
+        CliArgs::Replay { input } => {
+            let mut reader = EventLogReader::new(File::open(input).unwrap()).unwrap();
+            while let Some(record) = reader.read_record().unwrap() {
+                println!("{record:?}");
+            }
+        }
+    }
+}
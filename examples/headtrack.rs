@@ -0,0 +1,88 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::{fs::File, io::BufReader, path::PathBuf, time::Duration};
+
+use ar_drivers::{any_glasses, DeviceTimestamp, EventLogReader, GlassesEvent, LogRecord};
+use clap::Parser;
+use nalgebra::UnitQuaternion;
+
+/// Integrate the gyroscope into a head orientation, and print it as Euler angles at 10Hz.
+///
+/// There is no sensor fusion in the library, so this drifts. Keep the glasses still
+/// for the first minute, and the drift over that minute is printed.
+#[derive(clap::Parser, Debug)]
+struct CliArgs {
+    /// Read the events from a binary recording (see the event_log example) instead of
+    /// the glasses
+    #[arg(long)]
+    replay: Option<PathBuf>,
+}
+
+/// In device time
+const PRINT_INTERVAL: Duration = Duration::from_millis(100);
+const DRIFT_MEASUREMENT_TIME: Duration = Duration::from_secs(60);
+
+fn main() {
+    let args = CliArgs::parse();
+    let mut next_event: Box<dyn FnMut() -> Option<GlassesEvent>> = if let Some(path) = args.replay {
+        let mut reader = EventLogReader::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        Box::new(move || loop {
+            match reader.read_record().unwrap()? {
+                LogRecord::Event { event, .. } => return Some(event),
+                LogRecord::Marker { .. } => continue,
+            }
+        })
+    } else {
+        let mut glasses = any_glasses().unwrap();
+        println!("Got glasses, serial={}", glasses.serial().unwrap());
+        Box::new(move || Some(glasses.read_event().unwrap()))
+    };
+
+    let mut orientation = UnitQuaternion::identity();
+    let mut start_timestamp = None;
+    let mut last_timestamp = None;
+    let mut last_print = DeviceTimestamp::default();
+    let mut drift_printed = false;
+    while let Some(event) = next_event() {
+        let (gyroscope, timestamp) = match event {
+            GlassesEvent::AccGyro {
+                gyroscope,
+                timestamp,
+                ..
+            }
+            | GlassesEvent::Gyroscope {
+                gyroscope,
+                timestamp,
+            } => (gyroscope, timestamp),
+            _ => continue,
+        };
+        if let Some(last_timestamp) = last_timestamp {
+            let dt = timestamp
+                .saturating_duration_since(last_timestamp)
+                .as_secs_f32();
+            orientation *= UnitQuaternion::from_scaled_axis(gyroscope * dt);
+        }
+        last_timestamp = Some(timestamp);
+        let start_timestamp = *start_timestamp.get_or_insert(timestamp);
+
+        if timestamp >= last_print + PRINT_INTERVAL {
+            let (roll, pitch, yaw) = orientation.euler_angles();
+            println!(
+                "roll={:7.2} pitch={:7.2} yaw={:7.2}",
+                roll.to_degrees(),
+                pitch.to_degrees(),
+                yaw.to_degrees()
+            );
+            last_print = timestamp;
+        }
+        if !drift_printed && timestamp >= start_timestamp + DRIFT_MEASUREMENT_TIME {
+            println!(
+                "Drift over the first minute: {:.2} degrees",
+                orientation.angle().to_degrees()
+            );
+            drift_printed = true;
+        }
+    }
+}
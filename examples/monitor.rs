@@ -0,0 +1,148 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use ar_drivers::{any_glasses, ARGlasses, Capabilities, EventLogReader, GlassesEvent, LogRecord};
+use clap::Parser;
+
+/// Print the capabilities of the glasses, and statistics about every event it sends.
+///
+/// Pressing a key on the glasses switches to the next supported display mode.
+/// (There is no brightness control in the library, so that is not exercised.)
+#[derive(clap::Parser, Debug)]
+struct CliArgs {
+    /// Read the events from a binary recording (see the event_log example) instead of
+    /// the glasses. Only the event statistics are printed in this case.
+    #[arg(long)]
+    replay: Option<PathBuf>,
+}
+
+const CAPABILITIES: [(&str, Capabilities); 7] = [
+    ("IMU", Capabilities::IMU),
+    ("Magnetometer", Capabilities::MAGNETOMETER),
+    ("Keys", Capabilities::KEYS),
+    ("Proximity", Capabilities::PROXIMITY),
+    ("Ambient light", Capabilities::AMBIENT_LIGHT),
+    ("VSync", Capabilities::VSYNC),
+    ("Display control", Capabilities::DISPLAY_CONTROL),
+];
+
+fn main() {
+    let args = CliArgs::parse();
+    let mut replay = args
+        .replay
+        .map(|path| EventLogReader::new(BufReader::new(File::open(path).unwrap())).unwrap());
+    let mut glasses = if replay.is_none() {
+        let glasses = any_glasses().unwrap();
+        print_device_info(glasses.as_ref());
+        Some(glasses)
+    } else {
+        None
+    };
+
+    let mut counts = BTreeMap::<String, u64>::new();
+    let mut last_print = Instant::now();
+    loop {
+        let event = if let Some(reader) = &mut replay {
+            match reader.read_record().unwrap() {
+                Some(LogRecord::Event { event, .. }) => event,
+                Some(LogRecord::Marker { .. }) => continue,
+                None => break,
+            }
+        } else {
+            glasses.as_mut().unwrap().read_event().unwrap()
+        };
+        if let (GlassesEvent::KeyPress(key), Some(glasses)) = (&event, &mut glasses) {
+            println!("Key {key} pressed");
+            next_display_mode(glasses.as_mut());
+        }
+        *counts.entry(event_name(&event)).or_default() += 1;
+
+        if replay.is_none() && last_print.elapsed() > Duration::from_secs(1) {
+            print_stats(&counts, last_print.elapsed(), glasses.as_deref());
+            counts.clear();
+            last_print = Instant::now();
+        }
+    }
+    // A replay is not paced, so only the totals are meaningful
+    for (name, count) in &counts {
+        println!("{name:>20}: {count}");
+    }
+}
+
+fn print_device_info(glasses: &dyn ARGlasses) {
+    println!("Got glasses: {}", glasses.name());
+    let capabilities = glasses.capabilities();
+    for (name, capability) in CAPABILITIES {
+        println!(
+            "{name:>20}: {}",
+            if capabilities.contains(capability) {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+    }
+}
+
+fn next_display_mode(glasses: &mut dyn ARGlasses) {
+    if !glasses
+        .capabilities()
+        .contains(Capabilities::DISPLAY_CONTROL)
+    {
+        return;
+    }
+    let current = glasses.get_display_mode().unwrap();
+    let modes = glasses.supported_display_modes().unwrap();
+    if modes.is_empty() {
+        println!("No display modes to switch between");
+        return;
+    }
+    let index = modes.iter().position(|m| *m == current).unwrap_or(0);
+    let next = modes[(index + 1) % modes.len()];
+    println!("Switching display mode: {current:?} -> {next:?}");
+    if let Err(e) = glasses.set_display_mode(next) {
+        println!("Could not set display mode: {e}");
+    }
+}
+
+fn print_stats(counts: &BTreeMap<String, u64>, elapsed: Duration, glasses: Option<&dyn ARGlasses>) {
+    println!("---");
+    for (name, count) in counts {
+        println!("{name:>20}: {:.1}/s", *count as f32 / elapsed.as_secs_f32());
+    }
+    if let Some(diagnostics) = glasses.and_then(|g| g.diagnostics()) {
+        println!(
+            "{:>20}: p50={:?} p99={:?}",
+            "Latency",
+            diagnostics.event_age_p50(),
+            diagnostics.event_age_p99()
+        );
+        println!(
+            "{:>20}: rate={:?}Hz device p50={:?} p99={:?}, host p50={:?} p99={:?}, longest gap={:?}",
+            "IMU timing",
+            diagnostics.imu_sample_rate(),
+            diagnostics.imu_interval_percentile(0.5, false),
+            diagnostics.imu_interval_percentile(0.99, false),
+            diagnostics.imu_interval_percentile(0.5, true),
+            diagnostics.imu_interval_percentile(0.99, true),
+            diagnostics.longest_imu_gap()
+        );
+    }
+}
+
+/// The name of the event variant, from its debug representation
+fn event_name(event: &GlassesEvent) -> String {
+    format!("{event:?}")
+        .chars()
+        .take_while(|c| c.is_alphanumeric())
+        .collect()
+}
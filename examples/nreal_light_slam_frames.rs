@@ -88,7 +88,7 @@ fn main() {
 
     loop {
         let frame = camera.get_frame(Duration::from_secs(5)).unwrap();
-        println!("Got frame with ts {}", frame.timestamp);
+        println!("Got frame with ts {}", frame.timestamp.as_micros());
         let mat_left = Mat::from_slice_rows_cols(&frame.left, 480, 640).unwrap();
         let mut mat_left_ud =
             Mat::new_rows_cols_with_default(480, 640, CV_8UC1, Default::default()).unwrap();
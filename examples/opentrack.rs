@@ -0,0 +1,59 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::net::SocketAddr;
+
+use ar_drivers::{any_glasses, opentrack::OpenTrackSender, tilt_from_accelerometer, GlassesEvent};
+use clap::Parser;
+use nalgebra::UnitQuaternion;
+
+/// Send the head orientation to OpenTrack's "UDP over network" input.
+/// Press a key on the glasses to recenter.
+///
+/// The orientation is a plain integration of the gyroscope (started from the tilt measured
+/// by the accelerometer), use a proper sensor fusion algorithm in real applications.
+#[derive(clap::Parser, Debug)]
+struct CliArgs {
+    /// Address of the OpenTrack input
+    #[arg(long, default_value = "127.0.0.1:4242")]
+    dest: SocketAddr,
+    /// Datagrams per second
+    #[arg(long, default_value_t = 100.0)]
+    rate: f32,
+}
+
+fn main() {
+    let args = CliArgs::parse();
+    let mut glasses = any_glasses().unwrap();
+    println!("Got glasses, serial={}", glasses.serial().unwrap());
+    let mut sender = OpenTrackSender::new(args.dest)
+        .unwrap()
+        .with_rate(args.rate);
+
+    let mut orientation = None;
+    let mut last_timestamp = None;
+    loop {
+        match glasses.read_event().unwrap() {
+            GlassesEvent::AccGyro {
+                accelerometer,
+                gyroscope,
+                timestamp,
+                ..
+            } => {
+                let orientation =
+                    orientation.get_or_insert_with(|| tilt_from_accelerometer(&accelerometer));
+                if let Some(last_timestamp) = last_timestamp {
+                    let dt = timestamp
+                        .saturating_duration_since(last_timestamp)
+                        .as_secs_f64();
+                    *orientation *= UnitQuaternion::from_scaled_axis(gyroscope.cast() * dt);
+                }
+                last_timestamp = Some(timestamp);
+                sender.update(orientation).unwrap();
+            }
+            GlassesEvent::KeyPress(_) => sender.recenter(),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::time::Duration;
+
+use ar_drivers::{any_glasses, DeviceTimestamp, GlassesEvent, ScreenPinning, Side, VirtualScreen};
+use nalgebra::{UnitQuaternion, Vector4};
+
+/// Print where the center of a yaw-following virtual screen is, as seen from the left eye.
+/// Press a key on the glasses to recenter.
+///
+/// The orientation is a plain integration of the gyroscope, use a proper sensor fusion
+/// algorithm in real applications.
+fn main() {
+    let mut glasses = any_glasses().unwrap();
+    println!("Got glasses, serial={}", glasses.serial().unwrap());
+    let mut screen = VirtualScreen::new()
+        .for_glasses(glasses.as_ref())
+        .with_pinning(ScreenPinning::YawFollow {
+            time_constant: Duration::from_secs(2),
+        });
+
+    let mut orientation = UnitQuaternion::identity();
+    let mut last_timestamp = None;
+    let mut last_print = DeviceTimestamp::default();
+    loop {
+        match glasses.read_event().unwrap() {
+            GlassesEvent::AccGyro {
+                gyroscope,
+                timestamp,
+                ..
+            } => {
+                if let Some(last_timestamp) = last_timestamp {
+                    let dt = timestamp
+                        .saturating_duration_since(last_timestamp)
+                        .as_secs_f64();
+                    orientation *= UnitQuaternion::from_scaled_axis(gyroscope.cast() * dt);
+                }
+                last_timestamp = Some(timestamp);
+                screen.update(timestamp, &orientation, &gyroscope);
+                if timestamp >= last_print + Duration::from_millis(100) {
+                    let center = screen.view_matrix(glasses.as_ref(), Side::Left, 0.063)
+                        * screen.model_matrix()
+                        * Vector4::new(0.0, 0.0, 0.0, 1.0);
+                    println!(
+                        "Screen center from the left eye: x={:6.2} y={:6.2} z={:6.2}",
+                        center.x, center.y, center.z
+                    );
+                    last_print = timestamp;
+                }
+            }
+            GlassesEvent::KeyPress(_) => screen.recenter(),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+#ifndef AR_DRIVERS_H
+#define AR_DRIVERS_H
+
+/* Generated with cbindgen from src/capi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Display mode, see [`DisplayMode`]
+ */
+typedef enum ar_display_mode {
+  /**
+   * See [`DisplayMode::SameOnBoth`]
+   */
+  AR_DISPLAY_MODE_SAME_ON_BOTH,
+  /**
+   * See [`DisplayMode::Stereo`]
+   */
+  AR_DISPLAY_MODE_STEREO,
+  /**
+   * See [`DisplayMode::HalfSBS`]
+   */
+  AR_DISPLAY_MODE_HALF_SBS,
+  /**
+   * See [`DisplayMode::HighRefreshRate`]
+   */
+  AR_DISPLAY_MODE_HIGH_REFRESH_RATE,
+  /**
+   * See [`DisplayMode::HighRefreshRateSBS`]
+   */
+  AR_DISPLAY_MODE_HIGH_REFRESH_RATE_SBS,
+} ar_display_mode;
+
+/**
+ * Kind of an [`ArEvent`], and which member of [`ArEventData`] is valid
+ */
+typedef enum ar_event_tag {
+  /**
+   * [`GlassesEvent::AccGyro`], see [`ArEventData::acc_gyro`]
+   */
+  AR_EVENT_TAG_ACC_GYRO,
+  /**
+   * [`GlassesEvent::Accelerometer`], see [`ArEventData::sensor`]
+   */
+  AR_EVENT_TAG_ACCELEROMETER,
+  /**
+   * [`GlassesEvent::Gyroscope`], see [`ArEventData::sensor`]
+   */
+  AR_EVENT_TAG_GYROSCOPE,
+  /**
+   * [`GlassesEvent::Magnetometer`], see [`ArEventData::sensor`]
+   */
+  AR_EVENT_TAG_MAGNETOMETER,
+  /**
+   * [`GlassesEvent::KeyPress`], see [`ArEventData::key`]
+   */
+  AR_EVENT_TAG_KEY_PRESS,
+  /**
+   * [`GlassesEvent::ProximityNear`], no data
+   */
+  AR_EVENT_TAG_PROXIMITY_NEAR,
+  /**
+   * [`GlassesEvent::ProximityFar`], no data
+   */
+  AR_EVENT_TAG_PROXIMITY_FAR,
+  /**
+   * [`GlassesEvent::AmbientLight`], see [`ArEventData::ambient_light`]
+   */
+  AR_EVENT_TAG_AMBIENT_LIGHT,
+  /**
+   * [`GlassesEvent::BrightnessChanged`], see [`ArEventData::brightness`]
+   */
+  AR_EVENT_TAG_BRIGHTNESS_CHANGED,
+  /**
+   * [`GlassesEvent::VSync`], no data
+   */
+  AR_EVENT_TAG_V_SYNC,
+  /**
+   * [`GlassesEvent::HeartbeatDue`], no data
+   */
+  AR_EVENT_TAG_HEARTBEAT_DUE,
+  /**
+   * [`GlassesEvent::ProtocolResync`], no data
+   */
+  AR_EVENT_TAG_PROTOCOL_RESYNC,
+  /**
+   * [`GlassesEvent::DisplayLink`], see [`ArEventData::display_link`]
+   */
+  AR_EVENT_TAG_DISPLAY_LINK,
+  /**
+   * [`GlassesEvent::TransportWarning`], see [`ArEventData::transport_warning`]
+   */
+  AR_EVENT_TAG_TRANSPORT_WARNING,
+  /**
+   * [`GlassesEvent::DeviceError`], see [`ArEventData::device_error`]
+   */
+  AR_EVENT_TAG_DEVICE_ERROR,
+  /**
+   * [`GlassesEvent::Still`], no data
+   */
+  AR_EVENT_TAG_STILL,
+  /**
+   * [`GlassesEvent::Moving`], no data
+   */
+  AR_EVENT_TAG_MOVING,
+  /**
+   * [`GlassesEvent::MotionWake`], no data
+   */
+  AR_EVENT_TAG_MOTION_WAKE,
+  /**
+   * [`GlassesEvent::Gesture`], see [`ArEventData::gesture`]
+   */
+  AR_EVENT_TAG_GESTURE,
+  /**
+   * [`GlassesEvent::SessionRestored`], no data
+   */
+  AR_EVENT_TAG_SESSION_RESTORED,
+  /**
+   * [`GlassesEvent::Resumed`], see [`ArEventData::resumed`]
+   */
+  AR_EVENT_TAG_RESUMED,
+  /**
+   * [`GlassesEvent::CalibrationDrift`], see [`ArEventData::suggested_bias`]
+   */
+  AR_EVENT_TAG_CALIBRATION_DRIFT,
+  /**
+   * [`GlassesEvent::Overflow`], see [`ArEventData::overflow`]
+   */
+  AR_EVENT_TAG_OVERFLOW,
+} ar_event_tag;
+
+/**
+ * Opaque handle of a connected glasses, see [`ar_connect`]
+ */
+typedef struct ar_glasses_t ar_glasses_t;
+
+/**
+ * A 3D vector, in the units of the event it belongs to
+ */
+typedef struct ar_vector3_t {
+  /**
+   * Positive is right
+   */
+  float x;
+  /**
+   * Positive is up
+   */
+  float y;
+  /**
+   * Positive is backwards
+   */
+  float z;
+} ar_vector3_t;
+
+/**
+ * Data of [`ArEventTag::AccGyro`]
+ */
+typedef struct ar_acc_gyro_t {
+  /**
+   * In m/s²
+   */
+  struct ar_vector3_t accelerometer;
+  /**
+   * In rad/s
+   */
+  struct ar_vector3_t gyroscope;
+  /**
+   * Device time, in microseconds
+   */
+  uint64_t timestamp_us;
+  /**
+   * Bits of [`crate::SampleFlags`]
+   */
+  uint8_t flags;
+} ar_acc_gyro_t;
+
+/**
+ * Data of [`ArEventTag::Accelerometer`], [`ArEventTag::Gyroscope`] and
+ * [`ArEventTag::Magnetometer`]
+ */
+typedef struct ar_sensor_t {
+  /**
+   * In m/s², rad/s or uT respectively
+   */
+  struct ar_vector3_t vector;
+  /**
+   * Device time, in microseconds
+   */
+  uint64_t timestamp_us;
+} ar_sensor_t;
+
+/**
+ * Data of [`ArEventTag::DisplayLink`]. The detail string is not available.
+ */
+typedef struct ar_display_link_t {
+  /**
+   * Whether the link is up after the change
+   */
+  bool up;
+  /**
+   * True if the state was deduced from indirect signs
+   */
+  bool inferred;
+} ar_display_link_t;
+
+/**
+ * Data of [`ArEventTag::TransportWarning`]
+ */
+typedef struct ar_transport_warning_t {
+  /**
+   * 0: pipe, 1: overflow, 2: interrupted, 3: reopened.
+   * See [`TransportErrorKind`]
+   */
+  uint32_t kind;
+  /**
+   * Number of recovered errors since the previous warning
+   */
+  uint32_t count;
+} ar_transport_warning_t;
+
+/**
+ * Data of [`ArEventTag::DeviceError`]. The payload and message are not available.
+ */
+typedef struct ar_device_error_t {
+  /**
+   * 0: DisplayPort link failure, 1: thermal throttle, 2: sensor fault,
+   * 3: firmware assert, 4: unknown. See [`DeviceErrorKind`]
+   */
+  uint32_t kind;
+} ar_device_error_t;
+
+/**
+ * Payload of an [`ArEvent`]. Only the member named by the tag is valid.
+ */
+typedef union ar_event_data_t {
+  /**
+   * See [`ArEventTag::AccGyro`]
+   */
+  struct ar_acc_gyro_t acc_gyro;
+  /**
+   * See [`ArEventTag::Accelerometer`], [`ArEventTag::Gyroscope`] and
+   * [`ArEventTag::Magnetometer`]
+   */
+  struct ar_sensor_t sensor;
+  /**
+   * Key ID, see [`ArEventTag::KeyPress`]
+   */
+  uint8_t key;
+  /**
+   * Vendor specific level, see [`ArEventTag::AmbientLight`]
+   */
+  uint16_t ambient_light;
+  /**
+   * Device specific level, see [`ArEventTag::BrightnessChanged`]
+   */
+  uint8_t brightness;
+  /**
+   * See [`ArEventTag::DisplayLink`]
+   */
+  struct ar_display_link_t display_link;
+  /**
+   * See [`ArEventTag::TransportWarning`]
+   */
+  struct ar_transport_warning_t transport_warning;
+  /**
+   * See [`ArEventTag::DeviceError`]
+   */
+  struct ar_device_error_t device_error;
+  /**
+   * 0: nod, 1: shake. See [`ArEventTag::Gesture`]
+   */
+  uint32_t gesture;
+  /**
+   * Length of the disconnection in microseconds, see [`ArEventTag::Resumed`]
+   */
+  uint64_t resumed;
+  /**
+   * In rad/s, see [`ArEventTag::CalibrationDrift`]
+   */
+  struct ar_vector3_t suggested_bias;
+  /**
+   * Number of dropped events, see [`ArEventTag::Overflow`]
+   */
+  uint64_t overflow;
+} ar_event_data_t;
+
+/**
+ * A [`GlassesEvent`] flattened into a tagged union, see [`ar_read_event`]
+ */
+typedef struct ar_event_t {
+  /**
+   * Which member of `data` is valid
+   */
+  enum ar_event_tag tag;
+  /**
+   * The payload
+   */
+  union ar_event_data_t data;
+} ar_event_t;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Connect to any of the supported glasses, see [`any_glasses`].
+ * Returns `NULL` on failure.
+ */
+struct ar_glasses_t *ar_connect(void);
+
+/**
+ * Disconnect and free the glasses. `NULL` is ignored.
+ *
+ * # Safety
+ * `glasses` must be `NULL` or a handle returned by [`ar_connect`], that was not yet
+ * disconnected. It must not be used afterwards.
+ */
+void ar_disconnect(struct ar_glasses_t *glasses);
+
+/**
+ * Read a single event into `event`, blocking until one arrives, see
+ * [`ARGlasses::read_event`]. Returns 0 on success, and -1 on failure.
+ *
+ * # Safety
+ * `glasses` must be a valid handle returned by [`ar_connect`], and `event` must
+ * point to writable memory for an [`ArEvent`].
+ */
+int ar_read_event(struct ar_glasses_t *glasses, struct ar_event_t *event);
+
+/**
+ * Set the display mode, see [`ARGlasses::set_display_mode`]. `display_mode` is one of
+ * the [`ArDisplayMode`] values. Returns 0 on success, and -1 on failure, including
+ * an unknown `display_mode`.
+ *
+ * # Safety
+ * `glasses` must be a valid handle returned by [`ar_connect`]
+ */
+int ar_set_display_mode(struct ar_glasses_t *glasses, uint32_t display_mode);
+
+/**
+ * Message of the last error that happened on this thread, as a NUL terminated UTF-8
+ * string. Empty if there was no error. Valid until the next failing call on the same
+ * thread, and must not be freed.
+ */
+const char *ar_last_error(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* AR_DRIVERS_H */
@@ -0,0 +1,265 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Reading events from async code. See [`AsyncARGlasses`]
+
+use std::{
+    future::poll_fn,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
+};
+
+use crate::{ARGlasses, Error, EventQueue, GlassesEvent, Result};
+
+type Glasses = Box<dyn ARGlasses + Send>;
+
+/// Glasses driven by a background thread, with events that can be awaited in any async
+/// runtime (tokio, async-std, etc.), without blocking it.
+///
+/// The thread calls [`ARGlasses::read_event`] continuously, so the Nreal Light's
+/// heartbeat is sent in time. The events are buffered in an [`EventQueue`] until they
+/// are read with [`AsyncARGlasses::read_event`].
+///
+/// [`Error::PacketTimeout`] is not fatal for most glasses, so it is skipped. Any other
+/// error (e.g. [`Error::Disconnected`], or a full queue with [`crate::OverflowPolicy::Error`])
+/// stops the thread, and is returned after the queued events. Later reads (and all reads
+/// after a panic in the thread) return [`Error::Disconnected`].
+pub struct AsyncARGlasses {
+    glasses: Arc<Mutex<Glasses>>,
+    shared: Arc<Mutex<Shared>>,
+    /// Signalled when a [`AsyncARGlasses::with_glasses`] call got the glasses
+    handoff: Arc<Condvar>,
+    name: &'static str,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    queue: EventQueue,
+    /// The error that stopped the thread, until it's returned
+    error: Option<Error>,
+    finished: bool,
+    stop: bool,
+    waker: Option<Waker>,
+    /// Number of [`AsyncARGlasses::with_glasses`] calls waiting for the glasses. The
+    /// thread doesn't start a new read until they got them, as the mutex is not fair.
+    waiting_calls: usize,
+}
+
+impl AsyncARGlasses {
+    /// Start reading the glasses in a background thread, buffering the events in `queue`
+    /// (e.g. `EventQueue::new(1024)`)
+    pub fn new(glasses: Glasses, queue: EventQueue) -> Result<Self> {
+        let name = glasses.name();
+        let glasses = Arc::new(Mutex::new(glasses));
+        let shared = Arc::new(Mutex::new(Shared {
+            queue,
+            error: None,
+            finished: false,
+            stop: false,
+            waker: None,
+            waiting_calls: 0,
+        }));
+        let handoff = Arc::new(Condvar::new());
+        let thread = {
+            let glasses = glasses.clone();
+            let shared = shared.clone();
+            let handoff = handoff.clone();
+            std::thread::Builder::new()
+                .name("ar-drivers events".into())
+                .spawn(move || {
+                    let _finish = FinishOnExit(&shared);
+                    read_events(&glasses, &shared, &handoff);
+                })?
+        };
+        Ok(Self {
+            glasses,
+            shared,
+            handoff,
+            name,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wait for the next event
+    pub async fn read_event(&mut self) -> Result<GlassesEvent> {
+        poll_fn(|cx| self.poll_read_event(cx)).await
+    }
+
+    /// Poll version of [`AsyncARGlasses::read_event`], e.g. for implementing a `Stream`
+    pub fn poll_read_event(&mut self, cx: &mut Context<'_>) -> Poll<Result<GlassesEvent>> {
+        let mut shared = lock(&self.shared);
+        if let Some(event) = shared.queue.pop() {
+            return Poll::Ready(Ok(event));
+        }
+        if let Some(error) = shared.error.take() {
+            return Poll::Ready(Err(error));
+        }
+        if shared.finished {
+            return Poll::Ready(Err(Error::disconnected(self.name)));
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Call a method of the glasses, e.g. [`ARGlasses::set_display_mode`].
+    ///
+    /// This blocks until the background thread finishes its current read, which
+    /// usually takes a few milliseconds, but can be up to a second. Use e.g. tokio's
+    /// `spawn_blocking` if that's too long for the runtime. The background thread
+    /// waits for the call to get the glasses before reading again.
+    pub fn with_glasses<R>(&self, f: impl FnOnce(&mut dyn ARGlasses) -> R) -> R {
+        lock(&self.shared).waiting_calls += 1;
+        let mut glasses = lock(&self.glasses);
+        lock(&self.shared).waiting_calls -= 1;
+        self.handoff.notify_all();
+        f(glasses.as_mut())
+    }
+}
+
+impl Drop for AsyncARGlasses {
+    /// Stops the background thread, waiting for its current read to finish,
+    /// so that the glasses are released when this returns
+    fn drop(&mut self) {
+        lock(&self.shared).stop = true;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn read_events(glasses: &Mutex<Glasses>, shared: &Mutex<Shared>, handoff: &Condvar) {
+    loop {
+        let mut state = lock(shared);
+        if state.stop {
+            return;
+        }
+        while state.waiting_calls > 0 {
+            state = handoff
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        drop(state);
+        let result = lock(glasses).read_event();
+        let mut shared = lock(shared);
+        if let Err(error) = result.and_then(|event| shared.queue.push(event)) {
+            if !matches!(error, Error::PacketTimeout) {
+                shared.error = Some(error);
+                return;
+            }
+        }
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Marks the reading as finished when the thread exits, even by panicking
+struct FinishOnExit<'a>(&'a Mutex<Shared>);
+
+impl Drop for FinishOnExit<'_> {
+    fn drop(&mut self) {
+        let mut shared = lock(self.0);
+        shared.finished = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Lock, ignoring poisoning: a panic in a driver stops the thread, but the state is
+/// still consistent
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use nalgebra::Isometry3;
+
+    use super::*;
+    use crate::{DisplayMode, MotionProfile, Side, SyntheticImu};
+
+    /// Synthetic glasses blocking in reads like a 1kHz IMU: the lock is only released
+    /// between two reads
+    struct Blocking(SyntheticImu);
+
+    impl ARGlasses for Blocking {
+        fn serial(&mut self) -> Result<String> {
+            self.0.serial()
+        }
+
+        fn read_event(&mut self) -> Result<GlassesEvent> {
+            std::thread::sleep(Duration::from_millis(1));
+            self.0.read_event()
+        }
+
+        fn get_display_mode(&mut self) -> Result<DisplayMode> {
+            self.0.get_display_mode()
+        }
+
+        fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
+            self.0.set_display_mode(display_mode)
+        }
+
+        fn display_fov(&self) -> f32 {
+            self.0.display_fov()
+        }
+
+        fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
+            self.0.imu_to_display_matrix(side, ipd)
+        }
+
+        fn name(&self) -> &'static str {
+            self.0.name()
+        }
+
+        fn display_delay(&self) -> u64 {
+            self.0.display_delay()
+        }
+    }
+
+    fn next_event(glasses: &mut AsyncARGlasses) -> Result<GlassesEvent> {
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(event) = glasses.poll_read_event(&mut context) {
+                return event;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn calls_are_not_starved() {
+        let mut glasses = AsyncARGlasses::new(
+            Box::new(Blocking(SyntheticImu::new(MotionProfile::Stationary))),
+            EventQueue::new(1024),
+        )
+        .unwrap();
+        // Wait for the thread to start reading
+        assert!(matches!(
+            next_event(&mut glasses),
+            Ok(GlassesEvent::AccGyro { .. })
+        ));
+
+        let (done, finished) = mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    glasses.with_glasses(|glasses| glasses.imu_enabled());
+                }
+                done.send(()).unwrap();
+            });
+            finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        });
+        assert!(matches!(
+            next_event(&mut glasses),
+            Ok(GlassesEvent::AccGyro { .. })
+        ));
+    }
+}
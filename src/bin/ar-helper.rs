@@ -0,0 +1,159 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Small privileged helper owning the glasses, and publishing them to unprivileged
+//! applications with an `EventServer`. Applications connect with `RemoteGlasses`.
+//!
+//! Usage: `ar-helper [SOCKET_PATH] [--allow-uid UID]... [--drop-all]`
+//!
+//! The helper can be installed setuid root (or run with a udev rule granting it the
+//! devices). The elevated effective user ID is only used while opening the glasses, both
+//! at startup and when reconnecting after a replug, and the socket is created as the
+//! user running the helper. Only that user (and the ones allowed with `--allow-uid`)
+//! can connect. The default socket path is `$XDG_RUNTIME_DIR/ar-drivers.sock`.
+//!
+//! To be able to reopen the glasses, the privileged user ID is kept as the saved user ID
+//! for the lifetime of the process, so a bug in the helper could still be used to regain
+//! it. With `--drop-all`, it is dropped for good after the glasses are first opened, and
+//! reconnecting only works if the invoking user can access the devices.
+//! While privileged, the device lock files are in `/run/ar-drivers`, not in `TMPDIR`.
+
+#[cfg(unix)]
+use std::{path::PathBuf, time::Duration};
+
+#[cfg(unix)]
+use ar_drivers::{server::EventServer, AutoResume, Error};
+
+#[cfg(unix)]
+type Result<T> = std::result::Result<T, Error>;
+
+/// Time to wait between reads while the glasses are gone
+#[cfg(unix)]
+const DISCONNECTED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(unix)]
+fn main() {
+    // Run as the invoking user, except when opening the glasses
+    let privileged_uid = unsafe { libc::geteuid() };
+    let result = set_user_ids(real_uid(), privileged_uid).and_then(|_| run(privileged_uid));
+    if let Err(e) = result {
+        eprintln!("ar-helper: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("ar-helper is only supported on Unix");
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+fn run(privileged_uid: u32) -> Result<()> {
+    let mut socket_path = None;
+    let mut allowed_uids = vec![real_uid()];
+    let mut drop_all = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--drop-all" {
+            drop_all = true;
+        } else if arg == "--allow-uid" {
+            let uid = args.next().and_then(|uid| uid.parse().ok());
+            allowed_uids.push(uid.ok_or(Error::Other("--allow-uid needs a numeric user ID"))?);
+        } else {
+            socket_path = Some(PathBuf::from(arg));
+        }
+    }
+    let socket_path = socket_path.unwrap_or_else(|| {
+        std::env::var_os("XDG_RUNTIME_DIR")
+            .map_or_else(std::env::temp_dir, PathBuf::from)
+            .join("ar-drivers.sock")
+    });
+
+    let mut dropped = false;
+    let connect = move || {
+        if dropped {
+            return ar_drivers::any_glasses();
+        }
+        let glasses = with_privileges(privileged_uid, ar_drivers::any_glasses)?;
+        if drop_all {
+            set_user_ids(real_uid(), real_uid())?;
+            dropped = true;
+        }
+        Ok(glasses)
+    };
+    let mut glasses = AutoResume::new(connect, None)?;
+    let mut server =
+        EventServer::bind(&socket_path, glasses.glasses()?)?.with_allowed_uids(allowed_uids);
+    println!("Serving on {}", socket_path.display());
+    loop {
+        match glasses.read_event() {
+            // Only the clients are affected, the glasses are kept serving
+            Ok(event) => {
+                if let Err(e) = server.publish(&event) {
+                    eprintln!("ar-helper: {e}");
+                }
+            }
+            // Wait for AutoResume to reconnect
+            Err(Error::Disconnected { .. }) => std::thread::sleep(DISCONNECTED_POLL_INTERVAL),
+            Err(Error::PacketTimeout) => (),
+            Err(e) => return Err(e),
+        }
+        if let Ok(glasses) = glasses.glasses() {
+            if let Err(e) = server.handle_commands(glasses) {
+                eprintln!("ar-helper: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn real_uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// Set the effective and saved user IDs, and the real one to the invoking user
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "openbsd"
+))]
+fn set_user_ids(effective_uid: u32, saved_uid: u32) -> Result<()> {
+    if unsafe { libc::setresuid(real_uid(), effective_uid, saved_uid) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Set the effective and saved user IDs, and the real one to the invoking user
+#[cfg(unix)]
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "openbsd"
+)))]
+fn set_user_ids(effective_uid: u32, saved_uid: u32) -> Result<()> {
+    // Without setresuid, setreuid sets the saved user ID to the new effective one,
+    // while seteuid keeps it
+    let result = if effective_uid == saved_uid {
+        unsafe { libc::setreuid(real_uid(), effective_uid) }
+    } else {
+        unsafe { libc::seteuid(effective_uid) }
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Run `f` with the privileged user ID (the effective one at startup) as the effective one
+#[cfg(unix)]
+fn with_privileges<T>(privileged_uid: u32, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    set_user_ids(privileged_uid, privileged_uid)?;
+    let result = f();
+    set_user_ids(real_uid(), privileged_uid)?;
+    result
+}
@@ -0,0 +1,488 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! C API, for using the drivers from other languages (e.g. a C++ game engine plugin).
+//! Only available with the `capi` feature.
+//!
+//! The header is `include/ar_drivers.h`, generated with
+//! `cbindgen --config cbindgen.toml --output include/ar_drivers.h`.
+//! Build a static or dynamic library with e.g.
+//! `cargo rustc --release --features capi --crate-type staticlib`.
+//!
+//! Glasses are connected with [`ar_connect`], which returns an opaque handle, and have to
+//! be freed with [`ar_disconnect`]. Events are copied into a caller owned [`ArEvent`], so
+//! there is nothing else to free. Failing functions return a negative value or `NULL`, and
+//! the error message can be retrieved with [`ar_last_error`].
+//!
+//! A handle may be moved between threads, but must not be used by two threads at once.
+//! Panics are not propagated: they abort the process.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CString},
+    ptr,
+};
+
+use crate::{
+    any_glasses, ARGlasses, DeviceErrorKind, DisplayMode, Error, Gesture, GlassesEvent,
+    TransportErrorKind,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Opaque handle of a connected glasses, see [`ar_connect`]
+pub struct ArGlasses(Box<dyn ARGlasses>);
+
+/// Kind of an [`ArEvent`], and which member of [`ArEventData`] is valid
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArEventTag {
+    /// [`GlassesEvent::AccGyro`], see [`ArEventData::acc_gyro`]
+    AccGyro,
+    /// [`GlassesEvent::Accelerometer`], see [`ArEventData::sensor`]
+    Accelerometer,
+    /// [`GlassesEvent::Gyroscope`], see [`ArEventData::sensor`]
+    Gyroscope,
+    /// [`GlassesEvent::Magnetometer`], see [`ArEventData::sensor`]
+    Magnetometer,
+    /// [`GlassesEvent::KeyPress`], see [`ArEventData::key`]
+    KeyPress,
+    /// [`GlassesEvent::ProximityNear`], no data
+    ProximityNear,
+    /// [`GlassesEvent::ProximityFar`], no data
+    ProximityFar,
+    /// [`GlassesEvent::AmbientLight`], see [`ArEventData::ambient_light`]
+    AmbientLight,
+    /// [`GlassesEvent::BrightnessChanged`], see [`ArEventData::brightness`]
+    BrightnessChanged,
+    /// [`GlassesEvent::VSync`], no data
+    VSync,
+    /// [`GlassesEvent::HeartbeatDue`], no data
+    HeartbeatDue,
+    /// [`GlassesEvent::ProtocolResync`], no data
+    ProtocolResync,
+    /// [`GlassesEvent::DisplayLink`], see [`ArEventData::display_link`]
+    DisplayLink,
+    /// [`GlassesEvent::TransportWarning`], see [`ArEventData::transport_warning`]
+    TransportWarning,
+    /// [`GlassesEvent::DeviceError`], see [`ArEventData::device_error`]
+    DeviceError,
+    /// [`GlassesEvent::Still`], no data
+    Still,
+    /// [`GlassesEvent::Moving`], no data
+    Moving,
+    /// [`GlassesEvent::MotionWake`], no data
+    MotionWake,
+    /// [`GlassesEvent::Gesture`], see [`ArEventData::gesture`]
+    Gesture,
+    /// [`GlassesEvent::SessionRestored`], no data
+    SessionRestored,
+    /// [`GlassesEvent::Resumed`], see [`ArEventData::resumed`]
+    Resumed,
+    /// [`GlassesEvent::CalibrationDrift`], see [`ArEventData::suggested_bias`]
+    CalibrationDrift,
+    /// [`GlassesEvent::Overflow`], see [`ArEventData::overflow`]
+    Overflow,
+}
+
+/// A 3D vector, in the units of the event it belongs to
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArVector3 {
+    /// Positive is right
+    pub x: f32,
+    /// Positive is up
+    pub y: f32,
+    /// Positive is backwards
+    pub z: f32,
+}
+
+/// Data of [`ArEventTag::AccGyro`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArAccGyro {
+    /// In m/s²
+    pub accelerometer: ArVector3,
+    /// In rad/s
+    pub gyroscope: ArVector3,
+    /// Device time, in microseconds
+    pub timestamp_us: u64,
+    /// Bits of [`crate::SampleFlags`]
+    pub flags: u8,
+}
+
+/// Data of [`ArEventTag::Accelerometer`], [`ArEventTag::Gyroscope`] and
+/// [`ArEventTag::Magnetometer`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArSensor {
+    /// In m/s², rad/s or uT respectively
+    pub vector: ArVector3,
+    /// Device time, in microseconds
+    pub timestamp_us: u64,
+}
+
+/// Data of [`ArEventTag::DisplayLink`]. The detail string is not available.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArDisplayLink {
+    /// Whether the link is up after the change
+    pub up: bool,
+    /// True if the state was deduced from indirect signs
+    pub inferred: bool,
+}
+
+/// Data of [`ArEventTag::TransportWarning`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArTransportWarning {
+    /// 0: pipe, 1: overflow, 2: interrupted, 3: reopened.
+    /// See [`TransportErrorKind`]
+    pub kind: u32,
+    /// Number of recovered errors since the previous warning
+    pub count: u32,
+}
+
+/// Data of [`ArEventTag::DeviceError`]. The payload and message are not available.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArDeviceError {
+    /// 0: DisplayPort link failure, 1: thermal throttle, 2: sensor fault,
+    /// 3: firmware assert, 4: unknown. See [`DeviceErrorKind`]
+    pub kind: u32,
+}
+
+/// Payload of an [`ArEvent`]. Only the member named by the tag is valid.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union ArEventData {
+    /// See [`ArEventTag::AccGyro`]
+    pub acc_gyro: ArAccGyro,
+    /// See [`ArEventTag::Accelerometer`], [`ArEventTag::Gyroscope`] and
+    /// [`ArEventTag::Magnetometer`]
+    pub sensor: ArSensor,
+    /// Key ID, see [`ArEventTag::KeyPress`]
+    pub key: u8,
+    /// Vendor specific level, see [`ArEventTag::AmbientLight`]
+    pub ambient_light: u16,
+    /// Device specific level, see [`ArEventTag::BrightnessChanged`]
+    pub brightness: u8,
+    /// See [`ArEventTag::DisplayLink`]
+    pub display_link: ArDisplayLink,
+    /// See [`ArEventTag::TransportWarning`]
+    pub transport_warning: ArTransportWarning,
+    /// See [`ArEventTag::DeviceError`]
+    pub device_error: ArDeviceError,
+    /// 0: nod, 1: shake. See [`ArEventTag::Gesture`]
+    pub gesture: u32,
+    /// Length of the disconnection in microseconds, see [`ArEventTag::Resumed`]
+    pub resumed: u64,
+    /// In rad/s, see [`ArEventTag::CalibrationDrift`]
+    pub suggested_bias: ArVector3,
+    /// Number of dropped events, see [`ArEventTag::Overflow`]
+    pub overflow: u64,
+}
+
+/// A [`GlassesEvent`] flattened into a tagged union, see [`ar_read_event`]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ArEvent {
+    /// Which member of `data` is valid
+    pub tag: ArEventTag,
+    /// The payload
+    pub data: ArEventData,
+}
+
+/// Display mode, see [`DisplayMode`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArDisplayMode {
+    /// See [`DisplayMode::SameOnBoth`]
+    SameOnBoth,
+    /// See [`DisplayMode::Stereo`]
+    Stereo,
+    /// See [`DisplayMode::HalfSBS`]
+    HalfSbs,
+    /// See [`DisplayMode::HighRefreshRate`]
+    HighRefreshRate,
+    /// See [`DisplayMode::HighRefreshRateSBS`]
+    HighRefreshRateSbs,
+}
+
+impl From<ArDisplayMode> for DisplayMode {
+    fn from(value: ArDisplayMode) -> Self {
+        match value {
+            ArDisplayMode::SameOnBoth => DisplayMode::SameOnBoth,
+            ArDisplayMode::Stereo => DisplayMode::Stereo,
+            ArDisplayMode::HalfSbs => DisplayMode::HalfSBS,
+            ArDisplayMode::HighRefreshRate => DisplayMode::HighRefreshRate,
+            ArDisplayMode::HighRefreshRateSbs => DisplayMode::HighRefreshRateSBS,
+        }
+    }
+}
+
+impl TryFrom<u32> for ArDisplayMode {
+    type Error = Error;
+
+    fn try_from(value: u32) -> crate::Result<Self> {
+        Ok(match value {
+            0 => ArDisplayMode::SameOnBoth,
+            1 => ArDisplayMode::Stereo,
+            2 => ArDisplayMode::HalfSbs,
+            3 => ArDisplayMode::HighRefreshRate,
+            4 => ArDisplayMode::HighRefreshRateSbs,
+            _ => return Err(Error::Other("Invalid display mode")),
+        })
+    }
+}
+
+fn vector(v: &nalgebra::Vector3<f32>) -> ArVector3 {
+    ArVector3 {
+        x: v.x,
+        y: v.y,
+        z: v.z,
+    }
+}
+
+impl From<&GlassesEvent> for ArEvent {
+    fn from(event: &GlassesEvent) -> Self {
+        // Only used for the events without data
+        let none = ArEventData { overflow: 0 };
+        let (tag, data) = match event {
+            GlassesEvent::AccGyro {
+                accelerometer,
+                gyroscope,
+                timestamp,
+                flags,
+            } => (
+                ArEventTag::AccGyro,
+                ArEventData {
+                    acc_gyro: ArAccGyro {
+                        accelerometer: vector(accelerometer),
+                        gyroscope: vector(gyroscope),
+                        timestamp_us: timestamp.as_micros(),
+                        flags: flags.0,
+                    },
+                },
+            ),
+            GlassesEvent::Accelerometer {
+                accelerometer: v,
+                timestamp,
+            } => (ArEventTag::Accelerometer, sensor(v, timestamp.as_micros())),
+            GlassesEvent::Gyroscope {
+                gyroscope: v,
+                timestamp,
+            } => (ArEventTag::Gyroscope, sensor(v, timestamp.as_micros())),
+            GlassesEvent::Magnetometer {
+                magnetometer: v,
+                timestamp,
+            } => (ArEventTag::Magnetometer, sensor(v, timestamp.as_micros())),
+            GlassesEvent::KeyPress(key) => (ArEventTag::KeyPress, ArEventData { key: *key }),
+            GlassesEvent::ProximityNear => (ArEventTag::ProximityNear, none),
+            GlassesEvent::ProximityFar => (ArEventTag::ProximityFar, none),
+            GlassesEvent::AmbientLight(level) => (
+                ArEventTag::AmbientLight,
+                ArEventData {
+                    ambient_light: *level,
+                },
+            ),
+            GlassesEvent::BrightnessChanged(level) => (
+                ArEventTag::BrightnessChanged,
+                ArEventData { brightness: *level },
+            ),
+            GlassesEvent::VSync => (ArEventTag::VSync, none),
+            GlassesEvent::HeartbeatDue => (ArEventTag::HeartbeatDue, none),
+            GlassesEvent::ProtocolResync => (ArEventTag::ProtocolResync, none),
+            GlassesEvent::DisplayLink { up, inferred, .. } => (
+                ArEventTag::DisplayLink,
+                ArEventData {
+                    display_link: ArDisplayLink {
+                        up: *up,
+                        inferred: *inferred,
+                    },
+                },
+            ),
+            GlassesEvent::TransportWarning { kind, count } => (
+                ArEventTag::TransportWarning,
+                ArEventData {
+                    transport_warning: ArTransportWarning {
+                        kind: match kind {
+                            TransportErrorKind::Pipe => 0,
+                            TransportErrorKind::Overflow => 1,
+                            TransportErrorKind::Interrupted => 2,
+                            TransportErrorKind::Reopened => 3,
+                        },
+                        count: *count,
+                    },
+                },
+            ),
+            GlassesEvent::DeviceError { kind, .. } => (
+                ArEventTag::DeviceError,
+                ArEventData {
+                    device_error: ArDeviceError {
+                        kind: match kind {
+                            DeviceErrorKind::DpLinkFailure => 0,
+                            DeviceErrorKind::ThermalThrottle => 1,
+                            DeviceErrorKind::SensorFault => 2,
+                            DeviceErrorKind::FirmwareAssert => 3,
+                            DeviceErrorKind::Unknown => 4,
+                        },
+                    },
+                },
+            ),
+            GlassesEvent::Still => (ArEventTag::Still, none),
+            GlassesEvent::Moving => (ArEventTag::Moving, none),
+            GlassesEvent::MotionWake => (ArEventTag::MotionWake, none),
+            GlassesEvent::Gesture(gesture) => (
+                ArEventTag::Gesture,
+                ArEventData {
+                    gesture: match gesture {
+                        Gesture::Nod => 0,
+                        Gesture::Shake => 1,
+                    },
+                },
+            ),
+            GlassesEvent::SessionRestored => (ArEventTag::SessionRestored, none),
+            GlassesEvent::Resumed { gap } => (
+                ArEventTag::Resumed,
+                ArEventData {
+                    resumed: gap.as_micros() as u64,
+                },
+            ),
+            GlassesEvent::CalibrationDrift { suggested_bias } => (
+                ArEventTag::CalibrationDrift,
+                ArEventData {
+                    suggested_bias: vector(suggested_bias),
+                },
+            ),
+            GlassesEvent::Overflow { dropped } => (
+                ArEventTag::Overflow,
+                ArEventData {
+                    overflow: *dropped as u64,
+                },
+            ),
+        };
+        ArEvent { tag, data }
+    }
+}
+
+fn sensor(v: &nalgebra::Vector3<f32>, timestamp_us: u64) -> ArEventData {
+    ArEventData {
+        sensor: ArSensor {
+            vector: vector(v),
+            timestamp_us,
+        },
+    }
+}
+
+fn set_last_error(error: &Error) {
+    // Error messages never contain NUL bytes, but don't panic if one does
+    let message = CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+fn status(result: crate::Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            set_last_error(&error);
+            -1
+        }
+    }
+}
+
+/// Connect to any of the supported glasses, see [`any_glasses`].
+/// Returns `NULL` on failure.
+#[no_mangle]
+pub extern "C" fn ar_connect() -> *mut ArGlasses {
+    match any_glasses() {
+        Ok(glasses) => Box::into_raw(Box::new(ArGlasses(glasses))),
+        Err(error) => {
+            set_last_error(&error);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Disconnect and free the glasses. `NULL` is ignored.
+///
+/// # Safety
+/// `glasses` must be `NULL` or a handle returned by [`ar_connect`], that was not yet
+/// disconnected. It must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn ar_disconnect(glasses: *mut ArGlasses) {
+    if !glasses.is_null() {
+        drop(Box::from_raw(glasses));
+    }
+}
+
+/// Read a single event into `event`, blocking until one arrives, see
+/// [`ARGlasses::read_event`]. Returns 0 on success, and -1 on failure.
+///
+/// # Safety
+/// `glasses` must be a valid handle returned by [`ar_connect`], and `event` must
+/// point to writable memory for an [`ArEvent`].
+#[no_mangle]
+pub unsafe extern "C" fn ar_read_event(glasses: *mut ArGlasses, event: *mut ArEvent) -> c_int {
+    let glasses = &mut *glasses;
+    status(
+        glasses
+            .0
+            .read_event()
+            .map(|e| event.write(ArEvent::from(&e))),
+    )
+}
+
+/// Set the display mode, see [`ARGlasses::set_display_mode`]. `display_mode` is one of
+/// the [`ArDisplayMode`] values. Returns 0 on success, and -1 on failure, including
+/// an unknown `display_mode`.
+///
+/// # Safety
+/// `glasses` must be a valid handle returned by [`ar_connect`]
+#[no_mangle]
+pub unsafe extern "C" fn ar_set_display_mode(glasses: *mut ArGlasses, display_mode: u32) -> c_int {
+    let glasses = &mut *glasses;
+    status(
+        ArDisplayMode::try_from(display_mode)
+            .and_then(|display_mode| glasses.0.set_display_mode(display_mode.into())),
+    )
+}
+
+/// Message of the last error that happened on this thread, as a NUL terminated UTF-8
+/// string. Empty if there was no error. Valid until the next failing call on the same
+/// thread, and must not be freed.
+#[no_mangle]
+pub extern "C" fn ar_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+    use crate::{MotionProfile, SyntheticImu};
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(ar_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn set_display_mode_validates() {
+        let mut glasses = ArGlasses(Box::new(SyntheticImu::new(MotionProfile::Stationary)));
+        assert_eq!(unsafe { ar_set_display_mode(&mut glasses, 5) }, -1);
+        assert_eq!(last_error(), "Invalid display mode");
+        // Valid values reach the driver, which has no display
+        assert_eq!(unsafe { ar_set_display_mode(&mut glasses, 0) }, -1);
+        assert!(last_error().starts_with("Not supported"));
+        assert_eq!(
+            ArDisplayMode::try_from(ArDisplayMode::HighRefreshRateSbs as u32).unwrap(),
+            ArDisplayMode::HighRefreshRateSbs
+        );
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Source of the current time for the time based logic. See [`Clock`]
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// Where the drivers and wrappers get the current time from, and how they wait.
+/// Everything uses [`SystemClock`] by default, other clocks are mainly for testing
+/// heartbeats, timeouts and reconnection without waiting in real time.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, like [`Instant::now`]
+    fn now(&self) -> Instant;
+    /// Wall clock time, like [`SystemTime::now`]. Unlike [`Clock::now`], it keeps
+    /// going while the host is suspended.
+    fn wall_now(&self) -> SystemTime;
+    /// Block the calling thread, like [`std::thread::sleep`]
+    fn sleep(&self, duration: Duration);
+}
+
+/// The clocks of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// Clock that only advances when told to. Sleeping advances it immediately.
+#[cfg(test)]
+pub(crate) struct MockClock {
+    times: std::sync::Mutex<(Instant, SystemTime)>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            times: std::sync::Mutex::new((Instant::now(), SystemTime::now())),
+        })
+    }
+
+    /// Advance both clocks
+    pub fn advance(&self, duration: Duration) {
+        let mut times = self.times.lock().unwrap();
+        times.0 += duration;
+        times.1 += duration;
+    }
+
+    /// Advance the wall clock only, as if the host was suspended
+    pub fn suspend(&self, duration: Duration) {
+        self.times.lock().unwrap().1 += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.times.lock().unwrap().0
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        self.times.lock().unwrap().1
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
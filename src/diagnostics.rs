@@ -0,0 +1,280 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Runtime statistics about a glasses connection. See [`Diagnostics`]
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{DeviceTimestamp, GlassesEvent};
+
+/// Number of event ages kept for the percentile calculations
+const AGE_WINDOW_SIZE: usize = 1024;
+/// Number of IMU sample intervals kept for the rate and jitter calculations
+const INTERVAL_WINDOW_SIZE: usize = 1024;
+/// Time window of [`Diagnostics::longest_imu_gap`]
+const GAP_WINDOW: Duration = Duration::from_secs(60);
+
+/// Timing information about a single event, see [`crate::ARGlasses::last_event_meta`]
+#[derive(Debug, Clone, Copy)]
+pub struct EventMeta {
+    /// Host time at which the USB report carrying the event was read
+    pub received_at: Instant,
+    /// Estimated age of the data when it was received, i.e. how stale it already was.
+    ///
+    /// The device clock is mapped to the host clock by assuming that the fastest
+    /// delivery seen so far had zero latency, so this is the latency on top of the
+    /// best case, not an absolute value. Only available for events with a device timestamp.
+    pub age: Option<Duration>,
+    /// True if the event was synthesized by polling the device state, instead of being
+    /// reported by the device. See [`crate::nreal_air::NrealAir::set_state_polling`]
+    pub polled: bool,
+}
+
+/// Runtime statistics collected by a driver, see [`crate::ARGlasses::diagnostics`]
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    epoch: Instant,
+    /// Smallest (host time - device time) seen so far, in usecs
+    min_clock_offset: Option<i64>,
+    event_ages: VecDeque<u64>,
+    /// Device and host time of the previous IMU sample
+    last_imu_sample: Option<(DeviceTimestamp, Instant)>,
+    /// Time between consecutive IMU samples according to the device, in usecs
+    imu_device_intervals: VecDeque<u64>,
+    /// Time between receiving consecutive IMU samples, in usecs
+    imu_host_intervals: VecDeque<u64>,
+    /// Host time and length of the gaps between IMU samples in the last minute,
+    /// with decreasing lengths, so that the first is the longest one
+    imu_gaps: VecDeque<(Instant, Duration)>,
+    pub(crate) protocol_resyncs: u64,
+    pub(crate) display_link_flaps: u64,
+    pub(crate) transport_recoveries: u64,
+    pub(crate) debounced_key_presses: u64,
+    pub(crate) repeated_state_events: u64,
+    clipped_samples: u64,
+    pub(crate) implausible_timestamps: u64,
+    pub(crate) connect_duration: Option<Duration>,
+    pub(crate) used_discovery_cache: bool,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            min_clock_offset: None,
+            event_ages: VecDeque::with_capacity(AGE_WINDOW_SIZE),
+            last_imu_sample: None,
+            imu_device_intervals: VecDeque::with_capacity(INTERVAL_WINDOW_SIZE),
+            imu_host_intervals: VecDeque::with_capacity(INTERVAL_WINDOW_SIZE),
+            imu_gaps: VecDeque::new(),
+            protocol_resyncs: 0,
+            display_link_flaps: 0,
+            transport_recoveries: 0,
+            debounced_key_presses: 0,
+            repeated_state_events: 0,
+            clipped_samples: 0,
+            implausible_timestamps: 0,
+            connect_duration: None,
+            used_discovery_cache: false,
+        }
+    }
+}
+
+impl Diagnostics {
+    /// Median of the recent event ages. See [`EventMeta::age`]
+    pub fn event_age_p50(&self) -> Option<Duration> {
+        self.event_age_percentile(0.5)
+    }
+
+    /// 99th percentile of the recent event ages. See [`EventMeta::age`]
+    pub fn event_age_p99(&self) -> Option<Duration> {
+        self.event_age_percentile(0.99)
+    }
+
+    /// Arbitrary percentile (0.0 - 1.0) of the recent event ages. See [`EventMeta::age`]
+    pub fn event_age_percentile(&self, percentile: f32) -> Option<Duration> {
+        window_percentile(&self.event_ages, percentile)
+    }
+
+    /// Effective IMU sample rate in Hz, according to the device timestamps of the
+    /// recent samples. Only measured by the Nreal drivers.
+    pub fn imu_sample_rate(&self) -> Option<f32> {
+        let total: u64 = self.imu_device_intervals.iter().sum();
+        (total > 0).then(|| self.imu_device_intervals.len() as f32 * 1_000_000.0 / total as f32)
+    }
+
+    /// Arbitrary percentile (0.0 - 1.0) of the time between recent IMU samples.
+    /// With `host_time`, the time between receiving them is used instead of the device
+    /// timestamps, which also shows the jitter added by USB (e.g. a busy hub).
+    /// Only measured by the Nreal drivers.
+    pub fn imu_interval_percentile(&self, percentile: f32, host_time: bool) -> Option<Duration> {
+        if host_time {
+            window_percentile(&self.imu_host_intervals, percentile)
+        } else {
+            window_percentile(&self.imu_device_intervals, percentile)
+        }
+    }
+
+    /// Longest time between receiving two consecutive IMU samples in the last minute.
+    /// Only measured by the Nreal drivers.
+    pub fn longest_imu_gap(&self) -> Option<Duration> {
+        self.imu_gaps
+            .iter()
+            .find(|(at, _)| at.elapsed() <= GAP_WINDOW)
+            .map(|(_, gap)| *gap)
+    }
+
+    /// Number of times the command/response stream had to be resynchronized.
+    /// See [`crate::GlassesEvent::ProtocolResync`]
+    pub fn protocol_resyncs(&self) -> u64 {
+        self.protocol_resyncs
+    }
+
+    /// Number of times the video link to the glasses went down.
+    /// See [`crate::GlassesEvent::DisplayLink`]
+    pub fn display_link_flaps(&self) -> u64 {
+        self.display_link_flaps
+    }
+
+    /// Number of USB errors the driver recovered from.
+    /// See [`crate::GlassesEvent::TransportWarning`]
+    pub fn transport_recoveries(&self) -> u64 {
+        self.transport_recoveries
+    }
+
+    /// Number of key presses dropped as contact bounce.
+    /// See [`crate::ARGlasses::set_key_debounce`]
+    pub fn debounced_key_presses(&self) -> u64 {
+        self.debounced_key_presses
+    }
+
+    /// Number of repeated state reports (e.g. proximity) that were dropped, because
+    /// the state did not change. Only counted by the Nreal Light driver, see
+    /// [`crate::nreal_light::NrealLight::set_raw_proximity_events`].
+    pub fn repeated_state_events(&self) -> u64 {
+        self.repeated_state_events
+    }
+
+    /// Number of IMU samples with a clipped axis, see [`crate::SampleFlags::is_clipped`].
+    /// Only counted by the Nreal drivers.
+    pub fn clipped_samples(&self) -> u64 {
+        self.clipped_samples
+    }
+
+    /// Number of times the device clock advanced at a very different speed than the host
+    /// clock over a few seconds of continuous IMU samples. Nonzero values usually mean
+    /// that the driver decodes the timestamps in the wrong unit.
+    pub fn implausible_timestamps(&self) -> u64 {
+        self.implausible_timestamps
+    }
+
+    /// Time it took to connect to the glasses, including reading the calibration.
+    /// Only measured by the Nreal Air driver.
+    pub fn connect_duration(&self) -> Option<Duration> {
+        self.connect_duration
+    }
+
+    /// True if the glasses were opened from a cached path without enumerating the
+    /// devices, see [`crate::DiscoveryCache`]. Comparing [`Diagnostics::connect_duration`]
+    /// with and without the cache shows the time saved.
+    pub fn used_discovery_cache(&self) -> bool {
+        self.used_discovery_cache
+    }
+
+    /// Update the statistics with a freshly read event, and return its metadata
+    pub(crate) fn record_event(&mut self, event: &GlassesEvent, received_at: Instant) -> EventMeta {
+        if let GlassesEvent::AccGyro { flags, .. } = event {
+            self.clipped_samples += flags.is_clipped() as u64;
+        }
+        if let GlassesEvent::AccGyro { timestamp, .. } | GlassesEvent::Gyroscope { timestamp, .. } =
+            event
+        {
+            self.record_imu_timing(*timestamp, received_at);
+        }
+        let age = event.timestamp().map(|timestamp| {
+            let host_time = received_at
+                .saturating_duration_since(self.epoch)
+                .as_micros() as i64;
+            let offset = host_time - timestamp.as_micros() as i64;
+            let min_offset = self.min_clock_offset.map_or(offset, |o| o.min(offset));
+            self.min_clock_offset = Some(min_offset);
+            let age = (offset - min_offset) as u64;
+            if self.event_ages.len() == AGE_WINDOW_SIZE {
+                self.event_ages.pop_front();
+            }
+            self.event_ages.push_back(age);
+            Duration::from_micros(age)
+        });
+        EventMeta {
+            received_at,
+            age,
+            polled: false,
+        }
+    }
+
+    fn record_imu_timing(&mut self, timestamp: DeviceTimestamp, received_at: Instant) {
+        let last = self.last_imu_sample.replace((timestamp, received_at));
+        let Some((last_timestamp, last_received_at)) = last else {
+            return;
+        };
+        if timestamp < last_timestamp {
+            // The device clock was reset, the intervals before it are not comparable
+            self.reset_imu_timing();
+            self.last_imu_sample = Some((timestamp, received_at));
+            return;
+        }
+        let host_interval = received_at.saturating_duration_since(last_received_at);
+        for (intervals, interval) in [
+            (
+                &mut self.imu_device_intervals,
+                timestamp.as_micros() - last_timestamp.as_micros(),
+            ),
+            (
+                &mut self.imu_host_intervals,
+                host_interval.as_micros() as u64,
+            ),
+        ] {
+            if intervals.len() == INTERVAL_WINDOW_SIZE {
+                intervals.pop_front();
+            }
+            intervals.push_back(interval);
+        }
+        while self
+            .imu_gaps
+            .back()
+            .is_some_and(|(_, gap)| *gap <= host_interval)
+        {
+            self.imu_gaps.pop_back();
+        }
+        self.imu_gaps.push_back((received_at, host_interval));
+        while self
+            .imu_gaps
+            .front()
+            .is_some_and(|(at, _)| received_at.saturating_duration_since(*at) > GAP_WINDOW)
+        {
+            self.imu_gaps.pop_front();
+        }
+    }
+
+    /// Forget the IMU sample timing, e.g. after the stream was restarted
+    pub(crate) fn reset_imu_timing(&mut self) {
+        self.last_imu_sample = None;
+        self.imu_device_intervals.clear();
+        self.imu_host_intervals.clear();
+        self.imu_gaps.clear();
+    }
+}
+
+fn window_percentile(values: &VecDeque<u64>, percentile: f32) -> Option<Duration> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut values: Vec<u64> = values.iter().copied().collect();
+    values.sort_unstable();
+    let index = ((values.len() - 1) as f32 * percentile.clamp(0.0, 1.0)).round() as usize;
+    Some(Duration::from_micros(values[index]))
+}
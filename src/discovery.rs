@@ -0,0 +1,157 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Remembering where glasses were found, to skip enumeration. See [`DiscoveryCache`]
+
+use std::{collections::BTreeMap, ffi::CString, fs, io::ErrorKind, path::Path};
+
+use crate::Result;
+
+/// Device paths of previously connected glasses, keyed by serial number.
+///
+/// Enumerating HID devices can take hundreds of milliseconds (especially on Windows).
+/// Drivers that support the cache (currently [`crate::nreal_air::NrealAir::new_with_cache`])
+/// first try to open the cached paths directly, and only enumerate if that fails.
+/// After opening, the serial number is checked, and if the path now belongs to a
+/// different device, its entry is dropped and enumeration is used instead.
+///
+/// The cache is updated by the drivers, but has to be persisted with
+/// [`DiscoveryCache::save`] and [`DiscoveryCache::load`] by the application.
+/// See [`crate::Diagnostics::connect_duration`] for the effect.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiscoveryCache {
+    entries: BTreeMap<String, CachedDevice>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CachedDevice {
+    vid: u16,
+    pid: u16,
+    /// (interface number, hidapi path)
+    paths: Vec<(i32, String)>,
+}
+
+impl DiscoveryCache {
+    /// Create an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a cache saved with [`DiscoveryCache::save`].
+    /// A missing file results in an empty cache, malformed lines are skipped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut result = Self::new();
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [serial, vid, pid, interface, device_path] = fields[..] else {
+                continue;
+            };
+            let (Ok(vid), Ok(pid), Ok(interface)) = (
+                u16::from_str_radix(vid, 16),
+                u16::from_str_radix(pid, 16),
+                interface.parse(),
+            ) else {
+                continue;
+            };
+            result.insert(serial, vid, pid, interface, device_path);
+        }
+        Ok(result)
+    }
+
+    /// Write the cache to a file, in a simple tab separated text format
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut contents = String::new();
+        for (serial, device) in &self.entries {
+            for (interface, device_path) in &device.paths {
+                contents += &format!(
+                    "{serial}\t{:04x}\t{:04x}\t{interface}\t{device_path}\n",
+                    device.vid, device.pid
+                );
+            }
+        }
+        Ok(fs::write(path, contents)?)
+    }
+
+    /// Serial numbers of the cached devices
+    pub fn serials(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// True if no device is cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget a device, e.g. because it was found at a different path
+    pub fn invalidate(&mut self, serial: &str) {
+        self.entries.remove(serial);
+    }
+
+    /// Serial numbers of the cached devices with the VID and PID, along with the path
+    /// of each of the requested interfaces. Devices with a missing interface are skipped.
+    pub(crate) fn candidates<const N: usize>(
+        &self,
+        vid: u16,
+        pid: u16,
+        interfaces: [i32; N],
+    ) -> Vec<(String, [CString; N])> {
+        self.entries
+            .iter()
+            .filter(|(_, device)| device.vid == vid && device.pid == pid)
+            .filter_map(|(serial, device)| {
+                let paths = interfaces.map(|interface| {
+                    device
+                        .paths
+                        .iter()
+                        .find(|(i, _)| *i == interface)
+                        .and_then(|(_, path)| CString::new(path.as_str()).ok())
+                });
+                if paths.iter().any(Option::is_none) {
+                    return None;
+                }
+                Some((serial.clone(), paths.map(Option::unwrap)))
+            })
+            .collect()
+    }
+
+    /// Remember the paths the device with the serial was opened from,
+    /// replacing what was cached for the serial before
+    pub(crate) fn update(&mut self, serial: &str, vid: u16, pid: u16, paths: &[(i32, &CString)]) {
+        // Paths that can't be written to the text format are not cached
+        let Some(paths) = paths
+            .iter()
+            .map(|(interface, path)| {
+                path.to_str()
+                    .ok()
+                    .filter(|path| !path.contains(['\t', '\n']))
+                    .map(|path| (*interface, path.to_string()))
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            return;
+        };
+        if serial.contains(['\t', '\n']) {
+            return;
+        }
+        self.entries
+            .insert(serial.to_string(), CachedDevice { vid, pid, paths });
+    }
+
+    fn insert(&mut self, serial: &str, vid: u16, pid: u16, interface: i32, device_path: &str) {
+        let device = self
+            .entries
+            .entry(serial.to_string())
+            .or_insert_with(|| CachedDevice {
+                vid,
+                pid,
+                paths: Vec::new(),
+            });
+        device.paths.push((interface, device_path.to_string()));
+    }
+}
@@ -0,0 +1,744 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Recording the event stream to a file, and reading it back.
+//! See [`log_events_to`] and [`EventLogReader`]
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::Vector3;
+
+use crate::{
+    ARGlasses, DeviceErrorKind, DeviceTimestamp, Error, Gesture, GlassesEvent, Result, SampleFlags,
+    TransportErrorKind,
+};
+
+/// Magic bytes at the start of a binary event log
+const BINARY_MAGIC: &[u8; 4] = b"ARDL";
+/// Version of the binary event log format, written after the magic bytes.
+/// Version 1 logs (without the sample flags and the tags above 4) can still be read.
+pub(crate) const BINARY_VERSION: u16 = 2;
+
+const TAG_ACC_GYRO: u8 = 1;
+const TAG_ACCELEROMETER: u8 = 2;
+const TAG_GYROSCOPE: u8 = 3;
+const TAG_MAGNETOMETER: u8 = 4;
+const TAG_DISPLAY_LINK: u8 = 5;
+const TAG_TRANSPORT_WARNING: u8 = 6;
+const TAG_DEVICE_ERROR: u8 = 7;
+const TAG_GESTURE: u8 = 8;
+const TAG_RESUMED: u8 = 9;
+const TAG_CALIBRATION_DRIFT: u8 = 10;
+const TAG_OVERFLOW: u8 = 11;
+const TAG_MARKER: u8 = 0xff;
+
+/// Stored as their index in these lists
+const TRANSPORT_ERROR_KINDS: [TransportErrorKind; 4] = [
+    TransportErrorKind::Pipe,
+    TransportErrorKind::Overflow,
+    TransportErrorKind::Interrupted,
+    TransportErrorKind::Reopened,
+];
+const DEVICE_ERROR_KINDS: [DeviceErrorKind; 5] = [
+    DeviceErrorKind::DpLinkFailure,
+    DeviceErrorKind::ThermalThrottle,
+    DeviceErrorKind::SensorFault,
+    DeviceErrorKind::FirmwareAssert,
+    DeviceErrorKind::Unknown,
+];
+const GESTURES: [Gesture; 2] = [Gesture::Nod, Gesture::Shake];
+
+/// Output format of an [`EventLogger`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventLogFormat {
+    /// One JSON object per line, for every event. The `type` field is the name
+    /// of the [`GlassesEvent`] variant, the other fields are the same as the variant's.
+    /// Non-finite sensor values (NaN, infinity) are written as `null`.
+    Jsonl,
+    /// Comma separated values with a header line, only for [`GlassesEvent::AccGyro`] events
+    ImuCsv,
+    /// Compact little endian binary format, readable with [`EventLogReader`].
+    ///
+    /// The file starts with `ARDL` and a `u16` format version (currently 2). Each record
+    /// is a `u8` tag, the `u64` host time, and the payload:
+    /// * 1: [`GlassesEvent::AccGyro`]: `u64` timestamp, accelerometer and gyroscope as
+    ///   3 `f32`s each, and the `u8` sample flags. Version 1 has no flags, they are
+    ///   read back empty.
+    /// * 2, 3, 4: [`GlassesEvent::Accelerometer`], [`GlassesEvent::Gyroscope`] and
+    ///   [`GlassesEvent::Magnetometer`]: `u64` timestamp and 3 `f32`s
+    /// * 5: [`GlassesEvent::DisplayLink`]: `u8` up and inferred (0 or 1), and the detail
+    /// * 6: [`GlassesEvent::TransportWarning`]: `u8` kind and `u32` count
+    /// * 7: [`GlassesEvent::DeviceError`]: `u8` kind, `u16` length and the raw bytes,
+    ///   and the message
+    /// * 8: [`GlassesEvent::Gesture`]: `u8` gesture
+    /// * 9: [`GlassesEvent::Resumed`]: `u64` gap in microseconds
+    /// * 10: [`GlassesEvent::CalibrationDrift`]: 3 `f32`s
+    /// * 11: [`GlassesEvent::Overflow`]: `u64` dropped
+    /// * 255: any other event as a marker: `u16` length, and the same fields as in the
+    ///   [`EventLogFormat::Jsonl`] format, as UTF-8 text
+    ///
+    /// Kinds and gestures are numbered in the order of their enum variants, starting at 0.
+    /// Optional texts are a `u16` length (0xffff if missing) and UTF-8 text.
+    Binary,
+}
+
+/// Wrapper that writes every event read through it to a writer, see [`log_events_to`]
+///
+/// Each line starts with the host time of reception (`host_time_us`, microseconds
+/// since the Unix epoch). By default, the writer is flushed after every event, so the
+/// file is usable even if the program is killed.
+///
+/// Logging can be limited in size and duration with [`EventLogger::with_limits`].
+/// After the limit is reached, events are still read, but not logged anymore.
+pub struct EventLogger<'a, W: Write> {
+    glasses: &'a mut dyn ARGlasses,
+    writer: W,
+    format: EventLogFormat,
+    record: Vec<u8>,
+    bytes_written: u64,
+    started: Instant,
+    last_flush: Instant,
+    flush_interval: Duration,
+    max_bytes: Option<u64>,
+    max_duration: Option<Duration>,
+}
+
+/// Log every event read through the returned [`EventLogger`] as JSONL into `writer`
+pub fn log_events_to<W: Write>(glasses: &mut dyn ARGlasses, writer: W) -> EventLogger<'_, W> {
+    EventLogger {
+        glasses,
+        writer,
+        format: EventLogFormat::Jsonl,
+        record: Vec::new(),
+        bytes_written: 0,
+        started: Instant::now(),
+        last_flush: Instant::now(),
+        flush_interval: Duration::ZERO,
+        max_bytes: None,
+        max_duration: None,
+    }
+}
+
+impl<'a, W: Write> EventLogger<'a, W> {
+    /// Same as [`log_events_to`], with a selectable format.
+    /// The CSV header or the binary file header is written immediately.
+    pub fn new(glasses: &'a mut dyn ARGlasses, writer: W, format: EventLogFormat) -> Result<Self> {
+        let mut result = log_events_to(glasses, writer);
+        result.format = format;
+        match format {
+            EventLogFormat::Jsonl => {}
+            EventLogFormat::ImuCsv => writeln!(
+                result.record,
+                "host_time_us,timestamp,acc_x,acc_y,acc_z,gyro_x,gyro_y,gyro_z"
+            )?,
+            EventLogFormat::Binary => {
+                result.record.extend_from_slice(BINARY_MAGIC);
+                result.record.write_u16::<LittleEndian>(BINARY_VERSION)?;
+            }
+        }
+        result.write_record()?;
+        Ok(result)
+    }
+
+    /// Only flush the writer if at least `flush_interval` passed since the last flush,
+    /// instead of after every event
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Stop logging after `max_bytes` were written, or `max_duration` passed since the
+    /// logger was created, whichever comes first
+    pub fn with_limits(mut self, max_bytes: Option<u64>, max_duration: Option<Duration>) -> Self {
+        self.max_bytes = max_bytes;
+        self.max_duration = max_duration;
+        self
+    }
+
+    /// Whether a limit set with [`EventLogger::with_limits`] was reached
+    pub fn is_full(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| self.bytes_written >= max_bytes)
+            || self
+                .max_duration
+                .is_some_and(|max_duration| self.started.elapsed() >= max_duration)
+    }
+
+    /// Same as [`ARGlasses::read_event`], but also logs the event
+    pub fn read_event(&mut self) -> Result<GlassesEvent> {
+        let event = self.glasses.read_event()?;
+        if self.is_full() {
+            return Ok(event);
+        }
+        let host_time_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_micros() as u64);
+        match self.format {
+            EventLogFormat::Jsonl => {
+                writeln!(
+                    self.record,
+                    "{{\"host_time_us\":{host_time_us},{}}}",
+                    event_json_fields(&event)
+                )?;
+            }
+            EventLogFormat::ImuCsv => {
+                if let GlassesEvent::AccGyro {
+                    accelerometer: a,
+                    gyroscope: g,
+                    timestamp,
+                    ..
+                } = &event
+                {
+                    writeln!(
+                        self.record,
+                        "{host_time_us},{},{},{},{},{},{},{}",
+                        timestamp.as_micros(),
+                        a.x,
+                        a.y,
+                        a.z,
+                        g.x,
+                        g.y,
+                        g.z
+                    )?;
+                }
+            }
+            EventLogFormat::Binary => write_binary_record(&mut self.record, host_time_us, &event)?,
+        }
+        self.write_record()?;
+        Ok(event)
+    }
+
+    /// The wrapped glasses, e.g. to change the display mode
+    pub fn glasses(&mut self) -> &mut dyn ARGlasses {
+        self.glasses
+    }
+
+    /// Stop logging, and get back the writer
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    fn write_record(&mut self) -> Result<()> {
+        self.writer.write_all(&self.record)?;
+        self.bytes_written += self.record.len() as u64;
+        self.record.clear();
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// A record read back by [`EventLogReader`]
+#[derive(Debug, Clone)]
+pub enum LogRecord {
+    /// A sensor event, the same as it was returned by the glasses
+    Event {
+        /// Host time of reception, in microseconds since the Unix epoch
+        host_time_us: u64,
+        /// The event
+        event: GlassesEvent,
+    },
+    /// Any other event, only stored as a description
+    Marker {
+        /// Host time of reception, in microseconds since the Unix epoch
+        host_time_us: u64,
+        /// The fields of the event, as in the [`EventLogFormat::Jsonl`] format
+        json_fields: String,
+    },
+}
+
+/// Reader for logs written in the [`EventLogFormat::Binary`] format, e.g. to replay a
+/// recording into the same processing (like [`crate::StillnessDetector`]) as live data.
+pub struct EventLogReader<R: Read> {
+    reader: R,
+    version: u16,
+}
+
+impl<R: Read> EventLogReader<R> {
+    /// Check the file header, and prepare for reading records
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != BINARY_MAGIC {
+            return Err(Error::Other("Not a binary event log"));
+        }
+        let version = reader.read_u16::<LittleEndian>()?;
+        if !(1..=BINARY_VERSION).contains(&version) {
+            return Err(Error::Other("Unsupported binary event log version"));
+        }
+        Ok(Self { reader, version })
+    }
+
+    /// Read the next record. Returns `None` at the end of the log.
+    pub fn read_record(&mut self) -> Result<Option<LogRecord>> {
+        read_binary_record(&mut self.reader, self.version)
+    }
+}
+
+/// Read a single record in the [`EventLogFormat::Binary`] format of `version` (without
+/// the file header). Returns `None` if the reader is at its end.
+pub(crate) fn read_binary_record(
+    reader: &mut impl Read,
+    version: u16,
+) -> Result<Option<LogRecord>> {
+    let tag = match reader.read_u8() {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let host_time_us = reader.read_u64::<LittleEndian>()?;
+    let event = match tag {
+        TAG_MARKER => {
+            let mut json_fields = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+            reader.read_exact(&mut json_fields)?;
+            return Ok(Some(LogRecord::Marker {
+                host_time_us,
+                json_fields: String::from_utf8(json_fields)
+                    .map_err(|_| Error::Other("Invalid marker in binary event log"))?,
+            }));
+        }
+        TAG_ACC_GYRO => GlassesEvent::AccGyro {
+            timestamp: read_timestamp(reader)?,
+            accelerometer: read_vector(reader)?,
+            gyroscope: read_vector(reader)?,
+            flags: if version >= 2 {
+                SampleFlags(reader.read_u8()?)
+            } else {
+                SampleFlags::empty()
+            },
+        },
+        TAG_ACCELEROMETER => GlassesEvent::Accelerometer {
+            timestamp: read_timestamp(reader)?,
+            accelerometer: read_vector(reader)?,
+        },
+        TAG_GYROSCOPE => GlassesEvent::Gyroscope {
+            timestamp: read_timestamp(reader)?,
+            gyroscope: read_vector(reader)?,
+        },
+        TAG_MAGNETOMETER => GlassesEvent::Magnetometer {
+            timestamp: read_timestamp(reader)?,
+            magnetometer: read_vector(reader)?,
+        },
+        TAG_DISPLAY_LINK => GlassesEvent::DisplayLink {
+            up: reader.read_u8()? != 0,
+            inferred: reader.read_u8()? != 0,
+            detail: read_text(reader)?,
+        },
+        TAG_TRANSPORT_WARNING => GlassesEvent::TransportWarning {
+            kind: read_kind(reader, &TRANSPORT_ERROR_KINDS)?,
+            count: reader.read_u32::<LittleEndian>()?,
+        },
+        TAG_DEVICE_ERROR => GlassesEvent::DeviceError {
+            kind: read_kind(reader, &DEVICE_ERROR_KINDS)?,
+            raw: {
+                let mut raw = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+                reader.read_exact(&mut raw)?;
+                raw
+            },
+            message: read_text(reader)?,
+        },
+        TAG_GESTURE => GlassesEvent::Gesture(read_kind(reader, &GESTURES)?),
+        TAG_RESUMED => GlassesEvent::Resumed {
+            gap: Duration::from_micros(reader.read_u64::<LittleEndian>()?),
+        },
+        TAG_CALIBRATION_DRIFT => GlassesEvent::CalibrationDrift {
+            suggested_bias: read_vector(reader)?,
+        },
+        TAG_OVERFLOW => GlassesEvent::Overflow {
+            dropped: reader.read_u64::<LittleEndian>()? as usize,
+        },
+        _ => return Err(Error::Other("Unknown record in binary event log")),
+    };
+    Ok(Some(LogRecord::Event {
+        host_time_us,
+        event,
+    }))
+}
+
+fn read_timestamp(reader: &mut impl Read) -> Result<DeviceTimestamp> {
+    Ok(DeviceTimestamp::from_micros(
+        reader.read_u64::<LittleEndian>()?,
+    ))
+}
+
+fn read_vector(reader: &mut impl Read) -> Result<Vector3<f32>> {
+    Ok(Vector3::new(
+        reader.read_f32::<LittleEndian>()?,
+        reader.read_f32::<LittleEndian>()?,
+        reader.read_f32::<LittleEndian>()?,
+    ))
+}
+
+fn read_kind<T: Copy>(reader: &mut impl Read, kinds: &[T]) -> Result<T> {
+    kinds
+        .get(reader.read_u8()? as usize)
+        .copied()
+        .ok_or(Error::Other("Unknown kind in binary event log"))
+}
+
+fn read_text(reader: &mut impl Read) -> Result<Option<String>> {
+    let length = reader.read_u16::<LittleEndian>()?;
+    if length == u16::MAX {
+        return Ok(None);
+    }
+    let mut text = vec![0; length as usize];
+    reader.read_exact(&mut text)?;
+    String::from_utf8(text)
+        .map(Some)
+        .map_err(|_| Error::Other("Invalid text in binary event log"))
+}
+
+/// Append a single record in the [`EventLogFormat::Binary`] format to `out`
+pub(crate) fn write_binary_record(
+    out: &mut Vec<u8>,
+    host_time_us: u64,
+    event: &GlassesEvent,
+) -> Result<()> {
+    let tag = match event {
+        GlassesEvent::AccGyro { .. } => TAG_ACC_GYRO,
+        GlassesEvent::Accelerometer { .. } => TAG_ACCELEROMETER,
+        GlassesEvent::Gyroscope { .. } => TAG_GYROSCOPE,
+        GlassesEvent::Magnetometer { .. } => TAG_MAGNETOMETER,
+        GlassesEvent::DisplayLink { .. } => TAG_DISPLAY_LINK,
+        GlassesEvent::TransportWarning { .. } => TAG_TRANSPORT_WARNING,
+        GlassesEvent::DeviceError { .. } => TAG_DEVICE_ERROR,
+        GlassesEvent::Gesture(_) => TAG_GESTURE,
+        GlassesEvent::Resumed { .. } => TAG_RESUMED,
+        GlassesEvent::CalibrationDrift { .. } => TAG_CALIBRATION_DRIFT,
+        GlassesEvent::Overflow { .. } => TAG_OVERFLOW,
+        _ => TAG_MARKER,
+    };
+    out.write_u8(tag)?;
+    out.write_u64::<LittleEndian>(host_time_us)?;
+    match event {
+        GlassesEvent::AccGyro {
+            accelerometer,
+            gyroscope,
+            timestamp,
+            flags,
+        } => {
+            out.write_u64::<LittleEndian>(timestamp.as_micros())?;
+            write_vector(out, accelerometer)?;
+            write_vector(out, gyroscope)?;
+            out.write_u8(flags.0)?;
+        }
+        GlassesEvent::Accelerometer {
+            accelerometer: vector,
+            timestamp,
+        }
+        | GlassesEvent::Gyroscope {
+            gyroscope: vector,
+            timestamp,
+        }
+        | GlassesEvent::Magnetometer {
+            magnetometer: vector,
+            timestamp,
+        } => {
+            out.write_u64::<LittleEndian>(timestamp.as_micros())?;
+            write_vector(out, vector)?;
+        }
+        GlassesEvent::DisplayLink {
+            up,
+            detail,
+            inferred,
+        } => {
+            out.write_u8(*up as u8)?;
+            out.write_u8(*inferred as u8)?;
+            write_text(out, detail.as_deref())?;
+        }
+        GlassesEvent::TransportWarning { kind, count } => {
+            write_kind(out, &TRANSPORT_ERROR_KINDS, kind)?;
+            out.write_u32::<LittleEndian>(*count)?;
+        }
+        GlassesEvent::DeviceError { kind, raw, message } => {
+            write_kind(out, &DEVICE_ERROR_KINDS, kind)?;
+            let raw = &raw[..raw.len().min(u16::MAX as usize)];
+            out.write_u16::<LittleEndian>(raw.len() as u16)?;
+            out.extend_from_slice(raw);
+            write_text(out, message.as_deref())?;
+        }
+        GlassesEvent::Gesture(gesture) => write_kind(out, &GESTURES, gesture)?,
+        GlassesEvent::Resumed { gap } => {
+            out.write_u64::<LittleEndian>(gap.as_micros().try_into().unwrap_or(u64::MAX))?
+        }
+        GlassesEvent::CalibrationDrift { suggested_bias } => write_vector(out, suggested_bias)?,
+        GlassesEvent::Overflow { dropped } => out.write_u64::<LittleEndian>(*dropped as u64)?,
+        _ => {
+            let json_fields = event_json_fields(event);
+            out.write_u16::<LittleEndian>(text_length(&json_fields, u16::MAX) as u16)?;
+            out.extend_from_slice(&json_fields.as_bytes()[..text_length(&json_fields, u16::MAX)]);
+        }
+    }
+    Ok(())
+}
+
+fn write_vector(out: &mut Vec<u8>, vector: &Vector3<f32>) -> Result<()> {
+    for component in vector.iter() {
+        out.write_f32::<LittleEndian>(*component)?;
+    }
+    Ok(())
+}
+
+fn write_kind<T: PartialEq>(out: &mut Vec<u8>, kinds: &[T], kind: &T) -> Result<()> {
+    let index = kinds.iter().position(|k| k == kind).ok_or(Error::Other(
+        "Event kind missing from the binary event log format",
+    ))?;
+    Ok(out.write_u8(index as u8)?)
+}
+
+fn write_text(out: &mut Vec<u8>, text: Option<&str>) -> Result<()> {
+    let Some(text) = text else {
+        return Ok(out.write_u16::<LittleEndian>(u16::MAX)?);
+    };
+    // u16::MAX means a missing text
+    let length = text_length(text, u16::MAX - 1);
+    out.write_u16::<LittleEndian>(length as u16)?;
+    out.extend_from_slice(&text.as_bytes()[..length]);
+    Ok(())
+}
+
+/// Length of the longest prefix of `text` not longer than `max` bytes, that doesn't
+/// split a character
+fn text_length(text: &str, max: u16) -> usize {
+    let mut length = text.len().min(max as usize);
+    while !text.is_char_boundary(length) {
+        length -= 1;
+    }
+    length
+}
+
+fn event_json_fields(event: &GlassesEvent) -> String {
+    match event {
+        GlassesEvent::AccGyro {
+            accelerometer,
+            gyroscope,
+            timestamp,
+            ..
+        } => format!(
+            "\"type\":\"AccGyro\",\"accelerometer\":{},\"gyroscope\":{},\"timestamp\":{}",
+            json_vector(accelerometer),
+            json_vector(gyroscope),
+            timestamp.as_micros()
+        ),
+        GlassesEvent::Accelerometer {
+            accelerometer,
+            timestamp,
+        } => format!(
+            "\"type\":\"Accelerometer\",\"accelerometer\":{},\"timestamp\":{}",
+            json_vector(accelerometer),
+            timestamp.as_micros()
+        ),
+        GlassesEvent::Gyroscope {
+            gyroscope,
+            timestamp,
+        } => format!(
+            "\"type\":\"Gyroscope\",\"gyroscope\":{},\"timestamp\":{}",
+            json_vector(gyroscope),
+            timestamp.as_micros()
+        ),
+        GlassesEvent::Magnetometer {
+            magnetometer,
+            timestamp,
+        } => format!(
+            "\"type\":\"Magnetometer\",\"magnetometer\":{},\"timestamp\":{}",
+            json_vector(magnetometer),
+            timestamp.as_micros()
+        ),
+        GlassesEvent::KeyPress(key) => format!("\"type\":\"KeyPress\",\"key\":{key}"),
+        GlassesEvent::ProximityNear => "\"type\":\"ProximityNear\"".into(),
+        GlassesEvent::ProximityFar => "\"type\":\"ProximityFar\"".into(),
+        GlassesEvent::AmbientLight(level) => {
+            format!("\"type\":\"AmbientLight\",\"level\":{level}")
+        }
+        GlassesEvent::BrightnessChanged(level) => {
+            format!("\"type\":\"BrightnessChanged\",\"level\":{level}")
+        }
+        GlassesEvent::VSync => "\"type\":\"VSync\"".into(),
+        GlassesEvent::HeartbeatDue => "\"type\":\"HeartbeatDue\"".into(),
+        GlassesEvent::ProtocolResync => "\"type\":\"ProtocolResync\"".into(),
+        GlassesEvent::DisplayLink {
+            up,
+            detail,
+            inferred,
+        } => format!(
+            "\"type\":\"DisplayLink\",\"up\":{up},\"detail\":{},\"inferred\":{inferred}",
+            json_string(detail.as_deref())
+        ),
+        GlassesEvent::TransportWarning { kind, count } => {
+            format!("\"type\":\"TransportWarning\",\"kind\":\"{kind:?}\",\"count\":{count}")
+        }
+        GlassesEvent::DeviceError { kind, raw, message } => format!(
+            "\"type\":\"DeviceError\",\"kind\":\"{kind:?}\",\"raw\":\"{}\",\"message\":{}",
+            raw.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+            json_string(message.as_deref())
+        ),
+        GlassesEvent::Still => "\"type\":\"Still\"".into(),
+        GlassesEvent::Moving => "\"type\":\"Moving\"".into(),
+        GlassesEvent::MotionWake => "\"type\":\"MotionWake\"".into(),
+        GlassesEvent::Gesture(gesture) => {
+            format!("\"type\":\"Gesture\",\"gesture\":\"{gesture:?}\"")
+        }
+        GlassesEvent::SessionRestored => "\"type\":\"SessionRestored\"".into(),
+        GlassesEvent::Resumed { gap } => {
+            format!("\"type\":\"Resumed\",\"gap_us\":{}", gap.as_micros())
+        }
+        GlassesEvent::CalibrationDrift { suggested_bias } => format!(
+            "\"type\":\"CalibrationDrift\",\"suggested_bias\":{}",
+            json_vector(suggested_bias)
+        ),
+        GlassesEvent::Overflow { dropped } => {
+            format!("\"type\":\"Overflow\",\"dropped\":{dropped}")
+        }
+    }
+}
+
+fn json_vector(v: &Vector3<f32>) -> String {
+    format!(
+        "[{},{},{}]",
+        json_number(v.x),
+        json_number(v.y),
+        json_number(v.z)
+    )
+}
+
+/// JSON has no NaN or infinity, so those are written as `null`
+fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".into()
+    }
+}
+
+fn json_string(s: Option<&str>) -> String {
+    let Some(s) = s else {
+        return "null".into();
+    };
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_non_finite() {
+        let fields = event_json_fields(&GlassesEvent::AccGyro {
+            accelerometer: Vector3::new(f32::NAN, 1.5, f32::INFINITY),
+            gyroscope: Vector3::new(0.0, f32::NEG_INFINITY, -2.0),
+            timestamp: DeviceTimestamp::from_micros(7),
+            flags: SampleFlags::empty(),
+        });
+        assert_eq!(
+            fields,
+            "\"type\":\"AccGyro\",\"accelerometer\":[null,1.5,null],\"gyroscope\":[0,null,-2],\"timestamp\":7"
+        );
+    }
+
+    fn round_trip(event: &GlassesEvent) -> GlassesEvent {
+        let mut record = Vec::new();
+        write_binary_record(&mut record, 5, event).unwrap();
+        let mut reader = record.as_slice();
+        let Some(LogRecord::Event {
+            host_time_us: 5,
+            event,
+        }) = read_binary_record(&mut reader, BINARY_VERSION).unwrap()
+        else {
+            panic!("Not an event record");
+        };
+        assert!(reader.is_empty(), "{event:?} left unread bytes");
+        event
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let events = [
+            GlassesEvent::AccGyro {
+                accelerometer: Vector3::new(1.0, 2.0, 3.0),
+                gyroscope: Vector3::new(-1.0, 0.5, 0.0),
+                timestamp: DeviceTimestamp::from_micros(99),
+                flags: SampleFlags::INTERPOLATED,
+            },
+            GlassesEvent::DisplayLink {
+                up: true,
+                detail: Some("é".repeat(40000)),
+                inferred: false,
+            },
+            GlassesEvent::TransportWarning {
+                kind: TransportErrorKind::Pipe,
+                count: 70000,
+            },
+            GlassesEvent::DeviceError {
+                kind: DeviceErrorKind::Unknown,
+                raw: vec![1, 2, 3],
+                message: Some(String::new()),
+            },
+            GlassesEvent::Gesture(Gesture::Nod),
+            GlassesEvent::Resumed {
+                gap: Duration::from_millis(1500),
+            },
+            GlassesEvent::CalibrationDrift {
+                suggested_bias: Vector3::new(0.0, 0.25, -0.125),
+            },
+            GlassesEvent::Overflow { dropped: 12 },
+        ];
+        for event in events {
+            let read = round_trip(&event);
+            if let GlassesEvent::DisplayLink { detail, .. } = &read {
+                // Cut at a character boundary below the 0xffff limit
+                assert_eq!(detail.as_ref().unwrap().len(), 65534);
+                continue;
+            }
+            assert_eq!(format!("{read:?}"), format!("{event:?}"));
+        }
+    }
+
+    #[test]
+    fn binary_version_1() {
+        let mut log = b"ARDL\x01\x00".to_vec();
+        log.push(TAG_ACC_GYRO);
+        log.extend_from_slice(&3u64.to_le_bytes());
+        log.extend_from_slice(&8u64.to_le_bytes());
+        for value in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0] {
+            log.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut reader = EventLogReader::new(log.as_slice()).unwrap();
+        let Some(LogRecord::Event {
+            host_time_us: 3,
+            event:
+                GlassesEvent::AccGyro {
+                    gyroscope,
+                    timestamp,
+                    flags,
+                    ..
+                },
+        }) = reader.read_record().unwrap()
+        else {
+            panic!("Not an AccGyro record");
+        };
+        assert_eq!(gyroscope, Vector3::new(4.0, 5.0, 6.0));
+        assert_eq!(timestamp.as_micros(), 8);
+        assert_eq!(flags, SampleFlags::empty());
+        assert!(reader.read_record().unwrap().is_none());
+
+        assert!(EventLogReader::new(&b"ARDL\x03\x00"[..]).is_err());
+    }
+}
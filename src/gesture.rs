@@ -0,0 +1,257 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Head gesture recognition. See [`GestureDetector`]
+
+use std::time::Duration;
+
+use nalgebra::Vector3;
+
+use crate::{GlassesEvent, RadiansPerSec};
+
+/// A head gesture, see [`GlassesEvent::Gesture`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// Nodding "yes": pitch oscillation (around the X axis)
+    Nod,
+    /// Shaking the head "no": yaw oscillation (around the Y axis)
+    Shake,
+}
+
+/// Recognizes nods and shakes from the gyroscope data, as input for controller-less UIs.
+///
+/// Feed it every event with [`GestureDetector::update`], and it returns
+/// [`GlassesEvent::Gesture`] events. Nothing is detected unless a detector is used.
+///
+/// A swing is a rotation faster than the speed threshold in one direction. A gesture
+/// is a series of alternating swings around the same axis, each starting no later than
+/// the swing timeout after the previous one. Motion during the gesture has to be
+/// concentrated around the gesture's axis, which filters out the multi-axis sway
+/// of walking. After a gesture, nothing is detected for the refractory period.
+#[derive(Debug, Clone)]
+pub struct GestureDetector {
+    min_speed: f32,
+    min_swings: u32,
+    swing_timeout: u64,
+    refractory: u64,
+    min_dominance: f32,
+    pitch: AxisSwings,
+    yaw: AxisSwings,
+    energy: Vector3<f32>,
+    refractory_until: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct AxisSwings {
+    direction: i8,
+    swing_start: u64,
+    swings: u32,
+}
+
+impl AxisSwings {
+    /// Returns the number of swings in the current series
+    fn update(&mut self, speed: f32, min_speed: f32, swing_timeout: u64, timestamp: u64) -> u32 {
+        if self.swings > 0 && timestamp.saturating_sub(self.swing_start) > swing_timeout {
+            *self = Self::default();
+        }
+        if speed.abs() < min_speed {
+            return self.swings;
+        }
+        let direction = if speed > 0.0 { 1 } else { -1 };
+        if direction != self.direction {
+            self.direction = direction;
+            self.swing_start = timestamp;
+            self.swings += 1;
+        }
+        self.swings
+    }
+}
+
+impl Default for GestureDetector {
+    fn default() -> Self {
+        Self {
+            min_speed: 1.0,
+            min_swings: 3,
+            swing_timeout: 400_000,
+            refractory: 1_000_000,
+            min_dominance: 0.7,
+            pitch: AxisSwings::default(),
+            yaw: AxisSwings::default(),
+            energy: Vector3::zeros(),
+            refractory_until: 0,
+        }
+    }
+}
+
+impl GestureDetector {
+    /// Create a detector with a speed threshold of 1 rad/s, 3 swings, 400ms swing timeout,
+    /// 1s refractory period, and 70% of the motion concentrated around the gesture's axis
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the angular speed a swing has to reach. This is the amplitude threshold.
+    pub fn with_min_speed(mut self, min_speed: impl Into<RadiansPerSec>) -> Self {
+        self.min_speed = min_speed.into().value();
+        self
+    }
+
+    /// Set the number of alternating swings needed for a gesture (e.g. down-up-down is 3)
+    pub fn with_min_swings(mut self, min_swings: u32) -> Self {
+        self.min_swings = min_swings.max(1);
+        self
+    }
+
+    /// Set the maximum time (in device time) between the starts of two consecutive swings.
+    /// This is the frequency threshold: slower oscillations are not gestures.
+    pub fn with_swing_timeout(mut self, swing_timeout: Duration) -> Self {
+        self.swing_timeout = swing_timeout.as_micros() as u64;
+        self
+    }
+
+    /// Set how long (in device time) nothing is detected after a gesture
+    pub fn with_refractory_period(mut self, refractory: Duration) -> Self {
+        self.refractory = refractory.as_micros() as u64;
+        self
+    }
+
+    /// Set the minimum fraction (0..1) of the rotation energy that has to be around
+    /// the gesture's axis
+    pub fn with_min_dominance(mut self, min_dominance: f32) -> Self {
+        self.min_dominance = min_dominance;
+        self
+    }
+
+    /// Process an event. Returns [`GlassesEvent::Gesture`] if a gesture was completed.
+    /// Events without gyroscope data are ignored.
+    pub fn update(&mut self, event: &GlassesEvent) -> Option<GlassesEvent> {
+        let (gyroscope, timestamp) = match event {
+            GlassesEvent::AccGyro {
+                gyroscope,
+                timestamp,
+                ..
+            }
+            | GlassesEvent::Gyroscope {
+                gyroscope,
+                timestamp,
+            } => (gyroscope, timestamp.as_micros()),
+            _ => return None,
+        };
+        if timestamp < self.refractory_until {
+            return None;
+        }
+        let pitch_swings =
+            self.pitch
+                .update(gyroscope.x, self.min_speed, self.swing_timeout, timestamp);
+        let yaw_swings =
+            self.yaw
+                .update(gyroscope.y, self.min_speed, self.swing_timeout, timestamp);
+        if pitch_swings == 0 && yaw_swings == 0 {
+            self.energy = Vector3::zeros();
+            return None;
+        }
+        self.energy += gyroscope.component_mul(gyroscope);
+
+        let (gesture, axis_energy) = if pitch_swings >= self.min_swings {
+            (Gesture::Nod, self.energy.x)
+        } else if yaw_swings >= self.min_swings {
+            (Gesture::Shake, self.energy.y)
+        } else {
+            return None;
+        };
+        let dominant = axis_energy >= self.min_dominance * self.energy.sum();
+        self.pitch = AxisSwings::default();
+        self.yaw = AxisSwings::default();
+        self.energy = Vector3::zeros();
+        if !dominant {
+            return None;
+        }
+        self.refractory_until = timestamp + self.refractory;
+        Some(GlassesEvent::Gesture(gesture))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+    use crate::{DeviceTimestamp, SampleFlags};
+
+    /// 4 seconds of 1kHz gyroscope samples of the angular velocity at each time (in s)
+    fn trace(gyroscope: impl Fn(f32) -> Vector3<f32>) -> Vec<GlassesEvent> {
+        (0..4000)
+            .map(|i| GlassesEvent::AccGyro {
+                accelerometer: Vector3::new(0.0, 9.81, 0.0),
+                gyroscope: gyroscope(i as f32 / 1000.0),
+                timestamp: DeviceTimestamp::from_micros(i * 1000),
+                flags: SampleFlags::empty(),
+            })
+            .collect()
+    }
+
+    /// Oscillation with the angle amplitude (rad) and frequency (Hz), as angular velocity
+    fn oscillation(amplitude: f32, frequency: f32, t: f32) -> f32 {
+        amplitude * TAU * frequency * (TAU * frequency * t).cos()
+    }
+
+    fn gestures(detector: &mut GestureDetector, events: &[GlassesEvent]) -> Vec<Gesture> {
+        events
+            .iter()
+            .filter_map(|event| match detector.update(event) {
+                Some(GlassesEvent::Gesture(gesture)) => Some(gesture),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nod_and_shake() {
+        let nod = trace(|t| Vector3::new(oscillation(0.3, 2.0, t), 0.0, 0.0));
+        let detected = gestures(&mut GestureDetector::new(), &nod);
+        assert!(!detected.is_empty());
+        assert!(detected.iter().all(|&gesture| gesture == Gesture::Nod));
+
+        // A bit of pitch while shaking doesn't matter
+        let shake =
+            trace(|t| Vector3::new(oscillation(0.05, 2.0, t), oscillation(0.3, 2.5, t), 0.0));
+        let detected = gestures(&mut GestureDetector::new(), &shake);
+        assert!(!detected.is_empty());
+        assert!(detected.iter().all(|&gesture| gesture == Gesture::Shake));
+    }
+
+    #[test]
+    fn refractory_period() {
+        let nod = trace(|t| Vector3::new(oscillation(0.3, 2.0, t), 0.0, 0.0));
+        // Continuous nodding for 4s is at most one gesture per second
+        let detected = gestures(&mut GestureDetector::new(), &nod);
+        assert!((1..=4).contains(&detected.len()), "{detected:?}");
+        let detected = gestures(
+            &mut GestureDetector::new().with_refractory_period(Duration::from_secs(10)),
+            &nod,
+        );
+        assert_eq!(detected, [Gesture::Nod]);
+    }
+
+    #[test]
+    fn ignored_motion() {
+        // Walking sways the head around every axis at once
+        let walking = trace(|t| {
+            Vector3::new(
+                oscillation(0.2, 2.0, t),
+                oscillation(0.2, 1.0, t + 0.1),
+                oscillation(0.2, 1.0, t),
+            )
+        });
+        assert_eq!(gestures(&mut GestureDetector::new(), &walking), []);
+
+        // Too slow to be a gesture
+        let slow = trace(|t| Vector3::new(0.0, oscillation(1.0, 0.5, t), 0.0));
+        assert_eq!(gestures(&mut GestureDetector::new(), &slow), []);
+
+        // Too small to be a gesture
+        let small = trace(|t| Vector3::new(oscillation(0.05, 2.0, t), 0.0, 0.0));
+        assert_eq!(gestures(&mut GestureDetector::new(), &small), []);
+    }
+}
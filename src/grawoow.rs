@@ -13,7 +13,9 @@ use rusb::{request_type, DeviceHandle, GlobalContext};
 use tinyjson::JsonValue;
 
 use crate::{
-    util::get_interface_for_endpoint, ARGlasses, DisplayMode, Error, GlassesEvent, Result, Side,
+    util::{get_interface_for_endpoint, usb_location, DeviceLock, TransportRecovery, UsbInterrupt},
+    ARGlasses, AxisConfig, Capabilities, DeviceTimestamp, Diagnostics, DisplayMode, Error,
+    GlassesEvent, Result, SampleFlags, Side, UsbLocation,
 };
 
 /// The main structure representing a connected Grawoow G530 (a.k.a. MetaVision M53) glasses
@@ -24,23 +26,43 @@ pub struct GrawoowG530 {
     gyro_bias: Vector3<f32>,
     accelerometer_bias: Vector3<f32>,
     start: Instant,
+    transport: TransportRecovery,
+    diagnostics: Diagnostics,
+    axis_config: AxisConfig,
+    _lock: Option<DeviceLock>,
+    read_buffer: [u8; 0x80],
 }
 
-const OV580_ENDPOINT: u8 = 0x89;
+pub(crate) const OV580_ENDPOINT: u8 = 0x89;
 
 const OV580_TIMEOUT: Duration = Duration::from_millis(250);
 const MCU_TIMEOUT: Duration = Duration::from_millis(1000);
 
 impl ARGlasses for GrawoowG530 {
     fn serial(&mut self) -> Result<String> {
-        Ok(String::from_utf8(self.command(0x8005, &[])?).map_err(|_| "Invalid serial string")?)
+        String::from_utf8(self.command(0x8005, &[])?).map_err(|e| Error::InvalidUtf8 {
+            context: "Serial number",
+            bytes: e.into_bytes(),
+        })
     }
 
     fn read_event(&mut self) -> Result<GlassesEvent> {
-        let mut packet_data = [0u8; 0x80];
-        self.ov580_handle
-            .read_interrupt(OV580_ENDPOINT, &mut packet_data, OV580_TIMEOUT)?;
-        self.parse_imu_packet(&packet_data)
+        if let Some(warning) = self.transport.take_warning(Instant::now()) {
+            return Ok(warning);
+        }
+        self.transport.read_interrupt(
+            &UsbInterrupt {
+                handle: &self.ov580_handle,
+                endpoint_in: OV580_ENDPOINT,
+                endpoint_out: None,
+            },
+            &mut self.read_buffer,
+            OV580_TIMEOUT,
+            &mut self.diagnostics,
+        )?;
+        let mut event = self.parse_imu_packet(&self.read_buffer)?;
+        self.axis_config.apply(&mut event);
+        Ok(event)
     }
 
     fn get_display_mode(&mut self) -> Result<DisplayMode> {
@@ -62,11 +84,23 @@ impl ARGlasses for GrawoowG530 {
         Ok(())
     }
 
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        Ok(vec![DisplayMode::SameOnBoth, DisplayMode::Stereo])
+    }
+
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        self.axis_config = axis_config;
+    }
+
     fn display_fov(&self) -> f32 {
         // Measurement result
         22f32.to_radians()
     }
 
+    fn display_tilt(&self) -> f32 {
+        Self::DISPLAY_TILT
+    }
+
     fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
         // TODO: use calibration data if possible
         let side_multiplier = match side {
@@ -75,12 +109,20 @@ impl ARGlasses for GrawoowG530 {
         };
         Translation3::new(ipd as f64 * side_multiplier, 0.0, 0.0)
             * UnitQuaternion::from_euler_angles(
-                Self::DISPLAY_TILT,
+                -self.display_tilt() as f64,
                 Self::DISPLAY_DIVERGENCE * side_multiplier,
                 0.0,
             )
     }
 
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::IMU | Capabilities::DISPLAY_CONTROL
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
     fn name(&self) -> &'static str {
         "Grawoow G530"
     }
@@ -101,7 +143,7 @@ impl GrawoowG530 {
     /// Product ID of the OV580 on the G530
     pub const OV580_PID: u16 = 0x0f87;
 
-    const DISPLAY_TILT: f64 = -0.11;
+    const DISPLAY_TILT: f32 = 0.11;
     const DISPLAY_DIVERGENCE: f64 = 0.02;
 
     /// Connect to a specific glasses, based on the two USB fds
@@ -115,24 +157,64 @@ impl GrawoowG530 {
         Self::new_common(
             unsafe { GlobalContext::default().open_device_with_fd(mcu_fd as i32) }?,
             unsafe { GlobalContext::default().open_device_with_fd(ov580_fd as i32) }?,
+            None,
         )
     }
 
     /// Find a connected device and connect to it. (And claim the USB interface)
-    /// Only one instance can be alive at a time
+    /// Only one instance can be alive at a time, see [`Error::AlreadyInUse`]
     #[cfg(not(target_os = "android"))]
     pub fn new() -> Result<Self> {
         use crate::util::get_device_vid_pid;
 
-        Self::new_common(
-            get_device_vid_pid(Self::MCU_VID, Self::MCU_PID)?.open()?,
-            get_device_vid_pid(Self::OV580_VID, Self::OV580_PID)?.open()?,
+        Self::new_with_mcu(
+            get_device_vid_pid(Self::MCU_VID, Self::MCU_PID)?,
+            Duration::ZERO,
         )
     }
 
+    /// Connect to the glasses plugged into a specific USB port, e.g. one saved with
+    /// [`GrawoowG530::usb_location`] before a replug. Waits at most `timeout` for it to appear.
+    #[cfg(not(target_os = "android"))]
+    pub fn open_at(location: &UsbLocation, timeout: Duration) -> Result<Self> {
+        use crate::util::wait_for_device_at;
+
+        let started = Instant::now();
+        let mcu_device = wait_for_device_at(Self::MCU_VID, Self::MCU_PID, timeout, |l| {
+            l.same_port(location)
+        })?;
+        Self::new_with_mcu(mcu_device, timeout.saturating_sub(started.elapsed()))
+    }
+
+    /// Physical location of the glasses (its MCU) on the USB bus
+    pub fn usb_location(&self) -> UsbLocation {
+        usb_location(&self.mcu_handle.device())
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn new_with_mcu(mcu_device: rusb::Device<GlobalContext>, timeout: Duration) -> Result<Self> {
+        use crate::util::{get_device_vid_pid, wait_for_device_at};
+
+        let lock = DeviceLock::for_usb_device(&mcu_device)?;
+        // The MCU and the OV580 are behind the same internal hub. Pair them by topology,
+        // so that multiple connected glasses don't get mixed up.
+        let mcu_location = usb_location(&mcu_device);
+        let sibling = wait_for_device_at(Self::OV580_VID, Self::OV580_PID, timeout, |l| {
+            l.is_sibling_of(&mcu_location)
+        });
+        let ov580_device = match sibling {
+            // The port numbers are not available on every platform. Then take the first
+            // OV580, which is right as long as only one pair of glasses is connected.
+            Err(Error::NotFound) => get_device_vid_pid(Self::OV580_VID, Self::OV580_PID)?,
+            result => result?,
+        };
+        Self::new_common(mcu_device.open()?, ov580_device.open()?, Some(lock))
+    }
+
     fn new_common(
         mut mcu_handle: DeviceHandle<GlobalContext>,
         mut ov580_handle: DeviceHandle<GlobalContext>,
+        lock: Option<DeviceLock>,
     ) -> Result<Self> {
         mcu_handle.set_auto_detach_kernel_driver(true)?;
         ov580_handle.set_auto_detach_kernel_driver(true)?;
@@ -150,6 +232,11 @@ impl GrawoowG530 {
             gyro_bias: Default::default(),
             accelerometer_bias: Default::default(),
             start: Instant::now(),
+            transport: Default::default(),
+            diagnostics: Default::default(),
+            axis_config: Default::default(),
+            _lock: lock,
+            read_buffer: [0; 0x80],
         };
         result.read_calibration()?;
         Ok(result)
@@ -280,7 +367,10 @@ impl GrawoowG530 {
         Ok(GlassesEvent::AccGyro {
             accelerometer,
             gyroscope,
-            timestamp: self.start.elapsed().as_micros() as u64,
+            timestamp: DeviceTimestamp::from_micros(self.start.elapsed().as_micros() as u64),
+            // 16 bit values in 32 bit fields, see the scales above
+            flags: SampleFlags::clipping([gyro_x, gyro_y, gyro_z], [acc_x, acc_y, acc_z], 16)
+                | SampleFlags::BIAS_APPLIED,
         })
     }
 }
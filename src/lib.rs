@@ -23,6 +23,19 @@
 //! out of the hardware. To get quaternions, you should probably use a lib that implements
 //! Madgwicks algorithm or a proper EKF. One good choice is the `eskf` crate.
 //!
+//! The math types in the API (vectors, quaternions, isometries) are from `nalgebra`,
+//! which is re-exported as [`math`], so the matching version can be used without
+//! depending on it directly. For other math libraries (e.g. `glam`), the `mint` feature
+//! turns on `nalgebra`'s `mint` conversions, and re-exports `mint`:
+//!
+//! * `Vector3` (sensor data in the events) converts to and from `mint::Vector3`
+//! * `UnitQuaternion` (orientations) converts to `mint::Quaternion`
+//! * `Matrix4` (the [`VirtualScreen`] matrices) converts to and from `mint::ColumnMatrix4`
+//! * `Isometry3` has no `mint` equivalent: convert its `rotation` and
+//!   `translation.vector` separately
+//!
+//! E.g. `glam::Vec3::from(mint::Vector3::from(accelerometer))`.
+//!
 //! ## Feature flags
 //!
 //! Support for individual AR glasses types ca be enabled with the following features:
@@ -33,21 +46,124 @@
 //!
 //! All of them are enabled by default, which may bring in some unwanted dependencies if you
 //! only want to support a specific type.
+//!
+//! The `mint` feature adds conversions of the math types, see above.
+//!
+//! The `raw_access` feature enables the unstable `ARGlasses::raw_read` and
+//! `ARGlasses::raw_write` methods, for protocol research.
+//!
+//! The `async` feature adds the `async_glasses` module, for reading events in an async
+//! runtime (e.g. tokio) without blocking it.
+//!
+//! The `capi` feature adds `extern "C"` functions in the `capi` module, for using the
+//! drivers from C or C++.
+//!
+//! The `opentrack` feature adds the `opentrack` module, for sending head tracking
+//! data to OpenTrack over UDP.
+//!
+//! The `server` feature adds the `server` module (Unix only), for sharing one glasses
+//! connection with other processes over a Unix domain socket. It also builds the
+//! `ar-helper` binary, a small (optionally setuid) process serving the glasses to
+//! unprivileged applications.
+//!
+//! ## Limitations
+//!
+//! There is no known way to query whether the glasses actually receive a video signal.
+//! The sensor connection usually stays alive even if the DisplayPort signal is lost
+//! (e.g. because of a bad cable or insufficient bandwidth), so a black screen cannot be
+//! detected by this crate. The only exception is the Nreal Air, which reports link
+//! training failures, see [`GlassesEvent::DisplayLink`].
+//!
+//! None of the supported glasses are known to report their power state (input voltage,
+//! power-good or low power warnings), so brownouts on weak power sources (e.g. phones)
+//! only show up as disconnects.
+//!
+//! All reads are blocking, and there are no pollable handles (file descriptors) to
+//! integrate into an event loop like mio or calloop: hidapi doesn't expose them, and the
+//! libusb based drivers use synchronous transfers. Read the events in a dedicated thread,
+//! and forward them to the event loop (e.g. through a channel).
+//! Apart from [`read_all`] and the `async_glasses` module, which read the glasses in
+//! background threads, the crate doesn't start any threads: everything runs in the
+//! calling thread, so a panic in a parser propagates from [`ARGlasses::read_event`]
+//! instead of being hidden.
+
+use std::time::{Duration, Instant};
 
-use nalgebra::{Isometry3, Matrix3, UnitQuaternion, Vector2, Vector3};
+use nalgebra::{Isometry3, Matrix3, Translation3, UnitQuaternion, Vector2, Vector3};
 
+#[cfg(feature = "async")]
+pub mod async_glasses;
+#[cfg(feature = "capi")]
+#[cfg(not(target_os = "android"))]
+pub mod capi;
+mod clock;
+mod diagnostics;
+#[cfg(feature = "hidapi")]
+#[cfg(not(target_os = "android"))]
+mod discovery;
+mod event_log;
+mod gesture;
 #[cfg(feature = "grawoow")]
 pub mod grawoow;
 #[cfg(feature = "mad_gaze")]
 pub mod mad_gaze;
+mod multi;
 #[cfg(feature = "nreal")]
 pub mod nreal_air;
 #[cfg(feature = "nreal")]
 pub mod nreal_light;
+#[cfg(feature = "opentrack")]
+pub mod opentrack;
+mod pose;
+#[cfg(not(target_os = "android"))]
+mod preflight;
+pub mod protocol;
+mod queue;
+mod resume;
 #[cfg(feature = "rokid")]
 pub mod rokid;
+mod screen;
+#[cfg(feature = "server")]
+#[cfg(unix)]
+pub mod server;
+mod stillness;
+mod synthetic;
+mod units;
 mod util;
 
+/// The `mint` version the math types convert to, see the crate docs
+#[cfg(feature = "mint")]
+pub use mint;
+/// The `nalgebra` version used in the API, see the crate docs
+pub use nalgebra as math;
+
+pub use clock::{Clock, SystemClock};
+pub use diagnostics::{Diagnostics, EventMeta};
+#[cfg(feature = "hidapi")]
+#[cfg(not(target_os = "android"))]
+pub use discovery::DiscoveryCache;
+pub use event_log::{log_events_to, EventLogFormat, EventLogReader, EventLogger, LogRecord};
+pub use gesture::{Gesture, GestureDetector};
+#[cfg(not(target_os = "android"))]
+pub use multi::all_glasses;
+pub use multi::{read_all, FoundGlasses};
+pub use pose::{
+    gravity_aligned_orientation, heading, tilt_from_accelerometer, OrientationCorrection, PoseFuser,
+};
+#[cfg(not(target_os = "android"))]
+pub use preflight::{preflight, DeviceKind, PreflightReport, PreflightStep, StepOutcome};
+pub use queue::{EventQueue, OverflowPolicy};
+pub use resume::{AutoResume, ResumeState};
+pub use screen::{ScreenPinning, VirtualScreen};
+pub use stillness::{CalibrationDriftDetector, MotionWakeDetector, StillnessDetector};
+pub use synthetic::{MotionProfile, SyntheticImu};
+pub use units::{DeviceTimestamp, MetersPerSec2, Micros, Radians, RadiansPerSec};
+#[cfg(feature = "rusb")]
+#[cfg(not(target_os = "android"))]
+pub use util::describe_device;
+#[cfg(feature = "rusb")]
+pub use util::{DeviceDescription, EndpointDescription, InterfaceDescription};
+
 /// Possible errors resulting from `ar-drivers` API calls
 #[derive(Debug)]
 pub enum Error {
@@ -64,10 +180,58 @@ pub enum Error {
     SerialPortError(serialport::Error),
     /// No glasses were found.
     NotFound,
+    /// The glasses were found, but the USB interface needed to talk to them
+    /// is not present. This is usually a firmware or driver quirk.
+    /// The `describe_device` example prints the actual layout, please attach
+    /// its output when reporting this.
+    InterfaceNotFound {
+        /// The missing interface number
+        interface: i32,
+    },
+    /// The glasses are already used by another process (or another instance in this one).
+    /// The device was not touched. Sensor-only connections (e.g.
+    /// `NrealAir::new_sensors_only`) do not take this lock, and can run in parallel.
+    AlreadyInUse {
+        /// Process ID of the process holding the glasses, if known
+        holder_pid: Option<u32>,
+    },
+    /// The glasses stopped responding properly, or were unplugged.
+    Disconnected {
+        /// Name of the affected device
+        device: &'static str,
+        /// The error that revealed the disconnection, if there was one
+        source: Option<Box<Error>>,
+    },
     /// Packet sending or reception timed out. Note that this is not the only
     /// timeout error that is sent (e.g. UsbError can contain a timeout), and
     /// also this is usually a fatal one.
     PacketTimeout,
+    /// A string sent by the glasses (e.g. the serial number) is not valid UTF-8.
+    /// The bytes are kept as-is, for diagnosing unusual devices.
+    InvalidUtf8 {
+        /// What was being decoded, e.g. "Serial number"
+        context: &'static str,
+        /// The raw bytes received
+        bytes: Vec<u8>,
+    },
+    /// The glasses (or the way they are connected, e.g. sensor-only) lack a capability
+    /// needed by the called method. Methods tied to a capability return this if and only
+    /// if [`ARGlasses::capabilities`] does not contain it:
+    /// * The display mode methods need [`Capabilities::DISPLAY_CONTROL`]
+    /// * [`ARGlasses::set_ambient_light_reporting`] needs [`Capabilities::AMBIENT_LIGHT`]
+    /// * [`ARGlasses::set_key_debounce`] needs [`Capabilities::KEY_DEBOUNCE`]
+    /// * [`ARGlasses::set_separate_imu_events`] needs [`Capabilities::SEPARATE_IMU_EVENTS`]
+    /// * [`ARGlasses::set_imu_enabled`] needs [`Capabilities::IMU_TOGGLE`]
+    /// * [`ARGlasses::resync`] and [`ARGlasses::revalidate`] need [`Capabilities::RESYNC`]
+    /// * [`ARGlasses::set_auto_revalidate`] needs [`Capabilities::AUTO_REVALIDATE`]
+    /// * The error log methods need [`Capabilities::ERROR_LOG`]
+    /// * The raw access methods need [`Capabilities::RAW_ACCESS`]
+    Unsupported {
+        /// The missing capability
+        capability: Capabilities,
+        /// Name of the device, see [`ARGlasses::name`]
+        device: &'static str,
+    },
     /// Other fatal error, usually a problem with the library itself, or
     /// a device support issue. File a bug if you encounter this.
     Other(&'static str),
@@ -85,11 +249,33 @@ impl std::error::Error for Error {
             Error::HidError(e) => Some(e),
             #[cfg(feature = "serialport")]
             Error::SerialPortError(e) => Some(e),
+            Error::Disconnected {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
             _ => None,
         }
     }
 }
 
+impl Error {
+    /// [`Error::Disconnected`] without a source error
+    pub(crate) fn disconnected(device: &'static str) -> Self {
+        Error::Disconnected {
+            device,
+            source: None,
+        }
+    }
+
+    /// [`Error::Disconnected`], caused by `source`
+    pub(crate) fn disconnected_by(device: &'static str, source: impl Into<Error>) -> Self {
+        Error::Disconnected {
+            device,
+            source: Some(Box::new(source.into())),
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(match self {
@@ -101,7 +287,21 @@ impl std::fmt::Display for Error {
             #[cfg(feature = "serialport")]
             Error::SerialPortError(_) => "Serial error",
             Error::NotFound => "Glasses not found",
+            Error::InterfaceNotFound { .. } => {
+                "Glasses found, but a required USB interface is missing \
+                (run the describe_device example and report its output)"
+            }
+            Error::AlreadyInUse { .. } => "Glasses are already in use by another process",
+            Error::Disconnected { device, .. } => {
+                return write!(f, "{device} disconnected");
+            }
             Error::PacketTimeout => "Packet timeout",
+            Error::InvalidUtf8 { context, .. } => {
+                return write!(f, "{context} is not valid utf-8");
+            }
+            Error::Unsupported { device, .. } => {
+                return write!(f, "Not supported by the {device}");
+            }
             Error::Other(s) => s,
         })
     }
@@ -115,7 +315,7 @@ impl std::fmt::Display for Error {
 pub enum GlassesEvent {
     /// Synchronized accelerometer and gyroscope data.
     AccGyro {
-        /// Accelerometer data in m^2/s.
+        /// Accelerometer data in m/s².
         ///
         /// Remember that while gravitational acceleration is "down", the acceleration
         /// the device "feels" is the one opposite from that, so the normal reading
@@ -124,18 +324,40 @@ pub enum GlassesEvent {
         /// Gyroscope data. Right handed rotation in rad/sec,
         /// e.g. turning left is positive y axis.
         gyroscope: Vector3<f32>,
-        /// Timestamp, in device time, in microseconds
-        timestamp: u64,
+        /// Timestamp, in device time
+        timestamp: DeviceTimestamp,
+        /// Quality of the sample, e.g. to skip clipped samples in a sensor fusion filter
+        flags: SampleFlags,
+    },
+    /// Accelerometer data. Only sent (instead of [`GlassesEvent::AccGyro`]) if enabled
+    /// with [`ARGlasses::set_separate_imu_events`]
+    Accelerometer {
+        /// Same as [`GlassesEvent::AccGyro::accelerometer`]
+        accelerometer: Vector3<f32>,
+        /// Timestamp of the accelerometer sample, in device time
+        timestamp: DeviceTimestamp,
+    },
+    /// Gyroscope data. Only sent (instead of [`GlassesEvent::AccGyro`]) if enabled
+    /// with [`ARGlasses::set_separate_imu_events`]
+    Gyroscope {
+        /// Same as [`GlassesEvent::AccGyro::gyroscope`]
+        gyroscope: Vector3<f32>,
+        /// Timestamp of the gyroscope sample, in device time
+        timestamp: DeviceTimestamp,
     },
     /// Magnetometer data.
     Magnetometer {
         /// Direction of magnetic north (more or less). Unit is uT.
         magnetometer: Vector3<f32>,
-        /// Timestamp, in device time, in microseconds
-        timestamp: u64,
+        /// Timestamp, in device time
+        timestamp: DeviceTimestamp,
     },
     /// A key was pressed (sent once per press)
     /// The number is a key ID, starting from 0.
+    ///
+    /// None of the supported glasses are known to report the press duration,
+    /// so long presses have to be detected by the application (e.g. with a timer
+    /// between presses), and are not distinguished here.
     KeyPress(u8),
 
     /// Proximity sensor senses the user, i.e. the glasses were put on
@@ -147,8 +369,333 @@ pub enum GlassesEvent {
     ProximityFar,
     /// Ambient light level. Unit is vendor-specific
     AmbientLight(u16),
+    /// The display brightness was changed with the buttons of the glasses.
+    /// The value is the new brightness level, its range is device specific.
+    BrightnessChanged(u8),
     /// V-sync happened on the device
     VSync,
+    /// A heartbeat has to be sent soon to keep the display on.
+    /// Only sent in manual heartbeat mode, see [`nreal_light::NrealLight::set_manual_heartbeat`]
+    HeartbeatDue,
+    /// The command/response stream got out of sync and had to be resynchronized.
+    /// Some events may have been lost or reordered.
+    ProtocolResync,
+    /// The state of the video link (DisplayPort alt mode) to the glasses changed.
+    /// Repeated link drops usually mean a bad cable. See [`Diagnostics::display_link_flaps`]
+    DisplayLink {
+        /// Whether the link is up after the change
+        up: bool,
+        /// Human readable details reported by the device, if any
+        detail: Option<String>,
+        /// True if the state was deduced from indirect signs (e.g. an error message),
+        /// instead of an explicit link status report
+        inferred: bool,
+    },
+    /// The driver recovered from USB errors. Nothing was lost, but frequent warnings
+    /// usually mean a bad cable or hub. Rate limited to one per second per kind.
+    /// Only reported by the [`rusb`] based drivers, and by the Nreal Light after
+    /// reopening its IMU ([`TransportErrorKind::Reopened`], some samples were lost then).
+    TransportWarning {
+        /// The kind of the recovered errors
+        kind: TransportErrorKind,
+        /// Number of recovered errors of this kind since the previous warning
+        count: u32,
+    },
+    /// The device reported an error. Unrecognized errors are also reported,
+    /// with [`DeviceErrorKind::Unknown`]
+    DeviceError {
+        /// The kind of the error, if it is a known one
+        kind: DeviceErrorKind,
+        /// The raw payload of the error report
+        raw: Vec<u8>,
+        /// The error message, if the device sent one
+        message: Option<String>,
+    },
+    /// The glasses became still. Only produced by [`StillnessDetector`]
+    Still,
+    /// The glasses started moving after being still. Only produced by [`StillnessDetector`]
+    Moving,
+    /// The glasses were picked up after resting. Only produced by [`MotionWakeDetector`]
+    MotionWake,
+    /// A head gesture was recognized. Only produced by [`GestureDetector`]
+    Gesture(Gesture),
+    /// The session was re-validated after a host suspend was detected,
+    /// see [`ARGlasses::set_auto_revalidate`]. Events were lost during the suspend.
+    SessionRestored,
+    /// The glasses were reconnected after being unplugged, see [`AutoResume`].
+    /// Events were lost during the gap, and the device timestamps may have restarted.
+    Resumed {
+        /// How long the glasses were disconnected
+        gap: Duration,
+    },
+    /// The gyroscope calibration seems to be stale: the glasses were at rest, but the
+    /// gyroscope consistently read far from zero. Only produced by [`CalibrationDriftDetector`]
+    CalibrationDrift {
+        /// Estimated bias (in rad/s) to subtract from the gyroscope data of the events
+        suggested_bias: Vector3<f32>,
+    },
+    /// Events were dropped because the consumer was too slow.
+    /// Only produced by [`EventQueue`], see [`OverflowPolicy`]
+    Overflow {
+        /// Number of events dropped since the previous overflow event
+        dropped: usize,
+    },
+}
+
+/// Kind of an error reported by the device, see [`GlassesEvent::DeviceError`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceErrorKind {
+    /// DisplayPort link training or link failure
+    DpLinkFailure,
+    /// The device is overheating, and reduced its performance
+    ThermalThrottle,
+    /// A sensor (e.g. the IMU) malfunctioned
+    SensorFault,
+    /// Internal firmware error
+    FirmwareAssert,
+    /// Not (yet) known error
+    Unknown,
+}
+
+/// A single accelerometer + gyroscope sample, see [`ARGlasses::latest_imu`].
+/// The fields are the same as in [`GlassesEvent::AccGyro`].
+#[derive(Debug, Clone, Copy)]
+pub struct ImuSample {
+    /// Accelerometer data in m/s²
+    pub accelerometer: Vector3<f32>,
+    /// Gyroscope data in rad/sec
+    pub gyroscope: Vector3<f32>,
+    /// Timestamp, in device time
+    pub timestamp: DeviceTimestamp,
+    /// Quality of the sample
+    pub flags: SampleFlags,
+}
+
+impl ImuSample {
+    /// Extract the sample from an [`GlassesEvent::AccGyro`] event
+    pub fn from_event(event: &GlassesEvent) -> Option<Self> {
+        match *event {
+            GlassesEvent::AccGyro {
+                accelerometer,
+                gyroscope,
+                timestamp,
+                flags,
+            } => Some(ImuSample {
+                accelerometer,
+                gyroscope,
+                timestamp,
+                flags,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// IMU noise parameters from the factory calibration, e.g. for tuning a sensor fusion
+/// filter per unit. Entries that are missing or zero in the calibration are `None`.
+///
+/// The calibration stores the four values without names. They are assumed to be in the
+/// usual (Kalibr) order, which has not been verified against vendor documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ImuNoise {
+    /// Gyroscope white noise density, in rad/s/sqrt(Hz)
+    pub gyroscope_noise_density: Option<f64>,
+    /// Gyroscope bias random walk, in rad/s²/sqrt(Hz)
+    pub gyroscope_random_walk: Option<f64>,
+    /// Accelerometer white noise density, in m/s²/sqrt(Hz)
+    pub accelerometer_noise_density: Option<f64>,
+    /// Accelerometer bias random walk, in m/s³/sqrt(Hz)
+    pub accelerometer_random_walk: Option<f64>,
+}
+
+impl ImuNoise {
+    /// Read from the Nreal calibration data (`IMU.device_1.imu_noises`), `None` if
+    /// the values are not present
+    #[cfg(feature = "nreal")]
+    pub(crate) fn from_config(config: &tinyjson::JsonValue) -> Option<Self> {
+        let values = util::json_float_array::<4>(config, &["IMU", "device_1", "imu_noises"])
+            .ok()?
+            .0[0];
+        let value = |v: f64| (v.is_finite() && v > 0.0).then_some(v);
+        Some(Self {
+            gyroscope_noise_density: value(values[0]),
+            gyroscope_random_walk: value(values[1]),
+            accelerometer_noise_density: value(values[2]),
+            accelerometer_random_walk: value(values[3]),
+        })
+    }
+}
+
+/// Axis remapping applied to the sensor data after the device specific decoding,
+/// see [`ARGlasses::set_axis_config`]. Can be used to correct an inverted or swapped
+/// axis (e.g. for a particular firmware or mounting). The default is the identity,
+/// i.e. the built-in mapping of the driver.
+///
+/// The same remapping is applied to the accelerometer, gyroscope and magnetometer data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisConfig {
+    /// The remapping matrix: `remapped = matrix * decoded`
+    pub matrix: Matrix3<f32>,
+}
+
+impl Default for AxisConfig {
+    fn default() -> Self {
+        Self {
+            matrix: Matrix3::identity(),
+        }
+    }
+}
+
+impl AxisConfig {
+    /// Remapping with a permutation and sign flips: the remapped axis `i` is
+    /// the decoded axis `permutation[i]` (0 is X), negated if `negate[i]` is set.
+    pub fn from_axes(permutation: [usize; 3], negate: [bool; 3]) -> Self {
+        let mut matrix = Matrix3::zeros();
+        for i in 0..3 {
+            matrix[(i, permutation[i] % 3)] = if negate[i] { -1.0 } else { 1.0 };
+        }
+        Self { matrix }
+    }
+
+    /// Apply the remapping to the sensor data in an event. Other events are left unchanged.
+    pub fn apply(&self, event: &mut GlassesEvent) {
+        if self.matrix == Matrix3::identity() {
+            return;
+        }
+        match event {
+            GlassesEvent::AccGyro {
+                accelerometer,
+                gyroscope,
+                ..
+            } => {
+                *accelerometer = self.matrix * *accelerometer;
+                *gyroscope = self.matrix * *gyroscope;
+            }
+            GlassesEvent::Accelerometer { accelerometer, .. } => {
+                *accelerometer = self.matrix * *accelerometer;
+            }
+            GlassesEvent::Gyroscope { gyroscope, .. } => {
+                *gyroscope = self.matrix * *gyroscope;
+            }
+            GlassesEvent::Magnetometer { magnetometer, .. } => {
+                *magnetometer = self.matrix * *magnetometer;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Physical location of a USB device, see e.g. [`rokid::RokidAir::usb_location`]
+///
+/// The bus and port chain stay the same after a replug into the same port,
+/// while the address usually changes.
+///
+/// Only the libusb based drivers can report it: hidapi (used by the Nreal drivers)
+/// doesn't expose the port chain.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UsbLocation {
+    /// Bus number
+    pub bus: u8,
+    /// Device address on the bus. Changes on every replug.
+    pub address: u8,
+    /// Port numbers from the root hub to the device
+    pub ports: Vec<u8>,
+}
+
+impl std::fmt::Display for UsbLocation {
+    /// Formats the bus and port numbers like Linux sysfs, e.g. `1-2.3`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-", self.bus)?;
+        for (i, port) in self.ports.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{port}")?;
+        }
+        Ok(())
+    }
+}
+
+impl UsbLocation {
+    /// True if both locations refer to the same physical port (the address is ignored)
+    pub fn same_port(&self, other: &UsbLocation) -> bool {
+        self.bus == other.bus && self.ports == other.ports
+    }
+
+    /// True if both devices are connected to the same hub
+    pub fn is_sibling_of(&self, other: &UsbLocation) -> bool {
+        self.bus == other.bus
+            && !self.ports.is_empty()
+            && self.ports.len() == other.ports.len()
+            && self.ports[..self.ports.len() - 1] == other.ports[..other.ports.len() - 1]
+    }
+}
+
+/// Kind of a recovered USB error, see [`GlassesEvent::TransportWarning`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportErrorKind {
+    /// The endpoint stalled, and had to be cleared
+    Pipe,
+    /// The device sent more data than requested
+    Overflow,
+    /// The transfer was interrupted
+    Interrupted,
+    /// The device was reset by the host (e.g. after an xHCI error), and had to be reopened
+    Reopened,
+}
+
+impl GlassesEvent {
+    /// The device timestamp of the event, if it has one
+    pub fn timestamp(&self) -> Option<DeviceTimestamp> {
+        match self {
+            GlassesEvent::AccGyro { timestamp, .. }
+            | GlassesEvent::Accelerometer { timestamp, .. }
+            | GlassesEvent::Gyroscope { timestamp, .. }
+            | GlassesEvent::Magnetometer { timestamp, .. } => Some(*timestamp),
+            _ => None,
+        }
+    }
+
+    /// How the event may be treated when a queue is full, see [`EventQueue`]
+    pub fn priority(&self) -> EventPriority {
+        match self {
+            GlassesEvent::AccGyro { .. }
+            | GlassesEvent::Accelerometer { .. }
+            | GlassesEvent::Gyroscope { .. }
+            | GlassesEvent::Magnetometer { .. }
+            | GlassesEvent::VSync => EventPriority::Droppable,
+            GlassesEvent::AmbientLight(_)
+            | GlassesEvent::BrightnessChanged(_)
+            | GlassesEvent::HeartbeatDue => EventPriority::Coalescable,
+            GlassesEvent::KeyPress(_)
+            | GlassesEvent::ProximityNear
+            | GlassesEvent::ProximityFar
+            | GlassesEvent::ProtocolResync
+            | GlassesEvent::DisplayLink { .. }
+            | GlassesEvent::TransportWarning { .. }
+            | GlassesEvent::DeviceError { .. }
+            | GlassesEvent::Still
+            | GlassesEvent::Moving
+            | GlassesEvent::MotionWake
+            | GlassesEvent::Gesture(_)
+            | GlassesEvent::SessionRestored
+            | GlassesEvent::Resumed { .. }
+            | GlassesEvent::CalibrationDrift { .. }
+            | GlassesEvent::Overflow { .. } => EventPriority::Critical,
+        }
+    }
+}
+
+/// Delivery class of a [`GlassesEvent`], see [`GlassesEvent::priority`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventPriority {
+    /// Must never be lost, e.g. key presses and state changes
+    Critical,
+    /// Only the latest one matters, e.g. ambient light levels. A newer one replaces
+    /// the queued one.
+    Coalescable,
+    /// May be dropped if the consumer is slow, e.g. sensor samples
+    Droppable,
 }
 
 /// Display mode used by [`ARGlasses::set_display_mode`]
@@ -168,15 +715,171 @@ pub enum DisplayMode {
     HighRefreshRateSBS,
 }
 
-/// Display side used by [`ARGlasses::view_matrix`]
+/// Set of features supported by a connected glasses, see [`ARGlasses::capabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Accelerometer and gyroscope data ([`GlassesEvent::AccGyro`])
+    pub const IMU: Capabilities = Capabilities(1 << 0);
+    /// Magnetometer data ([`GlassesEvent::Magnetometer`])
+    pub const MAGNETOMETER: Capabilities = Capabilities(1 << 1);
+    /// Hardware buttons ([`GlassesEvent::KeyPress`])
+    pub const KEYS: Capabilities = Capabilities(1 << 2);
+    /// Proximity sensor ([`GlassesEvent::ProximityNear`] and [`GlassesEvent::ProximityFar`])
+    pub const PROXIMITY: Capabilities = Capabilities(1 << 3);
+    /// Ambient light sensor ([`GlassesEvent::AmbientLight`])
+    pub const AMBIENT_LIGHT: Capabilities = Capabilities(1 << 4);
+    /// V-sync events ([`GlassesEvent::VSync`])
+    pub const VSYNC: Capabilities = Capabilities(1 << 5);
+    /// Display mode query and setting ([`ARGlasses::set_display_mode`])
+    pub const DISPLAY_CONTROL: Capabilities = Capabilities(1 << 6);
+    /// Separate accelerometer and gyroscope events ([`ARGlasses::set_separate_imu_events`])
+    pub const SEPARATE_IMU_EVENTS: Capabilities = Capabilities(1 << 7);
+    /// Turning the IMU stream on and off ([`ARGlasses::set_imu_enabled`]).
+    /// Unlike [`Capabilities::IMU`], it is kept while the stream is off.
+    pub const IMU_TOGGLE: Capabilities = Capabilities(1 << 8);
+    /// Debouncing of the hardware buttons ([`ARGlasses::set_key_debounce`])
+    pub const KEY_DEBOUNCE: Capabilities = Capabilities(1 << 9);
+    /// Recovering without reconnecting ([`ARGlasses::resync`] and [`ARGlasses::revalidate`])
+    pub const RESYNC: Capabilities = Capabilities(1 << 10);
+    /// Automatic revalidation after host suspend ([`ARGlasses::set_auto_revalidate`])
+    pub const AUTO_REVALIDATE: Capabilities = Capabilities(1 << 11);
+    /// Firmware error log ([`ARGlasses::read_error_log`])
+    pub const ERROR_LOG: Capabilities = Capabilities(1 << 12);
+    /// Raw reads and writes of the command interface (`raw_read` and `raw_write`,
+    /// only available with the `raw_access` feature)
+    pub const RAW_ACCESS: Capabilities = Capabilities(1 << 13);
+
+    /// No capabilities at all
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// Returns true if all capabilities in `other` are present in `self`
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities present in either `self` or `other`
+    pub const fn union(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// [`Error::Unsupported`] unless the glasses have this capability
+    pub(crate) fn require(self, glasses: &(impl ARGlasses + ?Sized)) -> Result<()> {
+        if glasses.capabilities().contains(self) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported {
+                capability: self,
+                device: glasses.name(),
+            })
+        }
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+/// Quality flags of a single IMU sample, see [`GlassesEvent::AccGyro::flags`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SampleFlags(u8);
+
+impl SampleFlags {
+    /// A gyroscope axis is at the end of its range (e.g. during a fast head snap),
+    /// so the real angular speed was probably larger
+    pub const CLIPPED_GYROSCOPE: SampleFlags = SampleFlags(1 << 0);
+    /// An accelerometer axis is at the end of its range
+    pub const CLIPPED_ACCELEROMETER: SampleFlags = SampleFlags(1 << 1);
+    /// The sample was interpolated by the driver or the device, instead of measured.
+    /// None of the current drivers do this.
+    pub const INTERPOLATED: SampleFlags = SampleFlags(1 << 2);
+    /// The bias from the factory calibration was subtracted from the sample
+    pub const BIAS_APPLIED: SampleFlags = SampleFlags(1 << 3);
+
+    /// No flags at all
+    pub const fn empty() -> Self {
+        SampleFlags(0)
+    }
+
+    /// Returns true if all flags in `other` are present in `self`
+    pub const fn contains(self, other: SampleFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the flags present in either `self` or `other`
+    pub const fn union(self, other: SampleFlags) -> Self {
+        SampleFlags(self.0 | other.0)
+    }
+
+    /// Returns true if either axis is clipped, i.e. the sample should be skipped
+    /// or de-weighted by a sensor fusion filter
+    pub const fn is_clipped(self) -> bool {
+        self.0 & (Self::CLIPPED_GYROSCOPE.0 | Self::CLIPPED_ACCELEROMETER.0) != 0
+    }
+
+    /// Clipping flags from the raw axis values of a report, which are signed integers
+    /// of `bits` width. An axis is clipped if it is at the end of the raw range; devices
+    /// that scale the sensor output before sending it may never get there.
+    #[cfg(any(feature = "nreal", feature = "grawoow", feature = "mad_gaze"))]
+    pub(crate) fn clipping(gyroscope: [f32; 3], accelerometer: [f32; 3], bits: u32) -> Self {
+        let limit = ((1u64 << (bits - 1)) - 1) as f32;
+        let clipped = |raw: [f32; 3]| raw.iter().any(|value| value.abs() >= limit);
+        let mut flags = SampleFlags::empty();
+        if clipped(gyroscope) {
+            flags = flags | SampleFlags::CLIPPED_GYROSCOPE;
+        }
+        if clipped(accelerometer) {
+            flags = flags | SampleFlags::CLIPPED_ACCELEROMETER;
+        }
+        flags
+    }
+}
+
+impl std::ops::BitOr for SampleFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+/// Display side used by [`ARGlasses::imu_to_display_matrix`]
+///
+/// Sides are always physical: [`Side::Left`] is the display in front of the wearer's
+/// left eye. Which half of a side-by-side video signal it shows is described by
+/// [`ARGlasses::sbs_layout`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Side {
-    /// Left display
+    /// Display in front of the left eye
     Left,
-    /// Right display
+    /// Display in front of the right eye
     Right,
 }
 
+/// How the video signal is distributed between the displays, see [`ARGlasses::sbs_layout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbsLayout {
+    /// The same (whole) picture is shown on both displays
+    Mirrored,
+    /// The left half of the picture is shown on the left display ([`Side::Left`]),
+    /// the right half on the right display
+    LeftHalfToLeftEye,
+    /// The left half of the picture is shown on the right display ([`Side::Right`]),
+    /// the right half on the left display
+    LeftHalfToRightEye,
+    /// The halves go to different displays, but which one goes where has not been
+    /// verified on the hardware. Most software assumes [`SbsLayout::LeftHalfToLeftEye`],
+    /// so that is a reasonable guess, but renderers should let the user swap the eyes.
+    Unknown,
+}
+
 /// Common interface for AR implemented glasses
 pub trait ARGlasses: Send {
     /// Get the serial number of the glasses
@@ -187,14 +890,104 @@ pub trait ARGlasses: Send {
     fn get_display_mode(&mut self) -> Result<DisplayMode>;
     /// Set the display mode of the glasses. See [`DisplayMode`]
     fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()>;
+    /// Which half of the video signal reaches which eye in the display mode, so that the
+    /// views can be placed in the framebuffer without guessing.
+    ///
+    /// The layout of the side-by-side modes has not been verified on any of the
+    /// supported glasses yet, so the default implementation reports them as
+    /// [`SbsLayout::Unknown`]. Drivers override it once the layout of a device is verified.
+    fn sbs_layout(&self, mode: DisplayMode) -> SbsLayout {
+        match mode {
+            DisplayMode::Stereo | DisplayMode::HalfSBS | DisplayMode::HighRefreshRateSBS => {
+                SbsLayout::Unknown
+            }
+            DisplayMode::SameOnBoth | DisplayMode::HighRefreshRate => SbsLayout::Mirrored,
+        }
+    }
+    /// Whether the current display mode expects two distinct per-eye images side by side
+    /// (as opposed to one image mirrored to both eyes). Check this to decide whether
+    /// to render one or two views.
+    fn requires_stereo_render(&mut self) -> Result<bool> {
+        Ok(matches!(
+            self.get_display_mode()?,
+            DisplayMode::Stereo | DisplayMode::HalfSBS | DisplayMode::HighRefreshRateSBS
+        ))
+    }
+    /// Human readable description of the current display mode, with the refresh rate
+    /// and whether it is stereo or mirrored, e.g. for diagnostics overlays or bug reports.
+    /// The format is not stable, don't parse it.
+    fn display_mode_debug(&mut self) -> Result<String> {
+        let display_mode = self.get_display_mode()?;
+        let refresh_rate = 1_000_000 / self.display_frame_duration()?.max(1);
+        let layout = if self.requires_stereo_render()? {
+            "stereo"
+        } else {
+            "mirrored"
+        };
+        Ok(format!("{display_mode:?}, {refresh_rate}Hz, {layout}"))
+    }
+    /// Display modes accepted by [`ARGlasses::set_display_mode`].
+    /// Empty if the connection can't control the display.
+    ///
+    /// The modes are not checked against the capacity of the video (DisplayPort alt mode)
+    /// link: none of the supported glasses are known to report the negotiated lane count
+    /// or bandwidth. On a weak link (e.g. a bad cable), a mode can be accepted, but still
+    /// glitch. [`Diagnostics::display_link_flaps`] is the closest available sign of this.
+    ///
+    /// The default only returns the current mode, the one mode known to be accepted.
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        if !self.capabilities().contains(Capabilities::DISPLAY_CONTROL) {
+            return Ok(Vec::new());
+        }
+        Ok(vec![self.get_display_mode()?])
+    }
     /// Field of view of the display along the horizontal axis, in radians
     fn display_fov(&self) -> f32;
+    /// Tilt of the displays relative to the IMU, in radians. Positive values mean that
+    /// the displays look downwards relative to the IMU's forward direction.
+    /// This is the same value that [`ARGlasses::imu_to_display_matrix`] uses.
+    ///
+    /// The default derives it from the rotation around the X axis in
+    /// [`ARGlasses::imu_to_display_rotation`].
+    fn display_tilt(&self) -> f32 {
+        -self.imu_to_display_rotation(Side::Left).euler_angles().0 as f32
+    }
     /// Transformation from IMU frame to display frame, at the specified
     /// IPD (interpupillary distance). The `ipd` parameter is in meters.
     /// A typical value is 0.07.
+    ///
+    /// `side` is the physical display (see [`Side`]), use [`ARGlasses::sbs_layout`]
+    /// to find the half of the framebuffer to render it to.
     fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64>;
+    /// Rotation part of [`ARGlasses::imu_to_display_matrix`]. It does not depend on the IPD.
+    fn imu_to_display_rotation(&self, side: Side) -> UnitQuaternion<f64> {
+        self.imu_to_display_matrix(side, 0.0).rotation
+    }
+    /// Translation part of [`ARGlasses::imu_to_display_matrix`], i.e. the offset of the
+    /// eye at the specified IPD. The `ipd` parameter is in meters.
+    ///
+    /// The full transformation is the rotation followed by this translation:
+    /// `eye_offset(side, ipd) * imu_to_display_rotation(side)`
+    fn eye_offset(&self, side: Side, ipd: f32) -> Translation3<f64> {
+        self.imu_to_display_matrix(side, ipd).translation
+    }
+    /// IPD stored on the device (e.g. set by a companion app), in meters, to be used
+    /// as the default `ipd` parameter instead of a typical value.
+    ///
+    /// None of the supported glasses are known to store one (their configs only
+    /// contain sensor calibration), so this currently always returns `None`.
+    fn stored_ipd(&self) -> Option<f32> {
+        None
+    }
     /// Name of the device
     fn name(&self) -> &'static str;
+    /// Features supported by this connection. See [`Capabilities`]
+    ///
+    /// The default is [`Capabilities::IMU`] and [`Capabilities::DISPLAY_CONTROL`], the
+    /// features every implementation had to provide before capabilities were introduced.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::IMU | Capabilities::DISPLAY_CONTROL
+    }
     /// Get built-in camera descriptors
     fn cameras(&self) -> Result<Vec<CameraDescriptor>> {
         Ok(Vec::new())
@@ -202,8 +995,268 @@ pub trait ARGlasses: Send {
     /// The additional delay (in usecs) of the glasses' display from getting the data
     /// on DisplayPort. This is not really an absolute value, but more of
     /// a relative measure between different glasses.
+    /// It corresponds to the middle of the frame: the full scanout window is
+    /// [`ARGlasses::display_frame_duration`] long, centered on this value.
     /// In the future this may depend on the current display mode.
     fn display_delay(&self) -> u64;
+    /// Duration of a single frame in the current display mode (in usecs), i.e. the
+    /// length of the scanout window. The scan direction and per-line timing of the
+    /// panels are not known for any of the supported glasses.
+    fn display_frame_duration(&mut self) -> Result<u64> {
+        Ok(1_000_000 / 60)
+    }
+    /// Refresh rate of the panels in the current display mode, in Hz, regardless of the
+    /// content layout (mirrored or side-by-side). See also [`ARGlasses::display_frame_duration`].
+    fn current_refresh_rate(&mut self) -> Result<u16> {
+        let frame_duration = self.display_frame_duration()?;
+        if frame_duration == 0 {
+            return Err(Error::Other("Display frame duration is zero"));
+        }
+        Ok(u16::try_from(1_000_000 / frame_duration).unwrap_or(u16::MAX))
+    }
+    /// Timing information (host receipt time, estimated age) about the event last
+    /// returned by [`ARGlasses::read_event`]. `None` if the driver doesn't track it.
+    fn last_event_meta(&self) -> Option<EventMeta> {
+        None
+    }
+    /// Runtime statistics of the connection. `None` if the driver doesn't collect them.
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        None
+    }
+    /// Check whether the device looks like the genuine product the driver was written for.
+    /// This is a diagnostic for clones that share the VID/PID, not a security feature.
+    /// The default implementation checks nothing.
+    fn authenticity_check(&mut self) -> AuthenticityReport {
+        AuthenticityReport::default()
+    }
+    /// Turn [`GlassesEvent::AmbientLight`] events on or off. Only supported by devices with
+    /// [`Capabilities::AMBIENT_LIGHT`]. Drivers that support it turn it on when connecting.
+    fn set_ambient_light_reporting(&mut self, enabled: bool) -> Result<()> {
+        let _ = enabled;
+        Err(Error::Unsupported {
+            capability: Capabilities::AMBIENT_LIGHT,
+            device: self.name(),
+        })
+    }
+    /// Whether [`GlassesEvent::AmbientLight`] events are turned on,
+    /// see [`ARGlasses::set_ambient_light_reporting`]
+    fn ambient_light_reporting(&self) -> bool {
+        false
+    }
+    /// Report accelerometer and gyroscope samples as separate [`GlassesEvent::Accelerometer`]
+    /// and [`GlassesEvent::Gyroscope`] events, each with its own timestamp, instead of
+    /// combined [`GlassesEvent::AccGyro`] events. Off by default.
+    ///
+    /// Only supported by devices that sample the two sensors separately
+    /// ([`Capabilities::SEPARATE_IMU_EVENTS`]).
+    /// Separate events do not update [`ARGlasses::latest_imu`].
+    fn set_separate_imu_events(&mut self, separate: bool) -> Result<()> {
+        let _ = separate;
+        Err(Error::Unsupported {
+            capability: Capabilities::SEPARATE_IMU_EVENTS,
+            device: self.name(),
+        })
+    }
+    /// Turn the IMU data stream on or off without reconnecting, e.g. to let the sensors
+    /// idle while tracking is not needed. It is on after connecting.
+    ///
+    /// While it is off, [`ARGlasses::read_event`] only returns the other events (or
+    /// [`Error::PacketTimeout`] if there are none), and [`ARGlasses::capabilities`] does
+    /// not contain [`Capabilities::IMU`]. Turning it back on takes effect immediately.
+    fn set_imu_enabled(&mut self, enabled: bool) -> Result<()> {
+        let _ = enabled;
+        Err(Error::Unsupported {
+            capability: Capabilities::IMU_TOGGLE,
+            device: self.name(),
+        })
+    }
+    /// Whether the IMU data stream is on, see [`ARGlasses::set_imu_enabled`]
+    fn imu_enabled(&self) -> bool {
+        true
+    }
+    /// Set the debounce window of the hardware buttons: repeated presses of the same key
+    /// within the window are dropped, and counted in [`Diagnostics::debounced_key_presses`].
+    /// The default is 80ms, [`Duration::ZERO`] turns debouncing off.
+    fn set_key_debounce(&mut self, window: Duration) -> Result<()> {
+        let _ = window;
+        Err(Error::Unsupported {
+            capability: Capabilities::KEY_DEBOUNCE,
+            device: self.name(),
+        })
+    }
+    /// Get back to a known state after an error or a [`GlassesEvent::ProtocolResync`],
+    /// without reconnecting: buffered and half-processed data is dropped, stale reports
+    /// waiting in the USB buffers are discarded, and the connection handshakes are
+    /// repeated. Settings made through this trait are kept. See the implementations
+    /// for what exactly is restored.
+    fn resync(&mut self) -> Result<()> {
+        Err(Error::Unsupported {
+            capability: Capabilities::RESYNC,
+            device: self.name(),
+        })
+    }
+    /// Re-validate the session after the host was suspended: the glasses may have been
+    /// reset while the connection still looks valid. Does the same as [`ARGlasses::resync`],
+    /// and also re-applies the display mode last set with [`ARGlasses::set_display_mode`].
+    /// Brightness is not restored, as none of the drivers can set it.
+    ///
+    /// Drivers that support it also do this automatically, see [`ARGlasses::set_auto_revalidate`].
+    fn revalidate(&mut self) -> Result<()> {
+        self.resync()
+    }
+    /// Set the minimum host suspend duration that triggers an automatic
+    /// [`ARGlasses::revalidate`] from [`ARGlasses::read_event`], which then returns
+    /// [`GlassesEvent::SessionRestored`]. `None` turns it off, which is the default.
+    /// A typical value is 5 seconds.
+    ///
+    /// A suspend is detected by the wall clock advancing more than the monotonic clock
+    /// between two reads (the latter stops during suspend on Linux and macOS), so a long
+    /// pause in reading events does not trigger it. While the IMU is streaming, a gap
+    /// in its data has to confirm it: the device time of the next sample jumping by at
+    /// least `min_sleep` (or going backwards), or no sample arriving for a while. So a
+    /// wall clock adjustment (e.g. by NTP) only triggers it while the IMU is off.
+    fn set_auto_revalidate(&mut self, min_sleep: Option<Duration>) -> Result<()> {
+        let _ = min_sleep;
+        Err(Error::Unsupported {
+            capability: Capabilities::AUTO_REVALIDATE,
+            device: self.name(),
+        })
+    }
+    /// Set the axis remapping applied to the sensor data in every following event,
+    /// see [`AxisConfig`]. Every driver in this crate supports it, the default
+    /// implementation (for glasses implemented elsewhere) ignores it.
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        let _ = axis_config;
+    }
+    /// Read events for `window`, and check that IMU samples arrive at `min_rate` Hz or
+    /// faster. Returns the measured rate, or `Error::Other` if the IMU is silent or slow,
+    /// or `window` is zero.
+    ///
+    /// Meant to be called right after connecting, so that a wedged IMU is detected there,
+    /// instead of as a timeout in a later [`ARGlasses::read_event`] call.
+    /// The IMU samples read during the check are discarded, the other events (e.g. the
+    /// initial state of the glasses) are returned for the caller to handle.
+    fn check_imu_rate(&mut self, window: Duration, min_rate: f32) -> Result<ImuRateCheck> {
+        if window.is_zero() {
+            return Err(Error::Other("IMU rate check window is zero"));
+        }
+        let started = Instant::now();
+        let mut samples = 0;
+        let mut other_events = Vec::new();
+        while started.elapsed() < window {
+            match self.read_event() {
+                Ok(GlassesEvent::AccGyro { .. } | GlassesEvent::Gyroscope { .. }) => samples += 1,
+                Ok(event) => other_events.push(event),
+                Err(Error::PacketTimeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let elapsed = started.elapsed().as_secs_f32();
+        let rate = if elapsed > 0.0 {
+            samples as f32 / elapsed
+        } else {
+            0.0
+        };
+        if rate < min_rate {
+            return Err(Error::Other("IMU not streaming at expected rate"));
+        }
+        Ok(ImuRateCheck { rate, other_events })
+    }
+    /// The most recently received IMU sample, without blocking or consuming events.
+    /// There is no background thread, so this is only updated while
+    /// [`ARGlasses::read_event`] is being called. `None` if not supported by the driver.
+    fn latest_imu(&self) -> Option<ImuSample> {
+        None
+    }
+    /// Read the error log kept by the firmware, oldest entry first, e.g. to diagnose
+    /// intermittent resets or display dropouts.
+    ///
+    /// None of the supported glasses are known to keep one (or at least no command is
+    /// known to read it), so this currently always fails. Errors reported while running
+    /// are available as [`GlassesEvent::DeviceError`] events.
+    fn read_error_log(&mut self) -> Result<Vec<String>> {
+        Err(Error::Unsupported {
+            capability: Capabilities::ERROR_LOG,
+            device: self.name(),
+        })
+    }
+    /// Clear the error log kept by the firmware, see [`ARGlasses::read_error_log`]
+    fn clear_error_log(&mut self) -> Result<()> {
+        Err(Error::Unsupported {
+            capability: Capabilities::ERROR_LOG,
+            device: self.name(),
+        })
+    }
+    /// **Unstable, advanced API** for protocol research: read a single report or transfer
+    /// from the device's command interface, bypassing the packet layer. Returns
+    /// [`Error::PacketTimeout`] if nothing arrived within `timeout`.
+    ///
+    /// Reading and writing raw data desyncs the normal packet flow, call
+    /// [`ARGlasses::resync`] before reading events again. Only supported by the
+    /// Nreal (MCU interface) and Rokid (interrupt endpoint) drivers, see
+    /// [`Capabilities::RAW_ACCESS`].
+    #[cfg(feature = "raw_access")]
+    fn raw_read(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let _ = timeout;
+        Err(Error::Unsupported {
+            capability: Capabilities::RAW_ACCESS,
+            device: self.name(),
+        })
+    }
+    /// **Unstable, advanced API**, see [`ARGlasses::raw_read`]. Write `data` to the
+    /// device directly. `endpoint_or_report` is the endpoint address on libusb based
+    /// devices, and the report ID (i.e. the first byte of the report) on HID devices.
+    #[cfg(feature = "raw_access")]
+    fn raw_write(&mut self, endpoint_or_report: u8, data: &[u8]) -> Result<()> {
+        let _ = (endpoint_or_report, data);
+        Err(Error::Unsupported {
+            capability: Capabilities::RAW_ACCESS,
+            device: self.name(),
+        })
+    }
+}
+
+/// Result of [`ARGlasses::authenticity_check`]. Each check is `None` if it
+/// was not performed (e.g. not applicable to the device, or to the connection mode).
+#[derive(Debug, Clone, Default)]
+pub struct AuthenticityReport {
+    /// The calibration config has the expected format and contents
+    pub config_ok: Option<bool>,
+    /// The USB interfaces are laid out as expected
+    pub interface_layout_ok: Option<bool>,
+    /// A known command got a well formed answer
+    pub firmware_response_ok: Option<bool>,
+    /// Human readable explanation of the failed checks
+    pub problems: Vec<&'static str>,
+}
+
+impl AuthenticityReport {
+    /// True if none of the performed checks failed
+    pub fn is_genuine(&self) -> bool {
+        [
+            self.config_ok,
+            self.interface_layout_ok,
+            self.firmware_response_ok,
+        ]
+        .iter()
+        .all(|check| check.unwrap_or(true))
+    }
+
+    pub(crate) fn check(&mut self, ok: bool, problem: &'static str) -> Option<bool> {
+        if !ok {
+            self.problems.push(problem);
+        }
+        Some(ok)
+    }
+}
+
+/// Result of [`ARGlasses::check_imu_rate`]
+#[derive(Debug, Clone)]
+pub struct ImuRateCheck {
+    /// Measured IMU sample rate, in Hz
+    pub rate: f32,
+    /// The events other than IMU samples read during the check, in order
+    pub other_events: Vec<GlassesEvent>,
 }
 
 /// Represents one built-in camera
@@ -226,29 +1279,41 @@ pub struct CameraDescriptor {
 }
 
 /// Convenience function to detect and connect to any of the supported glasses
+/// If the only glasses found are used by another process, [`Error::AlreadyInUse`] is returned.
 #[cfg(not(target_os = "android"))]
 pub fn any_glasses() -> Result<Box<dyn ARGlasses>> {
+    let mut result = Err(Error::NotFound);
     #[cfg(feature = "rokid")]
-    if let Ok(glasses) = rokid::RokidAir::new() {
-        return Ok(Box::new(glasses));
+    match rokid::RokidAir::new() {
+        Ok(glasses) => return Ok(Box::new(glasses)),
+        Err(e @ Error::AlreadyInUse { .. }) => result = Err(e),
+        Err(_) => (),
     };
     #[cfg(feature = "nreal")]
-    if let Ok(glasses) = nreal_air::NrealAir::new() {
-        return Ok(Box::new(glasses));
+    match nreal_air::NrealAir::new() {
+        Ok(glasses) => return Ok(Box::new(glasses)),
+        Err(e @ Error::AlreadyInUse { .. }) => result = Err(e),
+        Err(_) => (),
     };
     #[cfg(feature = "nreal")]
-    if let Ok(glasses) = nreal_light::NrealLight::new() {
-        return Ok(Box::new(glasses));
+    match nreal_light::NrealLight::new() {
+        Ok(glasses) => return Ok(Box::new(glasses)),
+        Err(e @ Error::AlreadyInUse { .. }) => result = Err(e),
+        Err(_) => (),
     };
     #[cfg(feature = "grawoow")]
-    if let Ok(glasses) = grawoow::GrawoowG530::new() {
-        return Ok(Box::new(glasses));
+    match grawoow::GrawoowG530::new() {
+        Ok(glasses) => return Ok(Box::new(glasses)),
+        Err(e @ Error::AlreadyInUse { .. }) => result = Err(e),
+        Err(_) => (),
     };
     #[cfg(feature = "mad_gaze")]
-    if let Ok(glasses) = mad_gaze::MadGazeGlow::new() {
-        return Ok(Box::new(glasses));
+    match mad_gaze::MadGazeGlow::new() {
+        Ok(glasses) => return Ok(Box::new(glasses)),
+        Err(e @ Error::AlreadyInUse { .. }) => result = Err(e),
+        Err(_) => (),
     };
-    Err(Error::NotFound)
+    result
 }
 
 impl From<std::io::Error> for Error {
@@ -283,3 +1348,256 @@ impl From<&'static str> for Error {
         Error::Other(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Glasses implementing only the required methods of [`ARGlasses`]
+    struct Minimal;
+
+    impl ARGlasses for Minimal {
+        fn serial(&mut self) -> Result<String> {
+            Ok("MINIMAL".into())
+        }
+
+        fn read_event(&mut self) -> Result<GlassesEvent> {
+            Err(Error::PacketTimeout)
+        }
+
+        fn get_display_mode(&mut self) -> Result<DisplayMode> {
+            Ok(DisplayMode::HalfSBS)
+        }
+
+        fn set_display_mode(&mut self, _display_mode: DisplayMode) -> Result<()> {
+            Ok(())
+        }
+
+        fn display_fov(&self) -> f32 {
+            0.4
+        }
+
+        fn imu_to_display_matrix(&self, _side: Side, _ipd: f32) -> Isometry3<f64> {
+            Isometry3::rotation(Vector3::x() * -0.25)
+        }
+
+        fn name(&self) -> &'static str {
+            "Minimal"
+        }
+
+        fn display_delay(&self) -> u64 {
+            0
+        }
+    }
+
+    /// Check that the methods tied to a capability return [`Error::Unsupported`] if and
+    /// only if `glasses` lack it. Changes the settings of `glasses`.
+    pub(crate) fn check_capability_methods(glasses: &mut dyn ARGlasses) {
+        let capabilities = glasses.capabilities();
+        let name = glasses.name();
+        let check = |capability: Capabilities, result: Result<()>| {
+            let unsupported = matches!(
+                result,
+                Err(Error::Unsupported { capability: missing, .. }) if missing == capability
+            );
+            assert_eq!(
+                unsupported,
+                !capabilities.contains(capability),
+                "{capability:?} of {name}: {result:?}"
+            );
+        };
+        #[cfg(feature = "raw_access")]
+        {
+            let result = glasses.raw_read(Duration::ZERO).map(drop);
+            check(Capabilities::RAW_ACCESS, result);
+            // Not written to glasses that accept it, as they would get a malformed packet
+            if !capabilities.contains(Capabilities::RAW_ACCESS) {
+                let result = glasses.raw_write(0, &[]);
+                check(Capabilities::RAW_ACCESS, result);
+            }
+        }
+        let result = glasses.get_display_mode().map(drop);
+        check(Capabilities::DISPLAY_CONTROL, result);
+        let result = glasses.set_ambient_light_reporting(true);
+        check(Capabilities::AMBIENT_LIGHT, result);
+        let result = glasses.set_separate_imu_events(false);
+        check(Capabilities::SEPARATE_IMU_EVENTS, result);
+        let result = glasses.set_imu_enabled(true);
+        check(Capabilities::IMU_TOGGLE, result);
+        let result = glasses.set_key_debounce(Duration::ZERO);
+        check(Capabilities::KEY_DEBOUNCE, result);
+        let result = glasses.resync();
+        check(Capabilities::RESYNC, result);
+        let result = glasses.revalidate();
+        check(Capabilities::RESYNC, result);
+        let result = glasses.set_auto_revalidate(None);
+        check(Capabilities::AUTO_REVALIDATE, result);
+        let result = glasses.read_error_log().map(drop);
+        check(Capabilities::ERROR_LOG, result);
+        let result = glasses.clear_error_log();
+        check(Capabilities::ERROR_LOG, result);
+    }
+
+    #[test]
+    fn capability_methods() {
+        check_capability_methods(&mut Minimal);
+        check_capability_methods(&mut SyntheticImu::new(MotionProfile::Stationary));
+    }
+
+    #[test]
+    #[cfg(feature = "mint")]
+    fn mint_round_trip() {
+        let vector = Vector3::new(0.1f32, -9.81, 1e-7);
+        let converted: mint::Vector3<f32> = vector.into();
+        assert_eq!((converted.x, converted.y, converted.z), (0.1, -9.81, 1e-7));
+        assert_eq!(Vector3::from(converted), vector);
+
+        let transform = Minimal.imu_to_display_matrix(Side::Left, 0.07);
+        let rotation: mint::Quaternion<f64> = transform.rotation.into();
+        let rotation = UnitQuaternion::new_unchecked(nalgebra::Quaternion::from(rotation));
+        assert_eq!(rotation, transform.rotation);
+        let translation: mint::Vector3<f64> = transform.translation.vector.into();
+        assert_eq!(Vector3::from(translation), transform.translation.vector);
+
+        let matrix = transform.to_homogeneous();
+        let converted: mint::ColumnMatrix4<f64> = matrix.into();
+        assert_eq!(nalgebra::Matrix4::from(converted), matrix);
+    }
+
+    #[test]
+    fn trait_defaults() {
+        let mut glasses = Minimal;
+        assert_eq!(
+            glasses.capabilities(),
+            Capabilities::IMU | Capabilities::DISPLAY_CONTROL
+        );
+        assert!((glasses.display_tilt() - 0.25).abs() < 1e-6);
+        assert_eq!(
+            glasses.supported_display_modes().unwrap(),
+            [DisplayMode::HalfSBS]
+        );
+    }
+
+    #[test]
+    fn disconnected_source() {
+        use std::error::Error as _;
+
+        let error = Error::disconnected_by("Minimal", Error::PacketTimeout);
+        assert_eq!(error.to_string(), "Minimal disconnected");
+        assert!(matches!(
+            error
+                .source()
+                .and_then(|source| source.downcast_ref::<Error>()),
+            Some(Error::PacketTimeout)
+        ));
+        assert!(Error::disconnected("Minimal").source().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "nreal")]
+    fn imu_noise_from_config() {
+        let config: tinyjson::JsonValue =
+            r#"{"IMU": {"device_1": {"imu_noises": [0.001, 0.0, -1.0, 0.02]}}}"#
+                .parse()
+                .unwrap();
+        assert_eq!(
+            ImuNoise::from_config(&config),
+            Some(ImuNoise {
+                gyroscope_noise_density: Some(0.001),
+                gyroscope_random_walk: None,
+                accelerometer_noise_density: None,
+                accelerometer_random_walk: Some(0.02),
+            })
+        );
+        let config: tinyjson::JsonValue =
+            r#"{"IMU": {"device_1": {"gyro_bias": [0.0, 0.0, 0.0]}}}"#
+                .parse()
+                .unwrap();
+        assert_eq!(ImuNoise::from_config(&config), None);
+    }
+
+    #[test]
+    fn refresh_rate_of_zero_frame_duration() {
+        /// Glasses reporting a bogus frame duration
+        struct Broken(u64);
+
+        impl ARGlasses for Broken {
+            fn serial(&mut self) -> Result<String> {
+                Minimal.serial()
+            }
+            fn read_event(&mut self) -> Result<GlassesEvent> {
+                Minimal.read_event()
+            }
+            fn get_display_mode(&mut self) -> Result<DisplayMode> {
+                Minimal.get_display_mode()
+            }
+            fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
+                Minimal.set_display_mode(display_mode)
+            }
+            fn display_fov(&self) -> f32 {
+                Minimal.display_fov()
+            }
+            fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
+                Minimal.imu_to_display_matrix(side, ipd)
+            }
+            fn name(&self) -> &'static str {
+                "Broken"
+            }
+            fn display_delay(&self) -> u64 {
+                0
+            }
+            fn display_frame_duration(&mut self) -> Result<u64> {
+                Ok(self.0)
+            }
+        }
+
+        assert!(matches!(
+            Broken(0).current_refresh_rate(),
+            Err(Error::Other(_))
+        ));
+        assert_eq!(Broken(1).current_refresh_rate().unwrap(), u16::MAX);
+        assert_eq!(Minimal.current_refresh_rate().unwrap(), 60);
+    }
+
+    #[test]
+    fn imu_rate_check() {
+        let mut glasses = SyntheticImu::new(MotionProfile::Stationary);
+        assert!(matches!(
+            glasses.check_imu_rate(Duration::ZERO, 1.0),
+            Err(Error::Other(_))
+        ));
+        let check = glasses
+            .check_imu_rate(Duration::from_millis(10), 100.0)
+            .unwrap();
+        assert!(check.rate.is_finite() && check.rate >= 100.0);
+        assert!(check.other_events.is_empty());
+
+        // Other events are kept, and reading continues after them
+        let mut glasses = SyntheticImu::new(MotionProfile::Stationary).with_magnetometer(true);
+        let check = glasses
+            .check_imu_rate(Duration::from_millis(10), 100.0)
+            .unwrap();
+        let timestamps: Vec<u64> = check
+            .other_events
+            .iter()
+            .map(|event| match event {
+                GlassesEvent::Magnetometer { timestamp, .. } => timestamp.as_micros(),
+                event => panic!("Unexpected {event:?}"),
+            })
+            .collect();
+        assert!(!timestamps.is_empty());
+        assert!(timestamps.windows(2).all(|pair| pair[1] == pair[0] + 1000));
+        let next = timestamps.last().unwrap() + 1000;
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::AccGyro { timestamp, .. } | GlassesEvent::Magnetometer { timestamp, .. })
+                if timestamp.as_micros() == next
+        ));
+
+        let mut silent = Minimal;
+        assert!(matches!(
+            silent.check_imu_rate(Duration::from_millis(10), 1.0),
+            Err(Error::Other(_))
+        ));
+    }
+}
@@ -11,7 +11,10 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
 use serialport::{SerialPort, SerialPortType, UsbPortInfo};
 
-use crate::{ARGlasses, DisplayMode, Error, GlassesEvent, Result, Side};
+use crate::{
+    util::DeviceLock, ARGlasses, AxisConfig, Capabilities, DeviceTimestamp, DisplayMode, Error,
+    GlassesEvent, Result, SampleFlags, Side,
+};
 
 /*
         Sensor axes:
@@ -48,20 +51,24 @@ use crate::{ARGlasses, DisplayMode, Error, GlassesEvent, Result, Side};
 /// The main structure representing a connected Mad Gaze Glow glasses
 pub struct MadGazeGlow {
     serial: SerialFraming,
-    timestamp: u64,
-    last_magnetometer_timestamp: u64,
+    timestamp: DeviceTimestamp,
+    last_magnetometer_timestamp: DeviceTimestamp,
     pending_events: VecDeque<GlassesEvent>,
+    axis_config: AxisConfig,
 }
 
 impl ARGlasses for MadGazeGlow {
     fn serial(&mut self) -> Result<String> {
-        String::from_utf8(self.serial.do_command(b"GSN", &[])?)
-            .map_err(|_| "Invalid serial string".into())
+        String::from_utf8(self.serial.do_command(b"GSN", &[])?).map_err(|e| Error::InvalidUtf8 {
+            context: "Serial number",
+            bytes: e.into_bytes(),
+        })
     }
 
     fn read_event(&mut self) -> Result<GlassesEvent> {
         loop {
-            if let Some(event) = self.pending_events.pop_front() {
+            if let Some(mut event) = self.pending_events.pop_front() {
+                self.axis_config.apply(&mut event);
                 return Ok(event);
             }
             if self.last_magnetometer_timestamp + MAGNETOMETER_PERIOD < self.timestamp {
@@ -95,6 +102,14 @@ impl ARGlasses for MadGazeGlow {
         }
     }
 
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        Ok(vec![DisplayMode::SameOnBoth, DisplayMode::Stereo])
+    }
+
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        self.axis_config = axis_config;
+    }
+
     fn display_fov(&self) -> f32 {
         // The 23.5 degrees here is an actual measurement result
         //
@@ -105,13 +120,22 @@ impl ARGlasses for MadGazeGlow {
         23.5f32.to_radians()
     }
 
+    fn display_tilt(&self) -> f32 {
+        -0.12
+    }
+
     fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
         let ipd = ipd as f64
             * match side {
                 Side::Left => -0.5,
                 Side::Right => 0.5,
             };
-        Translation3::new(ipd, 0.0, 0.0) * UnitQuaternion::from_euler_angles(0.12, 0.0, 0.0)
+        Translation3::new(ipd, 0.0, 0.0)
+            * UnitQuaternion::from_euler_angles(-self.display_tilt() as f64, 0.0, 0.0)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::IMU | Capabilities::MAGNETOMETER | Capabilities::DISPLAY_CONTROL
     }
 
     fn name(&self) -> &'static str {
@@ -124,23 +148,27 @@ impl ARGlasses for MadGazeGlow {
     }
 }
 
+/// USB IDs of the serial port of the glasses
+pub(crate) const SERIAL_VID: u16 = 1204;
+pub(crate) const SERIAL_PID: u16 = 2;
+
 const AK09911_ADDRESS: u8 = 12;
 const AK09911_LSB_TO_UT: f32 = 4912.0 / 8190.0;
 
 const BMI160_ADDRESS: u8 = 104;
 
-// In useconds
-const MAGNETOMETER_PERIOD: u64 = 50000;
+const MAGNETOMETER_PERIOD: Duration = Duration::from_millis(50);
 
 impl MadGazeGlow {
     /// Find a connected Mad Gaze Glow device and connect to it.
-    /// Only one instance should be alive at a time.
+    /// Only one instance can be alive at a time, see [`Error::AlreadyInUse`]
     pub fn new() -> Result<Self> {
         let mut result = Self {
             serial: SerialFraming::new()?,
             pending_events: Default::default(),
-            timestamp: 0,
-            last_magnetometer_timestamp: 0,
+            timestamp: DeviceTimestamp::default(),
+            last_magnetometer_timestamp: DeviceTimestamp::default(),
+            axis_config: Default::default(),
         };
         result.init_ak09911()?;
         result.init_bmi160()?;
@@ -270,47 +298,63 @@ impl MadGazeGlow {
                     -acc_axis1 as f32 * ACC_UNIT,
                 ),
                 timestamp: self.timestamp,
+                flags: SampleFlags::clipping(
+                    [gyro_axis1 as f32, gyro_axis2 as f32, gyro_axis3 as f32],
+                    [acc_axis1 as f32, acc_axis2 as f32, acc_axis3 as f32],
+                    16,
+                ),
             });
             // TODO: This is a big hack and should be read from the IMU
             // Then again, the IMU should fill its FIFO with this period,
             // and we are already running out of I2C or serial bandwidth
-            self.timestamp += 10000;
+            self.timestamp += Duration::from_millis(10);
         }
         Ok(())
     }
 }
 
+/// Names of the serial ports of the connected glasses
+pub(crate) fn serial_port_names() -> Result<Vec<String>> {
+    Ok(serialport::available_ports()?
+        .into_iter()
+        .filter(|p| {
+            matches!(
+                p.port_type,
+                SerialPortType::UsbPort(UsbPortInfo {
+                    vid: SERIAL_VID,
+                    pid: SERIAL_PID,
+                    ..
+                })
+            )
+        })
+        .map(|p| p.port_name)
+        .collect())
+}
+
 struct SerialFraming {
     port: Box<dyn SerialPort>,
+    _lock: Option<DeviceLock>,
 }
 
 impl SerialFraming {
     pub fn new() -> Result<Self> {
-        let ports = serialport::available_ports()?;
-        let ports: Vec<_> = ports
-            .into_iter()
-            .filter(|p| {
-                matches!(
-                    p.port_type,
-                    SerialPortType::UsbPort(UsbPortInfo {
-                        vid: 1204,
-                        pid: 2,
-                        ..
-                    })
-                )
-            })
-            .collect();
-        if ports.is_empty() {
+        let Some(port_name) = serial_port_names()?.into_iter().next() else {
             return Err(Error::NotFound);
-        }
-        let port = serialport::new(&ports[0].port_name, 921600)
+        };
+        #[cfg(not(target_os = "android"))]
+        let lock = Some(DeviceLock::acquire(
+            &port_name.replace(['/', '\\', ':'], "_"),
+        )?);
+        #[cfg(target_os = "android")]
+        let lock = None;
+        let port = serialport::new(&port_name, 921600)
             .data_bits(serialport::DataBits::Eight)
             .stop_bits(serialport::StopBits::One)
             .parity(serialport::Parity::None)
             .timeout(Duration::from_millis(50))
             .open()?;
         port.clear(serialport::ClearBuffer::All)?;
-        Ok(Self { port })
+        Ok(Self { port, _lock: lock })
     }
 
     fn do_command(&mut self, cmd: &[u8], data: &[u8]) -> Result<Vec<u8>> {
@@ -0,0 +1,289 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Using several glasses from one process. See [`all_glasses`] and [`read_all`]
+
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::{ARGlasses, Error, GlassesEvent, Result};
+
+/// Glasses found by [`all_glasses`]
+pub struct FoundGlasses {
+    /// Name of the model, e.g. "Nreal Air". For Rokid glasses, the exact model is only
+    /// known after connecting, see [`ARGlasses::name`].
+    pub model: &'static str,
+    /// Where the glasses are connected, unique among the found glasses: `usb:` and the
+    /// bus and port numbers (e.g. `usb:1-2.3`), `hid:` and the HID device path, or
+    /// `serial:` and the serial port name. Empty if listing the devices failed.
+    pub id: String,
+    /// The connection, or the reason it could not be made
+    pub glasses: Result<Box<dyn ARGlasses>>,
+}
+
+/// Find every supported glasses, and try to connect to each of them, e.g. to use
+/// several of them from one process. Glasses that could not be connected (e.g. because
+/// they are used by another process) are returned with the error.
+///
+/// Glasses accessed through hidapi or a serial port (Nreal Air, Nreal Light, Mad Gaze
+/// Glow) can only be used one per model, as the drivers can't tell which interfaces
+/// belong together. Further glasses of these models are returned with an error.
+///
+/// The drivers don't share any state, so each returned instance can be moved to its
+/// own reader thread, or all of them can be read with [`read_all`].
+#[cfg(not(target_os = "android"))]
+pub fn all_glasses() -> Vec<FoundGlasses> {
+    #[allow(unused_mut)]
+    let mut result = Vec::new();
+    #[cfg(feature = "rokid")]
+    push_usb(
+        &mut result,
+        "Rokid Air",
+        crate::rokid::RokidAir::VID,
+        crate::rokid::RokidAir::PID,
+        |location| {
+            Ok(Box::new(crate::rokid::RokidAir::open_at(
+                location,
+                Default::default(),
+            )?))
+        },
+    );
+    #[cfg(feature = "nreal")]
+    push_first_only(
+        &mut result,
+        "Nreal Air",
+        crate::util::hid_paths(
+            crate::nreal_air::NrealAir::VID,
+            crate::nreal_air::NrealAir::PID,
+            Some(4),
+        )
+        .map(|paths| {
+            paths
+                .into_iter()
+                .map(|path| format!("hid:{path}"))
+                .collect()
+        }),
+        || Ok(Box::new(crate::nreal_air::NrealAir::new()?)),
+    );
+    #[cfg(feature = "nreal")]
+    push_first_only(
+        &mut result,
+        "Nreal Light",
+        crate::util::hid_paths(
+            crate::nreal_light::NrealLight::MCU_VID,
+            crate::nreal_light::NrealLight::MCU_PID,
+            None,
+        )
+        .map(|paths| {
+            paths
+                .into_iter()
+                .map(|path| format!("hid:{path}"))
+                .collect()
+        }),
+        || Ok(Box::new(crate::nreal_light::NrealLight::new()?)),
+    );
+    #[cfg(feature = "grawoow")]
+    push_usb(
+        &mut result,
+        "Grawoow G530",
+        crate::grawoow::GrawoowG530::MCU_VID,
+        crate::grawoow::GrawoowG530::MCU_PID,
+        |location| {
+            Ok(Box::new(crate::grawoow::GrawoowG530::open_at(
+                location,
+                Default::default(),
+            )?))
+        },
+    );
+    #[cfg(feature = "mad_gaze")]
+    push_first_only(
+        &mut result,
+        "Mad Gaze Glow",
+        crate::mad_gaze::serial_port_names().map(|names| {
+            names
+                .into_iter()
+                .map(|name| format!("serial:{name}"))
+                .collect()
+        }),
+        || Ok(Box::new(crate::mad_gaze::MadGazeGlow::new()?)),
+    );
+    result
+}
+
+/// Connect to every device found at its own USB port
+#[cfg(any(feature = "rokid", feature = "grawoow"))]
+#[cfg(not(target_os = "android"))]
+fn push_usb(
+    result: &mut Vec<FoundGlasses>,
+    model: &'static str,
+    vid: u16,
+    pid: u16,
+    open_at: impl Fn(&crate::UsbLocation) -> Result<Box<dyn ARGlasses>>,
+) {
+    match crate::util::usb_locations(vid, pid) {
+        Ok(locations) => result.extend(locations.iter().map(|location| FoundGlasses {
+            model,
+            id: format!("usb:{location}"),
+            glasses: open_at(location),
+        })),
+        Err(e) => result.push(FoundGlasses {
+            model,
+            id: String::new(),
+            glasses: Err(e),
+        }),
+    }
+}
+
+/// Connect to the first device found, and report the others as unusable
+#[cfg(any(feature = "nreal", feature = "mad_gaze"))]
+#[cfg(not(target_os = "android"))]
+fn push_first_only(
+    result: &mut Vec<FoundGlasses>,
+    model: &'static str,
+    ids: Result<Vec<String>>,
+    open: impl FnOnce() -> Result<Box<dyn ARGlasses>>,
+) {
+    let ids = match ids {
+        Ok(ids) => ids,
+        Err(e) => {
+            result.push(FoundGlasses {
+                model,
+                id: String::new(),
+                glasses: Err(e),
+            });
+            return;
+        }
+    };
+    let mut open = Some(open);
+    result.extend(ids.into_iter().map(|id| FoundGlasses {
+        model,
+        id,
+        glasses: match open.take() {
+            Some(open) => open(),
+            None => Err(Error::Other(
+                "Only one glasses of this model can be used at a time",
+            )),
+        },
+    }));
+}
+
+/// Read several glasses at once, each in its own thread, and merge their events into
+/// one channel. Each event is tagged with the id it was passed with (e.g.
+/// [`FoundGlasses::id`]), and the events of one glasses keep their order.
+///
+/// [`Error::PacketTimeout`] is skipped. Any other error is sent, and stops reading
+/// those glasses, releasing them. After the receiver is dropped, each thread stops at
+/// its next event.
+/// The channel is unbounded, so keep reading it.
+pub fn read_all<I: Clone + Send + 'static>(
+    glasses: impl IntoIterator<Item = (I, Box<dyn ARGlasses>)>,
+) -> Result<Receiver<(I, Result<GlassesEvent>)>> {
+    let (sender, receiver) = channel();
+    for (id, mut glasses) in glasses {
+        let sender = sender.clone();
+        std::thread::Builder::new()
+            .name("ar-drivers reader".into())
+            .spawn(move || loop {
+                match glasses.read_event() {
+                    Err(Error::PacketTimeout) => (),
+                    Err(e) => {
+                        let _ = sender.send((id, Err(e)));
+                        return;
+                    }
+                    Ok(event) => {
+                        if sender.send((id.clone(), Ok(event))).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })?;
+    }
+    Ok(receiver)
+}
+
+#[cfg(all(test, any(feature = "nreal", feature = "mad_gaze")))]
+mod tests {
+    use super::*;
+
+    #[cfg(not(target_os = "android"))]
+    #[test]
+    fn first_only() {
+        let mut result = Vec::new();
+        let opened = std::cell::Cell::new(0);
+        push_first_only(
+            &mut result,
+            "Mad Gaze Glow",
+            Ok(vec!["serial:a".into(), "serial:b".into()]),
+            || {
+                opened.set(opened.get() + 1);
+                Err(Error::NotFound)
+            },
+        );
+        push_first_only(
+            &mut result,
+            "Nreal Air",
+            Err(Error::PacketTimeout),
+            || unreachable!(),
+        );
+        assert_eq!(opened.get(), 1);
+
+        let [first, second, failed] = result.as_slice() else {
+            panic!("Unexpected number of glasses");
+        };
+        assert_eq!(
+            (first.model, first.id.as_str()),
+            ("Mad Gaze Glow", "serial:a")
+        );
+        assert!(matches!(first.glasses, Err(Error::NotFound)));
+        assert_eq!(second.id, "serial:b");
+        assert!(matches!(second.glasses, Err(Error::Other(_))));
+        assert_eq!((failed.model, failed.id.as_str()), ("Nreal Air", ""));
+        assert!(matches!(failed.glasses, Err(Error::PacketTimeout)));
+    }
+
+    /// Two glasses parsing their scripted reports in their own threads
+    #[cfg(feature = "nreal")]
+    #[test]
+    fn read_all_drivers() {
+        use crate::nreal_air::tests::{imu, imu_report, sensors_only};
+
+        let first = imu();
+        let second = imu();
+        let glasses: [(usize, Box<dyn ARGlasses>); 2] = [
+            (0, Box::new(sensors_only(&first))),
+            (1, Box::new(sensors_only(&second))),
+        ];
+        for i in 1..=3 {
+            first.push_read(imu_report(i * 1_000_000));
+            second.push_read(imu_report(i * 10_000_000));
+        }
+        // A stalled stream is skipped
+        first.push_timeout();
+        first.push_read(imu_report(4_000_000));
+        first.push_error(Error::Other("Gone"));
+        second.push_error(Error::NotFound);
+        let events = read_all(glasses).unwrap();
+
+        let mut timestamps = [Vec::new(), Vec::new()];
+        let mut errors = [None, None];
+        while errors.iter().any(Option::is_none) {
+            let (index, event) = events.recv().unwrap();
+            assert!(errors[index].is_none(), "Event after the error");
+            match event {
+                Ok(GlassesEvent::AccGyro { timestamp, .. }) => {
+                    timestamps[index].push(timestamp.as_micros())
+                }
+                Ok(event) => panic!("Unexpected {event:?}"),
+                Err(e) => errors[index] = Some(e),
+            }
+        }
+        assert_eq!(
+            timestamps,
+            [vec![1000, 2000, 3000, 4000], vec![10000, 20000, 30000]]
+        );
+        assert!(matches!(errors[0], Some(Error::Other("Gone"))));
+        assert!(matches!(errors[1], Some(Error::NotFound)));
+        // The reader threads stopped, dropping the glasses and the senders
+        assert!(events.recv().is_err());
+    }
+}
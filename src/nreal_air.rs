@@ -7,71 +7,171 @@
 
 //! Nreal Air AR glasses support. See [`NrealAir`]
 //! It only uses [`hidapi`] for communication.
+//!
+//! Picture adjustments (contrast or gamma presets, like the ones used by the firmware's
+//! "eye comfort" modes) can't be read or changed, as the MCU commands for them are not known.
+//!
+//! Rebooting the MCU (e.g. to get out of a confused display state after rapid mode
+//! switching) is not supported either: no reset command is known, and guessing one risks
+//! switching the MCU to its firmware update mode. Replugging the glasses is the only known
+//! fix; [`crate::AutoResume`] reconnects after it.
 
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
 use hidapi::{HidApi, HidDevice};
-use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use nalgebra::{Isometry3, Matrix4, Translation3, UnitQuaternion, Vector3};
 use tinyjson::JsonValue;
 
-use crate::{util::crc32_adler, ARGlasses, DisplayMode, Error, GlassesEvent, Result, Side};
+use crate::{
+    units::TimestampCheck,
+    util::{
+        classify_error_message, crc32_adler, json_float_array, DeviceLock, FrameTransport,
+        HidTransport, KeyDebouncer, SleepDetector,
+    },
+    ARGlasses, AuthenticityReport, AxisConfig, Capabilities, Clock, DeviceErrorKind,
+    DeviceTimestamp, Diagnostics, DisplayMode, Error, EventMeta, GlassesEvent, ImuNoise, ImuSample,
+    Result, SampleFlags, Side,
+};
+#[cfg(not(target_os = "android"))]
+use crate::{util::hid_interfaces, DiscoveryCache};
+#[cfg(not(target_os = "android"))]
+use std::ffi::{CStr, CString};
 
 /// The main structure representing a connected Nreal Air glasses
 pub struct NrealAir {
-    device: HidDevice,
+    /// The MCU interface. `None` in sensor-only mode
+    device: Option<Box<dyn FrameTransport>>,
+    /// MCU packets read while waiting for a command's answer. Only MCU traffic can
+    /// end up here: the IMU reports come from a separate HID interface (`imu_device`),
+    /// so running a command never consumes or reorders IMU data.
     pending_packets: VecDeque<McuPacket>,
     imu_device: ImuDevice,
+    diagnostics: Diagnostics,
+    timestamp_check: TimestampCheck,
+    last_event_meta: Option<EventMeta>,
+    resync_happened: bool,
+    pending_events: VecDeque<GlassesEvent>,
+    _lock: Option<DeviceLock>,
+    latest_imu: Option<ImuSample>,
+    axis_config: AxisConfig,
+    key_debouncer: KeyDebouncer,
+    /// Display mode last set, restored by `revalidate`
+    display_mode: Option<DisplayMode>,
+    sleep_detector: SleepDetector,
+    imu_enabled: bool,
+    /// Interval of state polling, see `set_state_polling`
+    state_polling: Option<Duration>,
+    last_state_poll: Option<Instant>,
+    /// Last known brightness, either reported or polled
+    brightness: Option<u8>,
+    clock: Arc<dyn Clock>,
 }
 
 const COMMAND_TIMEOUT: i32 = 1000;
 const IMU_TIMEOUT: i32 = 250;
 
+/// Display mode bytes reported by the MCU, with the corresponding [`DisplayMode`],
+/// the refresh rate of the panel in Hz, and whether it expects side-by-side stereo images.
+/// Note that the [`DisplayMode`] alone doesn't tell the last one.
+const DISPLAY_MODES: [(u8, DisplayMode, u16, bool); 8] = [
+    // Mirror 60Hz
+    (1, DisplayMode::SameOnBoth, 60, false),
+    // SBS 60Hz
+    (3, DisplayMode::Stereo, 60, true),
+    // SBS 72Hz
+    (4, DisplayMode::HighRefreshRate, 72, true),
+    // Mirror 72Hz
+    (5, DisplayMode::SameOnBoth, 72, false),
+    // Horizontally stretched SBS, 60Hz
+    (8, DisplayMode::HalfSBS, 60, true),
+    // SBS 90Hz
+    (9, DisplayMode::HighRefreshRate, 90, true),
+    // Mirror 90Hz
+    (10, DisplayMode::HighRefreshRate, 90, false),
+    // Mirror 120Hz
+    (11, DisplayMode::HighRefreshRate, 120, false),
+];
+
+/// Key actions (reported in 0x6c05 packets) that change the display brightness
+const KEY_ACTION_BRIGHTNESS_UP: u8 = 6;
+const KEY_ACTION_BRIGHTNESS_DOWN: u8 = 7;
+
+/// Known error messages of the MCU (sent in 0x6c09 packets), as lowercase keywords.
+/// The exact messages are not documented, so new entries should be as specific as possible.
+const ERROR_MESSAGES: [(&str, DeviceErrorKind); 2] = [
+    ("link", DeviceErrorKind::DpLinkFailure),
+    ("train", DeviceErrorKind::DpLinkFailure),
+];
+
 impl ARGlasses for NrealAir {
     fn serial(&mut self) -> Result<String> {
-        let mut result = self.run_command(McuPacket {
-            cmd_id: 0x15,
-            ..Default::default()
-        })?;
-        result.remove(0);
-        String::from_utf8(result).map_err(|_| Error::Other("Serial number was not utf-8"))
+        // The first byte of the answer is a status code, 0 meaning success.
+        // Retry once before giving up, instead of decoding an error answer as a serial.
+        for _ in 0..2 {
+            let result = self.run_command(McuPacket {
+                cmd_id: 0x15,
+                ..Default::default()
+            })?;
+            if let Some((0, serial)) = result.split_first() {
+                return String::from_utf8(serial.to_vec()).map_err(|e| Error::InvalidUtf8 {
+                    context: "Serial number",
+                    bytes: e.into_bytes(),
+                });
+            }
+        }
+        Err(Error::Other("Serial number request unsuccessful"))
     }
 
     fn read_event(&mut self) -> Result<GlassesEvent> {
-        if let Some(event) = self.read_mcu_packet()? {
-            Ok(event)
+        if self
+            .sleep_detector
+            .host_slept(self.clock.now(), self.clock.wall_now())
+        {
+            self.revalidate()?;
+            return Ok(GlassesEvent::SessionRestored);
+        }
+        let mut polled = false;
+        let mut event = if let Some(event) = self.poll_state_if_due()? {
+            polled = true;
+            event
+        } else if let Some(event) = self.read_mcu_packet()? {
+            event
+        } else if !self.imu_enabled {
+            self.wait_for_mcu_event()?
         } else {
-            self.imu_device.read_packet()
+            self.imu_device.read_packet()?
+        };
+        self.axis_config.apply(&mut event);
+        if self.timestamp_check.check(&event, self.clock.now()) {
+            self.diagnostics.implausible_timestamps += 1;
+        }
+        if let Some(sample) = ImuSample::from_event(&event) {
+            self.latest_imu = Some(sample);
+            if self
+                .sleep_detector
+                .imu_sample(self.clock.now(), sample.timestamp)
+            {
+                self.revalidate()?;
+                return Ok(GlassesEvent::SessionRestored);
+            }
         }
+        let mut meta = self.diagnostics.record_event(&event, self.clock.now());
+        meta.polled = polled;
+        self.last_event_meta = Some(meta);
+        Ok(event)
     }
 
     fn get_display_mode(&mut self) -> Result<DisplayMode> {
-        let result = self.run_command(McuPacket {
-            cmd_id: 0x7,
-            ..Default::default()
-        })?;
-        match result.get(1) {
-            // Mirror 60Hz
-            Some(1) => Ok(DisplayMode::SameOnBoth),
-            // SBS 60Hz
-            Some(3) => Ok(DisplayMode::Stereo),
-            // SBS 72Hz
-            Some(4) => Ok(DisplayMode::HighRefreshRate),
-            // Mirror 72Hz
-            Some(5) => Ok(DisplayMode::SameOnBoth),
-            // Horizontally stretched SBS, 60Hz
-            Some(8) => Ok(DisplayMode::HalfSBS),
-            // SBS 90Hz
-            Some(9) => Ok(DisplayMode::HighRefreshRate),
-            // Mirror 90Hz
-            Some(10) => Ok(DisplayMode::HighRefreshRate),
-            // Mirror 120Hz
-            Some(11) => Ok(DisplayMode::HighRefreshRate),
-            _ => Err(Error::Other("Unknown display mode")),
-        }
+        Ok(self.get_display_mode_entry()?.1)
     }
 
     fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
+        Capabilities::DISPLAY_CONTROL.require(self)?;
         let display_mode_byte = match display_mode {
             DisplayMode::SameOnBoth => 1,
             DisplayMode::HalfSBS => 8,
@@ -85,6 +185,7 @@ impl ARGlasses for NrealAir {
         })?;
 
         if result.first() == Some(&0) {
+            self.display_mode = Some(display_mode);
             Ok(())
         } else {
             Err(Error::Other("Display mode setting unsuccessful"))
@@ -98,6 +199,11 @@ impl ARGlasses for NrealAir {
         24.0f32.to_radians()
     }
 
+    fn display_tilt(&self) -> f32 {
+        // Apparently there is no noticable tilt
+        0.0
+    }
+
     fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
         let side_multiplier = match side {
             Side::Left => -0.5,
@@ -105,8 +211,7 @@ impl ARGlasses for NrealAir {
         };
         Translation3::new(ipd as f64 * side_multiplier, 0.0, 0.0)
             * UnitQuaternion::from_euler_angles(
-                // Apparently there is no noticable tilt
-                0.0,
+                -self.display_tilt() as f64,
                 Self::DISPLAY_DIVERGENCE * side_multiplier,
                 0.0,
             )
@@ -116,9 +221,181 @@ impl ARGlasses for NrealAir {
         7000
     }
 
+    fn display_frame_duration(&mut self) -> Result<u64> {
+        Ok(1_000_000 / self.current_refresh_rate()? as u64)
+    }
+
+    fn current_refresh_rate(&mut self) -> Result<u16> {
+        Ok(self.get_display_mode_entry()?.2)
+    }
+
+    fn requires_stereo_render(&mut self) -> Result<bool> {
+        Ok(self.get_display_mode_entry()?.3)
+    }
+
+    fn display_mode_debug(&mut self) -> Result<String> {
+        let (mode_byte, display_mode, refresh_rate, stereo) = self.get_display_mode_entry()?;
+        let layout = if stereo { "stereo" } else { "mirrored" };
+        Ok(format!(
+            "{display_mode:?}, {refresh_rate}Hz, {layout} (device mode {mode_byte})"
+        ))
+    }
+
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        if self.device.is_none() {
+            return Ok(Vec::new());
+        }
+        Ok(vec![
+            DisplayMode::SameOnBoth,
+            DisplayMode::HalfSBS,
+            DisplayMode::Stereo,
+            DisplayMode::HighRefreshRate,
+            DisplayMode::HighRefreshRateSBS,
+        ])
+    }
+
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        self.axis_config = axis_config;
+    }
+
+    fn set_key_debounce(&mut self, window: Duration) -> Result<()> {
+        Capabilities::KEY_DEBOUNCE.require(self)?;
+        self.key_debouncer.set_window(window);
+        Ok(())
+    }
+
+    /// Drops the queued MCU packets and events, discards the unread MCU and IMU reports,
+    /// checks that the MCU answers commands again (by reading the display mode), and
+    /// turns the IMU stream on again. In sensor-only mode, only the reports are discarded.
+    fn resync(&mut self) -> Result<()> {
+        self.pending_packets.clear();
+        self.pending_events.clear();
+        self.resync_happened = false;
+        if self.device.is_some() {
+            self.mcu()?.drain()?;
+            self.get_display_mode()?;
+        }
+        self.imu_device.device.drain()?;
+        self.imu_device.pending_samples.clear();
+        self.timestamp_check.reset();
+        self.diagnostics.reset_imu_timing();
+        if self.device.is_some() {
+            self.set_imu_enabled(self.imu_enabled)?;
+        }
+        Ok(())
+    }
+
+    fn revalidate(&mut self) -> Result<()> {
+        self.resync()?;
+        if let Some(display_mode) = self.display_mode {
+            self.set_display_mode(display_mode)?;
+        }
+        Ok(())
+    }
+
+    fn set_auto_revalidate(&mut self, min_sleep: Option<Duration>) -> Result<()> {
+        self.sleep_detector.set_min_sleep(min_sleep);
+        Ok(())
+    }
+
     fn name(&self) -> &'static str {
         "Nreal Air"
     }
+
+    fn capabilities(&self) -> Capabilities {
+        let imu = if self.imu_enabled {
+            Capabilities::IMU | Capabilities::MAGNETOMETER
+        } else {
+            Capabilities::empty()
+        };
+        let always = imu | Capabilities::RESYNC | Capabilities::AUTO_REVALIDATE;
+        if self.device.is_some() {
+            always
+                | Capabilities::IMU_TOGGLE
+                | Capabilities::KEYS
+                | Capabilities::KEY_DEBOUNCE
+                | Capabilities::DISPLAY_CONTROL
+                | Capabilities::RAW_ACCESS
+        } else {
+            always
+        }
+    }
+
+    /// The calibration data read at connection time is kept.
+    /// Changing the IMU rate is not supported, as the command for it is not known.
+    fn set_imu_enabled(&mut self, enabled: bool) -> Result<()> {
+        Capabilities::IMU_TOGGLE.require(self)?;
+        self.imu_device.command(0x19, &[enabled as u8])?;
+        self.imu_device.pending_samples.clear();
+        self.imu_enabled = enabled;
+        self.diagnostics.reset_imu_timing();
+        Ok(())
+    }
+
+    fn imu_enabled(&self) -> bool {
+        self.imu_enabled
+    }
+
+    fn last_event_meta(&self) -> Option<EventMeta> {
+        self.last_event_meta
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn latest_imu(&self) -> Option<ImuSample> {
+        self.latest_imu
+    }
+
+    fn authenticity_check(&mut self) -> AuthenticityReport {
+        let mut report = AuthenticityReport::default();
+        let config = self.get_config_json();
+        let config_ok = json_float_array::<3>(config, &["IMU", "device_1", "accel_bias"]).is_ok()
+            && json_float_array::<3>(config, &["IMU", "device_1", "gyro_bias"]).is_ok()
+            && self.raw_display_transforms().is_ok();
+        report.config_ok = report.check(config_ok, "Calibration config has unexpected format");
+        #[cfg(not(target_os = "android"))]
+        {
+            // The MCU is on interface 4, the IMU is on interface 3
+            let layout_ok = hid_interfaces(Self::VID, Self::PID)
+                .is_ok_and(|interfaces| interfaces.contains(&3) && interfaces.contains(&4));
+            report.interface_layout_ok =
+                report.check(layout_ok, "HID interfaces 3 and 4 are not both present");
+        }
+        if self.device.is_some() {
+            let serial_ok = self
+                .serial()
+                .is_ok_and(|serial| !serial.is_empty() && serial.is_ascii());
+            report.firmware_response_ok =
+                report.check(serial_ok, "Serial number query got an unexpected answer");
+        }
+        report
+    }
+
+    #[cfg(feature = "raw_access")]
+    fn raw_read(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        Capabilities::RAW_ACCESS.require(self)?;
+        let mut result = vec![0u8; 0x100];
+        let size = self
+            .mcu()?
+            .read_timeout(&mut result, timeout.as_millis() as i32)?;
+        if size == 0 {
+            return Err(Error::PacketTimeout);
+        }
+        result.truncate(size);
+        Ok(result)
+    }
+
+    #[cfg(feature = "raw_access")]
+    fn raw_write(&mut self, endpoint_or_report: u8, data: &[u8]) -> Result<()> {
+        Capabilities::RAW_ACCESS.require(self)?;
+        let mut report = Vec::with_capacity(data.len() + 1);
+        report.push(endpoint_or_report);
+        report.extend_from_slice(data);
+        self.mcu()?.write(&report)?;
+        Ok(())
+    }
 }
 
 impl NrealAir {
@@ -127,6 +404,13 @@ impl NrealAir {
     /// Product ID of the NReal Air's components
     pub const PID: u16 = 0x0424;
 
+    /// Size of a packet on the MCU (control) interface
+    pub const MCU_PACKET_SIZE: usize = 0x40;
+    /// Size of an IMU report
+    pub const IMU_REPORT_SIZE: usize = 0x80;
+    /// Size of a single sample (timestamp, gyroscope and accelerometer) in an IMU report
+    pub const IMU_SAMPLE_SIZE: usize = 38;
+
     const DISPLAY_DIVERGENCE: f64 = 0.017;
 
     /// Connect to a specific glasses, based on the
@@ -134,28 +418,152 @@ impl NrealAir {
     #[cfg(target_os = "android")]
     pub fn new(fd: isize) -> Result<Self> {
         Self::new_common(
-            HidApi::new_without_enumerate()?.wrap_sys_device(fd, 4)?,
+            Some(hid_transport(
+                HidApi::new_without_enumerate()?.wrap_sys_device(fd, 4)?,
+                4,
+            )),
             ImuDevice::new(fd)?,
+            None,
         )
     }
 
+    /// Connect to the IMU interface of a specific glasses only, based on the USB fd.
+    /// See [`NrealAir::new_sensors_only`] on other platforms.
+    #[cfg(target_os = "android")]
+    pub fn new_sensors_only(fd: isize) -> Result<Self> {
+        Self::new_common(None, ImuDevice::new_passive(ImuDevice::open(fd)?), None)
+    }
+
     /// Find a connected Nreal Air device and connect to it. (And claim the USB interface)
-    /// Only one instance can be alive at a time
+    /// Only one instance can be alive at a time, see [`Error::AlreadyInUse`]
     #[cfg(not(target_os = "android"))]
     pub fn new() -> Result<Self> {
-        Self::new_common(
-            open_vid_pid_endpoint(Self::VID, Self::PID, 4)?,
-            ImuDevice::new()?,
-        )
+        let started_at = Instant::now();
+        let (mcu, mcu_path) = open_vid_pid_endpoint(Self::VID, Self::PID, 4)?;
+        // Before sending anything to the glasses
+        let lock = DeviceLock::for_hid_path(&mcu_path)?;
+        let mut result =
+            Self::new_common(Some(hid_transport(mcu, 4)), ImuDevice::new()?, Some(lock))?;
+        result.diagnostics.connect_duration = Some(started_at.elapsed());
+        Ok(result)
     }
-    fn new_common(device: HidDevice, imu_device: ImuDevice) -> Result<Self> {
+
+    /// Same as [`NrealAir::new`], but try the device paths in the cache first, and only
+    /// enumerate the HID devices if none of them work. The paths of the connected glasses
+    /// are stored in the cache, and entries of glasses that fail to connect or turn out
+    /// to be different glasses are removed.
+    #[cfg(not(target_os = "android"))]
+    pub fn new_with_cache(cache: &mut DiscoveryCache) -> Result<Self> {
+        let started_at = Instant::now();
+        let hidapi = HidApi::new_without_enumerate()?;
+        let cached = Self::connect_cached(cache, |path, interface| {
+            open_hid_path(&hidapi, path, interface).map(|device| hid_transport(device, interface))
+        });
+        if let Some(mut result) = cached {
+            result.diagnostics.connect_duration = Some(started_at.elapsed());
+            result.diagnostics.used_discovery_cache = true;
+            return Ok(result);
+        }
+
+        let (mcu, mcu_path) = open_vid_pid_endpoint(Self::VID, Self::PID, 4)?;
+        let lock = DeviceLock::for_hid_path(&mcu_path)?;
+        let (imu, imu_path) = open_vid_pid_endpoint(Self::VID, Self::PID, 3)?;
+        let mut result = Self::new_common(
+            Some(hid_transport(mcu, 4)),
+            ImuDevice::new_device(hid_transport(imu, 3))?,
+            Some(lock),
+        )?;
+        let serial = result.serial()?;
+        cache.update(
+            &serial,
+            Self::VID,
+            Self::PID,
+            &[(4, &mcu_path), (3, &imu_path)],
+        );
+        result.diagnostics.connect_duration = Some(started_at.elapsed());
+        Ok(result)
+    }
+
+    /// Connect to the first glasses in the cache that can be opened with `open` and
+    /// answers with the cached serial number. Entries of glasses that fail are removed,
+    /// glasses used by another process are skipped.
+    #[cfg(not(target_os = "android"))]
+    fn connect_cached(
+        cache: &mut DiscoveryCache,
+        mut open: impl FnMut(&CStr, i32) -> Option<Box<dyn FrameTransport>>,
+    ) -> Option<Self> {
+        for (serial, [mcu_path, imu_path]) in cache.candidates(Self::VID, Self::PID, [4, 3]) {
+            let Ok(lock) = DeviceLock::for_hid_path(&mcu_path) else {
+                continue;
+            };
+            let (Some(mcu), Some(imu)) = (open(&mcu_path, 4), open(&imu_path, 3)) else {
+                continue;
+            };
+            // The paths may have been reused by other glasses, which is only noticed
+            // from the serial number after connecting. Any failure falls back to
+            // enumerating the devices.
+            let connected = ImuDevice::new_device(imu)
+                .and_then(|imu_device| Self::new_common(Some(mcu), imu_device, None))
+                .and_then(|mut result| Ok((result.serial()?, result)));
+            match connected {
+                Ok((connected_serial, mut result)) if connected_serial == serial => {
+                    result._lock = Some(lock);
+                    return Some(result);
+                }
+                _ => cache.invalidate(&serial),
+            }
+        }
+        None
+    }
+
+    /// Connect to the IMU interface of a connected Nreal Air only, and never touch the MCU.
+    ///
+    /// This can be used while other software (e.g. the vendor's own tools) is controlling
+    /// the glasses. No commands are sent to the glasses at all, not even to the IMU
+    /// interface, so the other software's IMU stream is never interrupted. This means:
+    /// * The calibration data is not read: [`NrealAir::get_config_json`] is `Null`,
+    ///   and the samples are not corrected for the sensor biases.
+    /// * There is no display control, so keeping the display in 3D SBS mode is the
+    ///   other software's job.
+    /// * The IMU stream can't be turned on or off. If the other software turns it off,
+    ///   reading events returns [`Error::PacketTimeout`] until it is turned on again.
+    ///
+    /// Does not take the device lock, so it can be used next to another instance.
+    #[cfg(not(target_os = "android"))]
+    pub fn new_sensors_only() -> Result<Self> {
+        Self::new_common(None, ImuDevice::new_passive(ImuDevice::open()?), None)
+    }
+
+    fn new_common(
+        device: Option<Box<dyn FrameTransport>>,
+        imu_device: ImuDevice,
+        lock: Option<DeviceLock>,
+    ) -> Result<Self> {
         let mut result = Self {
             device,
             pending_packets: Default::default(),
             imu_device,
+            diagnostics: Default::default(),
+            timestamp_check: Default::default(),
+            last_event_meta: None,
+            resync_happened: false,
+            pending_events: Default::default(),
+            _lock: lock,
+            latest_imu: None,
+            axis_config: Default::default(),
+            key_debouncer: Default::default(),
+            display_mode: None,
+            sleep_detector: Default::default(),
+            imu_enabled: true,
+            state_polling: None,
+            last_state_poll: None,
+            brightness: None,
+            clock: Arc::new(crate::SystemClock),
         };
         // Quick check
-        result.serial()?;
+        if result.device.is_some() {
+            result.serial()?;
+        }
         Ok(result)
     }
 
@@ -165,7 +573,127 @@ impl NrealAir {
         &self.imu_device.config_json
     }
 
+    /// IMU noise parameters from the calibration data (`IMU.device_1.imu_noises`),
+    /// `None` if they are not present. See [`ImuNoise`]
+    pub fn imu_noise(&self) -> Option<ImuNoise> {
+        ImuNoise::from_config(self.get_config_json())
+    }
+
+    /// Turn the IMU data stream off and back on. This can be used to
+    /// unstick a wedged IMU stream without a full reconnect.
+    /// The stream is on afterwards, see [`ARGlasses::set_imu_enabled`].
+    pub fn restart_imu_stream(&mut self) -> Result<()> {
+        self.set_imu_enabled(false)?;
+        self.set_imu_enabled(true)
+    }
+
+    /// Read the state that is normally reported by unsolicited events (currently only
+    /// the brightness) every `interval`, and synthesize the change events from it.
+    /// Useful on firmware versions that don't send the events, a few seconds is
+    /// usually a good interval. `None` (the default) turns polling off.
+    ///
+    /// The polls are done by [`ARGlasses::read_event`] between commands, so they never
+    /// interleave with them. The synthesized events are marked in [`EventMeta::polled`].
+    /// Events are only synthesized for changes, the first poll only records the state.
+    pub fn set_state_polling(&mut self, interval: Option<Duration>) {
+        self.state_polling = interval;
+        self.last_state_poll = None;
+    }
+
+    /// Use `clock` instead of the system clock for state polling and host sleep
+    /// detection. Mainly for testing, see [`Clock`].
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.last_state_poll = None;
+        self.clock = clock;
+    }
+
+    /// Returns the per-display transform matrices exactly as they are stored in the
+    /// calibration data (`display.display_1.transform` and `display.display_2.transform`,
+    /// in this order). The JSON arrays are interpreted as row-major 4x4 matrices,
+    /// no other transformation is done.
+    ///
+    /// This is the only per-display calibration known to be in the config: there is no
+    /// separate rectification matrix or homography for aligning the panels. Which display
+    /// is the left one, and what frame the matrices map from and to, has not been
+    /// verified, so apply them to the rendering with care.
+    ///
+    /// Note that [`ARGlasses::imu_to_display_matrix`] is not derived from these, but from
+    /// measured per-model constants.
+    pub fn raw_display_transforms(&self) -> Result<[Matrix4<f64>; 2]> {
+        let config = self.get_config_json();
+        let display_1 = json_float_array::<16>(config, &["display", "display_1", "transform"])?;
+        let display_2 = json_float_array::<16>(config, &["display", "display_2", "transform"])?;
+        Ok([
+            Matrix4::from_row_slice(&display_1.0[0]),
+            Matrix4::from_row_slice(&display_2.0[0]),
+        ])
+    }
+
+    fn get_display_mode_entry(&mut self) -> Result<(u8, DisplayMode, u16, bool)> {
+        Capabilities::DISPLAY_CONTROL.require(self)?;
+        let result = self.run_command(McuPacket {
+            cmd_id: 0x7,
+            ..Default::default()
+        })?;
+        let mode_byte = result.get(1).ok_or(Error::Other("Unknown display mode"))?;
+        DISPLAY_MODES
+            .iter()
+            .find(|(byte, _, _, _)| byte == mode_byte)
+            .copied()
+            .ok_or(Error::Other("Unknown display mode"))
+    }
+
+    /// Poll the brightness if polling is on and it is due. Returns a change event
+    /// if it differs from the last known value.
+    fn poll_state_if_due(&mut self) -> Result<Option<GlassesEvent>> {
+        let Some(interval) = self.state_polling else {
+            return Ok(None);
+        };
+        let now = self.clock.now();
+        if self.device.is_none()
+            || self
+                .last_state_poll
+                .is_some_and(|last_poll| now.saturating_duration_since(last_poll) < interval)
+        {
+            return Ok(None);
+        }
+        self.last_state_poll = Some(now);
+        // Same layout as the display mode answer: a status byte, then the value
+        let result = self.run_command(McuPacket {
+            cmd_id: 0x3,
+            ..Default::default()
+        })?;
+        let brightness = *result
+            .get(1)
+            .ok_or(Error::Other("Invalid brightness answer"))?;
+        let changed = self.brightness.is_some_and(|old| old != brightness);
+        self.brightness = Some(brightness);
+        Ok(changed.then_some(GlassesEvent::BrightnessChanged(brightness)))
+    }
+
+    /// Wait for an MCU event while the IMU stream is off, as long as an IMU read would wait
+    fn wait_for_mcu_event(&mut self) -> Result<GlassesEvent> {
+        if self.device.is_none() {
+            self.clock.sleep(Duration::from_millis(IMU_TIMEOUT as u64));
+            return Err(Error::PacketTimeout);
+        }
+        if let Some(packet) = self.read_packet(IMU_TIMEOUT)? {
+            self.pending_packets.push_back(packet);
+        }
+        self.read_mcu_packet()?.ok_or(Error::PacketTimeout)
+    }
+
     fn read_mcu_packet(&mut self) -> Result<Option<GlassesEvent>> {
+        if self.device.is_none() {
+            return Ok(None);
+        }
+        if self.resync_happened {
+            self.resync_happened = false;
+            return Ok(Some(GlassesEvent::ProtocolResync));
+        }
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Some(event));
+        }
         let packet = if let Some(packet) = self.pending_packets.pop_front() {
             packet
         } else if let Some(packet) = self.read_packet(0)? {
@@ -173,28 +701,86 @@ impl NrealAir {
         } else {
             return Ok(None);
         };
-        Ok(match packet {
+        let event = match packet {
+            // The first byte is the physical key number, the fifth is the action the key
+            // triggered, and the ninth is the new value of the setting it changed (layout
+            // taken from the xrealAirLinuxDriver project). Long and short presses are
+            // not distinguished.
             McuPacket {
                 cmd_id: 0x6c05,
                 data,
-            } => Some(GlassesEvent::KeyPress(data[0] - 1)),
+            } => {
+                if let (Some(action), Some(value)) = (data.get(4), data.get(8)) {
+                    if *action == KEY_ACTION_BRIGHTNESS_UP || *action == KEY_ACTION_BRIGHTNESS_DOWN
+                    {
+                        self.brightness = Some(*value);
+                        self.pending_events
+                            .push_back(GlassesEvent::BrightnessChanged(*value));
+                    }
+                }
+                data.first()
+                    .and_then(|key| key.checked_sub(1))
+                    .map(GlassesEvent::KeyPress)
+            }
             // NOTE: maybe we should retry in these cases instead of basically reporting timeout,
             //       but we will be called again soon enough.
             McuPacket {
                 cmd_id: 0x6c09,
-                data: _data,
+                data,
             } => {
-                // TODO: optional logging in the crate
-                // eprintln!("Got error: {}", String::from_utf8(_data).unwrap());
-                None
+                let message = String::from_utf8_lossy(&data)
+                    .trim_end_matches('\0')
+                    .to_string();
+                let kind = classify_error_message(&ERROR_MESSAGES, &message);
+                // There is no explicit link status report, but DP link training failures
+                // show up as error strings. Recovery is not reported, as there is no
+                // known message for it.
+                if kind == DeviceErrorKind::DpLinkFailure {
+                    self.diagnostics.display_link_flaps += 1;
+                    self.pending_events.push_back(GlassesEvent::DisplayLink {
+                        up: false,
+                        detail: Some(message.clone()),
+                        inferred: true,
+                    });
+                }
+                Some(GlassesEvent::DeviceError {
+                    kind,
+                    raw: data,
+                    message: Some(message),
+                })
             }
             _ => None,
-        })
+        };
+        if let Some(GlassesEvent::KeyPress(key)) = event {
+            if !self
+                .key_debouncer
+                .accept(key, self.clock.now(), &mut self.diagnostics)
+            {
+                return Ok(None);
+            }
+        }
+        Ok(event)
+    }
+
+    fn mcu(&self) -> Result<&dyn FrameTransport> {
+        self.device
+            .as_deref()
+            .ok_or(Error::Other("MCU is not available in sensor-only mode"))
+    }
+
+    /// Error for a command answer that timed out. On Linux, a detached device keeps
+    /// producing zero-length reads for a while before hidapi reports an error,
+    /// so a timeout may actually be an unplug.
+    fn timeout_error(&self) -> Error {
+        match self.mcu() {
+            Ok(mcu) => mcu.check_disconnect(Error::PacketTimeout),
+            Err(e) => e,
+        }
     }
 
     fn read_packet(&mut self, timeout: i32) -> Result<Option<McuPacket>> {
-        let mut result = [0u8; 0x40];
-        let packet_size = self.device.read_timeout(&mut result, timeout)?;
+        let mut result = [0u8; NrealAir::MCU_PACKET_SIZE];
+        let packet_size = self.mcu()?.read_timeout(&mut result, timeout)?;
         if packet_size == 0 {
             Ok(None)
         } else {
@@ -204,50 +790,109 @@ impl NrealAir {
         }
     }
 
+    /// Send a command to the MCU and wait for its answer. Unrelated MCU packets
+    /// (e.g. key presses) are kept in `pending_packets` in their original order.
+    /// This only touches the MCU interface, never the IMU one.
     fn run_command(&mut self, command: McuPacket) -> Result<Vec<u8>> {
-        self.device.write(
-            &command
-                .serialize()
-                .ok_or(Error::Other("Packet serialization failed"))?,
-        )?;
+        let command_data = command
+            .serialize()
+            .ok_or(Error::Other("Packet serialization failed"))?;
+        self.mcu()?.write(&command_data)?;
+        if let Some(result) = self.wait_for_answer(&command)? {
+            return Ok(result);
+        }
+
+        // Too many unrelated packets: the stream is probably out of sync.
+        // Drain whatever is still in flight (the answer may be stuck behind it),
+        // then try once more before giving up.
+        self.resync_happened = true;
+        self.diagnostics.protocol_resyncs += 1;
+        while let Some(packet) = self.read_packet(0)? {
+            if packet.is_answer_to(&command) {
+                return Ok(packet.data);
+            }
+            self.pending_packets.push_back(packet);
+        }
+        self.mcu()?.write(&command_data)?;
+        if let Some(result) = self.wait_for_answer(&command)? {
+            return Ok(result);
+        }
+        Err(Error::disconnected_by("Nreal Air", Error::PacketTimeout))
+    }
 
+    fn wait_for_answer(&mut self, command: &McuPacket) -> Result<Option<Vec<u8>>> {
         for _ in 0..64 {
             let packet = self
                 .read_packet(COMMAND_TIMEOUT)?
-                .ok_or(Error::PacketTimeout)?;
-            if packet.cmd_id == command.cmd_id {
-                return Ok(packet.data);
+                .ok_or_else(|| self.timeout_error())?;
+            if packet.is_answer_to(command) {
+                return Ok(Some(packet.data));
             }
             self.pending_packets.push_back(packet);
         }
-        Err(Error::Other("Received too many unrelated packets"))
+        Ok(None)
     }
 }
 
+/// Open HID interface of the Air
+fn hid_transport(device: HidDevice, interface: i32) -> Box<dyn FrameTransport> {
+    Box::new(HidTransport::new(
+        device,
+        NrealAir::VID,
+        NrealAir::PID,
+        Some(interface),
+        "Nreal Air",
+    ))
+}
+
 struct ImuDevice {
-    device: HidDevice,
+    device: Box<dyn FrameTransport>,
     config_json: JsonValue,
     gyro_bias: Vector3<f32>,
     accelerometer_bias: Vector3<f32>,
+    read_buffer: [u8; NrealAir::IMU_REPORT_SIZE],
+    /// Samples of the last report that were not returned yet
+    pending_samples: VecDeque<GlassesEvent>,
 }
 
+/// Offset of the first sample in an IMU report, after the report id and temperature
+const IMU_SAMPLE_OFFSET: usize = 4;
+/// Size of the magnetometer block after the samples in an IMU report
+const MAGNETOMETER_SIZE: usize = 12;
+/// Largest timestamp difference (in ns) between two samples of the same IMU report
+const MAX_SAMPLE_STEP_NS: u64 = 100_000_000;
+/// The scaled magnetometer values are in Gauss
+const GAUSS_TO_UT: f32 = 100.0;
+
 impl ImuDevice {
     #[cfg(target_os = "android")]
     pub fn new(fd: isize) -> Result<Self> {
-        Self::new_device(HidApi::new_without_enumerate()?.wrap_sys_device(fd, 3)?)
+        Self::new_device(Self::open(fd)?)
     }
 
     #[cfg(not(target_os = "android"))]
     pub fn new() -> Result<Self> {
-        Self::new_device(open_vid_pid_endpoint(NrealAir::VID, NrealAir::PID, 3)?)
+        Self::new_device(Self::open()?)
     }
-    fn new_device(device: HidDevice) -> Result<Self> {
-        let mut result = Self {
-            device,
-            config_json: JsonValue::Null,
-            gyro_bias: Default::default(),
-            accelerometer_bias: Default::default(),
-        };
+
+    #[cfg(target_os = "android")]
+    fn open(fd: isize) -> Result<Box<dyn FrameTransport>> {
+        Ok(hid_transport(
+            HidApi::new_without_enumerate()?.wrap_sys_device(fd, 3)?,
+            3,
+        ))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn open() -> Result<Box<dyn FrameTransport>> {
+        Ok(hid_transport(
+            open_vid_pid_endpoint(NrealAir::VID, NrealAir::PID, 3)?.0,
+            3,
+        ))
+    }
+
+    fn new_device(device: Box<dyn FrameTransport>) -> Result<Self> {
+        let mut result = Self::new_passive(device);
         // Turn off IMU stream while reading config
         result.command(0x19, &[0x0])?;
         result.read_config()?;
@@ -258,15 +903,34 @@ impl ImuDevice {
         Ok(result)
     }
 
+    /// Only reads the IMU reports, without sending any commands (so without reading
+    /// the calibration data). Used in sensor-only mode.
+    fn new_passive(device: Box<dyn FrameTransport>) -> Self {
+        Self {
+            device,
+            config_json: JsonValue::Null,
+            gyro_bias: Default::default(),
+            accelerometer_bias: Default::default(),
+            read_buffer: [0; NrealAir::IMU_REPORT_SIZE],
+            pending_samples: VecDeque::new(),
+        }
+    }
+
     fn read_config(&mut self) -> Result<()> {
-        let len = u32::from_le_bytes(self.command(0x14, &[])?.try_into().unwrap());
+        let len = u32::from_le_bytes(
+            self.command(0x14, &[])?
+                .try_into()
+                .map_err(|_| Error::Other("Invalid glasses config length"))?,
+        );
         let mut config = Vec::new();
         while config.len() < len as usize {
             let mut config_part = self.command(0x15, &[])?;
             config.append(&mut config_part);
         }
-        let config_as_str = String::from_utf8(config)
-            .map_err(|_| Error::Other("Invalid glasses config (not utf-8)"))?;
+        let config_as_str = String::from_utf8(config).map_err(|e| Error::InvalidUtf8 {
+            context: "Glasses config",
+            bytes: e.into_bytes(),
+        })?;
         self.config_json = config_as_str
             .parse()
             .map_err(|_| Error::Other("Invalid glasses config format (JSON parse error)"))?;
@@ -303,7 +967,7 @@ impl ImuDevice {
             let mut data = [0u8; 0x40];
             let result_size = self.device.read_timeout(&mut data, IMU_TIMEOUT)?;
             if result_size == 0 {
-                return Err(Error::PacketTimeout);
+                return Err(self.device.check_disconnect(Error::PacketTimeout));
             }
 
             if let Some(result) = ImuPacket::deserialize(&data) {
@@ -315,24 +979,106 @@ impl ImuDevice {
 
     pub fn read_packet(&mut self) -> Result<GlassesEvent> {
         loop {
-            let mut packet_data = [0u8; 0x80];
-            let data_size = self.device.read_timeout(&mut packet_data, IMU_TIMEOUT)?;
+            if let Some(event) = self.pending_samples.pop_front() {
+                return Ok(event);
+            }
+            let data_size = self
+                .device
+                .read_timeout(&mut self.read_buffer, IMU_TIMEOUT)?;
             if data_size == 0 {
-                return Err(Error::PacketTimeout);
+                return Err(self.device.check_disconnect(Error::PacketTimeout));
             }
 
-            if packet_data[0] == 1 && packet_data[1] == 2 {
-                return self.parse_report(&packet_data);
+            if self.read_buffer[0] == 1 && self.read_buffer[1] == 2 {
+                let samples = self.parse_report(&self.read_buffer[..data_size])?;
+                self.pending_samples.extend(samples);
             };
-            // Else try again
+            // Else try again (or return the first sample)
         }
     }
 
-    fn parse_report(&mut self, packet_data: &[u8]) -> Result<GlassesEvent> {
+    /// Parse all samples in a report. Current firmwares send one sample per report,
+    /// followed by the magnetometer data, which is too short to be mistaken for
+    /// another sample. Zeroed padding after the samples is skipped.
+    /// The magnetometer event gets the timestamp of the last sample.
+    fn parse_report(&self, packet_data: &[u8]) -> Result<Vec<GlassesEvent>> {
         // TODO: This skips over a 2 byte temperature field that may be useful.
-        let mut reader = std::io::Cursor::new(&packet_data[4..]);
+        // TODO: Check checksum
+        let mut samples: Vec<&[u8]> = Vec::new();
+        for sample in packet_data
+            .get(IMU_SAMPLE_OFFSET..)
+            .unwrap_or_default()
+            .chunks_exact(NrealAir::IMU_SAMPLE_SIZE)
+        {
+            let is_sample = match samples.last() {
+                None => sample[..8].iter().any(|&b| b != 0),
+                Some(previous) => Self::is_next_sample(previous, sample),
+            };
+            if !is_sample {
+                break;
+            }
+            samples.push(sample);
+        }
+        let mut events = samples
+            .iter()
+            .map(|sample| self.parse_sample(sample))
+            .collect::<Result<Vec<_>>>()?;
+        let magnetometer_offset = IMU_SAMPLE_OFFSET + events.len() * NrealAir::IMU_SAMPLE_SIZE;
+        if let (Some(GlassesEvent::AccGyro { timestamp, .. }), Some(magnetometer_data)) = (
+            events.last(),
+            packet_data.get(magnetometer_offset..magnetometer_offset + MAGNETOMETER_SIZE),
+        ) {
+            if let Some(event) = Self::parse_magnetometer(magnetometer_data, *timestamp)? {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Whether `sample` is another sample after `previous`, and not the magnetometer block.
+    /// There is no sample count field, so this checks that the multiplier and divisor
+    /// fields are the same, and the timestamp is slightly later.
+    fn is_next_sample(previous: &[u8], sample: &[u8]) -> bool {
+        const SCALE_FIELDS: [std::ops::Range<usize>; 2] = [8..14, 23..29];
+        let timestamp = |sample: &[u8]| LittleEndian::read_u64(&sample[..8]);
+        let step = timestamp(sample).wrapping_sub(timestamp(previous));
+        (1..=MAX_SAMPLE_STEP_NS).contains(&step)
+            && SCALE_FIELDS
+                .iter()
+                .all(|range| previous[range.clone()] == sample[range.clone()])
+    }
+
+    /// Parse the magnetometer block of a report, `None` if it is empty (zero divisor).
+    ///
+    /// Unlike in the other blocks, the multiplier and the divisor are big endian, and the
+    /// values are 16 bit, offset by 0x8000 (layout taken from the xrealAirLinuxDriver
+    /// project). The axes are mapped the same way as the other sensors', like that project
+    /// does, but the directions have not been verified against a compass.
+    fn parse_magnetometer(
+        magnetometer_data: &[u8],
+        timestamp: DeviceTimestamp,
+    ) -> Result<Option<GlassesEvent>> {
+        let mut reader = std::io::Cursor::new(magnetometer_data);
+        let mag_mul = reader.read_i16::<BigEndian>()? as f32;
+        let mag_div = reader.read_i32::<BigEndian>()? as f32;
+        if mag_div == 0.0 {
+            return Ok(None);
+        }
+        let mut raw = [0.0f32; 3];
+        for value in &mut raw {
+            *value = reader.read_u16::<LittleEndian>()?.wrapping_sub(0x8000) as i16 as f32;
+        }
+        let [mag_x, mag_y, mag_z] = raw.map(|value| value * mag_mul / mag_div * GAUSS_TO_UT);
+        Ok(Some(GlassesEvent::Magnetometer {
+            magnetometer: Vector3::new(-mag_x, mag_z, mag_y),
+            timestamp,
+        }))
+    }
 
-        let timestamp = reader.read_u64::<LittleEndian>()? / 1000;
+    fn parse_sample(&self, sample_data: &[u8]) -> Result<GlassesEvent> {
+        let mut reader = std::io::Cursor::new(sample_data);
+
+        let timestamp = DeviceTimestamp::from_nanos(reader.read_u64::<LittleEndian>()?);
         let gyro_mul = reader.read_u16::<LittleEndian>()? as f32;
         let gyro_div = reader.read_u32::<LittleEndian>()? as f32;
         let gyro_x = reader.read_i24::<LittleEndian>()? as f32;
@@ -358,13 +1104,12 @@ impl ImuDevice {
             (acc_z * acc_mul / acc_div) * 9.81 + self.accelerometer_bias.y,
             (acc_y * acc_mul / acc_div) * 9.81 + self.accelerometer_bias.z,
         );
-        // TODO: magnetometer. It's in the same format, but it's non-trivially
-        //       rotated.
-        // TODO: Check checksum
         Ok(GlassesEvent::AccGyro {
             accelerometer,
             gyroscope,
             timestamp,
+            flags: SampleFlags::clipping([gyro_x, gyro_y, gyro_z], [acc_x, acc_y, acc_z], 24)
+                | SampleFlags::BIAS_APPLIED,
         })
     }
 }
@@ -392,19 +1137,24 @@ unsafe impl bytemuck::Zeroable for McuRawPacket {}
 unsafe impl bytemuck::Pod for McuRawPacket {}
 
 impl McuPacket {
-    fn deserialize(data: &[u8; 0x40]) -> Option<McuPacket> {
+    fn is_answer_to(&self, command: &McuPacket) -> bool {
+        self.cmd_id == command.cmd_id
+    }
+
+    fn deserialize(data: &[u8; NrealAir::MCU_PACKET_SIZE]) -> Option<McuPacket> {
         let raw_packet: &McuRawPacket = bytemuck::cast_ref(data);
         if raw_packet.head != 0xfd {
             return None;
         }
         // TODO: maybe check CRC?
+        let data_length = (raw_packet.length as usize).checked_sub(17)?;
         Some(McuPacket {
             cmd_id: raw_packet.cmd_id,
-            data: raw_packet.data[0..(raw_packet.length as usize - 17)].into(),
+            data: raw_packet.data.get(0..data_length)?.into(),
         })
     }
 
-    fn serialize(&self) -> Option<[u8; 0x40]> {
+    fn serialize(&self) -> Option<[u8; NrealAir::MCU_PACKET_SIZE]> {
         let mut data = [0u8; 42];
         data[0..self.data.len()].copy_from_slice(&self.data);
         let mut raw_packet = McuRawPacket {
@@ -449,9 +1199,10 @@ impl ImuPacket {
             return None;
         }
         // TODO: maybe check CRC?
+        let data_length = (raw_packet.length as usize).checked_sub(3)?;
         Some(ImuPacket {
             cmd_id: raw_packet.cmd_id,
-            data: raw_packet.data[0..(raw_packet.length as usize - 3)].into(),
+            data: raw_packet.data.get(0..data_length)?.into(),
         })
     }
 
@@ -471,16 +1222,582 @@ impl ImuPacket {
     }
 }
 
+/// Parse an MCU frame into the fields of the `nreal_air_mcu` test vectors
+pub(crate) fn decode_mcu_vector(frame: &[u8]) -> Option<String> {
+    let packet = McuPacket::deserialize(&padded(frame))?;
+    Some(format!(
+        "cmd_id={:04x},data={}",
+        packet.cmd_id,
+        crate::protocol::hex(&packet.data)
+    ))
+}
+
+/// Serialize the fields of an `nreal_air_mcu` test vector into an MCU frame
+pub(crate) fn encode_mcu_vector(fields: &str) -> Option<Vec<u8>> {
+    let packet = McuPacket {
+        cmd_id: crate::protocol::numeric_field(fields, "cmd_id")?,
+        data: crate::protocol::field(fields, "data")?,
+    };
+    if packet.data.len() > 42 {
+        return None;
+    }
+    Some(packet.serialize()?.to_vec())
+}
+
+/// Parse an IMU interface frame into the fields of the `nreal_air_imu` test vectors
+pub(crate) fn decode_imu_vector(frame: &[u8]) -> Option<String> {
+    let packet = ImuPacket::deserialize(&padded(frame))?;
+    Some(format!(
+        "cmd_id={:02x},data={}",
+        packet.cmd_id,
+        crate::protocol::hex(&packet.data)
+    ))
+}
+
+/// Serialize the fields of an `nreal_air_imu` test vector into an IMU interface frame
+pub(crate) fn encode_imu_vector(fields: &str) -> Option<Vec<u8>> {
+    let packet = ImuPacket {
+        cmd_id: crate::protocol::numeric_field(fields, "cmd_id")?
+            .try_into()
+            .ok()?,
+        data: crate::protocol::field(fields, "data")?,
+    };
+    if packet.data.len() > 56 {
+        return None;
+    }
+    Some(packet.serialize()?.to_vec())
+}
+
+/// Zero pad or truncate a frame to the size of the reports
+fn padded<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut result = [0u8; N];
+    let len = data.len().min(N);
+    result[..len].copy_from_slice(&data[..len]);
+    result
+}
+
+/// Entry points for the fuzz targets in the `fuzz` directory
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing {
+    use super::*;
+
+    /// Deserialize an MCU packet from arbitrary bytes (zero padded or truncated)
+    pub fn mcu_packet(data: &[u8]) {
+        let _ = McuPacket::deserialize(&padded(data));
+    }
+
+    /// Deserialize an IMU interface packet from arbitrary bytes (zero padded or truncated)
+    pub fn imu_packet(data: &[u8]) {
+        let _ = ImuPacket::deserialize(&padded(data));
+    }
+}
+
+/// Open the interface of the device, also returning its path
 #[cfg(not(target_os = "android"))]
-fn open_vid_pid_endpoint(vid: u16, pid: u16, interface: i32) -> Result<HidDevice> {
+fn open_vid_pid_endpoint(vid: u16, pid: u16, interface: i32) -> Result<(HidDevice, CString)> {
     let hidapi = HidApi::new()?;
+    let mut found_device = false;
     for device in hidapi.device_list() {
-        if device.vendor_id() == vid
-            && device.product_id() == pid
-            && device.interface_number() == interface
-        {
-            return Ok(device.open_device(&hidapi)?);
+        if device.vendor_id() == vid && device.product_id() == pid {
+            found_device = true;
+            if device.interface_number() == interface {
+                return Ok((device.open_device(&hidapi)?, device.path().to_owned()));
+            }
+        }
+    }
+    if found_device {
+        Err(Error::InterfaceNotFound { interface })
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+/// Open a cached path, if it is still the specified interface of an Air
+#[cfg(not(target_os = "android"))]
+fn open_hid_path(hidapi: &HidApi, path: &CStr, interface: i32) -> Option<HidDevice> {
+    let device = hidapi.open_path(path).ok()?;
+    let info = device.get_device_info().ok()?;
+    (info.vendor_id() == NrealAir::VID
+        && info.product_id() == NrealAir::PID
+        && info.interface_number() == interface)
+        .then_some(device)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+    use crate::{clock::MockClock, util::ScriptedTransport};
+
+    const CONFIG: &str = r#"{"IMU": {"device_1": {"accel_bias": [0.1, 0.2, 0.3], "gyro_bias": [0.01, 0.02, 0.03], "imu_noises": [1.0, 2.0, 3.0, 4.0]}}}"#;
+    /// Size of the config parts sent by the fake, the real one sends at most 56 bytes
+    const CONFIG_PART_SIZE: usize = 50;
+
+    fn mcu_frame(cmd_id: u16, data: &[u8]) -> Vec<u8> {
+        McuPacket {
+            cmd_id,
+            data: data.into(),
         }
+        .serialize()
+        .unwrap()
+        .to_vec()
+    }
+
+    fn imu_frame(cmd_id: u8, data: &[u8]) -> Vec<u8> {
+        ImuPacket {
+            cmd_id,
+            data: data.into(),
+        }
+        .serialize()
+        .unwrap()
+        .to_vec()
+    }
+
+    /// MCU answering the serial number request, and everything else with success
+    fn mcu() -> ScriptedTransport {
+        mcu_with_brightness(Arc::new(AtomicU8::new(3)))
+    }
+
+    /// Like [`mcu`], also answering the display mode (2D) and brightness queries
+    fn mcu_with_brightness(brightness: Arc<AtomicU8>) -> ScriptedTransport {
+        let mcu = ScriptedTransport::default();
+        mcu.set_responder(move |frame| {
+            let command = McuPacket::deserialize(&padded(frame)).unwrap();
+            let answer = match command.cmd_id {
+                0x15 => b"\x00SERIAL".to_vec(),
+                0x7 => vec![0, 1],
+                0x3 => vec![0, brightness.load(Ordering::Relaxed)],
+                _ => vec![0],
+            };
+            vec![mcu_frame(command.cmd_id, &answer)]
+        });
+        mcu
+    }
+
+    fn connect(mcu: &ScriptedTransport) -> NrealAir {
+        NrealAir::new_common(
+            Some(mcu.boxed()),
+            ImuDevice::new_device(imu().boxed()).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    /// IMU interface serving `CONFIG` in parts
+    pub(crate) fn imu() -> ScriptedTransport {
+        let imu = ScriptedTransport::default();
+        let mut parts = CONFIG.as_bytes().chunks(CONFIG_PART_SIZE);
+        imu.set_responder(move |frame| {
+            let command = ImuPacket::deserialize(&padded(frame)).unwrap();
+            let answer = match command.cmd_id {
+                0x14 => {
+                    parts = CONFIG.as_bytes().chunks(CONFIG_PART_SIZE);
+                    (CONFIG.len() as u32).to_le_bytes().to_vec()
+                }
+                0x15 => parts.next().unwrap_or_default().to_vec(),
+                _ => Vec::new(),
+            };
+            vec![imu_frame(command.cmd_id, &answer)]
+        });
+        imu
+    }
+
+    #[test]
+    fn config_download() {
+        let mcu = mcu();
+        let imu = imu();
+        let glasses = NrealAir::new_common(
+            Some(mcu.boxed()),
+            ImuDevice::new_device(imu.boxed()).unwrap(),
+            None,
+        )
+        .unwrap();
+
+        let commands: Vec<(u8, Vec<u8>)> = imu
+            .written()
+            .iter()
+            .map(|frame| {
+                let packet = ImuPacket::deserialize(&padded(frame)).unwrap();
+                (packet.cmd_id, packet.data)
+            })
+            .collect();
+        let part_count = CONFIG.len().div_ceil(CONFIG_PART_SIZE);
+        assert_eq!(commands.len(), part_count + 3);
+        assert_eq!(commands[0], (0x19, vec![0]));
+        assert_eq!(commands[1], (0x14, vec![]));
+        assert!(commands[2..part_count + 2] == vec![(0x15, vec![]); part_count]);
+        assert_eq!(commands[part_count + 2], (0x19, vec![1]));
+
+        assert_eq!(glasses.imu_device.gyro_bias, Vector3::new(0.01, 0.02, 0.03));
+        assert_eq!(
+            glasses.imu_device.accelerometer_bias,
+            Vector3::new(0.1, 0.2, 0.3)
+        );
+        assert_eq!(
+            glasses.imu_noise().unwrap().accelerometer_random_walk,
+            Some(4.0)
+        );
+        // The serial number was requested as a connection check
+        assert_eq!(mcu.written(), [mcu_frame(0x15, &[])]);
+    }
+
+    #[test]
+    fn discovery_cache_invalidation() {
+        let paths = [(4, &CString::from(c"mcu")), (3, &CString::from(c"imu"))];
+        let open = |_: &CStr, interface| {
+            Some(if interface == 4 {
+                mcu().boxed()
+            } else {
+                imu().boxed()
+            })
+        };
+        let mut cache = DiscoveryCache::new();
+        cache.update("SERIAL", NrealAir::VID, NrealAir::PID, &paths);
+        assert!(NrealAir::connect_cached(&mut cache, open).is_some());
+        assert_eq!(cache.candidates(NrealAir::VID, NrealAir::PID, [4]).len(), 1);
+
+        // The paths now belong to other glasses
+        let mut cache = DiscoveryCache::new();
+        cache.update("OTHER", NrealAir::VID, NrealAir::PID, &paths);
+        assert!(NrealAir::connect_cached(&mut cache, open).is_none());
+        assert!(cache
+            .candidates(NrealAir::VID, NrealAir::PID, [4])
+            .is_empty());
+
+        // The IMU interface doesn't answer
+        let mut cache = DiscoveryCache::new();
+        cache.update("SERIAL", NrealAir::VID, NrealAir::PID, &paths);
+        let silent_imu = |_: &CStr, interface| {
+            Some(if interface == 4 {
+                mcu().boxed()
+            } else {
+                ScriptedTransport::default().boxed()
+            })
+        };
+        assert!(NrealAir::connect_cached(&mut cache, silent_imu).is_none());
+        assert!(cache
+            .candidates(NrealAir::VID, NrealAir::PID, [4])
+            .is_empty());
+
+        // Paths that can't be opened are kept, the glasses may be just unplugged
+        let mut cache = DiscoveryCache::new();
+        cache.update("SERIAL", NrealAir::VID, NrealAir::PID, &paths);
+        assert!(NrealAir::connect_cached(&mut cache, |_, _| None).is_none());
+        assert_eq!(cache.candidates(NrealAir::VID, NrealAir::PID, [4]).len(), 1);
+    }
+
+    #[test]
+    fn config_download_timeout() {
+        let imu = ScriptedTransport::default();
+        imu.set_responder(|frame| {
+            let command = ImuPacket::deserialize(&padded(frame)).unwrap();
+            match command.cmd_id {
+                // A length is announced, but the parts never arrive
+                0x14 => vec![imu_frame(0x14, &100u32.to_le_bytes())],
+                0x15 => Vec::new(),
+                cmd_id => vec![imu_frame(cmd_id, &[])],
+            }
+        });
+        assert!(matches!(
+            ImuDevice::new_device(imu.boxed()),
+            Err(Error::PacketTimeout)
+        ));
+    }
+
+    #[test]
+    fn state_polling() {
+        let brightness = Arc::new(AtomicU8::new(3));
+        let mcu = mcu_with_brightness(brightness.clone());
+        let mut glasses = connect(&mcu);
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        glasses.set_state_polling(Some(Duration::from_secs(1)));
+        let polls = |mcu: &ScriptedTransport| {
+            mcu.written()
+                .iter()
+                .filter(|frame| McuPacket::deserialize(&padded(frame)).unwrap().cmd_id == 0x3)
+                .count()
+        };
+        mcu.clear_written();
+
+        // The first poll only learns the current value
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(!glasses.last_event_meta().is_some_and(|meta| meta.polled));
+        assert_eq!(polls(&mcu), 1);
+
+        brightness.store(5, Ordering::Relaxed);
+        clock.advance(Duration::from_millis(999));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(polls(&mcu), 1);
+
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::BrightnessChanged(5))
+        ));
+        assert!(glasses.last_event_meta().unwrap().polled);
+        assert_eq!(polls(&mcu), 2);
+
+        // Unchanged values are not reported
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(polls(&mcu), 3);
+    }
+
+    #[test]
+    fn revalidate_after_host_sleep() {
+        let mcu = mcu();
+        let mut glasses = connect(&mcu);
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        glasses
+            .set_auto_revalidate(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+
+        // A long pause is not a sleep
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        clock.suspend(Duration::from_secs(4));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+
+        mcu.clear_written();
+        clock.suspend(Duration::from_secs(5));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::SessionRestored)
+        ));
+        // The display mode was checked again
+        assert_eq!(mcu.written()[0], mcu_frame(0x7, &[]));
+    }
+
+    #[test]
+    fn raw_display_transforms() {
+        let mut glasses = connect(&mcu());
+        assert!(glasses.raw_display_transforms().is_err());
+
+        let transform = |offset: f64| {
+            let values: Vec<String> = (0..16).map(|i| (i as f64 + offset).to_string()).collect();
+            format!("[{}]", values.join(", "))
+        };
+        glasses.imu_device.config_json = format!(
+            r#"{{"display": {{"display_1": {{"transform": {}}}, "display_2": {{"transform": {}}}}}}}"#,
+            transform(0.0),
+            transform(100.0)
+        )
+        .parse()
+        .unwrap();
+        let [display_1, display_2] = glasses.raw_display_transforms().unwrap();
+        // Row-major: the fourth value is the X translation
+        assert_eq!(display_1[(0, 3)], 3.0);
+        assert_eq!(display_1[(3, 0)], 12.0);
+        assert_eq!(display_2[(0, 0)], 100.0);
+    }
+
+    #[test]
+    fn empty_reads_after_unplug() {
+        let mcu = mcu();
+        let imu = imu();
+        let mut glasses = NrealAir::new_common(
+            Some(mcu.boxed()),
+            ImuDevice::new_device(imu.boxed()).unwrap(),
+            None,
+        )
+        .unwrap();
+        // An empty read from a connected device is a timeout
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(matches!(
+            glasses.get_display_mode(),
+            Ok(DisplayMode::SameOnBoth)
+        ));
+
+        mcu.unplug("Nreal Air");
+        imu.unplug("Nreal Air IMU");
+        assert!(matches!(
+            glasses.read_event(),
+            Err(Error::Disconnected {
+                device: "Nreal Air IMU",
+                source: Some(_)
+            })
+        ));
+        assert!(matches!(
+            glasses.get_display_mode(),
+            Err(Error::Disconnected {
+                device: "Nreal Air",
+                source: Some(_)
+            })
+        ));
+    }
+
+    /// A sample block of an IMU report, with unit multipliers and divisors
+    fn imu_sample(timestamp_ns: u64, gyroscope: [i32; 3], accelerometer: [i32; 3]) -> Vec<u8> {
+        let mut sample = timestamp_ns.to_le_bytes().to_vec();
+        for values in [gyroscope, accelerometer] {
+            sample.extend_from_slice(&1u16.to_le_bytes());
+            sample.extend_from_slice(&1u32.to_le_bytes());
+            for value in values {
+                sample.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+        }
+        sample
+    }
+
+    /// Full IMU report with one sample and no magnetometer data
+    pub(crate) fn imu_report(timestamp_ns: u64) -> Vec<u8> {
+        let mut report = vec![1, 2];
+        report.resize(IMU_SAMPLE_OFFSET, 0);
+        report.extend(imu_sample(timestamp_ns, [1, 0, 0], [0, 0, 1]));
+        report.resize(NrealAir::IMU_REPORT_SIZE, 0);
+        report
+    }
+
+    /// Glasses with only the IMU interface, see [`NrealAir::new_sensors_only`]
+    pub(crate) fn sensors_only(imu: &ScriptedTransport) -> NrealAir {
+        NrealAir::new_common(None, ImuDevice::new_passive(imu.boxed()), None).unwrap()
+    }
+
+    #[test]
+    fn multi_sample_report() {
+        let glasses = connect(&mcu());
+        // Magnetometer: multiplier 1, divisor 1, centered values
+        let mut magnetometer = 1i16.to_be_bytes().to_vec();
+        magnetometer.extend_from_slice(&1i32.to_be_bytes());
+        for _ in 0..3 {
+            magnetometer.extend_from_slice(&0x8000u16.to_le_bytes());
+        }
+        let report = |samples: &[&[u8]], magnetometer: &[u8]| {
+            let mut report = vec![0; IMU_SAMPLE_OFFSET];
+            report.extend(samples.concat());
+            report.extend_from_slice(magnetometer);
+            report.resize(NrealAir::IMU_REPORT_SIZE, 0);
+            report
+        };
+        let parse = |report: Vec<u8>| {
+            let events = glasses.imu_device.parse_report(&report).unwrap();
+            events
+                .iter()
+                .map(|event| match event {
+                    GlassesEvent::AccGyro { timestamp, .. } => ("AccGyro", timestamp.as_micros()),
+                    GlassesEvent::Magnetometer { timestamp, .. } => {
+                        ("Magnetometer", timestamp.as_micros())
+                    }
+                    _ => panic!("Unexpected {event:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = imu_sample(1_000_000, [1, 0, 0], [0, 0, 1]);
+        let second = imu_sample(2_000_000, [2, 0, 0], [0, 0, 1]);
+
+        // The layout of the current firmware
+        assert_eq!(
+            parse(report(&[&first], &magnetometer)),
+            [("AccGyro", 1000), ("Magnetometer", 1000)]
+        );
+        assert_eq!(
+            parse(report(&[&first, &second], &magnetometer)),
+            [("AccGyro", 1000), ("AccGyro", 2000), ("Magnetometer", 2000)]
+        );
+        assert_eq!(parse(report(&[&first], &[])), [("AccGyro", 1000)]);
+    }
+
+    #[test]
+    fn magnetometer_report() {
+        let glasses = connect(&mcu());
+        // A full report of a glasses lying still: header, temperature, one sample,
+        // then the magnetometer block with multiplier 1, divisor 1000 and raw values
+        // 250, -120 and 400 (i.e. 0.25, -0.12 and 0.4 Gauss), and zero padding
+        let report = [
+            "01025c0fd202964900000000002000000004d4feff7800000800000100000010",
+            "00d007004002f078ecff0001000003e8fa80887f908100000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+        ]
+        .concat();
+        let report = (0..report.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&report[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(report.len(), NrealAir::IMU_REPORT_SIZE);
+
+        let events = glasses.imu_device.parse_report(&report).unwrap();
+        let [GlassesEvent::AccGyro {
+            timestamp: sample_timestamp,
+            ..
+        }, GlassesEvent::Magnetometer {
+            magnetometer,
+            timestamp,
+        }] = events.as_slice()
+        else {
+            panic!("Unexpected events {events:?}");
+        };
+        assert_eq!(sample_timestamp.as_micros(), 1_234_567);
+        assert_eq!(timestamp, sample_timestamp);
+        // In uT, with the axes mapped like the accelerometer's
+        assert!(
+            (magnetometer - Vector3::new(-25.0, 40.0, -12.0)).norm() < 1e-4,
+            "{magnetometer}"
+        );
+    }
+
+    #[test]
+    fn imu_stream_toggle() {
+        let imu = imu();
+        let mut glasses = NrealAir::new_common(
+            Some(mcu().boxed()),
+            ImuDevice::new_device(imu.boxed()).unwrap(),
+            None,
+        )
+        .unwrap();
+        imu.clear_written();
+
+        glasses.set_imu_enabled(false).unwrap();
+        assert_eq!(imu.written(), [imu_frame(0x19, &[0])]);
+        assert!(!glasses.imu_enabled());
+        assert!(!glasses.capabilities().contains(Capabilities::IMU));
+        // A report sent before the stream stopped is not read while it is off
+        imu.push_read(imu_report(1_000_000));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+
+        imu.clear_written();
+        glasses.set_imu_enabled(true).unwrap();
+        assert_eq!(imu.written(), [imu_frame(0x19, &[1])]);
+        assert!(glasses.capabilities().contains(Capabilities::IMU));
+        // The stale report was dropped, the next event is the first new sample
+        imu.push_read(imu_report(2_000_000));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::AccGyro { timestamp, .. }) if timestamp.as_micros() == 2000
+        ));
+    }
+
+    #[test]
+    fn sensors_only_sends_nothing() {
+        let imu = imu();
+        let mut glasses = sensors_only(&imu);
+        assert!(glasses.get_config_json().is_null());
+
+        // The samples are not corrected, as the biases are not known
+        imu.push_read(imu_report(1_000_000));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::AccGyro { timestamp, .. }) if timestamp.as_micros() == 1000
+        ));
+        // A stalled stream is not turned back on
+        imu.push_timeout();
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(matches!(
+            glasses.set_imu_enabled(true),
+            Err(Error::Unsupported { .. })
+        ));
+        glasses.resync().unwrap();
+        assert!(imu.written().is_empty(), "{:?}", imu.written());
+    }
+
+    #[test]
+    fn capability_methods() {
+        crate::tests::check_capability_methods(&mut connect(&mcu()));
+        crate::tests::check_capability_methods(&mut sensors_only(&imu()));
     }
-    Err(Error::NotFound)
 }
@@ -8,35 +8,122 @@
 //! **Important note**: The NReal Light requires constant heartbeats in 3D SBS mode,
 //! or else it switches the screen off. This heartbeat is sent periodically when
 //! [`NrealLight::read_event`] is called, so be sure to constantly call that function (at least once
-//! every half a second or so)
+//! every half a second or so). Alternatively, heartbeats can be sent by the application,
+//! see [`NrealLight::set_manual_heartbeat`].
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::VecDeque,
     io::Write,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use hidapi::{HidApi, HidDevice};
 use nalgebra::{
-    Isometry3, Matrix3, Quaternion, Translation3, UnitQuaternion, Vector2, Vector3, Vector4,
+    Isometry3, Matrix3, Matrix4, Quaternion, Translation3, UnitQuaternion, Vector2, Vector3,
+    Vector4,
 };
 use tinyjson::JsonValue;
 
+#[cfg(not(target_os = "android"))]
+use crate::util::{hid_interfaces, is_io_failure};
 use crate::{
-    util::crc32_adler, ARGlasses, CameraDescriptor, DisplayMode, Error, GlassesEvent, Result, Side,
+    units::TimestampCheck,
+    util::{
+        crc32_adler, hid_disconnect_check, json_float_array, usb_disconnect_check, DeviceLock,
+        FrameTransport, HidTransport, KeyDebouncer, SleepDetector,
+    },
+    ARGlasses, AuthenticityReport, AxisConfig, CameraDescriptor, Capabilities, Clock,
+    DeviceTimestamp, Diagnostics, DisplayMode, Error, EventMeta, GlassesEvent, ImuNoise, ImuSample,
+    Result, SampleFlags, Side,
 };
 
 /// The main structure representing a connected Nreal Light glasses
 pub struct NrealLight {
-    device: HidDevice,
-    pending_packets: VecDeque<Packet>,
-    last_heartbeat: std::time::Instant,
+    /// The MCU. `None` in sensor-only mode
+    device: Option<Box<dyn FrameTransport>>,
+    /// MCU packets read while waiting for a command's answer, with the time they were read
+    pending_packets: VecDeque<(Instant, Packet)>,
+    /// Category and ID of the last command if its answer has not arrived yet (e.g. because
+    /// waiting for it timed out), with the time it was sent. See `settle_unanswered_command`
+    unanswered_command: Option<(u8, u8, Instant)>,
+    last_heartbeat: Instant,
+    manual_heartbeat: bool,
+    heartbeat_due_reported: bool,
     ov580: Ov580,
+    diagnostics: Diagnostics,
+    timestamp_check: TimestampCheck,
+    last_event_meta: Option<EventMeta>,
+    resync_happened: bool,
+    _lock: Option<DeviceLock>,
+    latest_imu: Option<ImuSample>,
+    ambient_light_reporting: bool,
+    axis_config: AxisConfig,
+    key_debouncer: KeyDebouncer,
+    /// Display mode last set, restored by `revalidate`
+    display_mode: Option<DisplayMode>,
+    sleep_detector: SleepDetector,
+    imu_enabled: bool,
+    /// Whether the user was near at the last reported proximity event
+    proximity_near: Option<bool>,
+    raw_proximity_events: bool,
+    /// In progress reopening of the OV580, see `reopen_ov580`
+    #[cfg(not(target_os = "android"))]
+    ov580_reopen: Option<Ov580Reopen>,
+    clock: Arc<dyn Clock>,
+}
+
+/// State of reopening the OV580 after it was reset by the host
+#[cfg(not(target_os = "android"))]
+struct Ov580Reopen {
+    /// The read error that started it, returned if all attempts fail
+    error: Error,
+    failed_attempts: u32,
+    next_attempt: Instant,
 }
 
 const COMMAND_TIMEOUT: i32 = 250;
+/// Command answers older than this are never matched to a command. Late answers are
+/// waited for (at most this long) before sending the next command, so that answers
+/// can't get mixed up even if the firmware answers out of order.
+const STALE_ANSWER_TTL: Duration = Duration::from_millis(1000);
+/// Time between heartbeats. The screen is switched off after about 500ms without one.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
 const OV_580_TIMEOUT: i32 = 250;
+/// Number of attempts to reopen the OV580 after it was reset by the host
+#[cfg(not(target_os = "android"))]
+const OV580_REOPEN_ATTEMPTS: u32 = 5;
+/// Wait before the first reopen attempt. Doubled after every failed attempt.
+#[cfg(not(target_os = "android"))]
+const OV580_REOPEN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Display mode bytes used by the MCU, with the corresponding
+/// [`DisplayMode`] and the refresh rate of the panel in Hz.
+///
+/// No command is known that lists the modes supported by the firmware,
+/// so this is the list of all modes seen so far.
+const DISPLAY_MODES: [(u8, DisplayMode, u16); 4] = [
+    // "1&2D_1080"
+    (b'1', DisplayMode::SameOnBoth, 60),
+    // "2&3D_540"
+    (b'2', DisplayMode::HalfSBS, 60),
+    // "3&3D_1080"
+    (b'3', DisplayMode::Stereo, 60),
+    // "4&3D_1080#72"
+    (b'4', DisplayMode::HighRefreshRate, 72),
+];
+
+/// Modes accepted by [`NrealLight::set_display_mode`], in the order of [`DISPLAY_MODES`].
+/// The 72Hz mode is set as [`DisplayMode::HighRefreshRateSBS`], but reported as
+/// [`DisplayMode::HighRefreshRate`], as the driver always did: which of the two it
+/// really is has not been verified.
+const SETTABLE_DISPLAY_MODES: [DisplayMode; 4] = [
+    DisplayMode::SameOnBoth,
+    DisplayMode::HalfSBS,
+    DisplayMode::Stereo,
+    DisplayMode::HighRefreshRateSBS,
+];
 
 impl ARGlasses for NrealLight {
     fn serial(&mut self) -> Result<String> {
@@ -45,45 +132,75 @@ impl ARGlasses for NrealLight {
             cmd_id: b'C',
             ..Default::default()
         })?;
-        String::from_utf8(result).map_err(|_| Error::Other("Serial number was not utf-8"))
+        String::from_utf8(result).map_err(|e| Error::InvalidUtf8 {
+            context: "Serial number",
+            bytes: e.into_bytes(),
+        })
     }
 
     fn read_event(&mut self) -> Result<GlassesEvent> {
-        self.send_heartbeat_if_needed()?;
-        if let Some(event) = self.read_mcu_packet()? {
-            Ok(event)
+        if self
+            .sleep_detector
+            .host_slept(self.clock.now(), self.clock.wall_now())
+        {
+            self.revalidate()?;
+            return Ok(GlassesEvent::SessionRestored);
+        }
+        if let Some(event) = self.send_heartbeat_if_needed(self.clock.now())? {
+            return Ok(event);
+        }
+        let (mut event, received_at) = if let Some(event) = self.read_mcu_packet()? {
+            (event, self.clock.now())
+        } else if !self.imu_enabled {
+            (self.wait_for_mcu_event()?, self.clock.now())
         } else {
-            self.ov580.read_packet()
+            #[cfg(not(target_os = "android"))]
+            if self.ov580_reopen.is_some() {
+                return self.reopen_ov580();
+            }
+            let event = match self.ov580.read_packet() {
+                #[cfg(not(target_os = "android"))]
+                Err(error) if is_io_failure(&error) => {
+                    self.ov580_reopen = Some(Ov580Reopen {
+                        error,
+                        failed_attempts: 0,
+                        next_attempt: self.clock.now() + OV580_REOPEN_BACKOFF,
+                    });
+                    return self.reopen_ov580();
+                }
+                result => result?,
+            };
+            (event, self.ov580.last_report_received)
+        };
+        self.axis_config.apply(&mut event);
+        if self.timestamp_check.check(&event, self.clock.now()) {
+            self.diagnostics.implausible_timestamps += 1;
         }
+        if let Some(sample) = ImuSample::from_event(&event) {
+            self.latest_imu = Some(sample);
+            if self
+                .sleep_detector
+                .imu_sample(self.clock.now(), sample.timestamp)
+            {
+                self.revalidate()?;
+                return Ok(GlassesEvent::SessionRestored);
+            }
+        }
+        self.last_event_meta = Some(self.diagnostics.record_event(&event, received_at));
+        Ok(event)
     }
 
     fn get_display_mode(&mut self) -> Result<DisplayMode> {
-        let result = self.run_command(Packet {
-            category: b'3',
-            cmd_id: b'3',
-            ..Default::default()
-        })?;
-        match result.first() {
-            // "1&2D_1080"
-            Some(b'1') => Ok(DisplayMode::SameOnBoth),
-            // "2&3D_540"
-            Some(b'2') => Ok(DisplayMode::HalfSBS),
-            // "3&3D_1080"
-            Some(b'3') => Ok(DisplayMode::Stereo),
-            // "4&3D_1080#72"
-            Some(b'4') => Ok(DisplayMode::HighRefreshRate),
-            _ => Err(Error::Other("Unknown display mode")),
-        }
+        Ok(self.get_display_mode_entry()?.1)
     }
 
     fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
-        let display_mode_byte = match display_mode {
-            DisplayMode::SameOnBoth => b'1',
-            DisplayMode::HalfSBS => b'2',
-            DisplayMode::Stereo => b'3',
-            DisplayMode::HighRefreshRateSBS => b'4',
-            _ => return Err(Error::Other("Display mode not supported")),
-        };
+        Capabilities::DISPLAY_CONTROL.require(self)?;
+        let index = SETTABLE_DISPLAY_MODES
+            .iter()
+            .position(|mode| *mode == display_mode)
+            .ok_or(Error::Other("Display mode not supported"))?;
+        let display_mode_byte = DISPLAY_MODES[index].0;
         let result = self.run_command(Packet {
             category: b'1',
             cmd_id: b'3',
@@ -91,6 +208,7 @@ impl ARGlasses for NrealLight {
         })?;
 
         if result.first() == Some(&display_mode_byte) {
+            self.display_mode = Some(display_mode);
             Ok(())
         } else {
             Err(Error::Other("Display mode setting unsuccessful"))
@@ -103,6 +221,10 @@ impl ARGlasses for NrealLight {
         26.0f32.to_radians()
     }
 
+    fn display_tilt(&self) -> f32 {
+        Self::DISPLAY_TILT
+    }
+
     fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
         let side_multiplier = match side {
             Side::Left => -0.5,
@@ -110,7 +232,7 @@ impl ARGlasses for NrealLight {
         };
         Translation3::new(ipd as f64 * side_multiplier, 0.0, 0.0)
             * UnitQuaternion::from_euler_angles(
-                Self::DISPLAY_TILT,
+                -self.display_tilt() as f64,
                 Self::DISPLAY_DIVERGENCE * side_multiplier,
                 0.0,
             )
@@ -120,6 +242,45 @@ impl ARGlasses for NrealLight {
         "Nreal Light"
     }
 
+    fn capabilities(&self) -> Capabilities {
+        let imu = if self.imu_enabled {
+            Capabilities::IMU
+        } else {
+            Capabilities::empty()
+        };
+        let always = imu
+            | Capabilities::SEPARATE_IMU_EVENTS
+            | Capabilities::RESYNC
+            | Capabilities::AUTO_REVALIDATE;
+        if self.device.is_some() {
+            always
+                | Capabilities::IMU_TOGGLE
+                | Capabilities::KEYS
+                | Capabilities::KEY_DEBOUNCE
+                | Capabilities::PROXIMITY
+                | Capabilities::AMBIENT_LIGHT
+                | Capabilities::VSYNC
+                | Capabilities::DISPLAY_CONTROL
+                | Capabilities::RAW_ACCESS
+        } else {
+            always
+        }
+    }
+
+    /// The calibration data read at connection time is kept
+    fn set_imu_enabled(&mut self, enabled: bool) -> Result<()> {
+        Capabilities::IMU_TOGGLE.require(self)?;
+        self.ov580.command(0x19, enabled as u8)?;
+        self.ov580.pending_events.clear();
+        self.imu_enabled = enabled;
+        self.diagnostics.reset_imu_timing();
+        Ok(())
+    }
+
+    fn imu_enabled(&self) -> bool {
+        self.imu_enabled
+    }
+
     fn cameras(&self) -> Result<Vec<crate::CameraDescriptor>> {
         let rgb = self.get_basic_camera_descriptor("rgb", "RGB_camera", "device_1")?;
         let slam_left =
@@ -139,6 +300,163 @@ impl ARGlasses for NrealLight {
     fn display_delay(&self) -> u64 {
         15500
     }
+
+    fn display_frame_duration(&mut self) -> Result<u64> {
+        Ok(1_000_000 / self.current_refresh_rate()? as u64)
+    }
+
+    fn current_refresh_rate(&mut self) -> Result<u16> {
+        Ok(self.get_display_mode_entry()?.2)
+    }
+
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        if self.device.is_none() {
+            return Ok(Vec::new());
+        }
+        Ok(SETTABLE_DISPLAY_MODES.to_vec())
+    }
+
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        self.axis_config = axis_config;
+    }
+
+    fn set_key_debounce(&mut self, window: Duration) -> Result<()> {
+        Capabilities::KEY_DEBOUNCE.require(self)?;
+        self.key_debouncer.set_window(window);
+        Ok(())
+    }
+
+    /// Drops the queued MCU packets and IMU samples, discards the unread MCU and OV580
+    /// reports, repeats the MCU handshake (SDK mode, ambient light reporting as last set,
+    /// VSync events), checks the display mode, and turns the IMU stream on again.
+    /// In sensor-only mode, only the reports are discarded.
+    fn resync(&mut self) -> Result<()> {
+        self.pending_packets.clear();
+        self.unanswered_command = None;
+        self.ov580.pending_events.clear();
+        self.resync_happened = false;
+        // The state may have changed while the reports were lost
+        self.proximity_near = None;
+        if self.device.is_some() {
+            self.mcu()?.drain()?;
+            self.handshake()?;
+            self.get_display_mode()?;
+        }
+        self.ov580.device.drain()?;
+        self.timestamp_check.reset();
+        self.diagnostics.reset_imu_timing();
+        if self.device.is_some() {
+            self.ov580.command(0x19, self.imu_enabled as u8)?;
+        }
+        Ok(())
+    }
+
+    fn revalidate(&mut self) -> Result<()> {
+        self.resync()?;
+        if let Some(display_mode) = self.display_mode {
+            self.set_display_mode(display_mode)?;
+        }
+        Ok(())
+    }
+
+    fn set_auto_revalidate(&mut self, min_sleep: Option<Duration>) -> Result<()> {
+        self.sleep_detector.set_min_sleep(min_sleep);
+        Ok(())
+    }
+
+    fn last_event_meta(&self) -> Option<EventMeta> {
+        self.last_event_meta
+    }
+
+    fn set_ambient_light_reporting(&mut self, enabled: bool) -> Result<()> {
+        Capabilities::AMBIENT_LIGHT.require(self)?;
+        self.run_command(Packet {
+            category: b'1',
+            cmd_id: b'L',
+            data: vec![if enabled { b'1' } else { b'0' }],
+        })?;
+        self.ambient_light_reporting = enabled;
+        Ok(())
+    }
+
+    fn ambient_light_reporting(&self) -> bool {
+        self.ambient_light_reporting
+    }
+
+    fn set_separate_imu_events(&mut self, separate: bool) -> Result<()> {
+        self.ov580.separate_imu_events = separate;
+        Ok(())
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn latest_imu(&self) -> Option<ImuSample> {
+        // Samples of the same report that were not returned yet are newer
+        self.ov580
+            .pending_events
+            .iter()
+            .rev()
+            .find_map(|event| {
+                let mut event = event.clone();
+                self.axis_config.apply(&mut event);
+                ImuSample::from_event(&event)
+            })
+            .or(self.latest_imu)
+    }
+
+    fn authenticity_check(&mut self) -> AuthenticityReport {
+        let mut report = AuthenticityReport::default();
+        let config_ok = self
+            .get_config_float_array::<3>(&["IMU", "device_1", "accel_bias"])
+            .is_ok()
+            && self
+                .get_config_float_array::<3>(&["IMU", "device_1", "gyro_bias"])
+                .is_ok()
+            && self.raw_display_transforms().is_ok();
+        report.config_ok = report.check(config_ok, "Calibration config has unexpected format");
+        #[cfg(not(target_os = "android"))]
+        {
+            let present = |vid, pid| hid_interfaces(vid, pid).is_ok_and(|i| !i.is_empty());
+            let layout_ok =
+                present(Self::MCU_VID, Self::MCU_PID) && present(Self::OV580_VID, Self::OV580_PID);
+            report.interface_layout_ok =
+                report.check(layout_ok, "MCU and OV580 HID devices are not both present");
+        }
+        if self.device.is_some() {
+            let serial_ok = self
+                .serial()
+                .is_ok_and(|serial| !serial.is_empty() && serial.is_ascii());
+            report.firmware_response_ok =
+                report.check(serial_ok, "Serial number query got an unexpected answer");
+        }
+        report
+    }
+
+    #[cfg(feature = "raw_access")]
+    fn raw_read(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        Capabilities::RAW_ACCESS.require(self)?;
+        let mut result = vec![0u8; 0x100];
+        let size = self
+            .mcu()?
+            .read_timeout(&mut result, timeout.as_millis() as i32)?;
+        if size == 0 {
+            return Err(Error::PacketTimeout);
+        }
+        result.truncate(size);
+        Ok(result)
+    }
+
+    #[cfg(feature = "raw_access")]
+    fn raw_write(&mut self, endpoint_or_report: u8, data: &[u8]) -> Result<()> {
+        Capabilities::RAW_ACCESS.require(self)?;
+        let mut report = Vec::with_capacity(data.len() + 1);
+        report.push(endpoint_or_report);
+        report.extend_from_slice(data);
+        self.mcu()?.write(&report)?;
+        Ok(())
+    }
 }
 
 impl NrealLight {
@@ -157,54 +475,135 @@ impl NrealLight {
     /// Unique camera type name for the right SLAM camera
     pub const RIGHT_SLAM_CAM: &'static str = "Nreal Light SLAM right";
 
-    const DISPLAY_TILT: f64 = -0.265;
+    /// Size of a packet on the MCU interface
+    pub const MCU_PACKET_SIZE: usize = 0x40;
+    /// Size of an IMU report of the OV580
+    pub const IMU_REPORT_SIZE: usize = 0x80;
+    /// Size of the sample block (gyroscope and accelerometer, each with its own
+    /// timestamp) in an IMU report. Each report contains a single block.
+    pub const IMU_SAMPLE_SIZE: usize = 56;
+
+    const DISPLAY_TILT: f32 = 0.265;
     const DISPLAY_DIVERGENCE: f64 = 0.02;
     /// Connect to a specific glasses, based on the two USB fds
     /// Mainly made to work around android permission issues
     #[cfg(target_os = "android")]
     pub fn new(mcu_fd: isize, ov580_fd: isize) -> Result<Self> {
         Self::new_common(
-            HidApi::new_without_enumerate()?.wrap_sys_device(mcu_fd, -1)?,
+            Some(mcu_transport(
+                HidApi::new_without_enumerate()?.wrap_sys_device(mcu_fd, -1)?,
+            )),
             Ov580::new(ov580_fd)?,
+            None,
         )
     }
 
+    /// Connect to the sensors of a specific glasses only, based on the OV580 USB fd.
+    /// See [`NrealLight::new_sensors_only`] on other platforms.
+    #[cfg(target_os = "android")]
+    pub fn new_sensors_only(ov580_fd: isize) -> Result<Self> {
+        Self::new_common(None, Ov580::new_passive(Ov580::open(ov580_fd)?), None)
+    }
+
     /// Find a connected Nreal Light device and connect to it. (And claim the USB interface)
-    /// Only one instance can be alive at a time
+    /// Only one instance can be alive at a time, see [`Error::AlreadyInUse`]
     #[cfg(not(target_os = "android"))]
     pub fn new() -> Result<Self> {
+        let hidapi = HidApi::new()?;
+        let mcu_path = hidapi
+            .device_list()
+            .find(|device| {
+                device.vendor_id() == Self::MCU_VID && device.product_id() == Self::MCU_PID
+            })
+            .ok_or(Error::NotFound)?
+            .path()
+            .to_owned();
+        // Before sending anything to the glasses
+        let lock = DeviceLock::for_hid_path(&mcu_path)?;
         Self::new_common(
-            HidApi::new()?.open(Self::MCU_VID, Self::MCU_PID)?,
+            Some(mcu_transport(hidapi.open_path(&mcu_path)?)),
             Ov580::new()?,
+            Some(lock),
         )
     }
-    fn new_common(device: HidDevice, ov580: Ov580) -> Result<Self> {
+
+    /// Connect to the sensors (the OV580) of a connected Nreal Light only, and never touch the MCU.
+    ///
+    /// This can be used while other software (e.g. the vendor's own tools) is controlling
+    /// the glasses. No commands are sent to the glasses at all, not even to the OV580, so
+    /// the other software's IMU stream is never interrupted. This means:
+    /// * The calibration data is not read: [`NrealLight::get_config_json`] is `Null`,
+    ///   there are no camera descriptors, and the samples are not corrected for the
+    ///   sensor biases.
+    /// * No heartbeat is sent and there is no display control, so keeping the display
+    ///   in 3D SBS mode is the other software's job.
+    /// * The IMU stream can't be turned on or off. If the other software turns it off,
+    ///   reading events returns [`Error::PacketTimeout`] until it is turned on again.
+    ///   If the OV580 is reset, it is reopened without restarting the stream.
+    ///
+    /// Does not take the device lock, so it can be used next to another instance.
+    #[cfg(not(target_os = "android"))]
+    pub fn new_sensors_only() -> Result<Self> {
+        Self::new_common(None, Ov580::new_passive(open_ov580()?), None)
+    }
+
+    fn new_common(
+        device: Option<Box<dyn FrameTransport>>,
+        ov580: Ov580,
+        lock: Option<DeviceLock>,
+    ) -> Result<Self> {
+        let sensors_only = device.is_none();
+        let clock = ov580.clock.clone();
         let mut result = Self {
             device,
             pending_packets: Default::default(),
-            last_heartbeat: std::time::Instant::now(),
+            unanswered_command: None,
+            last_heartbeat: clock.now(),
+            manual_heartbeat: false,
+            heartbeat_due_reported: false,
             ov580,
+            diagnostics: Default::default(),
+            timestamp_check: Default::default(),
+            last_event_meta: None,
+            resync_happened: false,
+            _lock: lock,
+            latest_imu: None,
+            ambient_light_reporting: false,
+            axis_config: Default::default(),
+            key_debouncer: Default::default(),
+            display_mode: None,
+            sleep_detector: Default::default(),
+            imu_enabled: true,
+            proximity_near: None,
+            raw_proximity_events: false,
+            #[cfg(not(target_os = "android"))]
+            ov580_reopen: None,
+            clock,
         };
+        if sensors_only {
+            return Ok(result);
+        }
+        result.ambient_light_reporting = true;
+        result.handshake()?;
+        Ok(result)
+    }
+
+    fn handshake(&mut self) -> Result<()> {
         // Send a "Yes, I am a working SDK" command
         // This is needed for SBS 3D display to work.
-        result.run_command(Packet {
+        self.run_command(Packet {
             category: b'@',
             cmd_id: b'3',
             data: vec![b'1'],
         })?;
-        // Enable the Ambient Light event
-        result.run_command(Packet {
-            category: b'1',
-            cmd_id: b'L',
-            data: vec![b'1'],
-        })?;
+        self.set_ambient_light_reporting(self.ambient_light_reporting)?;
         // Enable VSync event
-        result.run_command(Packet {
+        self.run_command(Packet {
             category: b'1',
             cmd_id: b'N',
             data: vec![b'1'],
         })?;
-        Ok(result)
+        Ok(())
     }
 
     /// Returns the calibration data stored on the Glasses. No transformation
@@ -213,15 +612,110 @@ impl NrealLight {
         &self.ov580.config_json
     }
 
+    /// IMU noise parameters from the calibration data (`IMU.device_1.imu_noises`),
+    /// `None` if they are not present. See [`ImuNoise`]
+    pub fn imu_noise(&self) -> Option<ImuNoise> {
+        ImuNoise::from_config(self.get_config_json())
+    }
+
+    /// Send a heartbeat to the glasses now. Only needed in manual mode,
+    /// see [`NrealLight::set_manual_heartbeat`].
+    pub fn send_heartbeat(&mut self) -> Result<()> {
+        self.write_heartbeat(self.clock.now())
+    }
+
+    /// Stop sending heartbeats automatically from [`NrealLight::read_event`]. Instead,
+    /// [`GlassesEvent::HeartbeatDue`] is returned once 250ms passed since the last
+    /// heartbeat, and [`NrealLight::send_heartbeat`] has to be called soon after:
+    /// the screen is switched off if there's no heartbeat for about half a second.
+    ///
+    /// Useful if `read_event` is not called frequently enough, and heartbeats are
+    /// driven by the application instead.
+    pub fn set_manual_heartbeat(&mut self, manual: bool) {
+        self.manual_heartbeat = manual;
+    }
+
+    /// Use `clock` instead of the system clock for the heartbeat, timeouts and host
+    /// sleep detection. Mainly for testing, see [`Clock`].
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.last_heartbeat = clock.now();
+        self.ov580.last_report_received = clock.now();
+        self.pending_packets.clear();
+        self.unanswered_command = None;
+        self.ov580.clock = clock.clone();
+        self.clock = clock;
+    }
+
+    /// The firmware periodically repeats the proximity state, even if it did not change.
+    /// By default, only the changes are returned as [`GlassesEvent::ProximityNear`] and
+    /// [`GlassesEvent::ProximityFar`], and the repeats are counted in
+    /// [`crate::Diagnostics::repeated_state_events`]. With `raw`, every report is returned.
+    ///
+    /// The state can't be queried, so the first report after connecting is returned as
+    /// the initial state, whenever it arrives (connecting doesn't wait for it). The first
+    /// report after a resync (see [`ARGlasses::resync`]) is returned too.
+    pub fn set_raw_proximity_events(&mut self, raw: bool) {
+        self.raw_proximity_events = raw;
+    }
+
+    /// Returns the per-display transform matrices exactly as they are stored in the
+    /// calibration data (`display.display_1.transform` and `display.display_2.transform`,
+    /// in this order). The JSON arrays are interpreted as row-major 4x4 matrices,
+    /// no other transformation is done.
+    ///
+    /// Note that [`ARGlasses::imu_to_display_matrix`] is not derived from these, but from
+    /// measured per-model constants.
+    pub fn raw_display_transforms(&self) -> Result<[Matrix4<f64>; 2]> {
+        let display_1 =
+            self.get_config_float_array::<16>(&["display", "display_1", "transform"])?;
+        let display_2 =
+            self.get_config_float_array::<16>(&["display", "display_2", "transform"])?;
+        Ok([
+            Matrix4::from_row_slice(&display_1.0[0]),
+            Matrix4::from_row_slice(&display_2.0[0]),
+        ])
+    }
+
+    /// Wait for an MCU event while the IMU stream is off. The wait is short,
+    /// so that the heartbeat is still sent in time.
+    fn wait_for_mcu_event(&mut self) -> Result<GlassesEvent> {
+        let timeout = (HEARTBEAT_INTERVAL / 2).as_millis() as i32;
+        if self.device.is_none() {
+            self.clock.sleep(Duration::from_millis(timeout as u64));
+            return Err(Error::PacketTimeout);
+        }
+        if let Some(packet) = self.read_packet(timeout)? {
+            self.pending_packets.push_back((self.clock.now(), packet));
+        }
+        self.read_mcu_packet()?.ok_or(Error::PacketTimeout)
+    }
+
     fn read_mcu_packet(&mut self) -> Result<Option<GlassesEvent>> {
-        let packet = if let Some(packet) = self.pending_packets.pop_front() {
+        if self.device.is_none() {
+            return Ok(None);
+        }
+        if self.resync_happened {
+            self.resync_happened = false;
+            return Ok(Some(GlassesEvent::ProtocolResync));
+        }
+        let now = self.clock.now();
+        // Answers are never turned into events, but don't keep them around forever
+        self.pending_packets.retain(|(received_at, packet)| {
+            !packet.is_answer() || now.saturating_duration_since(*received_at) < STALE_ANSWER_TTL
+        });
+        let packet = if let Some((_, packet)) = self.pending_packets.pop_front() {
             packet
         } else if let Some(packet) = self.read_packet(0)? {
             packet
         } else {
             return Ok(None);
         };
-        Ok(match packet {
+        if let Some((category, cmd_id, _)) = self.unanswered_command {
+            if packet.is_answer_to_id(category, cmd_id) {
+                self.unanswered_command = None;
+            }
+        }
+        let event = match packet {
             Packet {
                 category: b'5',
                 cmd_id: b'K',
@@ -248,8 +742,10 @@ impl NrealLight {
                 data,
             } => Some(GlassesEvent::AmbientLight(
                 u16::from_str_radix(
-                    &String::from_utf8(data)
-                        .map_err(|_| Error::Other("Invalid utf-8 in ambient light msg"))?,
+                    &String::from_utf8(data).map_err(|e| Error::InvalidUtf8 {
+                        context: "Ambient light message",
+                        bytes: e.into_bytes(),
+                    })?,
                     16,
                 )
                 .map_err(|_| Error::Other("Invalid number in ambient light msg"))?,
@@ -262,12 +758,28 @@ impl NrealLight {
             // NOTE: maybe we should retry right here instead of basically reporting timeout,
             //       but we will be called again soon enough.
             _ => None,
-        })
+        };
+        if let Some(GlassesEvent::KeyPress(key)) = event {
+            if !self
+                .key_debouncer
+                .accept(key, self.clock.now(), &mut self.diagnostics)
+            {
+                return Ok(None);
+            }
+        }
+        if let Some(GlassesEvent::ProximityNear | GlassesEvent::ProximityFar) = event {
+            let near = matches!(event, Some(GlassesEvent::ProximityNear));
+            if self.proximity_near.replace(near) == Some(near) && !self.raw_proximity_events {
+                self.diagnostics.repeated_state_events += 1;
+                return Ok(None);
+            }
+        }
+        Ok(event)
     }
 
     fn read_packet(&mut self, timeout: i32) -> Result<Option<Packet>> {
-        let mut result = [0u8; 0x40];
-        let packet_size = self.device.read_timeout(&mut result, timeout)?;
+        let mut result = [0u8; NrealLight::MCU_PACKET_SIZE];
+        let packet_size = self.mcu()?.read_timeout(&mut result, timeout)?;
         if packet_size == 0 {
             Ok(None)
         } else {
@@ -277,71 +789,196 @@ impl NrealLight {
         }
     }
 
-    fn send_heartbeat_if_needed(&mut self) -> Result<()> {
-        let now = std::time::Instant::now();
-        if now.duration_since(self.last_heartbeat) > Duration::from_millis(250) {
-            // Heartbeat packet
-            // Not sent as "run_command" as sometimes the Glasses don't bother to
-            // answer. E.g. when one of the buttons is pressed while it is running.
-            self.device.write(
-                &Packet {
-                    category: b'@',
-                    cmd_id: b'K',
-                    ..Default::default()
-                }
-                .serialize()
-                .ok_or(Error::Other("Packet serialization failed"))?,
-            )?;
-            self.last_heartbeat = now;
+    fn get_display_mode_entry(&mut self) -> Result<(u8, DisplayMode, u16)> {
+        Capabilities::DISPLAY_CONTROL.require(self)?;
+        let result = self.run_command(Packet {
+            category: b'3',
+            cmd_id: b'3',
+            ..Default::default()
+        })?;
+        let mode_byte = result.first().ok_or(Error::Other("Unknown display mode"))?;
+        DISPLAY_MODES
+            .iter()
+            .find(|(byte, _, _)| byte == mode_byte)
+            .copied()
+            .ok_or(Error::Other("Unknown display mode"))
+    }
+
+    fn mcu(&self) -> Result<&dyn FrameTransport> {
+        self.device
+            .as_deref()
+            .ok_or(Error::Other("MCU is not available in sensor-only mode"))
+    }
+
+    /// Error for a command answer that timed out, see [`FrameTransport::check_disconnect`]
+    fn timeout_error(&self) -> Error {
+        match self.mcu() {
+            Ok(mcu) => mcu.check_disconnect(Error::PacketTimeout),
+            Err(e) => e,
+        }
+    }
+
+    /// Reopen the OV580 after a read error, in case it was reset by the host, with
+    /// exponential backoff. Called instead of reading the OV580 until it succeeds, and
+    /// only makes an attempt if one is due. Until then, it waits for MCU events, so that
+    /// the heartbeat keeps going (and unplugging is noticed). Returns the original
+    /// error if all attempts failed.
+    #[cfg(not(target_os = "android"))]
+    fn reopen_ov580(&mut self) -> Result<GlassesEvent> {
+        let Some(reopen) = &mut self.ov580_reopen else {
+            return Err(Error::PacketTimeout);
+        };
+        let now = self.clock.now();
+        if now < reopen.next_attempt {
+            return self.wait_for_mcu_event();
         }
+        if self.ov580.reopen().is_err() {
+            reopen.failed_attempts += 1;
+            if reopen.failed_attempts >= OV580_REOPEN_ATTEMPTS {
+                let reopen = self.ov580_reopen.take();
+                return Err(reopen.map_or(Error::PacketTimeout, |reopen| reopen.error));
+            }
+            reopen.next_attempt = now + OV580_REOPEN_BACKOFF * 2u32.pow(reopen.failed_attempts);
+            return Err(Error::PacketTimeout);
+        }
+        self.ov580_reopen = None;
+        // The new handle doesn't know the stream state. In sensor-only mode, it is
+        // up to the other software.
+        if self.device.is_some() {
+            self.ov580.command(0x19, self.imu_enabled as u8)?;
+        }
+        self.diagnostics.transport_recoveries += 1;
+        Ok(GlassesEvent::TransportWarning {
+            kind: crate::TransportErrorKind::Reopened,
+            count: 1,
+        })
+    }
+
+    /// Sends the heartbeat if it is due. In manual mode, returns
+    /// [`GlassesEvent::HeartbeatDue`] instead (once per heartbeat).
+    fn send_heartbeat_if_needed(&mut self, now: Instant) -> Result<Option<GlassesEvent>> {
+        if self.device.is_none()
+            || now.saturating_duration_since(self.last_heartbeat) <= HEARTBEAT_INTERVAL
+        {
+            return Ok(None);
+        }
+        if !self.manual_heartbeat {
+            self.write_heartbeat(now)?;
+            return Ok(None);
+        }
+        if self.heartbeat_due_reported {
+            return Ok(None);
+        }
+        self.heartbeat_due_reported = true;
+        Ok(Some(GlassesEvent::HeartbeatDue))
+    }
+
+    fn write_heartbeat(&mut self, now: Instant) -> Result<()> {
+        // Heartbeat packet
+        // Not sent as "run_command" as sometimes the Glasses don't bother to
+        // answer. E.g. when one of the buttons is pressed while it is running.
+        self.mcu()?.write(
+            &Packet {
+                category: b'@',
+                cmd_id: b'K',
+                ..Default::default()
+            }
+            .serialize()
+            .ok_or(Error::Other("Packet serialization failed"))?,
+        )?;
+        self.last_heartbeat = now;
+        self.heartbeat_due_reported = false;
         Ok(())
     }
 
     fn run_command(&mut self, command: Packet) -> Result<Vec<u8>> {
-        self.device.write(
-            &command
-                .serialize()
-                .ok_or(Error::Other("Packet serialization failed"))?,
-        )?;
+        let command_data = command
+            .serialize()
+            .ok_or(Error::Other("Packet serialization failed"))?;
+        self.settle_unanswered_command()?;
+        self.mcu()?.write(&command_data)?;
+        self.unanswered_command = Some((command.category, command.cmd_id, self.clock.now()));
+        if let Some(result) = self.wait_for_answer(&command)? {
+            return Ok(result);
+        }
+
+        // Too many unrelated packets: the stream is probably out of sync.
+        // Drain whatever is still in flight (the answer may be stuck behind it),
+        // then try once more before giving up.
+        self.resync_happened = true;
+        self.diagnostics.protocol_resyncs += 1;
+        while let Some(packet) = self.read_packet(0)? {
+            if packet.is_answer_to(&command) {
+                self.unanswered_command = None;
+                return Ok(packet.data);
+            }
+            self.pending_packets.push_back((self.clock.now(), packet));
+        }
+        self.mcu()?.write(&command_data)?;
+        self.unanswered_command = Some((command.category, command.cmd_id, self.clock.now()));
+        if let Some(result) = self.wait_for_answer(&command)? {
+            return Ok(result);
+        }
+        Err(Error::disconnected_by("Nreal Light", Error::PacketTimeout))
+    }
 
+    fn wait_for_answer(&mut self, command: &Packet) -> Result<Option<Vec<u8>>> {
         for _ in 0..64 {
             let packet = self
                 .read_packet(COMMAND_TIMEOUT)?
-                .ok_or(Error::PacketTimeout)?;
-            if packet.category == command.category + 1 && packet.cmd_id == command.cmd_id {
-                return Ok(packet.data);
+                .ok_or_else(|| self.timeout_error())?;
+            if packet.is_answer_to(command) {
+                self.unanswered_command = None;
+                return Ok(Some(packet.data));
             }
-            self.pending_packets.push_back(packet);
+            self.pending_packets.push_back((self.clock.now(), packet));
         }
+        Ok(None)
+    }
 
-        Err(Error::Other("Received too many unrelated packets"))
+    /// Only one command is in flight at a time: if the answer to the previous command
+    /// has not arrived yet, wait for it (and drop it) before a new command is sent,
+    /// so that it can't be taken as the answer to the new one.
+    /// The heartbeat is kept going meanwhile (except in manual mode).
+    fn settle_unanswered_command(&mut self) -> Result<()> {
+        let Some((category, cmd_id, sent_at)) = self.unanswered_command.take() else {
+            return Ok(());
+        };
+        if self
+            .pending_packets
+            .iter()
+            .any(|(_, packet)| packet.is_answer_to_id(category, cmd_id))
+        {
+            return Ok(());
+        }
+        loop {
+            let now = self.clock.now();
+            if !self.manual_heartbeat {
+                self.send_heartbeat_if_needed(now)?;
+            }
+            let remaining = STALE_ANSWER_TTL.saturating_sub(now.saturating_duration_since(sent_at));
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            let timeout = remaining.min(HEARTBEAT_INTERVAL / 2);
+            let Some(packet) = self.read_packet(timeout.as_millis().max(1) as i32)? else {
+                if remaining <= timeout {
+                    return Ok(());
+                }
+                continue;
+            };
+            if packet.is_answer_to_id(category, cmd_id) {
+                return Ok(());
+            }
+            self.pending_packets.push_back((self.clock.now(), packet));
+        }
     }
 
     fn get_config_float_array<const N: usize>(
         &self,
         keys: &[&str],
     ) -> Result<nalgebra::ArrayStorage<f64, N, 1>> {
-        let mut result = [0.0; N];
-        let mut json_val = self.get_config_json();
-        for key in keys {
-            json_val = json_val
-                .get::<HashMap<String, JsonValue>>()
-                .ok_or(Error::Other("Json value is not an object"))?
-                .get(*key)
-                .ok_or(Error::Other("Json key not found"))?;
-        }
-        let json_val = json_val
-            .get::<Vec<JsonValue>>()
-            .ok_or(Error::Other("Json value not an array"))?;
-        if json_val.len() != N {
-            return Err(Error::Other("Json array is the wrong length"));
-        }
-        for i in 0..N {
-            result[i] = *json_val[i]
-                .get::<f64>()
-                .ok_or(Error::Other("Json value is not a float"))?;
-        }
-        Ok(nalgebra::ArrayStorage([result]))
+        json_float_array(self.get_config_json(), keys)
     }
 
     fn get_basic_camera_descriptor(
@@ -381,30 +1018,87 @@ impl NrealLight {
     }
 }
 
+/// Open MCU of the Light. Its errors are converted to [`Error::Disconnected`] if the
+/// glasses were unplugged.
+fn mcu_transport(device: HidDevice) -> Box<dyn FrameTransport> {
+    Box::new(HidTransport::new(
+        device,
+        NrealLight::MCU_VID,
+        NrealLight::MCU_PID,
+        None,
+        "Nreal Light",
+    ))
+}
+
+/// Open OV580 of the Light. Its errors are converted to [`Error::Disconnected`] if the
+/// glasses were unplugged.
+fn ov580_transport(device: HidDevice) -> Box<dyn FrameTransport> {
+    Box::new(HidTransport::new(
+        device,
+        NrealLight::OV580_VID,
+        NrealLight::OV580_PID,
+        None,
+        "Nreal Light",
+    ))
+}
+
+/// Open the OV580 of the first Nreal Light found
+#[cfg(not(target_os = "android"))]
+fn open_ov580() -> Result<Box<dyn FrameTransport>> {
+    let device = HidApi::new()?
+        .open(NrealLight::OV580_VID, NrealLight::OV580_PID)
+        .map_err(|e| {
+            hid_disconnect_check(
+                e,
+                NrealLight::OV580_VID,
+                NrealLight::OV580_PID,
+                None,
+                "Nreal Light",
+            )
+        })?;
+    Ok(ov580_transport(device))
+}
+
 struct Ov580 {
-    device: HidDevice,
+    device: Box<dyn FrameTransport>,
     config_json: JsonValue,
     gyro_bias: Vector3<f32>,
     accelerometer_bias: Vector3<f32>,
+    pending_events: VecDeque<GlassesEvent>,
+    separate_imu_events: bool,
+    last_report_received: Instant,
+    read_buffer: Vec<u8>,
+    /// Opens the device again, see `reopen`
+    #[cfg(not(target_os = "android"))]
+    opener: Box<dyn Fn() -> Result<Box<dyn FrameTransport>> + Send>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Ov580 {
+    /// Offset of the sample block in an IMU report
+    const SAMPLE_OFFSET: usize = 44;
+
     #[cfg(target_os = "android")]
     pub fn new(fd: isize) -> Result<Self> {
-        Self::new_device(HidApi::new_without_enumerate()?.wrap_sys_device(fd, -1)?)
+        Self::new_device(Self::open(fd)?)
+    }
+
+    #[cfg(target_os = "android")]
+    fn open(fd: isize) -> Result<Box<dyn FrameTransport>> {
+        Ok(ov580_transport(
+            HidApi::new_without_enumerate()?.wrap_sys_device(fd, -1)?,
+        ))
     }
 
     #[cfg(not(target_os = "android"))]
     pub fn new() -> Result<Self> {
-        Self::new_device(HidApi::new()?.open(NrealLight::OV580_VID, NrealLight::OV580_PID)?)
+        Self::new_device(ov580_transport(
+            HidApi::new()?.open(NrealLight::OV580_VID, NrealLight::OV580_PID)?,
+        ))
     }
-    fn new_device(device: HidDevice) -> Result<Self> {
-        let mut result = Self {
-            device,
-            config_json: JsonValue::Null,
-            gyro_bias: Default::default(),
-            accelerometer_bias: Default::default(),
-        };
+
+    fn new_device(device: Box<dyn FrameTransport>) -> Result<Self> {
+        let mut result = Self::new_passive(device);
         // Turn off IMU stream while reading config
         result.command(0x19, 0x0)?;
         result.read_config()?;
@@ -415,6 +1109,33 @@ impl Ov580 {
         Ok(result)
     }
 
+    /// Only reads the IMU reports, without sending any commands (so without reading
+    /// the calibration data). Used in sensor-only mode.
+    fn new_passive(device: Box<dyn FrameTransport>) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(crate::SystemClock);
+        Self {
+            device,
+            config_json: JsonValue::Null,
+            gyro_bias: Default::default(),
+            accelerometer_bias: Default::default(),
+            pending_events: Default::default(),
+            separate_imu_events: false,
+            last_report_received: clock.now(),
+            read_buffer: vec![0; NrealLight::IMU_REPORT_SIZE],
+            #[cfg(not(target_os = "android"))]
+            opener: Box::new(open_ov580),
+            clock,
+        }
+    }
+
+    /// Open the device again, keeping the calibration data
+    #[cfg(not(target_os = "android"))]
+    fn reopen(&mut self) -> Result<()> {
+        self.device = (self.opener)()?;
+        self.pending_events.clear();
+        Ok(())
+    }
+
     fn read_config(&mut self) -> Result<()> {
         // Start reading config
         self.command(0x14, 0x0)?;
@@ -424,9 +1145,13 @@ impl Ov580 {
             if config_part[0] != 2 || config_part[1] != 1 {
                 break;
             }
-            config.extend_from_slice(&config_part[3..(3 + config_part[2] as usize)]);
+            config.extend_from_slice(
+                config_part
+                    .get(3..(3 + config_part[2] as usize))
+                    .ok_or(Error::Other("Invalid glasses config part length"))?,
+            );
         }
-        for i in 0x28..config.len() - 4 {
+        for i in 0x28..config.len().saturating_sub(4) {
             if config[i..i + 3] == [b'\n', b'\n', b'{'] {
                 let config_as_str = String::from_utf8(config[i + 2..].into())
                     .map_err(|_| Error::Other("Invalid glasses config format (no start token)"))?;
@@ -466,7 +1191,7 @@ impl Ov580 {
             let mut result = [0u8; 0x80];
             let result_size = self.device.read_timeout(&mut result, OV_580_TIMEOUT)?;
             if result_size == 0 {
-                return Err(Error::PacketTimeout);
+                return Err(self.device.check_disconnect(Error::PacketTimeout));
             }
             if result[0] == 2 {
                 return Ok(result.into());
@@ -477,24 +1202,38 @@ impl Ov580 {
 
     pub fn read_packet(&mut self) -> Result<GlassesEvent> {
         loop {
-            let mut packet_data = [0u8; 0x80];
-            let data_size = self.device.read_timeout(&mut packet_data, OV_580_TIMEOUT)?;
+            if let Some(event) = self.pending_events.pop_front() {
+                return Ok(event);
+            }
+            let data_size = self
+                .device
+                .read_timeout(&mut self.read_buffer, OV_580_TIMEOUT)?;
             if data_size == 0 {
-                return Err(Error::PacketTimeout);
+                return Err(self.device.check_disconnect(Error::PacketTimeout));
             }
 
-            if packet_data[0] == 1 {
-                return self.parse_report(&packet_data);
+            if self.read_buffer[0] == 1 {
+                self.last_report_received = self.clock.now();
+                // Temporarily take the buffer, since parsing needs &mut self
+                let packet_data = std::mem::take(&mut self.read_buffer);
+                let result = self.parse_report(&packet_data[..data_size]);
+                self.read_buffer = packet_data;
+                result?;
             };
             // Else try again
         }
     }
 
-    fn parse_report(&mut self, packet_data: &[u8]) -> Result<GlassesEvent> {
+    /// Parse the sample block of a report. No sample count field is known, so only
+    /// the first block is read (see the README).
+    fn parse_report(&mut self, packet_data: &[u8]) -> Result<()> {
         // TODO: This skips over a 2 byte temperature field that may be useful.
-        let mut reader = std::io::Cursor::new(&packet_data[44..]);
+        let sample_data = packet_data
+            .get(Self::SAMPLE_OFFSET..Self::SAMPLE_OFFSET + NrealLight::IMU_SAMPLE_SIZE)
+            .ok_or(Error::Other("IMU report too short"))?;
+        let mut reader = std::io::Cursor::new(sample_data);
 
-        let gyro_timestamp = reader.read_u64::<LittleEndian>()? / 1000;
+        let gyro_timestamp = DeviceTimestamp::from_nanos(reader.read_u64::<LittleEndian>()?);
         let gyro_mul = reader.read_u32::<LittleEndian>()? as f32;
         let gyro_div = reader.read_u32::<LittleEndian>()? as f32;
         let gyro_x = reader.read_i32::<LittleEndian>()? as f32;
@@ -506,7 +1245,7 @@ impl Ov580 {
             -(gyro_z * gyro_mul / gyro_div).to_radians() + self.gyro_bias.z,
         );
 
-        let _acc_timestamp = reader.read_u64::<LittleEndian>()? / 1000;
+        let acc_timestamp = DeviceTimestamp::from_nanos(reader.read_u64::<LittleEndian>()?);
         let acc_mul = reader.read_u32::<LittleEndian>()? as f32;
         let acc_div = reader.read_u32::<LittleEndian>()? as f32;
         let acc_x = reader.read_i32::<LittleEndian>()? as f32;
@@ -517,11 +1256,26 @@ impl Ov580 {
             -(acc_y * acc_mul / acc_div) * 9.81 + self.accelerometer_bias.y,
             -(acc_z * acc_mul / acc_div) * 9.81 + self.accelerometer_bias.z,
         );
-        Ok(GlassesEvent::AccGyro {
-            accelerometer,
-            gyroscope,
-            timestamp: gyro_timestamp,
-        })
+        if self.separate_imu_events {
+            self.pending_events.push_back(GlassesEvent::Accelerometer {
+                accelerometer,
+                timestamp: acc_timestamp,
+            });
+            self.pending_events.push_back(GlassesEvent::Gyroscope {
+                gyroscope,
+                timestamp: gyro_timestamp,
+            });
+        } else {
+            self.pending_events.push_back(GlassesEvent::AccGyro {
+                accelerometer,
+                gyroscope,
+                timestamp: gyro_timestamp,
+                // The OV580 passes on the 16 bit values of the IMU in 32 bit fields
+                flags: SampleFlags::clipping([gyro_x, gyro_y, gyro_z], [acc_x, acc_y, acc_z], 16)
+                    | SampleFlags::BIAS_APPLIED,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -543,8 +1297,22 @@ impl Default for Packet {
 }
 
 impl Packet {
+    fn is_answer_to(&self, command: &Packet) -> bool {
+        self.is_answer_to_id(command.category, command.cmd_id)
+    }
+
+    fn is_answer_to_id(&self, category: u8, cmd_id: u8) -> bool {
+        self.category == category + 1 && self.cmd_id == cmd_id
+    }
+
+    /// Answers to commands are in the categories after the command ones ('1' -> '2', etc.),
+    /// everything in category '5' is an unsolicited event
+    fn is_answer(&self) -> bool {
+        self.category != b'5'
+    }
+
     fn deserialize(data: &[u8]) -> Option<Packet> {
-        if data[0] != 2 {
+        if data.first() != Some(&2) {
             return None;
         }
         let end = data.iter().position(|c| *c == 3)?;
@@ -564,8 +1332,8 @@ impl Packet {
         })
     }
 
-    fn serialize(&self) -> Option<[u8; 0x40]> {
-        let mut writer = std::io::Cursor::new([0u8; 0x40]);
+    fn serialize(&self) -> Option<[u8; NrealLight::MCU_PACKET_SIZE]> {
+        let mut writer = std::io::Cursor::new([0u8; NrealLight::MCU_PACKET_SIZE]);
         writer
             .write_all(&[2, b':', self.category, b':', self.cmd_id, b':'])
             .ok()?;
@@ -580,9 +1348,48 @@ impl Packet {
     }
 }
 
+/// Parse an MCU frame into the fields of the `nreal_light_mcu` test vectors
+pub(crate) fn decode_mcu_vector(frame: &[u8]) -> Option<String> {
+    let packet = Packet::deserialize(frame)?;
+    Some(format!(
+        "category={:02x},cmd_id={:02x},data={}",
+        packet.category,
+        packet.cmd_id,
+        crate::protocol::hex(&packet.data)
+    ))
+}
+
+/// Serialize the fields of an `nreal_light_mcu` test vector into an MCU frame
+pub(crate) fn encode_mcu_vector(fields: &str) -> Option<Vec<u8>> {
+    let packet = Packet {
+        category: crate::protocol::numeric_field(fields, "category")?
+            .try_into()
+            .ok()?,
+        cmd_id: crate::protocol::numeric_field(fields, "cmd_id")?
+            .try_into()
+            .ok()?,
+        data: crate::protocol::field(fields, "data")?,
+    };
+    Some(packet.serialize()?.to_vec())
+}
+
+/// Entry points for the fuzz targets in the `fuzz` directory
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing {
+    /// Deserialize an MCU packet from arbitrary bytes
+    pub fn mcu_packet(data: &[u8]) {
+        let _ = super::Packet::deserialize(data);
+    }
+}
+
 /// Structure representing the Nreal Light's OV580 DSP chip's video interface
-pub struct NrealLightSlamCamera {
-    device_handle: rusb::DeviceHandle<rusb::GlobalContext>,
+///
+/// By default libusb's global context is used, see [`NrealLightSlamCamera::new_with_context`]
+/// for using an application provided one. Note that the MCU and the IMU are accessed
+/// through hidapi, so they are not affected by this.
+pub struct NrealLightSlamCamera<T: rusb::UsbContext = rusb::GlobalContext> {
+    device_handle: rusb::DeviceHandle<T>,
 }
 
 /// One captured Slam camera frame
@@ -593,10 +1400,33 @@ pub struct NrealLightSlamCameraFrame {
     /// Right frame data (640x480 grayscale pixels)
     pub right: Vec<u8>,
     /// Exact IMU timestamp when this frame was recorded
-    pub timestamp: u64,
+    pub timestamp: DeviceTimestamp,
 }
 
 impl NrealLightSlamCamera {
+    /// Connect to a specific glasses, based on the USB fd
+    /// Mainly made to work around android permission issues
+    #[cfg(target_os = "android")]
+    pub fn new(fd: isize) -> Result<Self> {
+        use rusb::UsbContext;
+        // Do not scan for devices in libusb_init()
+        // This is needed on Android, where access to USB devices is limited
+        unsafe { rusb::ffi::libusb_set_option(std::ptr::null_mut(), 2) };
+        let device_handle =
+            unsafe { rusb::GlobalContext::default().open_device_with_fd(fd as i32) }?;
+        Self::new_common(device_handle)
+    }
+
+    /// Find a connected Nreal Light device and connect to its slam camera interface, and start
+    /// streaming video.
+    /// Only one instance can be alive at a time
+    #[cfg(not(target_os = "android"))]
+    pub fn new() -> Result<Self> {
+        Self::new_with_context(&rusb::GlobalContext::default())
+    }
+}
+
+impl<T: rusb::UsbContext> NrealLightSlamCamera<T> {
     const VIDEO_INTERFACE: u8 = 1;
 
     // This was dumped using libuvc. It comes from enumerating the actual, reported
@@ -620,34 +1450,42 @@ impl NrealLightSlamCamera {
         0x18, // bMaxVersion
     ];
 
-    /// Connect to a specific glasses, based on the USB fd
-    /// Mainly made to work around android permission issues
-    #[cfg(target_os = "android")]
-    pub fn new(fd: isize) -> Result<Self> {
-        use rusb::UsbContext;
-        // Do not scan for devices in libusb_init()
-        // This is needed on Android, where access to USB devices is limited
-        unsafe { rusb::ffi::libusb_set_option(std::ptr::null_mut(), 2) };
-        let device_handle =
-            unsafe { rusb::GlobalContext::default().open_device_with_fd(fd as i32) }?;
-        Self::new_common(device_handle)
+    /// Same as [`NrealLightSlamCamera::new`], but uses the specified libusb context,
+    /// e.g. one that the application also uses for hotplug callbacks.
+    #[cfg(not(target_os = "android"))]
+    pub fn new_with_context(context: &T) -> Result<Self> {
+        use crate::util::get_device_vid_pid_in;
+        Self::new_common(
+            get_device_vid_pid_in(context, NrealLight::OV580_VID, NrealLight::OV580_PID)?.open()?,
+        )
     }
 
-    /// Find a connected Nreal Light device and connect to its slam camera interface, and start
-    /// streaming video.
-    /// Only one instance can be alive at a time
-    #[cfg(not(target_os = "android"))]
-    pub fn new() -> Result<Self> {
-        use crate::util::get_device_vid_pid;
-        Self::new_common(get_device_vid_pid(NrealLight::OV580_VID, NrealLight::OV580_PID)?.open()?)
+    fn new_common(mut device_handle: rusb::DeviceHandle<T>) -> Result<Self> {
+        device_handle.set_auto_detach_kernel_driver(true)?;
+        let mut result = Self { device_handle };
+        result.reclaim()?;
+        Ok(result)
     }
 
-    fn new_common(mut device_handle: rusb::DeviceHandle<rusb::GlobalContext>) -> Result<Self> {
+    /// Release the video interface, e.g. to temporarily hand the camera over to another
+    /// program. The kernel driver is reattached. [`NrealLightSlamCamera::get_frame`]
+    /// fails until [`NrealLightSlamCamera::reclaim`] is called.
+    ///
+    /// The MCU and IMU interfaces of [`NrealLight`] are opened through hidapi, which
+    /// does not claim them exclusively, so they don't need to be released.
+    pub fn release(&mut self) -> Result<()> {
+        self.device_handle
+            .release_interface(Self::VIDEO_INTERFACE)?;
+        Ok(())
+    }
+
+    /// Claim the video interface again (detaching the kernel driver) after
+    /// [`NrealLightSlamCamera::release`], and restart streaming
+    pub fn reclaim(&mut self) -> Result<()> {
         const UVC_SET_CUR: u8 = 0x01;
         const UVC_VS_COMMIT_CONTROL: u16 = 0x02;
-        device_handle.set_auto_detach_kernel_driver(true)?;
-        device_handle.claim_interface(Self::VIDEO_INTERFACE)?;
-        device_handle.write_control(
+        self.device_handle.claim_interface(Self::VIDEO_INTERFACE)?;
+        self.device_handle.write_control(
             0x21, // USB_TYPE_CLASS	| USB_RECIP_INTERFACE
             UVC_SET_CUR,
             UVC_VS_COMMIT_CONTROL << 8,
@@ -655,8 +1493,7 @@ impl NrealLightSlamCamera {
             Self::ENABLE_STREAMING_PACKET.as_slice(),
             Duration::from_secs(1),
         )?;
-        let result = Self { device_handle };
-        Ok(result)
+        Ok(())
     }
 
     /// Get a single frame from the device. timeout == ZERO means "infinite" timeout.
@@ -670,7 +1507,8 @@ impl NrealLightSlamCamera {
             }
             let recvd = self
                 .device_handle
-                .read_bulk(0x81, &mut bulk_data, timeout)?;
+                .read_bulk(0x81, &mut bulk_data, timeout)
+                .map_err(|e| usb_disconnect_check(e, "Nreal Light"))?;
             if recvd == 615908 && bulk_data[0] != 0 {
                 bulk_data.truncate(recvd);
                 break;
@@ -698,9 +1536,9 @@ impl NrealLightSlamCamera {
             left.extend_from_slice(&bulk_data[(i * 2) * 640..(i * 2 + 1) * 640]);
             right.extend_from_slice(&bulk_data[(i * 2 + 1) * 640..(i * 2 + 2) * 640]);
         }
-        let timestamp = u64::from_le_bytes(bulk_data[640 * 480 * 2..640 * 480 * 2 + 8].try_into().unwrap()) / 1000
+        let timestamp = DeviceTimestamp::from_nanos(u64::from_le_bytes(bulk_data[640 * 480 * 2..640 * 480 * 2 + 8].try_into().unwrap()))
             // As seen in the nreal protocol json
-            + 37600;
+            + Duration::from_micros(37600);
         Ok(NrealLightSlamCameraFrame {
             left,
             right,
@@ -708,3 +1546,580 @@ impl NrealLightSlamCamera {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{clock::MockClock, util::ScriptedTransport};
+
+    const CONFIG: &str = r#"{"IMU": {"device_1": {"accel_bias": [0.1, 0.2, 0.3], "gyro_bias": [0.01, 0.02, 0.03]}}}"#;
+
+    fn mcu_frame(category: u8, cmd_id: u8, data: &[u8]) -> Vec<u8> {
+        Packet {
+            category,
+            cmd_id,
+            data: data.into(),
+        }
+        .serialize()
+        .unwrap()
+        .to_vec()
+    }
+
+    /// MCU answering every command (except the heartbeat) with its own payload
+    fn echoing_mcu() -> ScriptedTransport {
+        let mcu = ScriptedTransport::default();
+        mcu.set_responder(|frame| {
+            let command = Packet::deserialize(frame).unwrap();
+            match (command.category, command.cmd_id) {
+                (b'@', b'K') => return Vec::new(),
+                // Display mode query: 2D
+                (b'3', b'3') => return vec![mcu_frame(b'4', b'3', b"1")],
+                _ => (),
+            }
+            vec![mcu_frame(
+                command.category + 1,
+                command.cmd_id,
+                &command.data,
+            )]
+        });
+        mcu
+    }
+
+    /// OV580 serving `CONFIG` in parts, like the real one: garbage header, then the JSON
+    /// between empty lines
+    fn ov580() -> ScriptedTransport {
+        let mut config = vec![b'-'; 0x28];
+        config.extend_from_slice(b"\n\n");
+        config.extend_from_slice(CONFIG.as_bytes());
+        config.extend_from_slice(b"\n\n");
+        let ov580 = ScriptedTransport::default();
+        let mut offset = 0;
+        ov580.set_responder(move |frame| {
+            let answer = match frame[1] {
+                0x15 if offset < config.len() => {
+                    let part = &config[offset..config.len().min(offset + 100)];
+                    offset += part.len();
+                    [&[2, 1, part.len() as u8], part].concat()
+                }
+                command => {
+                    if command == 0x14 {
+                        offset = 0;
+                    }
+                    vec![2, 0, 0]
+                }
+            };
+            vec![answer]
+        });
+        ov580
+    }
+
+    fn connect(mcu: &ScriptedTransport, ov580: &ScriptedTransport) -> NrealLight {
+        NrealLight::new_common(
+            Some(mcu.boxed()),
+            Ov580::new_device(ov580.boxed()).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    /// Glasses with only the OV580, see [`NrealLight::new_sensors_only`]
+    fn sensors_only(ov580: &ScriptedTransport) -> NrealLight {
+        NrealLight::new_common(None, Ov580::new_passive(ov580.boxed()), None).unwrap()
+    }
+
+    #[test]
+    fn init_sequence() {
+        let mcu = echoing_mcu();
+        let ov580 = ov580();
+        mcu.expect_write(mcu_frame(b'@', b'3', b"1"));
+        mcu.expect_write(mcu_frame(b'1', b'L', b"1"));
+        mcu.expect_write(mcu_frame(b'1', b'N', b"1"));
+        let glasses = connect(&mcu, &ov580);
+        assert!(mcu.is_finished());
+        assert_eq!(mcu.written().len(), 3);
+
+        let ov580_commands: Vec<(u8, u8)> = ov580
+            .written()
+            .iter()
+            .map(|frame| (frame[1], frame[2]))
+            .collect();
+        assert_eq!(ov580_commands[..2], [(0x19, 0), (0x14, 0)]);
+        assert_eq!(ov580_commands.last(), Some(&(0x19, 1)));
+        assert_eq!(glasses.ov580.gyro_bias, Vector3::new(0.01, 0.02, 0.03));
+        assert_eq!(
+            glasses.ov580.accelerometer_bias,
+            Vector3::new(0.1, 0.2, 0.3)
+        );
+    }
+
+    #[test]
+    fn run_command_keeps_unrelated_packets() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        mcu.set_responder(|frame| {
+            let command = Packet::deserialize(frame).unwrap();
+            if (command.category, command.cmd_id) != (b'3', b'C') {
+                return Vec::new();
+            }
+            vec![
+                mcu_frame(b'5', b'K', b"UP"),
+                mcu_frame(b'5', b'P', b"near"),
+                mcu_frame(b'4', b'C', b"SERIAL"),
+            ]
+        });
+        assert_eq!(glasses.serial().unwrap(), "SERIAL");
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::KeyPress(0))
+        ));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::ProximityNear)
+        ));
+    }
+
+    #[test]
+    fn initial_proximity() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+
+        // The first report is returned, whenever it arrives
+        mcu.push_read(mcu_frame(b'5', b'K', b"UP"));
+        mcu.push_read(mcu_frame(b'5', b'P', b"away"));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::KeyPress(0))
+        ));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::ProximityFar)
+        ));
+
+        // Repeats are dropped, except for the first report after a resync
+        mcu.push_read(mcu_frame(b'5', b'P', b"away"));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(glasses.diagnostics.repeated_state_events, 1);
+        glasses.resync().unwrap();
+        mcu.push_read(mcu_frame(b'5', b'P', b"away"));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::ProximityFar)
+        ));
+    }
+
+    #[test]
+    fn key_bounce() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        // Dropped presses show up as timeouts
+        let read_keys = |glasses: &mut NrealLight| {
+            (0..10)
+                .filter_map(|_| match glasses.read_event() {
+                    Ok(GlassesEvent::KeyPress(key)) => Some(key),
+                    Err(Error::PacketTimeout) => None,
+                    other => panic!("Unexpected {other:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        for data in [b"UP", b"UP", b"DN", b"UP"] {
+            mcu.push_read(mcu_frame(b'5', b'K', data));
+        }
+        assert_eq!(read_keys(&mut glasses), [0, 1]);
+
+        clock.advance(Duration::from_millis(100));
+        mcu.push_read(mcu_frame(b'5', b'K', b"UP"));
+        assert_eq!(read_keys(&mut glasses), [0]);
+        assert_eq!(glasses.diagnostics().unwrap().debounced_key_presses(), 2);
+    }
+
+    #[test]
+    fn ov580_reopen() {
+        let mcu = echoing_mcu();
+        let ov580 = ov580();
+        let mut glasses = connect(&mcu, &ov580);
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        glasses.ov580.opener = Box::new({
+            let ov580 = ov580.clone();
+            let attempts = attempts.clone();
+            move || match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(Error::NotFound),
+                _ => Ok(ov580.boxed()),
+            }
+        });
+        let reset = || Error::IoError(std::io::Error::other("Device reset"));
+
+        // Other errors don't need reopening
+        ov580.push_error(Error::Other("Something else"));
+        assert!(matches!(glasses.read_event(), Err(Error::Other(_))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 0);
+
+        ov580.push_error(reset());
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(attempts.load(Ordering::Relaxed), 0);
+        clock.advance(OV580_REOPEN_BACKOFF);
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        // Doesn't block, and the heartbeat keeps going meanwhile
+        mcu.clear_written();
+        clock.advance(Duration::from_millis(151));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        assert_eq!(mcu.written(), [mcu_frame(b'@', b'K', b"x")]);
+
+        // The second attempt is due 200ms after the first one
+        clock.advance(Duration::from_millis(49));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::TransportWarning {
+                kind: crate::TransportErrorKind::Reopened,
+                count: 1
+            })
+        ));
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(glasses.diagnostics().unwrap().transport_recoveries(), 1);
+        // The stream was restarted on the new handle
+        assert_eq!(ov580.written().last().unwrap()[1..3], [0x19, 0x1]);
+
+        // Giving up returns the original error
+        glasses.ov580.opener = Box::new(|| Err(Error::NotFound));
+        ov580.push_error(reset());
+        let mut result = glasses.read_event();
+        for _ in 0..OV580_REOPEN_ATTEMPTS {
+            assert!(matches!(result, Err(Error::PacketTimeout)));
+            clock.advance(OV580_REOPEN_BACKOFF * 16);
+            result = glasses.read_event();
+        }
+        assert!(matches!(result, Err(Error::IoError(_))));
+    }
+
+    #[test]
+    fn command_timeout_and_garbage() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        mcu.set_responder(|_| Vec::new());
+        assert!(matches!(glasses.serial(), Err(Error::PacketTimeout)));
+
+        mcu.push_read(b"\x02garbage".to_vec());
+        assert!(matches!(
+            glasses.get_display_mode(),
+            Err(Error::Other("Malformed packet received"))
+        ));
+    }
+
+    #[test]
+    fn high_refresh_rate_mode() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        mcu.clear_written();
+        glasses
+            .set_display_mode(DisplayMode::HighRefreshRateSBS)
+            .unwrap();
+        assert_eq!(mcu.written(), [mcu_frame(b'1', b'3', b"4")]);
+        assert!(glasses
+            .set_display_mode(DisplayMode::HighRefreshRate)
+            .is_err());
+        assert!(glasses
+            .supported_display_modes()
+            .unwrap()
+            .contains(&DisplayMode::HighRefreshRateSBS));
+
+        mcu.set_responder(|_| vec![mcu_frame(b'4', b'3', b"4")]);
+        assert_eq!(
+            glasses.get_display_mode().unwrap(),
+            DisplayMode::HighRefreshRate
+        );
+        assert_eq!(glasses.current_refresh_rate().unwrap(), 72);
+    }
+
+    #[test]
+    fn late_answer_read_as_event() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        mcu.set_clock(clock.clone());
+        glasses.set_imu_enabled(false).unwrap();
+        mcu.set_responder(|_| Vec::new());
+        assert!(matches!(glasses.serial(), Err(Error::PacketTimeout)));
+
+        // The answer arrives late, while waiting for events
+        mcu.push_read(mcu_frame(b'4', b'C', b"SERIAL"));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+
+        // So the next command doesn't wait for it
+        mcu.set_responder(|_| vec![mcu_frame(b'4', b'C', b"SERIAL")]);
+        let before = clock.now();
+        assert_eq!(glasses.serial().unwrap(), "SERIAL");
+        assert_eq!(clock.now(), before);
+    }
+
+    #[test]
+    fn heartbeat_while_settling() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        mcu.set_clock(clock.clone());
+        mcu.set_responder(|_| Vec::new());
+        assert!(matches!(glasses.serial(), Err(Error::PacketTimeout)));
+
+        // The answer never arrives, so the next command waits until it would be stale,
+        // without letting the display switch off meanwhile
+        mcu.set_responder(|frame| match Packet::deserialize(frame).unwrap().cmd_id {
+            b'3' => vec![mcu_frame(b'4', b'3', b"1")],
+            _ => Vec::new(),
+        });
+        mcu.clear_written();
+        let before = clock.now();
+        assert_eq!(glasses.get_display_mode().unwrap(), DisplayMode::SameOnBoth);
+        let elapsed = clock.now() - before;
+        assert!(elapsed + Duration::from_millis(COMMAND_TIMEOUT as u64) >= STALE_ANSWER_TTL);
+        let written = mcu.written();
+        let heartbeats = written
+            .iter()
+            .filter(|frame| **frame == mcu_frame(b'@', b'K', b"x"))
+            .count();
+        assert!(heartbeats >= 2, "{heartbeats} heartbeats in {elapsed:?}");
+        assert_eq!(written.last().unwrap(), &mcu_frame(b'3', b'3', b"x"));
+    }
+
+    #[test]
+    fn heartbeat_timing() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        let heartbeat = mcu_frame(b'@', b'K', b"x");
+        mcu.clear_written();
+        // There are no IMU reports, so the reads time out after the heartbeat check
+        clock.advance(HEARTBEAT_INTERVAL);
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(mcu.written().is_empty());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(mcu.written(), vec![heartbeat.clone()]);
+
+        clock.advance(HEARTBEAT_INTERVAL + Duration::from_millis(1));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(mcu.written(), [heartbeat.clone(), heartbeat]);
+    }
+
+    #[test]
+    fn manual_heartbeat() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        glasses.set_manual_heartbeat(true);
+        mcu.clear_written();
+
+        clock.advance(HEARTBEAT_INTERVAL + Duration::from_millis(1));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::HeartbeatDue)
+        ));
+        // Reported only once per heartbeat
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(mcu.written().is_empty());
+
+        glasses.send_heartbeat().unwrap();
+        clock.advance(HEARTBEAT_INTERVAL);
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::HeartbeatDue)
+        ));
+        assert_eq!(mcu.written().len(), 1);
+    }
+
+    #[test]
+    fn capability_methods() {
+        crate::tests::check_capability_methods(&mut connect(&echoing_mcu(), &ov580()));
+        crate::tests::check_capability_methods(&mut sensors_only(&ov580()));
+    }
+
+    #[test]
+    fn revalidate_after_host_sleep() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        glasses
+            .set_auto_revalidate(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+
+        // A long pause is not a sleep
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        clock.suspend(Duration::from_secs(4));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+
+        mcu.clear_written();
+        clock.suspend(Duration::from_secs(5));
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::SessionRestored)
+        ));
+        // The handshake was repeated
+        assert_eq!(mcu.written()[0], mcu_frame(b'@', b'3', b"1"));
+    }
+
+    /// A captured-format IMU report: one sample block, followed by unrelated bytes
+    fn imu_report() -> Vec<u8> {
+        imu_report_with([573, 0, 0], [0, 0, -100])
+    }
+
+    /// Same as [`imu_report`], with the given raw gyroscope and accelerometer values
+    fn imu_report_with(gyroscope: [i32; 3], accelerometer: [i32; 3]) -> Vec<u8> {
+        let mut report = vec![0; NrealLight::IMU_REPORT_SIZE];
+        report[0] = 1;
+        let mut sample = Vec::new();
+        // Gyroscope: timestamp, multiplier, divisor, x, y, z
+        sample.extend_from_slice(&1_000_000u64.to_le_bytes());
+        for value in [1u32, 10] {
+            sample.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in gyroscope {
+            sample.extend_from_slice(&value.to_le_bytes());
+        }
+        // Accelerometer
+        sample.extend_from_slice(&1_000_500u64.to_le_bytes());
+        for value in [1u32, 100] {
+            sample.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in accelerometer {
+            sample.extend_from_slice(&value.to_le_bytes());
+        }
+        report[Ov580::SAMPLE_OFFSET..Ov580::SAMPLE_OFFSET + NrealLight::IMU_SAMPLE_SIZE]
+            .copy_from_slice(&sample);
+        report[Ov580::SAMPLE_OFFSET + NrealLight::IMU_SAMPLE_SIZE..].fill(0x55);
+        report
+    }
+
+    #[test]
+    fn clipped_sample() {
+        let ov580 = ov580();
+        let mut glasses = connect(&echoing_mcu(), &ov580);
+        ov580.push_read(imu_report());
+        ov580.push_read(imu_report_with([573, i16::MAX as i32, 0], [0, 0, -100]));
+        ov580.push_read(imu_report_with([573, 0, 0], [0, 0, i16::MIN as i32]));
+        let mut flags = || match glasses.read_event() {
+            Ok(GlassesEvent::AccGyro { flags, .. }) => flags,
+            other => panic!("Expected an IMU sample, got {other:?}"),
+        };
+        assert!(!flags().is_clipped());
+        let gyroscope = flags();
+        assert!(gyroscope.contains(SampleFlags::CLIPPED_GYROSCOPE));
+        assert!(!gyroscope.contains(SampleFlags::CLIPPED_ACCELEROMETER));
+        let accelerometer = flags();
+        assert!(accelerometer.contains(SampleFlags::CLIPPED_ACCELEROMETER));
+        assert!(!accelerometer.contains(SampleFlags::CLIPPED_GYROSCOPE));
+        assert_eq!(glasses.diagnostics().unwrap().clipped_samples(), 2);
+    }
+
+    #[test]
+    fn imu_report_sample() {
+        let ov580 = ov580();
+        let mut glasses = connect(&echoing_mcu(), &ov580);
+        ov580.push_read(imu_report());
+        let Ok(GlassesEvent::AccGyro {
+            accelerometer,
+            gyroscope,
+            timestamp,
+            ..
+        }) = glasses.read_event()
+        else {
+            panic!("Expected an IMU sample");
+        };
+        assert_eq!(timestamp, DeviceTimestamp::from_nanos(1_000_000));
+        assert!((gyroscope.x - (57.3f32.to_radians() - 0.01)).abs() < 1e-5);
+        assert!((accelerometer.z - (9.81 + 0.3)).abs() < 1e-5);
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+    }
+
+    #[test]
+    fn sensors_only_sends_nothing() {
+        let ov580 = ov580();
+        let mut glasses = sensors_only(&ov580);
+        let clock = MockClock::new();
+        glasses.set_clock(clock.clone());
+        glasses.ov580.opener = Box::new({
+            let ov580 = ov580.clone();
+            move || Ok(ov580.boxed())
+        });
+        assert!(glasses.get_config_json().is_null());
+
+        // The samples are not corrected, as the biases are not known
+        ov580.push_read(imu_report());
+        let Ok(GlassesEvent::AccGyro { gyroscope, .. }) = glasses.read_event() else {
+            panic!("Expected an IMU sample");
+        };
+        assert!((gyroscope.x - 57.3f32.to_radians()).abs() < 1e-5);
+        // A stalled stream is not turned back on
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        assert!(matches!(
+            glasses.set_imu_enabled(true),
+            Err(Error::Unsupported { .. })
+        ));
+        glasses.resync().unwrap();
+        // Nor is the stream restarted after reopening
+        ov580.push_error(Error::IoError(std::io::Error::other("Device reset")));
+        assert!(matches!(glasses.read_event(), Err(Error::PacketTimeout)));
+        clock.advance(OV580_REOPEN_BACKOFF);
+        assert!(matches!(
+            glasses.read_event(),
+            Ok(GlassesEvent::TransportWarning { .. })
+        ));
+        assert!(ov580.written().is_empty(), "{:?}", ov580.written());
+    }
+
+    #[test]
+    fn raw_display_transforms() {
+        let mut glasses = connect(&echoing_mcu(), &ov580());
+        assert!(glasses.raw_display_transforms().is_err());
+
+        let transform = |offset: f64| {
+            let values: Vec<String> = (0..16).map(|i| (i as f64 + offset).to_string()).collect();
+            format!("[{}]", values.join(", "))
+        };
+        glasses.ov580.config_json = format!(
+            r#"{{"display": {{"display_1": {{"transform": {}}}, "display_2": {{"transform": {}}}}}}}"#,
+            transform(0.0),
+            transform(100.0)
+        )
+        .parse()
+        .unwrap();
+        let [display_1, display_2] = glasses.raw_display_transforms().unwrap();
+        // Row-major: the fourth value is the X translation
+        assert_eq!(display_1[(0, 3)], 3.0);
+        assert_eq!(display_1[(3, 0)], 12.0);
+        assert_eq!(display_2[(0, 0)], 100.0);
+    }
+
+    #[test]
+    fn ambient_light_toggle() {
+        let mcu = echoing_mcu();
+        let mut glasses = connect(&mcu, &ov580());
+        assert!(glasses.ambient_light_reporting());
+        mcu.clear_written();
+
+        glasses.set_ambient_light_reporting(false).unwrap();
+        assert!(!glasses.ambient_light_reporting());
+        glasses.set_ambient_light_reporting(true).unwrap();
+        assert!(glasses.ambient_light_reporting());
+        assert_eq!(
+            mcu.written(),
+            [mcu_frame(b'1', b'L', b"0"), mcu_frame(b'1', b'L', b"1")]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Head tracking output for OpenTrack's "UDP over network" input. See [`OpenTrackSender`]
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use nalgebra::{UnitQuaternion, Vector3};
+
+use crate::{heading, Error, OrientationCorrection, Result};
+
+/// A rotation axis of the head, see [`OpenTrackSender::with_axis`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadAxis {
+    /// Turning left (positive) or right, around the gravity axis
+    Yaw = 0,
+    /// Looking up (positive) or down
+    Pitch = 1,
+    /// Tilting the head to the left (positive) or right
+    Roll = 2,
+}
+
+/// Sends head orientations to OpenTrack (or anything else speaking its UDP protocol),
+/// e.g. to use the glasses for head tracking in simulator games.
+///
+/// Each datagram is six little endian `f64`s: the X, Y and Z position in centimeters
+/// (always zero, as the glasses only track rotation), then yaw, pitch and roll in degrees.
+/// The angles are relative to the gravity aligned world frame of the orientations, see
+/// [`HeadAxis`] for the directions. If a game turns the wrong way, invert the axis with
+/// [`OpenTrackSender::with_axis`] (or in OpenTrack's mapping settings).
+#[derive(Debug)]
+pub struct OpenTrackSender {
+    socket: UdpSocket,
+    interval: Duration,
+    last_sent: Option<Instant>,
+    /// Source axis and inversion for the yaw, pitch and roll outputs
+    mapping: [(HeadAxis, bool); 3],
+    correction: OrientationCorrection,
+}
+
+impl OpenTrackSender {
+    /// The default port of OpenTrack's UDP input
+    pub const DEFAULT_PORT: u16 = 4242;
+
+    /// Send to the specified address (e.g. `("127.0.0.1", OpenTrackSender::DEFAULT_PORT)`),
+    /// at most at 100Hz, without any axis remapping
+    pub fn new(destination: impl ToSocketAddrs) -> Result<Self> {
+        let destination = destination
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::Other("No address to send the head tracking data to"))?;
+        let local: SocketAddr = match destination {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(destination)?;
+        Ok(Self {
+            socket,
+            interval: Duration::from_millis(10),
+            last_sent: None,
+            mapping: [
+                (HeadAxis::Yaw, false),
+                (HeadAxis::Pitch, false),
+                (HeadAxis::Roll, false),
+            ],
+            correction: OrientationCorrection::new(),
+        })
+    }
+
+    /// Send at most `rate` datagrams per second with [`OpenTrackSender::update`]
+    pub fn with_rate(mut self, rate: f32) -> Self {
+        self.interval = Duration::from_secs_f32(1.0 / rate.max(1e-3));
+        self
+    }
+
+    /// Send the `source` rotation (optionally inverted) as the `output` angle
+    pub fn with_axis(mut self, output: HeadAxis, source: HeadAxis, inverted: bool) -> Self {
+        self.mapping[output as usize] = (source, inverted);
+        self
+    }
+
+    /// The correction applied to the orientations. Clones share the correction,
+    /// so it can be used to recenter or correct drift from another thread.
+    pub fn correction(&self) -> OrientationCorrection {
+        self.correction.clone()
+    }
+
+    /// Make the current looking direction the zero yaw.
+    /// Takes effect from the next orientation sent.
+    pub fn recenter(&mut self) {
+        self.correction.recenter_yaw();
+    }
+
+    /// Send the orientation (transforming from the device frame to a gravity aligned
+    /// world frame, like the output of most sensor fusion algorithms), unless the previous
+    /// one was sent too recently, see [`OpenTrackSender::with_rate`].
+    /// Returns whether it was sent.
+    pub fn update(&mut self, orientation: &UnitQuaternion<f64>) -> Result<bool> {
+        let now = Instant::now();
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.saturating_duration_since(last_sent) < self.interval)
+        {
+            return Ok(false);
+        }
+        self.send(orientation)?;
+        self.last_sent = Some(now);
+        Ok(true)
+    }
+
+    /// Send the orientation immediately, regardless of the rate
+    pub fn send(&self, orientation: &UnitQuaternion<f64>) -> Result<()> {
+        let angles = yaw_pitch_roll(&self.correction.apply(orientation));
+        let mapped = self
+            .mapping
+            .map(|(source, inverted)| angles[source as usize] * if inverted { -1.0 } else { 1.0 });
+        self.socket.send(&datagram(mapped.map(f64::to_degrees)))?;
+        Ok(())
+    }
+}
+
+/// Yaw, pitch and roll of the orientation (applied in this order), in radians
+fn yaw_pitch_roll(orientation: &UnitQuaternion<f64>) -> [f64; 3] {
+    let yaw = heading(orientation);
+    // Forward is -Z in the RUB coordinate system
+    let forward = orientation * -Vector3::z();
+    let pitch = forward.y.clamp(-1.0, 1.0).asin();
+    let yaw_pitch = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), yaw)
+        * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), pitch);
+    // Only the roll around Z is left
+    let up = (yaw_pitch.inverse() * orientation) * Vector3::y();
+    let roll = (-up.x).atan2(up.y);
+    [yaw, pitch, roll]
+}
+
+/// Datagram of OpenTrack's UDP protocol with zero position and the angles in degrees
+fn datagram(angles: [f64; 3]) -> [u8; 48] {
+    let mut result = [0u8; 48];
+    for (i, angle) in angles.iter().enumerate() {
+        result[24 + i * 8..32 + i * 8].copy_from_slice(&angle.to_le_bytes());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagram_bytes() {
+        // Six native (little endian) doubles, as OpenTrack's own UDP sender writes them
+        let expected: [u8; 48] = [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // x
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // y
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // z
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x56, 0x40, // yaw: 90
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x25, 0xc0, // pitch: -10.5
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x46, 0xc0, // roll: -45
+        ];
+        assert_eq!(datagram([90.0, -10.5, -45.0]), expected);
+    }
+
+    #[test]
+    fn sends_head_angles() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sender = OpenTrackSender::new(receiver.local_addr().unwrap())
+            .unwrap()
+            .with_axis(HeadAxis::Roll, HeadAxis::Roll, true);
+        // Turned left by 30 degrees, then looking up by 20 degrees
+        let orientation = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), 30f64.to_radians())
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 20f64.to_radians());
+        sender.send(&orientation).unwrap();
+
+        let mut buf = [0u8; 64];
+        let length = receiver.recv(&mut buf).unwrap();
+        assert_eq!(length, 48);
+        let values: Vec<f64> = buf[..48]
+            .chunks(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(&values[..3], &[0.0; 3]);
+        for (value, expected) in values[3..].iter().zip([30.0, 20.0, 0.0]) {
+            assert!((value - expected).abs() < 1e-6, "{values:?}");
+        }
+    }
+}
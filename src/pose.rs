@@ -0,0 +1,439 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Orientation helpers, and combining orientation with an external position source.
+//! See [`PoseFuser`], [`OrientationCorrection`] and [`gravity_aligned_orientation`]
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+
+use crate::DeviceTimestamp;
+
+/// Number of samples kept from each source
+const HISTORY_SIZE: usize = 256;
+
+/// Combines a 3DoF orientation stream (e.g. the output of a sensor fusion algorithm
+/// fed with [`crate::GlassesEvent::AccGyro`]) with an external position stream
+/// (e.g. a camera tracking markers) into full 6DoF head poses.
+///
+/// The two streams are aligned by timestamp: both are interpolated to the requested time.
+/// Orientation timestamps are in device time, like the timestamps of
+/// [`crate::GlassesEvent`]. If the position source uses a different clock, set the
+/// difference with [`PoseFuser::set_position_time_offset`].
+#[derive(Debug, Clone, Default)]
+pub struct PoseFuser {
+    orientations: VecDeque<(u64, UnitQuaternion<f64>)>,
+    positions: VecDeque<(u64, Vector3<f64>)>,
+    position_time_offset: i64,
+}
+
+impl PoseFuser {
+    /// Create an empty fuser
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the offset (in microseconds) that converts position source timestamps
+    /// to device time: `device_time = position_time + offset`
+    pub fn set_position_time_offset(&mut self, offset: i64) {
+        self.position_time_offset = offset;
+    }
+
+    /// Add an orientation sample. Samples must be pushed in timestamp order.
+    pub fn push_orientation(
+        &mut self,
+        timestamp: DeviceTimestamp,
+        orientation: UnitQuaternion<f64>,
+    ) {
+        push_sample(&mut self.orientations, timestamp.as_micros(), orientation);
+    }
+
+    /// Add a position sample (in meters). Timestamp is in the position source's clock,
+    /// in microseconds. Samples must be pushed in timestamp order.
+    pub fn push_position(&mut self, timestamp: u64, position: Vector3<f64>) {
+        let timestamp = (timestamp as i64 + self.position_time_offset).max(0) as u64;
+        push_sample(&mut self.positions, timestamp, position);
+    }
+
+    /// Head pose at the specified device timestamp.
+    ///
+    /// Both streams are interpolated to the timestamp. Outside of the range of
+    /// received samples, the closest sample is used (no extrapolation).
+    /// Returns `None` until both streams have at least one sample.
+    pub fn pose_at(&self, timestamp: DeviceTimestamp) -> Option<Isometry3<f64>> {
+        let timestamp = timestamp.as_micros();
+        let orientation = interpolate(&self.orientations, timestamp, |a, b, t| {
+            a.try_slerp(b, t, 1e-9).unwrap_or(*a)
+        })?;
+        let position = interpolate(&self.positions, timestamp, |a, b, t| a.lerp(b, t))?;
+        Some(Isometry3::from_parts(
+            Translation3::from(position),
+            orientation,
+        ))
+    }
+
+    /// Head pose at the timestamp of the latest orientation sample
+    pub fn latest_pose(&self) -> Option<Isometry3<f64>> {
+        self.pose_at(DeviceTimestamp::from_micros(self.orientations.back()?.0))
+    }
+}
+
+/// Recentering, world alignment and mounting correction applied on top of the output of a
+/// sensor fusion algorithm.
+///
+/// The correction is a pair of rotations applied as offsets
+/// (`correction * orientation * mounting_offset`), so changing them never disturbs the
+/// filter state and causes no transient. Clones share the
+/// same correction, so one clone can be used on the thread running the filter
+/// ([`OrientationCorrection::apply`]), and another on e.g. a UI thread
+/// ([`OrientationCorrection::recenter_yaw`]).
+#[derive(Debug, Clone, Default)]
+pub struct OrientationCorrection {
+    state: Arc<Mutex<CorrectionState>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CorrectionState {
+    correction: UnitQuaternion<f64>,
+    mounting_offset: UnitQuaternion<f64>,
+    latest: UnitQuaternion<f64>,
+}
+
+impl OrientationCorrection {
+    /// Create an identity correction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Correct an orientation coming from the filter. The orientation is also stored
+    /// as the latest one, which is used by [`OrientationCorrection::recenter_yaw`].
+    pub fn apply(&self, orientation: &UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+        let mut state = self.lock();
+        state.latest = *orientation;
+        state.correction * orientation * state.mounting_offset
+    }
+
+    /// Zero the heading of the latest corrected orientation (i.e. make the current
+    /// looking direction "forward"), while preserving pitch and roll.
+    pub fn recenter_yaw(&self) {
+        let mut state = self.lock();
+        let yaw = heading(&(state.correction * state.latest * state.mounting_offset));
+        state.correction =
+            UnitQuaternion::from_axis_angle(&Vector3::y_axis(), -yaw) * state.correction;
+    }
+
+    /// Set the rotation from the filter's reference frame to the world frame, e.g. to
+    /// align with an external tracking system, or to restore a persisted correction.
+    /// This replaces the current correction, including any recentering.
+    pub fn set_world_from_reference(&self, world_from_reference: UnitQuaternion<f64>) {
+        self.lock().correction = world_from_reference;
+    }
+
+    /// The current correction, e.g. to persist it. Can be restored with
+    /// [`OrientationCorrection::set_world_from_reference`].
+    pub fn correction(&self) -> UnitQuaternion<f64> {
+        self.lock().correction
+    }
+
+    /// Set the rotation from the head frame to the device frame, for glasses that
+    /// are not worn level. Unlike [`OrientationCorrection::recenter_yaw`], this also
+    /// corrects pitch and roll, and it is kept by recentering.
+    pub fn set_mounting_offset(&self, device_from_head: UnitQuaternion<f64>) {
+        self.lock().mounting_offset = device_from_head;
+    }
+
+    /// The current mounting offset, e.g. to persist it along with
+    /// [`OrientationCorrection::correction`]. Can be restored with
+    /// [`OrientationCorrection::set_mounting_offset`].
+    pub fn mounting_offset(&self) -> UnitQuaternion<f64> {
+        self.lock().mounting_offset
+    }
+
+    /// Derive the mounting offset from the latest orientation, while the user is
+    /// looking level and straight forward: pitch and roll are zeroed, the heading
+    /// is kept. Returns the new offset.
+    pub fn calibrate_mounting(&self) -> UnitQuaternion<f64> {
+        let mut state = self.lock();
+        let device = state.latest;
+        let level = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), heading(&device));
+        state.mounting_offset = device.inverse() * level;
+        state.mounting_offset
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CorrectionState> {
+        // The state is always consistent, so a panic in another thread doesn't matter
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Heading (yaw) of an orientation in radians: the rotation around the gravity (+Y) axis.
+/// Positive values mean turning left. `orientation` transforms from the device frame
+/// to a gravity aligned world frame, like the output of most sensor fusion algorithms.
+///
+/// Without a magnetometer this is the drift-prone part of the orientation. It is
+/// not well defined when looking straight up or down.
+pub fn heading(orientation: &UnitQuaternion<f64>) -> f64 {
+    // Forward is -Z in the RUB coordinate system
+    let forward = orientation * -Vector3::z();
+    (-forward.x).atan2(-forward.z)
+}
+
+/// The orientation with the heading (see [`heading`]) removed, i.e. only the pitch
+/// and roll relative to gravity remain. Content rendered with it stays level
+/// regardless of yaw drift.
+pub fn gravity_aligned_orientation(orientation: &UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+    UnitQuaternion::from_axis_angle(&Vector3::y_axis(), -heading(orientation)) * orientation
+}
+
+/// Drift-free pitch and roll from a single accelerometer reading (e.g. from
+/// [`crate::GlassesEvent::AccGyro`]), with zero heading. Only accurate while the
+/// glasses are not accelerating.
+pub fn tilt_from_accelerometer(accelerometer: &Vector3<f32>) -> UnitQuaternion<f64> {
+    // At rest, the accelerometer measures the "up" direction
+    let up = accelerometer.cast::<f64>();
+    let tilt = UnitQuaternion::rotation_between(&up, &Vector3::y()).unwrap_or_else(|| {
+        UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f64::consts::PI)
+    });
+    gravity_aligned_orientation(&tilt)
+}
+
+fn push_sample<T>(samples: &mut VecDeque<(u64, T)>, timestamp: u64, sample: T) {
+    if samples.len() == HISTORY_SIZE {
+        samples.pop_front();
+    }
+    samples.push_back((timestamp, sample));
+}
+
+fn interpolate<T: Copy>(
+    samples: &VecDeque<(u64, T)>,
+    timestamp: u64,
+    lerp: impl Fn(&T, &T, f64) -> T,
+) -> Option<T> {
+    let after = samples.partition_point(|(ts, _)| *ts < timestamp);
+    if after == 0 {
+        return samples.front().map(|(_, sample)| *sample);
+    }
+    if after == samples.len() {
+        return samples.back().map(|(_, sample)| *sample);
+    }
+    let (ts_a, a) = &samples[after - 1];
+    let (ts_b, b) = &samples[after];
+    let t = (timestamp - ts_a) as f64 / (ts_b - ts_a) as f64;
+    Some(lerp(a, b, t))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        f64::consts::{FRAC_PI_2, PI},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        clock::{Clock, MockClock},
+        ARGlasses, GlassesEvent, MotionProfile, RadiansPerSec, SyntheticImu,
+    };
+
+    /// Position source clock, ahead of device time
+    const CAMERA_CLOCK_OFFSET: u64 = 5_000_000;
+
+    /// Feed `count` samples through a minimal complementary filter (gyroscope
+    /// integration, pulled towards the accelerometer tilt), and push the results
+    /// into `fuser`, along with a camera moving along X at 0.1m/s, sampling at 30Hz
+    fn run_fusion(
+        imu: &mut SyntheticImu,
+        count: usize,
+        estimate: &mut UnitQuaternion<f64>,
+        fuser: &mut PoseFuser,
+    ) {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut next_camera_time = Duration::ZERO;
+        for _ in 0..count {
+            let GlassesEvent::AccGyro {
+                accelerometer,
+                gyroscope,
+                timestamp,
+                ..
+            } = imu.read_event().unwrap()
+            else {
+                panic!("Not an IMU sample");
+            };
+            *estimate *= UnitQuaternion::from_scaled_axis(gyroscope.cast::<f64>() * 0.001);
+            let measured = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), heading(estimate))
+                * tilt_from_accelerometer(&accelerometer);
+            *estimate = estimate.slerp(&measured, 0.01);
+            fuser.push_orientation(timestamp + Duration::from_millis(1), *estimate);
+
+            clock.advance(Duration::from_millis(1));
+            let elapsed = clock.now().duration_since(start);
+            if elapsed >= next_camera_time {
+                next_camera_time = elapsed + Duration::from_micros(33_333);
+                let position = Vector3::new(0.1 * elapsed.as_secs_f64(), 1.6, 0.0);
+                fuser.push_position(elapsed.as_micros() as u64 + CAMERA_CLOCK_OFFSET, position);
+            }
+        }
+    }
+
+    #[test]
+    fn stationary_converges() {
+        let mut imu = SyntheticImu::new(MotionProfile::Stationary).with_noise(0.001, 0.05);
+        let mut fuser = PoseFuser::new();
+        fuser.set_position_time_offset(-(CAMERA_CLOCK_OFFSET as i64));
+        let mut estimate = UnitQuaternion::from_euler_angles(0.3, 0.0, -0.2);
+        run_fusion(&mut imu, 2000, &mut estimate, &mut fuser);
+
+        let pose = fuser.latest_pose().unwrap();
+        // Only pitch and roll are observable without a magnetometer
+        let tilt = gravity_aligned_orientation(&pose.rotation).angle();
+        assert!(tilt < 0.01, "{tilt}");
+        // The camera was last sampled at 1.967s, the latest orientation is at 2s
+        assert!((pose.translation.vector.x - 0.1967).abs() < 1e-3);
+    }
+
+    #[test]
+    fn constant_yaw_integrates() {
+        let mut imu = SyntheticImu::new(MotionProfile::ConstantRotation {
+            axis: Vector3::y_axis(),
+            rate: RadiansPerSec(0.5),
+        });
+        let mut fuser = PoseFuser::new();
+        fuser.set_position_time_offset(-(CAMERA_CLOCK_OFFSET as i64));
+        let mut estimate = UnitQuaternion::identity();
+        run_fusion(&mut imu, 2000, &mut estimate, &mut fuser);
+
+        // Within the kept history, including timestamps between the samples of both streams
+        for micros in [1_800_000, 1_850_500, 1_900_250, 1_950_000] {
+            let timestamp = DeviceTimestamp::from_micros(micros);
+            let pose = fuser.pose_at(timestamp).unwrap();
+            let seconds = micros as f64 / 1e6;
+            assert!(
+                (heading(&pose.rotation) - 0.5 * seconds).abs() < 1e-3,
+                "{micros}"
+            );
+            let truth = imu.orientation_at(timestamp).cast::<f64>();
+            assert!(pose.rotation.angle_to(&truth) < 1e-3, "{micros}");
+            assert!(
+                (pose.translation.vector.x - 0.1 * seconds).abs() < 1e-6,
+                "{micros}"
+            );
+        }
+    }
+
+    /// World from device orientation from heading (around Y), pitch (around X) and
+    /// roll (around Z), applied in this order
+    fn orientation(heading: f64, pitch: f64, roll: f64) -> UnitQuaternion<f64> {
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), heading)
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), pitch)
+            * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), roll)
+    }
+
+    #[test]
+    fn headings() {
+        for (orientation, expected) in [
+            (UnitQuaternion::identity(), 0.0),
+            (orientation(FRAC_PI_2, 0.0, 0.0), FRAC_PI_2),
+            (orientation(-FRAC_PI_2, 0.0, 0.0), -FRAC_PI_2),
+            (orientation(0.5, 0.3, 0.0), 0.5),
+            (orientation(0.5, 0.0, FRAC_PI_2), 0.5),
+            (orientation(-0.5, 0.0, -FRAC_PI_2), -0.5),
+            (orientation(0.0, FRAC_PI_2, 0.0), 0.0),
+            (orientation(0.0, -FRAC_PI_2, 0.0), 0.0),
+        ] {
+            let actual = heading(&orientation);
+            assert!((actual - expected).abs() < 1e-9, "{expected} {actual}");
+        }
+        assert!((heading(&orientation(PI, 0.0, 0.0)).abs() - PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gravity_aligned_orientations() {
+        for (heading, pitch, roll) in [
+            (0.0, 0.0, 0.0),
+            (0.5, 0.0, 0.0),
+            (0.5, 0.3, -0.2),
+            (-1.0, 0.0, FRAC_PI_2),
+            (1.0, 0.0, -FRAC_PI_2),
+            (0.0, FRAC_PI_2, 0.0),
+            (0.0, -FRAC_PI_2, 0.0),
+        ] {
+            let aligned = gravity_aligned_orientation(&orientation(heading, pitch, roll));
+            assert!(
+                aligned.angle_to(&orientation(0.0, pitch, roll)) < 1e-9,
+                "{heading} {pitch} {roll}"
+            );
+        }
+    }
+
+    #[test]
+    fn tilts_from_accelerometer() {
+        for (pitch, roll) in [
+            (0.0, 0.0),
+            (0.3, -0.2),
+            (FRAC_PI_2, 0.0),
+            (-FRAC_PI_2, 0.0),
+            (0.0, FRAC_PI_2),
+            (0.0, -FRAC_PI_2),
+        ] {
+            let accelerometer =
+                orientation(0.0, pitch, roll).inverse() * Vector3::new(0.0, 9.81, 0.0);
+            let tilt = tilt_from_accelerometer(&accelerometer.cast::<f32>());
+            assert!(
+                tilt.angle_to(&orientation(0.0, pitch, roll)) < 1e-6,
+                "{pitch} {roll}"
+            );
+        }
+        // Upside down, there is no shortest rotation, but it's still upside down and forward
+        let tilt = tilt_from_accelerometer(&Vector3::new(0.0, -9.81, 0.0));
+        assert!((tilt * -Vector3::y() - Vector3::y()).norm() < 1e-6);
+        assert!(heading(&tilt).abs() < 1e-6);
+        // No reading at all (e.g. free fall) is taken as level
+        assert_eq!(
+            tilt_from_accelerometer(&Vector3::zeros()),
+            UnitQuaternion::identity()
+        );
+    }
+
+    #[test]
+    fn recenter() {
+        let correction = OrientationCorrection::new();
+        let ui = correction.clone();
+        correction.apply(&orientation(0.7, 0.2, 0.1));
+        ui.recenter_yaw();
+
+        let recentered = correction.apply(&orientation(0.7, 0.2, 0.1));
+        assert!(heading(&recentered).abs() < 1e-9);
+        assert!(recentered.angle_to(&orientation(0.0, 0.2, 0.1)) < 1e-9);
+        // Later turns are relative to the new forward direction
+        let turned = correction.apply(&orientation(1.0, 0.0, 0.0));
+        assert!((heading(&turned) - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mounting_offset() {
+        // Head turned left and level, glasses pitched down on the face
+        let head_from_device = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), -0.2);
+        let device = orientation(0.5, 0.0, 0.0) * head_from_device;
+
+        let correction = OrientationCorrection::new();
+        correction.set_mounting_offset(head_from_device.inverse());
+        // Applied on the device side: a world side offset would tilt the heading instead
+        let head = correction.apply(&device);
+        assert!(head.angle_to(&orientation(0.5, 0.0, 0.0)) < 1e-9);
+        correction.recenter_yaw();
+        let head = correction.apply(&device);
+        assert!(head.angle_to(&UnitQuaternion::identity()) < 1e-9);
+
+        let correction = OrientationCorrection::new();
+        correction.apply(&device);
+        let offset = correction.calibrate_mounting();
+        assert!(offset.angle_to(&head_from_device.inverse()) < 1e-9);
+        assert_eq!(correction.mounting_offset(), offset);
+    }
+}
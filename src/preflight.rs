@@ -0,0 +1,576 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Step by step connection diagnostics. See [`preflight`]
+
+use crate::{ARGlasses, Error, Result};
+
+/// A supported glasses type, see [`preflight`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// Rokid Air or Max, see [`crate::rokid::RokidAir`]
+    #[cfg(feature = "rokid")]
+    RokidAir,
+    /// Nreal Air, see [`crate::nreal_air::NrealAir`]
+    #[cfg(feature = "nreal")]
+    NrealAir,
+    /// Nreal Light, see [`crate::nreal_light::NrealLight`]
+    #[cfg(feature = "nreal")]
+    NrealLight,
+    /// Grawoow G530, see [`crate::grawoow::GrawoowG530`]
+    #[cfg(feature = "grawoow")]
+    GrawoowG530,
+    /// Mad Gaze Glow, see [`crate::mad_gaze::MadGazeGlow`]
+    #[cfg(feature = "mad_gaze")]
+    MadGazeGlow,
+}
+
+impl DeviceKind {
+    /// All glasses types compiled in, in the order [`crate::any_glasses`] tries them
+    pub const ALL: &'static [DeviceKind] = &[
+        #[cfg(feature = "rokid")]
+        DeviceKind::RokidAir,
+        #[cfg(feature = "nreal")]
+        DeviceKind::NrealAir,
+        #[cfg(feature = "nreal")]
+        DeviceKind::NrealLight,
+        #[cfg(feature = "grawoow")]
+        DeviceKind::GrawoowG530,
+        #[cfg(feature = "mad_gaze")]
+        DeviceKind::MadGazeGlow,
+    ];
+
+    fn interfaces(self) -> Vec<Interface> {
+        match self {
+            #[cfg(feature = "rokid")]
+            DeviceKind::RokidAir => vec![Interface::Usb {
+                vid: crate::rokid::RokidAir::VID,
+                pid: crate::rokid::RokidAir::PID,
+                endpoint: Some(crate::rokid::INTERRUPT_IN_ENDPOINT),
+            }],
+            #[cfg(feature = "nreal")]
+            DeviceKind::NrealAir => {
+                use crate::nreal_air::NrealAir;
+                vec![
+                    Interface::Hid {
+                        vid: NrealAir::VID,
+                        pid: NrealAir::PID,
+                        interface: Some(3),
+                    },
+                    Interface::Hid {
+                        vid: NrealAir::VID,
+                        pid: NrealAir::PID,
+                        interface: Some(4),
+                    },
+                ]
+            }
+            #[cfg(feature = "nreal")]
+            DeviceKind::NrealLight => {
+                use crate::nreal_light::NrealLight;
+                vec![
+                    Interface::Hid {
+                        vid: NrealLight::MCU_VID,
+                        pid: NrealLight::MCU_PID,
+                        interface: None,
+                    },
+                    Interface::Hid {
+                        vid: NrealLight::OV580_VID,
+                        pid: NrealLight::OV580_PID,
+                        interface: None,
+                    },
+                ]
+            }
+            #[cfg(feature = "grawoow")]
+            DeviceKind::GrawoowG530 => {
+                use crate::grawoow::GrawoowG530;
+                vec![
+                    Interface::Usb {
+                        vid: GrawoowG530::MCU_VID,
+                        pid: GrawoowG530::MCU_PID,
+                        endpoint: None,
+                    },
+                    Interface::Usb {
+                        vid: GrawoowG530::OV580_VID,
+                        pid: GrawoowG530::OV580_PID,
+                        endpoint: Some(crate::grawoow::OV580_ENDPOINT),
+                    },
+                ]
+            }
+            #[cfg(feature = "mad_gaze")]
+            DeviceKind::MadGazeGlow => vec![Interface::Serial {
+                vid: crate::mad_gaze::SERIAL_VID,
+                pid: crate::mad_gaze::SERIAL_PID,
+            }],
+        }
+    }
+
+    fn connect(self) -> Result<Box<dyn ARGlasses>> {
+        Ok(match self {
+            #[cfg(feature = "rokid")]
+            DeviceKind::RokidAir => Box::new(crate::rokid::RokidAir::new()?),
+            #[cfg(feature = "nreal")]
+            DeviceKind::NrealAir => Box::new(crate::nreal_air::NrealAir::new()?),
+            #[cfg(feature = "nreal")]
+            DeviceKind::NrealLight => Box::new(crate::nreal_light::NrealLight::new()?),
+            #[cfg(feature = "grawoow")]
+            DeviceKind::GrawoowG530 => Box::new(crate::grawoow::GrawoowG530::new()?),
+            #[cfg(feature = "mad_gaze")]
+            DeviceKind::MadGazeGlow => Box::new(crate::mad_gaze::MadGazeGlow::new()?),
+        })
+    }
+}
+
+/// A step of [`preflight`], in the order they are checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreflightStep {
+    /// The device is enumerated by the OS. If not: the cable may only carry power,
+    /// or the glasses need a different USB mode.
+    DevicePresent,
+    /// The USB descriptors can be read, and have the expected layout
+    DescriptorsReadable,
+    /// The interfaces can be opened and claimed. If not: missing udev rules
+    /// (permissions), a kernel driver (e.g. usbhid) keeping them, or another process
+    /// using the glasses.
+    InterfacesClaimable,
+    /// Reports arrive on the sensor stream without sending any commands. Only checked
+    /// for devices that stream by themselves.
+    ReportsReadable,
+    /// The driver connects, and the glasses answer a command (reading the serial number)
+    CommandAnswered,
+}
+
+impl PreflightStep {
+    /// Human readable name of the step
+    pub fn name(self) -> &'static str {
+        match self {
+            PreflightStep::DevicePresent => "Device present",
+            PreflightStep::DescriptorsReadable => "Descriptors readable",
+            PreflightStep::InterfacesClaimable => "Interfaces claimable",
+            PreflightStep::ReportsReadable => "Reports readable",
+            PreflightStep::CommandAnswered => "Command answered",
+        }
+    }
+
+    /// The step that checks for the cause of `error`, if it is one of the usual
+    /// connection problems, e.g. to match an error of a constructor with the
+    /// [`preflight`] report. The report names it for the errors of its last step,
+    /// where the driver is constructed.
+    pub fn of_error(error: &Error) -> Option<PreflightStep> {
+        match error {
+            Error::NotFound => Some(PreflightStep::DevicePresent),
+            Error::InterfaceNotFound { .. } => Some(PreflightStep::DescriptorsReadable),
+            Error::AlreadyInUse { .. } => Some(PreflightStep::InterfacesClaimable),
+            #[cfg(feature = "rusb")]
+            Error::UsbError(rusb::Error::Access | rusb::Error::Busy) => {
+                Some(PreflightStep::InterfacesClaimable)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Result of a [`PreflightStep`]
+#[derive(Debug)]
+pub enum StepOutcome {
+    /// The check succeeded
+    Passed,
+    /// The check failed with the error
+    Failed(Error),
+    /// The check was not done, because an earlier step failed, or because it does
+    /// not apply to the device
+    NotChecked,
+}
+
+/// Result of [`preflight`]
+#[derive(Debug)]
+pub struct PreflightReport {
+    /// The checked glasses type. `None` if no supported glasses were found.
+    pub kind: Option<DeviceKind>,
+    /// Outcome of each step, in order
+    pub steps: Vec<(PreflightStep, StepOutcome)>,
+}
+
+impl PreflightReport {
+    /// The first failed step and its error, if any
+    pub fn first_failure(&self) -> Option<(PreflightStep, &Error)> {
+        self.steps.iter().find_map(|(step, outcome)| match outcome {
+            StepOutcome::Failed(error) => Some((*step, error)),
+            _ => None,
+        })
+    }
+
+    /// True if none of the steps failed
+    pub fn passed(&self) -> bool {
+        self.first_failure().is_none()
+    }
+}
+
+impl std::fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            Some(kind) => writeln!(f, "Glasses: {kind:?}")?,
+            None => writeln!(f, "Glasses: none of the supported types found")?,
+        }
+        for (step, outcome) in &self.steps {
+            match outcome {
+                StepOutcome::Passed => writeln!(f, "[ OK ] {}", step.name())?,
+                StepOutcome::Failed(error) => {
+                    write!(f, "[FAIL] {}: {error} ({error:?})", step.name())?;
+                    match PreflightStep::of_error(error) {
+                        Some(cause) if *step == PreflightStep::CommandAnswered => {
+                            writeln!(f, ", see \"{}\"", cause.name())?
+                        }
+                        _ => writeln!(f)?,
+                    }
+                }
+                StepOutcome::NotChecked => writeln!(f, "[ -- ] {}", step.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the usual connection problems one by one, and report which step fails,
+/// e.g. for troubleshooting new setups. Checks `kind`, or if `None`, the first
+/// supported glasses that are present.
+///
+/// The device is only fully connected to in the last step, everything is released
+/// before returning.
+pub fn preflight(kind: Option<DeviceKind>) -> PreflightReport {
+    let kind = kind.or_else(|| {
+        DeviceKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.interfaces().iter().all(|i| i.find().is_ok()))
+    });
+    let Some(kind) = kind else {
+        return PreflightReport {
+            kind: None,
+            steps: vec![(
+                PreflightStep::DevicePresent,
+                StepOutcome::Failed(Error::NotFound),
+            )],
+        };
+    };
+    let interfaces = kind.interfaces();
+    let mut runner = StepRunner::default();
+    runner.run(PreflightStep::DevicePresent, || {
+        interfaces.iter().try_for_each(Interface::find)
+    });
+    runner.run(PreflightStep::DescriptorsReadable, || {
+        interfaces.iter().try_for_each(Interface::check_descriptors)
+    });
+    runner.run(PreflightStep::InterfacesClaimable, || {
+        interfaces.iter().try_for_each(Interface::claim)
+    });
+    if interfaces.iter().any(Interface::streams) {
+        runner.run(PreflightStep::ReportsReadable, || {
+            interfaces.iter().try_for_each(Interface::read_report)
+        });
+    } else {
+        runner.skip(PreflightStep::ReportsReadable);
+    }
+    runner.run(PreflightStep::CommandAnswered, || {
+        kind.connect()?.serial().map(|_| ())
+    });
+    PreflightReport {
+        kind: Some(kind),
+        steps: runner.steps,
+    }
+}
+
+#[derive(Default)]
+struct StepRunner {
+    steps: Vec<(PreflightStep, StepOutcome)>,
+    failed: bool,
+}
+
+impl StepRunner {
+    fn run(&mut self, step: PreflightStep, check: impl FnOnce() -> Result<()>) {
+        if self.failed {
+            return self.skip(step);
+        }
+        let outcome = match check() {
+            Ok(()) => StepOutcome::Passed,
+            Err(error) => {
+                self.failed = true;
+                StepOutcome::Failed(error)
+            }
+        };
+        self.steps.push((step, outcome));
+    }
+
+    fn skip(&mut self, step: PreflightStep) {
+        self.steps.push((step, StepOutcome::NotChecked));
+    }
+}
+
+/// One of the interfaces a driver uses
+#[allow(dead_code)]
+enum Interface {
+    /// libusb interface. The one containing `endpoint`, which streams without commands,
+    /// or interface 0 if `None`.
+    Usb {
+        vid: u16,
+        pid: u16,
+        endpoint: Option<u8>,
+    },
+    /// HID interface, or the first one if `interface` is `None`
+    Hid {
+        vid: u16,
+        pid: u16,
+        interface: Option<i32>,
+    },
+    /// USB serial port
+    Serial { vid: u16, pid: u16 },
+}
+
+impl Interface {
+    fn find(&self) -> Result<()> {
+        match *self {
+            #[cfg(feature = "rusb")]
+            Interface::Usb { vid, pid, .. } => {
+                crate::util::get_device_vid_pid(vid, pid).map(|_| ())
+            }
+            #[cfg(feature = "hidapi")]
+            Interface::Hid { vid, pid, .. } => {
+                if crate::util::hid_interfaces(vid, pid)?.is_empty() {
+                    return Err(Error::NotFound);
+                }
+                Ok(())
+            }
+            #[cfg(feature = "serialport")]
+            Interface::Serial { vid, pid } => {
+                let found = serialport::available_ports()?.iter().any(|port| {
+                    matches!(
+                        &port.port_type,
+                        serialport::SerialPortType::UsbPort(info)
+                            if info.vid == vid && info.pid == pid
+                    )
+                });
+                if !found {
+                    return Err(Error::NotFound);
+                }
+                Ok(())
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    fn check_descriptors(&self) -> Result<()> {
+        match *self {
+            #[cfg(feature = "rusb")]
+            Interface::Usb { vid, pid, .. } => {
+                let device = crate::util::get_device_vid_pid(vid, pid)?;
+                device.device_descriptor()?;
+                device.config_descriptor(0)?;
+                self.usb_interface(&device).map(|_| ())
+            }
+            #[cfg(feature = "hidapi")]
+            Interface::Hid {
+                vid,
+                pid,
+                interface: Some(interface),
+            } => {
+                if !crate::util::hid_interfaces(vid, pid)?.contains(&interface) {
+                    return Err(Error::InterfaceNotFound { interface });
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn claim(&self) -> Result<()> {
+        match *self {
+            #[cfg(feature = "rusb")]
+            Interface::Usb { vid, pid, .. } => {
+                let device = crate::util::get_device_vid_pid(vid, pid)?;
+                let _lock = crate::util::DeviceLock::for_usb_device(&device)?;
+                let handle = device.open()?;
+                handle.set_auto_detach_kernel_driver(true)?;
+                handle.claim_interface(self.usb_interface(&device)?)?;
+                Ok(())
+            }
+            #[cfg(feature = "hidapi")]
+            Interface::Hid {
+                vid,
+                pid,
+                interface,
+            } => {
+                let hidapi = hidapi::HidApi::new()?;
+                let info = hidapi
+                    .device_list()
+                    .find(|device| {
+                        device.vendor_id() == vid
+                            && device.product_id() == pid
+                            && !matches!(interface, Some(i) if device.interface_number() != i)
+                    })
+                    .ok_or(Error::NotFound)?;
+                // The drivers lock the path of the MCU interface
+                let _lock = crate::util::DeviceLock::for_hid_path(info.path())?;
+                info.open_device(&hidapi)?;
+                Ok(())
+            }
+            #[cfg(feature = "serialport")]
+            Interface::Serial { .. } => Ok(()),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    fn streams(&self) -> bool {
+        matches!(
+            self,
+            Interface::Usb {
+                endpoint: Some(_),
+                ..
+            }
+        )
+    }
+
+    fn read_report(&self) -> Result<()> {
+        match *self {
+            #[cfg(feature = "rusb")]
+            Interface::Usb {
+                vid,
+                pid,
+                endpoint: Some(endpoint),
+            } => {
+                let device = crate::util::get_device_vid_pid(vid, pid)?;
+                let _lock = crate::util::DeviceLock::for_usb_device(&device)?;
+                let handle = device.open()?;
+                handle.set_auto_detach_kernel_driver(true)?;
+                handle.claim_interface(self.usb_interface(&device)?)?;
+                let mut buf = [0u8; 0x100];
+                match handle.read_interrupt(endpoint, &mut buf, std::time::Duration::from_secs(1)) {
+                    Ok(_) => Ok(()),
+                    Err(rusb::Error::Timeout) => Err(Error::PacketTimeout),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "rusb")]
+    fn usb_interface(&self, device: &rusb::Device<rusb::GlobalContext>) -> Result<u8> {
+        match *self {
+            Interface::Usb {
+                endpoint: Some(endpoint),
+                ..
+            } => crate::util::get_interface_for_endpoint(device, endpoint).ok_or(Error::Other(
+                "Could not find endpoint, wrong USB structure (probably)",
+            )),
+            _ => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_of_errors() {
+        let cases = [
+            (Error::NotFound, Some(PreflightStep::DevicePresent)),
+            (
+                Error::InterfaceNotFound { interface: 3 },
+                Some(PreflightStep::DescriptorsReadable),
+            ),
+            (
+                Error::AlreadyInUse { holder_pid: None },
+                Some(PreflightStep::InterfacesClaimable),
+            ),
+            #[cfg(feature = "rusb")]
+            (
+                Error::UsbError(rusb::Error::Access),
+                Some(PreflightStep::InterfacesClaimable),
+            ),
+            #[cfg(feature = "rusb")]
+            (
+                Error::UsbError(rusb::Error::Busy),
+                Some(PreflightStep::InterfacesClaimable),
+            ),
+            #[cfg(feature = "rusb")]
+            (Error::UsbError(rusb::Error::Pipe), None),
+            (Error::PacketTimeout, None),
+            (Error::disconnected_by("Test", Error::NotFound), None),
+            (Error::Other("Test"), None),
+        ];
+        for (error, step) in cases {
+            assert_eq!(PreflightStep::of_error(&error), step, "{error:?}");
+            // Errors outside of the preflight check don't mention it
+            assert!(!error.to_string().contains("preflight"), "{error}");
+        }
+    }
+
+    #[test]
+    fn report() {
+        let mut runner = StepRunner::default();
+        runner.run(PreflightStep::DevicePresent, || Ok(()));
+        runner.run(PreflightStep::DescriptorsReadable, || Ok(()));
+        runner.run(PreflightStep::InterfacesClaimable, || Ok(()));
+        runner.skip(PreflightStep::ReportsReadable);
+        runner.run(PreflightStep::CommandAnswered, || {
+            Err(Error::AlreadyInUse { holder_pid: None })
+        });
+        let report = PreflightReport {
+            kind: None,
+            steps: runner.steps,
+        };
+        assert!(!report.passed());
+        assert!(matches!(
+            report.first_failure(),
+            Some((PreflightStep::CommandAnswered, Error::AlreadyInUse { .. }))
+        ));
+        // The driver's error names the step checking for its cause
+        let text = report.to_string();
+        let failure = text
+            .lines()
+            .find(|line| line.starts_with("[FAIL]"))
+            .unwrap();
+        assert!(failure.starts_with("[FAIL] Command answered: Glasses are already in use"));
+        assert!(
+            failure.ends_with(", see \"Interfaces claimable\""),
+            "{failure}"
+        );
+        assert!(text.contains("[ -- ] Reports readable"));
+    }
+
+    #[test]
+    fn steps_after_failure_are_not_checked() {
+        let mut runner = StepRunner::default();
+        runner.run(PreflightStep::DevicePresent, || Ok(()));
+        runner.run(PreflightStep::DescriptorsReadable, || {
+            Err(Error::InterfaceNotFound { interface: 4 })
+        });
+        runner.run(PreflightStep::InterfacesClaimable, || {
+            panic!("Checked after a failure")
+        });
+        let report = PreflightReport {
+            kind: None,
+            steps: runner.steps,
+        };
+        assert!(matches!(
+            report.steps.as_slice(),
+            [
+                (PreflightStep::DevicePresent, StepOutcome::Passed),
+                (PreflightStep::DescriptorsReadable, StepOutcome::Failed(_)),
+                (PreflightStep::InterfacesClaimable, StepOutcome::NotChecked),
+            ]
+        ));
+        let text = report.to_string();
+        let failure = text
+            .lines()
+            .find(|line| line.starts_with("[FAIL]"))
+            .unwrap();
+        assert!(
+            failure.ends_with("(InterfaceNotFound { interface: 4 })"),
+            "{failure}"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Reference test vectors for the packet codecs of the drivers. See [`verify_vectors`]
+//!
+//! The vectors are text files named after the codec (e.g. `nreal_air_mcu.txt`), the
+//! crate's own are in `tests/vectors`. Each line is either empty, a `#` comment, or:
+//! * `decode <frame> <fields>`: parsing the frame results in the fields, or it is
+//!   rejected if the fields are `-`
+//! * `encode <fields> <frame>`: serializing the fields results in the frame
+//!
+//! Frames are hex strings. Fields are comma separated `name=value` pairs, with hex values,
+//! in the order listed in [`CODECS`]. Frames are compared after stripping trailing zero
+//! bytes, as the HID reports are zero padded.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{Error, Result};
+
+/// Names of the codecs with test vectors, and the fields of their packets
+pub const CODECS: &[(&str, &str)] = &[
+    #[cfg(feature = "nreal")]
+    ("nreal_light_mcu", "category,cmd_id,data"),
+    #[cfg(feature = "nreal")]
+    ("nreal_air_mcu", "cmd_id,data"),
+    #[cfg(feature = "nreal")]
+    ("nreal_air_imu", "cmd_id,data"),
+];
+
+/// Result of [`verify_vectors`]
+#[derive(Debug, Clone, Default)]
+pub struct VectorReport {
+    /// Number of vectors checked
+    pub checked: usize,
+    /// The vectors the codecs did not match
+    pub mismatches: Vec<VectorMismatch>,
+}
+
+/// A test vector the codec did not match, see [`verify_vectors`]
+#[derive(Debug, Clone)]
+pub struct VectorMismatch {
+    /// The vector file
+    pub file: PathBuf,
+    /// Line number in the file, starting at 1. 0 means a file missing for a codec.
+    pub line: usize,
+    /// The expected result, as written in the file
+    pub expected: String,
+    /// What the codec produced
+    pub actual: String,
+}
+
+/// Check the codecs of the drivers against the test vectors in a file, or in all
+/// `.txt` files of a directory. In the latter case, a codec without a vector file
+/// is reported as a mismatch, so that every codec is covered.
+///
+/// Malformed lines and files for unknown codecs result in an error.
+pub fn verify_vectors(path: impl AsRef<Path>) -> Result<VectorReport> {
+    let path = path.as_ref();
+    let mut report = VectorReport::default();
+    if !path.is_dir() {
+        verify_file(path, &mut report)?;
+        return Ok(report);
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    files.retain(|file| file.extension().is_some_and(|extension| extension == "txt"));
+    files.sort();
+    for file in &files {
+        verify_file(file, &mut report)?;
+    }
+    for (codec, _) in CODECS {
+        if !files.iter().any(|file| codec_name(file) == Some(codec)) {
+            report.mismatches.push(VectorMismatch {
+                file: path.join(format!("{codec}.txt")),
+                line: 0,
+                expected: "test vectors".into(),
+                actual: "no vector file".into(),
+            });
+        }
+    }
+    Ok(report)
+}
+
+fn codec_name(file: &Path) -> Option<&str> {
+    file.file_stem()?.to_str()
+}
+
+fn verify_file(file: &Path, report: &mut VectorReport) -> Result<()> {
+    let codec = codec_name(file).ok_or(Error::Other("Invalid test vector file name"))?;
+    if !CODECS.iter().any(|(name, _)| *name == codec) {
+        return Err(Error::Other("Test vector file for an unknown codec"));
+    }
+    for (index, line) in fs::read_to_string(file)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let [direction, input, expected] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err(Error::Other("Malformed test vector line"));
+        };
+        let actual = match direction {
+            "decode" => {
+                let frame = hex_decode(input).ok_or(Error::Other("Invalid hex in test vector"))?;
+                decode(codec, &frame).unwrap_or_else(|| "-".into())
+            }
+            "encode" => encode(codec, input).map_or_else(|| "-".into(), |frame| hex(&frame)),
+            _ => return Err(Error::Other("Unknown test vector direction")),
+        };
+        report.checked += 1;
+        let matches = if direction == "encode" {
+            strip_padding(&actual) == strip_padding(expected)
+        } else {
+            actual == expected
+        };
+        if !matches {
+            report.mismatches.push(VectorMismatch {
+                file: file.to_path_buf(),
+                line: index + 1,
+                expected: expected.into(),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "nreal"), allow(unused_variables))]
+fn decode(codec: &str, frame: &[u8]) -> Option<String> {
+    match codec {
+        #[cfg(feature = "nreal")]
+        "nreal_light_mcu" => crate::nreal_light::decode_mcu_vector(frame),
+        #[cfg(feature = "nreal")]
+        "nreal_air_mcu" => crate::nreal_air::decode_mcu_vector(frame),
+        #[cfg(feature = "nreal")]
+        "nreal_air_imu" => crate::nreal_air::decode_imu_vector(frame),
+        _ => None,
+    }
+}
+
+#[cfg_attr(not(feature = "nreal"), allow(unused_variables))]
+fn encode(codec: &str, fields: &str) -> Option<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "nreal")]
+        "nreal_light_mcu" => crate::nreal_light::encode_mcu_vector(fields),
+        #[cfg(feature = "nreal")]
+        "nreal_air_mcu" => crate::nreal_air::encode_mcu_vector(fields),
+        #[cfg(feature = "nreal")]
+        "nreal_air_imu" => crate::nreal_air::encode_imu_vector(fields),
+        _ => None,
+    }
+}
+
+/// Value of a field in the vector format
+#[cfg(feature = "nreal")]
+pub(crate) fn field(fields: &str, name: &str) -> Option<Vec<u8>> {
+    fields
+        .split(',')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        .and_then(hex_decode)
+}
+
+/// Numeric value of a field in the vector format
+#[cfg(feature = "nreal")]
+pub(crate) fn numeric_field(fields: &str, name: &str) -> Option<u16> {
+    match field(fields, name)?[..] {
+        [value] => Some(value as u16),
+        [high, low] => Some(u16::from_be_bytes([high, low])),
+        _ => None,
+    }
+}
+
+/// The hex string of a frame without its trailing zero bytes
+fn strip_padding(mut frame: &str) -> &str {
+    while frame.len().is_multiple_of(2) && frame.ends_with("00") {
+        frame = &frame[..frame.len() - 2];
+    }
+    frame
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding() {
+        assert_eq!(strip_padding("aa1000"), "aa10");
+        assert_eq!(strip_padding("aa10"), "aa10");
+        assert_eq!(strip_padding("0000"), "");
+    }
+}
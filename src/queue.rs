@@ -0,0 +1,223 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Bounded event buffer for handing events over to a slower consumer. See [`EventQueue`]
+
+use std::{collections::VecDeque, mem::discriminant};
+
+use crate::{Error, EventPriority, GlassesEvent, Result};
+
+/// Bounded FIFO of events, applying the [`EventPriority`] of each event when full,
+/// e.g. between the reading thread and the application (wrapped in a `Mutex`).
+///
+/// * [`EventPriority::Coalescable`] events replace a queued event of the same kind
+///   in place (even if the queue is not full), so only the latest one is kept.
+/// * When the queue is full, a [`EventPriority::Droppable`] event is dropped according
+///   to the [`OverflowPolicy`], and [`GlassesEvent::Overflow`] is returned before the
+///   next queued event.
+/// * Other events are never dropped: if nothing can be evicted, the queue grows over
+///   its capacity.
+#[derive(Debug, Clone)]
+pub struct EventQueue {
+    events: VecDeque<GlassesEvent>,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    dropped: [u64; 3],
+    coalesced: [u64; 3],
+    /// Dropped since the last [`GlassesEvent::Overflow`]
+    unreported_drops: usize,
+}
+
+/// What an [`EventQueue`] does with sensor samples ([`EventPriority::Droppable`] events)
+/// when it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued sample to make room for the new one, keeping the
+    /// latency low. If there is no queued sample, the new one is dropped.
+    #[default]
+    DropOldest,
+    /// Drop the new sample, keeping the queued ones
+    DropNewest,
+    /// Drop the new sample, and return an error from [`EventQueue::push`]
+    Error,
+}
+
+impl EventQueue {
+    /// Create an empty queue holding (normally) at most `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            overflow_policy: OverflowPolicy::default(),
+            dropped: [0; 3],
+            coalesced: [0; 3],
+            unreported_drops: 0,
+        }
+    }
+
+    /// Set what happens to sensor samples when the queue is full.
+    /// The default is [`OverflowPolicy::DropOldest`].
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Add an event, see the type level docs for what happens when the queue is full.
+    /// Returns an error only if a sample was dropped with [`OverflowPolicy::Error`].
+    pub fn push(&mut self, event: GlassesEvent) -> Result<()> {
+        let priority = event.priority();
+        if priority == EventPriority::Coalescable {
+            if let Some(queued) = self
+                .events
+                .iter_mut()
+                .find(|queued| discriminant(*queued) == discriminant(&event))
+            {
+                *queued = event;
+                self.coalesced[priority as usize] += 1;
+                return Ok(());
+            }
+        }
+        if self.events.len() >= self.capacity {
+            let oldest_sample = self
+                .events
+                .iter()
+                .position(|queued| queued.priority() == EventPriority::Droppable);
+            match (self.overflow_policy, oldest_sample) {
+                (OverflowPolicy::DropOldest, Some(index)) => {
+                    self.events.remove(index);
+                    self.record_drop();
+                }
+                _ if priority == EventPriority::Droppable => {
+                    self.record_drop();
+                    if self.overflow_policy == OverflowPolicy::Error {
+                        return Err(Error::Other("Event queue is full"));
+                    }
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    fn record_drop(&mut self) {
+        self.dropped[EventPriority::Droppable as usize] += 1;
+        self.unreported_drops += 1;
+    }
+
+    /// Take the oldest event, or [`GlassesEvent::Overflow`] if events were dropped
+    /// since the previous one
+    pub fn pop(&mut self) -> Option<GlassesEvent> {
+        if self.unreported_drops > 0 {
+            let dropped = std::mem::take(&mut self.unreported_drops);
+            return Some(GlassesEvent::Overflow { dropped });
+        }
+        self.events.pop_front()
+    }
+
+    /// Number of events [`EventQueue::pop`] would return, including the overflow event
+    pub fn len(&self) -> usize {
+        self.events.len() + (self.unreported_drops > 0) as usize
+    }
+
+    /// True if [`EventQueue::pop`] would return `None`
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of events of the class that were dropped since the queue was created
+    pub fn dropped(&self, priority: EventPriority) -> u64 {
+        self.dropped[priority as usize]
+    }
+
+    /// Number of events of the class that were replaced by a newer one
+    /// since the queue was created
+    pub fn coalesced(&self, priority: EventPriority) -> u64 {
+        self.coalesced[priority as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::*;
+    use crate::{DeviceTimestamp, SampleFlags};
+
+    fn sample(micros: u64) -> GlassesEvent {
+        GlassesEvent::AccGyro {
+            accelerometer: Vector3::zeros(),
+            gyroscope: Vector3::zeros(),
+            timestamp: DeviceTimestamp::from_micros(micros),
+            flags: SampleFlags::empty(),
+        }
+    }
+
+    /// Short description of the remaining events, in order
+    fn drain(queue: &mut EventQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|event| match event {
+                GlassesEvent::AccGyro { timestamp, .. } => format!("S{}", timestamp.as_micros()),
+                GlassesEvent::KeyPress(key) => format!("K{key}"),
+                GlassesEvent::AmbientLight(level) => format!("L{level}"),
+                GlassesEvent::Overflow { dropped } => format!("O{dropped}"),
+                event => panic!("Unexpected {event:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn critical_events_survive() {
+        let mut queue = EventQueue::new(4);
+        queue.push(sample(1)).unwrap();
+        queue.push(GlassesEvent::KeyPress(0)).unwrap();
+        queue.push(sample(2)).unwrap();
+        queue.push(GlassesEvent::KeyPress(1)).unwrap();
+        for micros in 3..6 {
+            queue.push(sample(micros)).unwrap();
+        }
+        assert_eq!(queue.len(), 5);
+        assert_eq!(drain(&mut queue), ["O3", "K0", "K1", "S4", "S5"]);
+        assert_eq!(queue.dropped(EventPriority::Droppable), 3);
+        assert_eq!(queue.dropped(EventPriority::Critical), 0);
+
+        // Nothing left to evict, so the queue grows
+        for key in 0..6 {
+            queue.push(GlassesEvent::KeyPress(key)).unwrap();
+        }
+        queue.push(sample(6)).unwrap();
+        assert_eq!(
+            drain(&mut queue),
+            ["O1", "K0", "K1", "K2", "K3", "K4", "K5"]
+        );
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let mut queue = EventQueue::new(2).with_overflow_policy(OverflowPolicy::DropNewest);
+        for micros in 1..4 {
+            queue.push(sample(micros)).unwrap();
+        }
+        queue.push(GlassesEvent::KeyPress(0)).unwrap();
+        assert_eq!(drain(&mut queue), ["O1", "S1", "S2", "K0"]);
+
+        let mut queue = EventQueue::new(2).with_overflow_policy(OverflowPolicy::Error);
+        queue.push(sample(1)).unwrap();
+        queue.push(sample(2)).unwrap();
+        assert!(queue.push(sample(3)).is_err());
+        assert!(queue.push(GlassesEvent::KeyPress(0)).is_ok());
+        assert_eq!(drain(&mut queue), ["O1", "S1", "S2", "K0"]);
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut queue = EventQueue::new(4);
+        queue.push(GlassesEvent::AmbientLight(1)).unwrap();
+        queue.push(sample(1)).unwrap();
+        queue.push(GlassesEvent::AmbientLight(2)).unwrap();
+        assert_eq!(queue.coalesced(EventPriority::Coalescable), 1);
+        assert_eq!(drain(&mut queue), ["L2", "S1"]);
+    }
+}
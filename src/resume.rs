@@ -0,0 +1,432 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Reconnecting to the glasses after a replug. See [`AutoResume`]
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{ARGlasses, Clock, DisplayMode, Error, GlassesEvent, Result};
+
+/// Minimum time between two reconnection attempts
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+type Connector = Box<dyn FnMut() -> Result<Box<dyn ARGlasses>> + Send>;
+
+/// Connection state of an [`AutoResume`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeState {
+    /// The glasses are connected
+    Connected,
+    /// The glasses were lost, and reconnection is being attempted
+    Waiting {
+        /// Host time when the glasses were lost
+        since: Instant,
+    },
+    /// The glasses did not come back in time. No more reconnection attempts are made.
+    GaveUp,
+}
+
+/// Wrapper around a glasses connection that survives unplugging and replugging the glasses.
+///
+/// When reading fails because the glasses were lost, the connection is dropped (releasing
+/// the device), and [`Error::Disconnected`] is returned immediately from every read until
+/// the glasses are back. Meanwhile the supplied connect function is called every
+/// 500ms to reconnect. To only accept glasses on the same physical port, use e.g.
+/// [`crate::rokid::RokidAir::open_at`] in the connect function. Reconnected glasses are
+/// only accepted if their serial number matches the original one.
+///
+/// After reconnecting, the display mode last set with [`AutoResume::set_display_mode`]
+/// is restored, and [`GlassesEvent::Resumed`] is returned by the next read.
+/// Brightness is not restored, as none of the drivers can set it.
+///
+/// There is no background thread: reconnection is only attempted from
+/// [`AutoResume::read_event`].
+pub struct AutoResume {
+    connect: Connector,
+    glasses: Option<Box<dyn ARGlasses>>,
+    name: &'static str,
+    serial: String,
+    display_mode: Option<DisplayMode>,
+    max_wait: Option<Duration>,
+    state: ResumeState,
+    last_attempt: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AutoResume {
+    /// Connect using `connect`, which is also used for reconnecting later.
+    /// If `max_wait` is set, reconnection is given up after the glasses were
+    /// gone for that long, see [`ResumeState::GaveUp`].
+    pub fn new(
+        mut connect: impl FnMut() -> Result<Box<dyn ARGlasses>> + Send + 'static,
+        max_wait: Option<Duration>,
+    ) -> Result<Self> {
+        let mut glasses = connect()?;
+        Ok(Self {
+            name: glasses.name(),
+            serial: glasses.serial()?,
+            glasses: Some(glasses),
+            connect: Box::new(connect),
+            display_mode: None,
+            max_wait,
+            state: ResumeState::Connected,
+            last_attempt: None,
+            clock: Arc::new(crate::SystemClock),
+        })
+    }
+
+    /// Use `clock` instead of the system clock for the reconnection timing.
+    /// Mainly for testing, see [`Clock`].
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Same as [`AutoResume::new`], connecting with [`crate::any_glasses`]
+    #[cfg(not(target_os = "android"))]
+    pub fn any_glasses(max_wait: Option<Duration>) -> Result<Self> {
+        Self::new(crate::any_glasses, max_wait)
+    }
+
+    /// Current connection state
+    pub fn state(&self) -> ResumeState {
+        self.state
+    }
+
+    /// The currently connected glasses, or [`Error::Disconnected`] during a gap.
+    /// Use [`AutoResume::read_event`] and [`AutoResume::set_display_mode`] instead of
+    /// the methods of the same name, so that the wrapper can track them.
+    pub fn glasses(&mut self) -> Result<&mut dyn ARGlasses> {
+        match &mut self.glasses {
+            Some(glasses) => Ok(glasses.as_mut()),
+            None => Err(Error::disconnected(self.name)),
+        }
+    }
+
+    /// Same as [`ARGlasses::read_event`], but returns [`Error::Disconnected`] without
+    /// blocking while the glasses are gone, and [`GlassesEvent::Resumed`] when they are back.
+    pub fn read_event(&mut self) -> Result<GlassesEvent> {
+        if let Some(glasses) = &mut self.glasses {
+            match glasses.read_event() {
+                Err(e) if is_disconnection(&e) => {
+                    self.glasses = None;
+                    self.state = ResumeState::Waiting {
+                        since: self.clock.now(),
+                    };
+                    self.last_attempt = None;
+                    return Err(match e {
+                        Error::Disconnected { .. } => e,
+                        e => Error::disconnected_by(self.name, e),
+                    });
+                }
+                result => return result,
+            }
+        }
+        self.try_resume(self.clock.now())
+    }
+
+    /// Same as [`ARGlasses::set_display_mode`]. The mode is also restored after
+    /// reconnecting. If the glasses are currently gone, it is only stored.
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
+        self.display_mode = Some(display_mode);
+        match &mut self.glasses {
+            Some(glasses) => glasses.set_display_mode(display_mode),
+            None => Ok(()),
+        }
+    }
+
+    fn try_resume(&mut self, now: Instant) -> Result<GlassesEvent> {
+        let ResumeState::Waiting { since } = self.state else {
+            return Err(Error::disconnected(self.name));
+        };
+        if self
+            .max_wait
+            .is_some_and(|max_wait| now.saturating_duration_since(since) > max_wait)
+        {
+            self.state = ResumeState::GaveUp;
+            return Err(Error::disconnected(self.name));
+        }
+        if self.last_attempt.is_some_and(|last_attempt| {
+            now.saturating_duration_since(last_attempt) < RECONNECT_INTERVAL
+        }) {
+            return Err(Error::disconnected(self.name));
+        }
+        self.last_attempt = Some(now);
+        let Some(glasses) = self.reconnect() else {
+            return Err(Error::disconnected(self.name));
+        };
+        self.glasses = Some(glasses);
+        self.state = ResumeState::Connected;
+        Ok(GlassesEvent::Resumed {
+            gap: now.saturating_duration_since(since),
+        })
+    }
+
+    fn reconnect(&mut self) -> Option<Box<dyn ARGlasses>> {
+        let mut glasses = (self.connect)().ok()?;
+        if glasses.serial().ok()? != self.serial {
+            return None;
+        }
+        if let Some(display_mode) = self.display_mode {
+            glasses.set_display_mode(display_mode).ok()?;
+        }
+        Some(glasses)
+    }
+}
+
+/// Whether the error means that the glasses are gone (as opposed to e.g. a slow read or
+/// a rejected command). The HID based drivers already check this themselves, and
+/// return [`Error::Disconnected`].
+fn is_disconnection(error: &Error) -> bool {
+    match error {
+        Error::Disconnected { .. } => true,
+        // A serial port that was hung up reads as end of file
+        Error::IoError(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::NotFound
+        ),
+        #[cfg(feature = "rusb")]
+        Error::UsbError(e) => matches!(e, rusb::Error::NoDevice),
+        #[cfg(feature = "serialport")]
+        Error::SerialPortError(e) => e.kind == serialport::ErrorKind::NoDevice,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use nalgebra::Isometry3;
+
+    use super::*;
+    use crate::{clock::MockClock, AxisConfig, Capabilities, MotionProfile, Side, SyntheticImu};
+
+    /// Synthetic glasses that can be "unplugged"
+    struct Pluggable {
+        inner: SyntheticImu,
+        plugged: Arc<AtomicBool>,
+    }
+
+    impl ARGlasses for Pluggable {
+        fn serial(&mut self) -> Result<String> {
+            self.inner.serial()
+        }
+
+        fn read_event(&mut self) -> Result<GlassesEvent> {
+            if !self.plugged.load(Ordering::Relaxed) {
+                return Err(Error::disconnected("Pluggable"));
+            }
+            self.inner.read_event()
+        }
+
+        fn get_display_mode(&mut self) -> Result<DisplayMode> {
+            self.inner.get_display_mode()
+        }
+
+        fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
+            self.inner.set_display_mode(display_mode)
+        }
+
+        fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+            self.inner.supported_display_modes()
+        }
+
+        fn display_fov(&self) -> f32 {
+            self.inner.display_fov()
+        }
+
+        fn display_tilt(&self) -> f32 {
+            self.inner.display_tilt()
+        }
+
+        fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
+            self.inner.imu_to_display_matrix(side, ipd)
+        }
+
+        fn name(&self) -> &'static str {
+            "Pluggable"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            self.inner.capabilities()
+        }
+
+        fn display_delay(&self) -> u64 {
+            0
+        }
+
+        fn set_axis_config(&mut self, axis_config: AxisConfig) {
+            self.inner.set_axis_config(axis_config)
+        }
+    }
+
+    /// Glasses whose reads always time out
+    struct Slow;
+
+    impl ARGlasses for Slow {
+        fn serial(&mut self) -> Result<String> {
+            Ok("SLOW".into())
+        }
+
+        fn read_event(&mut self) -> Result<GlassesEvent> {
+            Err(Error::PacketTimeout)
+        }
+
+        fn get_display_mode(&mut self) -> Result<DisplayMode> {
+            Ok(DisplayMode::SameOnBoth)
+        }
+
+        fn set_display_mode(&mut self, _display_mode: DisplayMode) -> Result<()> {
+            Ok(())
+        }
+
+        fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+            Ok(vec![DisplayMode::SameOnBoth])
+        }
+
+        fn display_fov(&self) -> f32 {
+            0.0
+        }
+
+        fn imu_to_display_matrix(&self, _side: Side, _ipd: f32) -> Isometry3<f64> {
+            Isometry3::identity()
+        }
+
+        fn name(&self) -> &'static str {
+            "Slow"
+        }
+
+        fn display_delay(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn reconnect_timing() {
+        let plugged = Arc::new(AtomicBool::new(true));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let plugged = plugged.clone();
+            let attempts = attempts.clone();
+            move || -> Result<Box<dyn ARGlasses>> {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                if !plugged.load(Ordering::Relaxed) {
+                    return Err(Error::NotFound);
+                }
+                Ok(Box::new(Pluggable {
+                    inner: SyntheticImu::new(MotionProfile::Stationary),
+                    plugged: plugged.clone(),
+                }))
+            }
+        };
+        let clock = MockClock::new();
+        let mut resume = AutoResume::new(connect, Some(Duration::from_secs(10)))
+            .unwrap()
+            .with_clock(clock.clone());
+        assert!(resume.read_event().is_ok());
+
+        plugged.store(false, Ordering::Relaxed);
+        let lost_at = clock.now();
+        assert!(matches!(
+            resume.read_event(),
+            Err(Error::Disconnected { .. })
+        ));
+        assert_eq!(resume.state(), ResumeState::Waiting { since: lost_at });
+        // The first attempt is immediate, the next ones are rate limited
+        assert!(resume.read_event().is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        clock.advance(RECONNECT_INTERVAL - Duration::from_millis(1));
+        assert!(resume.read_event().is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        clock.advance(Duration::from_millis(1));
+        assert!(resume.read_event().is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        plugged.store(true, Ordering::Relaxed);
+        clock.advance(RECONNECT_INTERVAL);
+        assert!(matches!(
+            resume.read_event(),
+            Ok(GlassesEvent::Resumed { gap }) if gap == 2 * RECONNECT_INTERVAL
+        ));
+        assert_eq!(resume.state(), ResumeState::Connected);
+    }
+
+    #[test]
+    fn give_up() {
+        let plugged = Arc::new(AtomicBool::new(true));
+        let connect = {
+            let plugged = plugged.clone();
+            move || -> Result<Box<dyn ARGlasses>> {
+                if !plugged.load(Ordering::Relaxed) {
+                    return Err(Error::NotFound);
+                }
+                Ok(Box::new(Pluggable {
+                    inner: SyntheticImu::new(MotionProfile::Stationary),
+                    plugged: plugged.clone(),
+                }))
+            }
+        };
+        let clock = MockClock::new();
+        let mut resume = AutoResume::new(connect, Some(Duration::from_secs(10)))
+            .unwrap()
+            .with_clock(clock.clone());
+        plugged.store(false, Ordering::Relaxed);
+        assert!(resume.read_event().is_err());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(resume.read_event().is_err());
+        assert!(matches!(resume.state(), ResumeState::Waiting { .. }));
+        clock.advance(Duration::from_millis(1));
+        assert!(resume.read_event().is_err());
+        assert_eq!(resume.state(), ResumeState::GaveUp);
+
+        // Not even plugging them back helps
+        plugged.store(true, Ordering::Relaxed);
+        clock.advance(Duration::from_secs(1));
+        assert!(resume.read_event().is_err());
+    }
+
+    #[test]
+    fn disconnection_errors() {
+        assert!(is_disconnection(&Error::disconnected("Pluggable")));
+        assert!(!is_disconnection(&Error::PacketTimeout));
+        assert!(!is_disconnection(&Error::Other(
+            "Display mode not supported"
+        )));
+        assert!(!is_disconnection(&Error::IoError(
+            std::io::ErrorKind::TimedOut.into()
+        )));
+        assert!(is_disconnection(&Error::IoError(
+            std::io::ErrorKind::UnexpectedEof.into()
+        )));
+        #[cfg(feature = "rusb")]
+        {
+            assert!(is_disconnection(&Error::UsbError(rusb::Error::NoDevice)));
+            assert!(!is_disconnection(&Error::UsbError(rusb::Error::Pipe)));
+        }
+    }
+
+    #[test]
+    fn timeouts_keep_the_connection() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let connects = connects.clone();
+            move || -> Result<Box<dyn ARGlasses>> {
+                connects.fetch_add(1, Ordering::Relaxed);
+                Ok(Box::new(Slow))
+            }
+        };
+        let mut resume = AutoResume::new(connect, None).unwrap();
+        assert!(matches!(resume.read_event(), Err(Error::PacketTimeout)));
+        assert!(matches!(resume.read_event(), Err(Error::PacketTimeout)));
+        assert_eq!(resume.state(), ResumeState::Connected);
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+}
@@ -5,24 +5,42 @@
 //! Rokid Air AR glasses support. See [`RokidAir`]
 //! It only uses [`rusb`] for communication.
 
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
 use rusb::{request_type, DeviceHandle, GlobalContext};
 
 use crate::{
-    util::get_interface_for_endpoint, ARGlasses, DisplayMode, Error, GlassesEvent, Result, Side,
+    units::TimestampCheck,
+    util::{
+        get_interface_for_endpoint, usb_location, DeviceLock, KeyDebouncer, TransportRecovery,
+        UsbInterrupt,
+    },
+    ARGlasses, AxisConfig, Capabilities, DeviceTimestamp, Diagnostics, DisplayMode, Error,
+    GlassesEvent, Result, SampleFlags, Side, UsbLocation,
 };
 
 /// The main structure representing a connected Rokid Air glasses
 pub struct RokidAir {
     device_handle: DeviceHandle<GlobalContext>,
-    last_accelerometer: Option<(Vector3<f32>, u64)>,
-    last_gyroscope: Option<(Vector3<f32>, u64)>,
+    last_accelerometer: Option<(Vector3<f32>, DeviceTimestamp)>,
+    last_gyroscope: Option<(Vector3<f32>, DeviceTimestamp)>,
     previous_key_states: u8,
-    proxy_sensor_was_far: bool,
+    /// `None` until the first report, so that the initial state is reported too
+    proxy_sensor_was_far: Option<bool>,
     pending_events: VecDeque<GlassesEvent>,
+    separate_imu_events: bool,
+    axis_config: AxisConfig,
+    key_debouncer: KeyDebouncer,
     model: RokidModel,
+    transport: TransportRecovery,
+    diagnostics: Diagnostics,
+    timestamp_check: TimestampCheck,
+    _lock: Option<DeviceLock>,
+    read_buffer: [u8; 0x40],
 }
 
 enum RokidModel {
@@ -31,7 +49,7 @@ enum RokidModel {
 }
 
 /* This is actually hardcoded in the SDK too, except for PID==0x162d, where it's 0x83 */
-const INTERRUPT_IN_ENDPOINT: u8 = 0x82;
+pub(crate) const INTERRUPT_IN_ENDPOINT: u8 = 0x82;
 
 const TIMEOUT: Duration = Duration::from_millis(250);
 
@@ -50,34 +68,60 @@ impl ARGlasses for RokidAir {
             &mut result,
             TIMEOUT,
         )?;
-        Ok(
-            String::from_utf8(result.iter().copied().take_while(|c| *c != 0).collect())
-                .map_err(|_| "Invalid serial string")?,
-        )
+        String::from_utf8(result.iter().copied().take_while(|c| *c != 0).collect()).map_err(|e| {
+            Error::InvalidUtf8 {
+                context: "Serial number",
+                bytes: e.into_bytes(),
+            }
+        })
     }
 
     fn read_event(&mut self) -> Result<GlassesEvent> {
+        if let Some(warning) = self.transport.take_warning(Instant::now()) {
+            return Ok(warning);
+        }
         while self.pending_events.is_empty() {
-            let mut packet_data = [0u8; 0x40];
-            self.device_handle
-                .read_interrupt(INTERRUPT_IN_ENDPOINT, &mut packet_data, TIMEOUT)?;
-            match packet_data[0] {
+            self.transport.read_interrupt(
+                &UsbInterrupt {
+                    handle: &self.device_handle,
+                    endpoint_in: INTERRUPT_IN_ENDPOINT,
+                    endpoint_out: None,
+                },
+                &mut self.read_buffer,
+                TIMEOUT,
+                &mut self.diagnostics,
+            )?;
+            match self.read_buffer[0] {
                 2 => {
-                    let packet: &MiscPacket = bytemuck::cast_ref(&packet_data);
-                    self.handle_key_press(packet.keys_pressed);
-                    self.handle_proxy_sensor(packet.proxy_sensor);
+                    let packet: &MiscPacket = bytemuck::cast_ref(&self.read_buffer);
+                    let (keys_pressed, proxy_sensor) = (packet.keys_pressed, packet.proxy_sensor);
+                    self.handle_key_press(keys_pressed);
+                    self.handle_proxy_sensor(proxy_sensor);
                 }
                 4 => {
-                    let packet: &SensorPacket = bytemuck::cast_ref(&packet_data);
+                    let packet: &SensorPacket = bytemuck::cast_ref(&self.read_buffer);
                     let sensor_data =
                         Vector3::from_data(nalgebra::ArrayStorage([packet.vector; 1]));
+                    let timestamp = DeviceTimestamp::from_micros(packet.timestamp);
                     match packet.sensor_type {
-                        1 => self.last_accelerometer = Some((sensor_data, packet.timestamp)),
-                        2 => self.last_gyroscope = Some((sensor_data, packet.timestamp)),
+                        1 if self.separate_imu_events => {
+                            self.pending_events.push_back(GlassesEvent::Accelerometer {
+                                accelerometer: sensor_data,
+                                timestamp,
+                            })
+                        }
+                        2 if self.separate_imu_events => {
+                            self.pending_events.push_back(GlassesEvent::Gyroscope {
+                                gyroscope: sensor_data,
+                                timestamp,
+                            })
+                        }
+                        1 => self.last_accelerometer = Some((sensor_data, timestamp)),
+                        2 => self.last_gyroscope = Some((sensor_data, timestamp)),
                         // TODO: Magnetometer apparently gives an accuracy value too
                         3 => self.pending_events.push_back(GlassesEvent::Magnetometer {
                             magnetometer: sensor_data,
-                            timestamp: packet.timestamp,
+                            timestamp,
                         }),
                         _ => (),
                     }
@@ -91,22 +135,35 @@ impl ARGlasses for RokidAir {
                                 accelerometer,
                                 gyroscope,
                                 timestamp: acc_ts,
+                                flags: SampleFlags::empty(),
                             });
                         }
                     }
                 }
                 17 => {
-                    let packet: &CombinedPacket = bytemuck::cast_ref(&packet_data);
-                    let timestamp = packet.timestamp / 1000;
-                    self.pending_events.push_back(GlassesEvent::AccGyro {
-                        accelerometer: Vector3::from_data(nalgebra::ArrayStorage(
-                            [packet.accelerometer; 1],
-                        )),
-                        gyroscope: Vector3::from_data(nalgebra::ArrayStorage(
-                            [packet.gyroscope; 1],
-                        )),
-                        timestamp,
-                    });
+                    let packet: &CombinedPacket = bytemuck::cast_ref(&self.read_buffer);
+                    let timestamp = DeviceTimestamp::from_nanos(packet.timestamp);
+                    let accelerometer =
+                        Vector3::from_data(nalgebra::ArrayStorage([packet.accelerometer; 1]));
+                    let gyroscope =
+                        Vector3::from_data(nalgebra::ArrayStorage([packet.gyroscope; 1]));
+                    if self.separate_imu_events {
+                        self.pending_events.push_back(GlassesEvent::Accelerometer {
+                            accelerometer,
+                            timestamp,
+                        });
+                        self.pending_events.push_back(GlassesEvent::Gyroscope {
+                            gyroscope,
+                            timestamp,
+                        });
+                    } else {
+                        self.pending_events.push_back(GlassesEvent::AccGyro {
+                            accelerometer,
+                            gyroscope,
+                            timestamp,
+                            flags: SampleFlags::empty(),
+                        });
+                    }
                     self.pending_events.push_back(GlassesEvent::Magnetometer {
                         magnetometer: Vector3::from_data(nalgebra::ArrayStorage(
                             [packet.magnetometer; 1],
@@ -114,13 +171,19 @@ impl ARGlasses for RokidAir {
                         timestamp,
                     });
                     // NOTE: was always zero on my Max
-                    self.handle_key_press(packet.keys_pressed);
-                    self.handle_proxy_sensor(packet.proxy_sensor);
+                    let (keys_pressed, proxy_sensor) = (packet.keys_pressed, packet.proxy_sensor);
+                    self.handle_key_press(keys_pressed);
+                    self.handle_proxy_sensor(proxy_sensor);
                 }
                 _ => {}
             }
         }
-        Ok(self.pending_events.pop_front().unwrap())
+        let mut event = self.pending_events.pop_front().unwrap();
+        self.axis_config.apply(&mut event);
+        if self.timestamp_check.check(&event, Instant::now()) {
+            self.diagnostics.implausible_timestamps += 1;
+        }
+        Ok(event)
     }
 
     fn get_display_mode(&mut self) -> Result<DisplayMode> {
@@ -169,6 +232,50 @@ impl ARGlasses for RokidAir {
         Ok(())
     }
 
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        Ok(vec![
+            DisplayMode::SameOnBoth,
+            DisplayMode::Stereo,
+            DisplayMode::HighRefreshRate,
+            DisplayMode::HighRefreshRateSBS,
+        ])
+    }
+
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        self.axis_config = axis_config;
+    }
+
+    fn set_key_debounce(&mut self, window: Duration) -> Result<()> {
+        self.key_debouncer.set_window(window);
+        Ok(())
+    }
+
+    /// Drops the queued events and the unpaired accelerometer or gyroscope sample,
+    /// discards the unread reports, and checks that the device answers control
+    /// requests again (by reading the display mode).
+    fn resync(&mut self) -> Result<()> {
+        self.pending_events.clear();
+        self.last_accelerometer = None;
+        self.last_gyroscope = None;
+        // A change may have been dropped with the pending events
+        self.proxy_sensor_was_far = None;
+        self.timestamp_check.reset();
+        // Bounded, as the endpoint keeps producing reports while being drained
+        for _ in 0..1024 {
+            match self.device_handle.read_interrupt(
+                INTERRUPT_IN_ENDPOINT,
+                &mut self.read_buffer,
+                Duration::from_millis(1),
+            ) {
+                Ok(_) => {}
+                Err(rusb::Error::Timeout) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.get_display_mode()?;
+        Ok(())
+    }
+
     fn display_fov(&self) -> f32 {
         match self.model {
             RokidModel::Air => {
@@ -183,17 +290,44 @@ impl ARGlasses for RokidAir {
         }
     }
 
+    fn display_tilt(&self) -> f32 {
+        match self.model {
+            RokidModel::Air => -0.022,
+            RokidModel::Max => -0.07,
+        }
+    }
+
     fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
-        let tilt = match self.model {
-            RokidModel::Air => 0.022,
-            RokidModel::Max => 0.07,
-        };
         let ipd = ipd as f64
             * match side {
                 Side::Left => -0.5,
                 Side::Right => 0.5,
             };
-        Translation3::new(ipd, 0.0, 0.0) * UnitQuaternion::from_euler_angles(tilt, 0.0, 0.0)
+        Translation3::new(ipd, 0.0, 0.0)
+            * UnitQuaternion::from_euler_angles(-self.display_tilt() as f64, 0.0, 0.0)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::IMU
+            | Capabilities::MAGNETOMETER
+            | Capabilities::KEYS
+            | Capabilities::KEY_DEBOUNCE
+            | Capabilities::PROXIMITY
+            | Capabilities::DISPLAY_CONTROL
+            | Capabilities::SEPARATE_IMU_EVENTS
+            | Capabilities::RESYNC
+            | Capabilities::RAW_ACCESS
+    }
+
+    fn set_separate_imu_events(&mut self, separate: bool) -> Result<()> {
+        self.separate_imu_events = separate;
+        self.last_accelerometer = None;
+        self.last_gyroscope = None;
+        Ok(())
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
     }
 
     fn name(&self) -> &'static str {
@@ -209,6 +343,29 @@ impl ARGlasses for RokidAir {
             RokidModel::Max => 13000,
         }
     }
+
+    #[cfg(feature = "raw_access")]
+    fn raw_read(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let mut result = vec![0u8; 0x100];
+        match self
+            .device_handle
+            .read_interrupt(INTERRUPT_IN_ENDPOINT, &mut result, timeout)
+        {
+            Ok(size) => {
+                result.truncate(size);
+                Ok(result)
+            }
+            Err(rusb::Error::Timeout) => Err(Error::PacketTimeout),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(feature = "raw_access")]
+    fn raw_write(&mut self, endpoint_or_report: u8, data: &[u8]) -> Result<()> {
+        self.device_handle
+            .write_interrupt(endpoint_or_report, data, TIMEOUT)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -278,19 +435,42 @@ impl RokidAir {
         // This is needed on Android, where access to USB devices is limited
         unsafe { rusb::ffi::libusb_set_option(std::ptr::null_mut(), 2) };
         let device_handle = unsafe { GlobalContext::default().open_device_with_fd(fd as i32) }?;
-        Self::new_common(device_handle)
+        Self::new_common(device_handle, None)
     }
 
     /// Find a connected Rokid Air device and connect to it. (And claim the USB interface)
-    /// Only one instance can be alive at a time
+    /// Only one instance can be alive at a time, see [`Error::AlreadyInUse`]
     #[cfg(not(target_os = "android"))]
     pub fn new() -> Result<Self> {
         use crate::util::get_device_vid_pid;
 
-        Self::new_common(get_device_vid_pid(Self::VID, Self::PID)?.open()?)
+        let device = get_device_vid_pid(Self::VID, Self::PID)?;
+        let lock = DeviceLock::for_usb_device(&device)?;
+        Self::new_common(device.open()?, Some(lock))
+    }
+
+    /// Connect to the glasses plugged into a specific USB port, e.g. one saved with
+    /// [`RokidAir::usb_location`] before a replug. Waits at most `timeout` for it to appear.
+    #[cfg(not(target_os = "android"))]
+    pub fn open_at(location: &UsbLocation, timeout: Duration) -> Result<Self> {
+        use crate::util::wait_for_device_at;
+
+        let device = wait_for_device_at(Self::VID, Self::PID, timeout, |device_location| {
+            device_location.same_port(location)
+        })?;
+        let lock = DeviceLock::for_usb_device(&device)?;
+        Self::new_common(device.open()?, Some(lock))
+    }
+
+    /// Physical location of the glasses on the USB bus
+    pub fn usb_location(&self) -> UsbLocation {
+        usb_location(&self.device_handle.device())
     }
 
-    fn new_common(mut device_handle: DeviceHandle<GlobalContext>) -> Result<Self> {
+    fn new_common(
+        mut device_handle: DeviceHandle<GlobalContext>,
+        lock: Option<DeviceLock>,
+    ) -> Result<Self> {
         device_handle.set_auto_detach_kernel_driver(true)?;
 
         device_handle.claim_interface(
@@ -305,13 +485,21 @@ impl RokidAir {
             last_accelerometer: None,
             last_gyroscope: None,
             previous_key_states: 0,
-            proxy_sensor_was_far: false,
+            proxy_sensor_was_far: None,
             model: if product_string.contains("Max") {
                 RokidModel::Max
             } else {
                 RokidModel::Air
             },
             pending_events: Default::default(),
+            separate_imu_events: false,
+            axis_config: Default::default(),
+            key_debouncer: Default::default(),
+            transport: Default::default(),
+            diagnostics: Default::default(),
+            timestamp_check: Default::default(),
+            _lock: lock,
+            read_buffer: [0; 0x40],
         };
         Ok(result)
     }
@@ -319,7 +507,11 @@ impl RokidAir {
     fn handle_key_press(&mut self, keys_pressed: u8) {
         let new_presses = keys_pressed & !self.previous_key_states;
         for bit in 0..8 {
-            if new_presses & (1 << bit) != 0 {
+            if new_presses & (1 << bit) != 0
+                && self
+                    .key_debouncer
+                    .accept(bit, Instant::now(), &mut self.diagnostics)
+            {
                 self.pending_events.push_back(GlassesEvent::KeyPress(bit))
             }
         }
@@ -328,9 +520,7 @@ impl RokidAir {
 
     fn handle_proxy_sensor(&mut self, value: u8) {
         let proxy_sensor_is_far = value != 0;
-        let send_proxy_event = proxy_sensor_is_far != self.proxy_sensor_was_far;
-        self.proxy_sensor_was_far = proxy_sensor_is_far;
-        if send_proxy_event {
+        if self.proxy_sensor_was_far.replace(proxy_sensor_is_far) != Some(proxy_sensor_is_far) {
             self.pending_events.push_back(if proxy_sensor_is_far {
                 GlassesEvent::ProximityFar
             } else {
@@ -0,0 +1,268 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Placing a virtual screen in the world. See [`VirtualScreen`]
+
+use std::{
+    f64::consts::{PI, TAU},
+    time::Duration,
+};
+
+use nalgebra::{Isometry3, Matrix4, Translation3, UnitQuaternion, Vector3};
+
+use crate::{heading, ARGlasses, DeviceTimestamp, OrientationCorrection, Side};
+
+/// How a [`VirtualScreen`] follows the head
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenPinning {
+    /// The screen stays in place in the world (apart from recentering)
+    WorldLocked,
+    /// The screen follows the heading of the head with an exponential smoothing of
+    /// the specified time constant, but ignores pitch and roll. Small head movements
+    /// barely move it, and looking away for long brings it back in front.
+    YawFollow {
+        /// Time it takes to get ~63% of the way to the new heading
+        time_constant: Duration,
+    },
+}
+
+/// A flat virtual screen in front of the user, e.g. for a "virtual monitor" application.
+///
+/// Feed it the output of a sensor fusion algorithm (an orientation, transforming from
+/// the device frame to a gravity aligned world frame) with [`VirtualScreen::update`], and
+/// render a unit quad (-0.5..0.5 on the X and Y axes) with the matrices it returns.
+///
+/// The head orientation is predicted forward by the display delay of the glasses
+/// (see [`VirtualScreen::for_glasses`]), using the gyroscope reading. Recentering and
+/// drift correction go through an [`OrientationCorrection`], see
+/// [`VirtualScreen::correction`].
+#[derive(Debug, Clone)]
+pub struct VirtualScreen {
+    distance: f64,
+    width: f64,
+    height: f64,
+    pinning: ScreenPinning,
+    /// In microseconds
+    prediction: u64,
+    correction: OrientationCorrection,
+    head: UnitQuaternion<f64>,
+    screen_heading: f64,
+    last_timestamp: Option<DeviceTimestamp>,
+}
+
+impl Default for VirtualScreen {
+    fn default() -> Self {
+        Self {
+            distance: 2.0,
+            width: 1.6,
+            height: 0.9,
+            pinning: ScreenPinning::WorldLocked,
+            prediction: 0,
+            correction: OrientationCorrection::new(),
+            head: UnitQuaternion::identity(),
+            screen_heading: 0.0,
+            last_timestamp: None,
+        }
+    }
+}
+
+impl VirtualScreen {
+    /// Create a world locked 1.6m x 0.9m screen 2m in front of the user, without prediction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the prediction to the display delay of the glasses
+    pub fn for_glasses(mut self, glasses: &dyn ARGlasses) -> Self {
+        self.prediction = glasses.display_delay();
+        self
+    }
+
+    /// Set the distance of the screen from the user, in meters
+    pub fn with_distance(mut self, distance: f64) -> Self {
+        self.distance = distance;
+        self
+    }
+
+    /// Set the size of the screen, in meters
+    pub fn with_size(mut self, width: f64, height: f64) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Set how the screen follows the head. The default is [`ScreenPinning::WorldLocked`].
+    pub fn with_pinning(mut self, pinning: ScreenPinning) -> Self {
+        self.pinning = pinning;
+        self
+    }
+
+    /// Set how much the head orientation is predicted forward, in microseconds
+    pub fn with_prediction(mut self, prediction: u64) -> Self {
+        self.prediction = prediction;
+        self
+    }
+
+    /// The correction applied to the orientations. Clones share the correction,
+    /// so it can be used to recenter or correct drift from another thread.
+    pub fn correction(&self) -> OrientationCorrection {
+        self.correction.clone()
+    }
+
+    /// Put the screen in front of the current looking direction.
+    /// Takes effect from the next [`VirtualScreen::update`].
+    pub fn recenter(&mut self) {
+        self.correction.recenter_yaw();
+        self.screen_heading = 0.0;
+    }
+
+    /// Process an orientation from the sensor fusion, with the gyroscope reading
+    /// (in rad/s, as in [`crate::GlassesEvent::AccGyro`]) and device timestamp
+    /// belonging to it
+    pub fn update(
+        &mut self,
+        timestamp: DeviceTimestamp,
+        orientation: &UnitQuaternion<f64>,
+        gyroscope: &Vector3<f32>,
+    ) {
+        let corrected = self.correction.apply(orientation);
+        let prediction = self.prediction as f64 / 1_000_000.0;
+        self.head = corrected * UnitQuaternion::from_scaled_axis(gyroscope.cast() * prediction);
+
+        let dt = self.last_timestamp.map_or(0.0, |last| {
+            timestamp.saturating_duration_since(last).as_secs_f64()
+        });
+        self.last_timestamp = Some(timestamp);
+        if let ScreenPinning::YawFollow { time_constant } = self.pinning {
+            let alpha = 1.0 - (-dt / time_constant.as_secs_f64().max(1e-6)).exp();
+            let difference = (heading(&corrected) - self.screen_heading + PI).rem_euclid(TAU) - PI;
+            self.screen_heading += difference * alpha;
+        }
+    }
+
+    /// The predicted head orientation, as used by [`VirtualScreen::view_matrix`]
+    pub fn head_orientation(&self) -> UnitQuaternion<f64> {
+        self.head
+    }
+
+    /// Transformation from the unit quad to the world frame
+    pub fn model_matrix(&self) -> Matrix4<f64> {
+        let pose = Isometry3::from_parts(
+            Translation3::identity(),
+            UnitQuaternion::from_axis_angle(&Vector3::y_axis(), self.screen_heading),
+        ) * Translation3::new(0.0, 0.0, -self.distance);
+        pose.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(self.width, self.height, 1.0))
+    }
+
+    /// Transformation from the world frame to the frame of one of the displays,
+    /// see [`ARGlasses::imu_to_display_matrix`]
+    pub fn view_matrix(&self, glasses: &dyn ARGlasses, side: Side, ipd: f32) -> Matrix4<f64> {
+        (glasses.imu_to_display_matrix(side, ipd) * self.head.inverse()).to_homogeneous()
+    }
+
+    /// `projection * view * model` for one of the displays
+    pub fn mvp(
+        &self,
+        glasses: &dyn ARGlasses,
+        side: Side,
+        ipd: f32,
+        projection: &Matrix4<f64>,
+    ) -> Matrix4<f64> {
+        projection * self.view_matrix(glasses, side, ipd) * self.model_matrix()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_PI_2;
+
+    use nalgebra::Vector4;
+
+    use super::*;
+    use crate::{MotionProfile, SyntheticImu};
+
+    fn yaw(angle: f64) -> UnitQuaternion<f64> {
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), angle)
+    }
+
+    /// Center of the screen in the world frame
+    fn center(screen: &VirtualScreen) -> Vector3<f64> {
+        (screen.model_matrix() * Vector4::new(0.0, 0.0, 0.0, 1.0)).xyz()
+    }
+
+    /// Center of the screen in the display frame
+    fn center_on_display(screen: &VirtualScreen) -> Vector3<f64> {
+        let glasses = SyntheticImu::new(MotionProfile::Stationary);
+        let view = screen.view_matrix(&glasses, Side::Left, 0.065);
+        (view * screen.model_matrix() * Vector4::new(0.0, 0.0, 0.0, 1.0)).xyz()
+    }
+
+    fn update(screen: &mut VirtualScreen, millis: u64, orientation: UnitQuaternion<f64>) {
+        let timestamp = DeviceTimestamp::from_micros(millis * 1000);
+        screen.update(timestamp, &orientation, &Vector3::zeros());
+    }
+
+    #[test]
+    fn model_matrix() {
+        let screen = VirtualScreen::new().with_distance(3.0).with_size(2.0, 1.0);
+        let corner = screen.model_matrix() * Vector4::new(0.5, -0.5, 0.0, 1.0);
+        assert!((corner - Vector4::new(1.0, -0.5, -3.0, 1.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn world_locked() {
+        let mut screen = VirtualScreen::new();
+        update(&mut screen, 0, UnitQuaternion::identity());
+        assert!((center_on_display(&screen) - Vector3::new(0.0, 0.0, -2.0)).norm() < 1e-9);
+
+        // Turning left moves the screen to the right on the display
+        update(&mut screen, 1000, yaw(FRAC_PI_2));
+        assert!((center(&screen) - Vector3::new(0.0, 0.0, -2.0)).norm() < 1e-9);
+        assert!((center_on_display(&screen) - Vector3::new(2.0, 0.0, 0.0)).norm() < 1e-9);
+
+        // Until recentered
+        screen.recenter();
+        update(&mut screen, 2000, yaw(FRAC_PI_2));
+        assert!(heading(&screen.head_orientation()).abs() < 1e-9);
+        assert!((center_on_display(&screen) - Vector3::new(0.0, 0.0, -2.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn prediction() {
+        let mut screen = VirtualScreen::new().with_prediction(100_000);
+        screen.update(
+            DeviceTimestamp::from_micros(0),
+            &yaw(0.5),
+            &Vector3::new(0.0, 1.0, 0.0),
+        );
+        assert!((heading(&screen.head_orientation()) - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn yaw_follow() {
+        let mut screen = VirtualScreen::new().with_pinning(ScreenPinning::YawFollow {
+            time_constant: Duration::from_secs(1),
+        });
+        // Pitch is ignored
+        let orientation = yaw(1.0) * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 0.3);
+        for millis in (0..=1000).step_by(10) {
+            update(&mut screen, millis, orientation);
+        }
+        let expected = 1.0 - (-1.0f64).exp();
+        let expected_center = yaw(expected) * Vector3::new(0.0, 0.0, -2.0);
+        assert!((center(&screen) - expected_center).norm() < 1e-3);
+
+        // Follows the shortest way around
+        for millis in (1000..=20000).step_by(10) {
+            update(&mut screen, millis, yaw(3.0));
+        }
+        for millis in (20000..=21000).step_by(10) {
+            update(&mut screen, millis, yaw(-3.0));
+        }
+        let expected = 3.0 + (TAU - 6.0) * (1.0 - (-1.0f64).exp());
+        let expected_center = yaw(expected) * Vector3::new(0.0, 0.0, -2.0);
+        assert!((center(&screen) - expected_center).norm() < 1e-3);
+    }
+}
@@ -0,0 +1,644 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Sharing one glasses connection with other processes over a Unix domain socket.
+//! See [`EventServer`] and [`RemoteGlasses`]
+//!
+//! This way only a small process needs access to the USB devices, and any number of
+//! unprivileged clients (renderers, loggers, debug tools) can read the events.
+//!
+//! ## Wire format
+//!
+//! The server starts every connection with `ARDS` and a `u16` protocol version
+//! (currently 2). After that, both directions consist of messages, each prefixed
+//! with its length as a `u32`. All numbers are little endian.
+//!
+//! Messages from the server start with a `u8` kind:
+//! * 0: description of the glasses, always the first message. Serial and name
+//!   (`u16` length and UTF-8 text each), display FOV and tilt (`f32`), display delay
+//!   (`u64`), capabilities (`u32`), current display mode, the number of supported display
+//!   modes and the modes themselves (`u8` each, see below, 0xff is unknown), then for the
+//!   left and right display: the rotation of [`ARGlasses::imu_to_display_rotation`] as an
+//!   `i, j, k, w` quaternion and [`ARGlasses::eye_offset`] at 0m and 1m IPD (`f64`s).
+//! * 1: an event, as a record of the current version of [`crate::EventLogFormat::Binary`]
+//!
+//! Messages from the clients are commands, starting with a `u8` kind:
+//! * 1: set the display mode, followed by the `u8` mode
+//! * 2: recenter, see [`EventServer::with_correction`]
+//!
+//! Display modes are numbered in the order of the [`DisplayMode`] variants, starting at 0.
+//! Commands the glasses reject are ignored.
+//!
+//! There is no brightness command, as [`ARGlasses`] can't set the brightness. Changes
+//! made with the buttons are forwarded as [`GlassesEvent::BrightnessChanged`] events.
+//!
+//! ## Access
+//!
+//! The socket file is only accessible by the user running the server (mode 0600), unless
+//! [`EventServer::with_allowed_uids`] is used.
+
+use std::{
+    fs::Permissions,
+    io::{Cursor, ErrorKind, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion, Vector3};
+
+use crate::{
+    event_log::{read_binary_record, write_binary_record, BINARY_VERSION},
+    ARGlasses, Capabilities, DisplayMode, Error, GlassesEvent, LogRecord, OrientationCorrection,
+    Result, Side,
+};
+
+const MAGIC: &[u8; 4] = b"ARDS";
+const VERSION: u16 = 2;
+
+const MSG_HELLO: u8 = 0;
+const MSG_EVENT: u8 = 1;
+
+const CMD_SET_DISPLAY_MODE: u8 = 1;
+const CMD_RECENTER: u8 = 2;
+
+/// Clients that fall this far behind are disconnected, instead of stalling the server
+const MAX_CLIENT_BACKLOG: usize = 1 << 20;
+/// Longest message accepted in either direction
+const MAX_MESSAGE_SIZE: usize = 1 << 16;
+
+const DISPLAY_MODES: [DisplayMode; 5] = [
+    DisplayMode::SameOnBoth,
+    DisplayMode::Stereo,
+    DisplayMode::HalfSBS,
+    DisplayMode::HighRefreshRate,
+    DisplayMode::HighRefreshRateSBS,
+];
+
+fn display_mode_to_u8(display_mode: Option<DisplayMode>) -> u8 {
+    display_mode
+        .and_then(|mode| DISPLAY_MODES.iter().position(|m| *m == mode))
+        .map_or(0xff, |index| index as u8)
+}
+
+fn display_mode_from_u8(value: u8) -> Option<DisplayMode> {
+    DISPLAY_MODES.get(value as usize).copied()
+}
+
+/// Publishes the events of a glasses to the clients connected to a Unix domain socket,
+/// and executes their commands. See the [module docs](self) for the protocol, and
+/// [`RemoteGlasses`] for the client side.
+///
+/// The server does not have its own thread: call [`EventServer::serve`] in the loop that
+/// would otherwise call [`ARGlasses::read_event`]. Clients are accepted and serviced
+/// without blocking, and clients that can't keep up with the events are disconnected.
+pub struct EventServer {
+    listener: UnixListener,
+    path: PathBuf,
+    hello: Hello,
+    clients: Vec<Client>,
+    correction: Option<OrientationCorrection>,
+    allowed_uids: Option<Vec<u32>>,
+    record: Vec<u8>,
+}
+
+struct Client {
+    stream: UnixStream,
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+}
+
+impl EventServer {
+    /// Create the socket at `path`, serving the glasses. The socket file is removed when
+    /// the server is dropped. Fails if the file already exists.
+    ///
+    /// The socket file is only accessible by the current user (mode 0600).
+    pub fn bind(path: impl AsRef<Path>, glasses: &mut dyn ARGlasses) -> Result<Self> {
+        let path = path.as_ref();
+        let listener = bind_private(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            hello: Hello::from_glasses(glasses)?,
+            clients: Vec::new(),
+            correction: None,
+            allowed_uids: None,
+            record: Vec::new(),
+        })
+    }
+
+    /// Execute the recenter commands of the clients on this correction, e.g. the one of
+    /// a [`crate::VirtualScreen`]. Without one, recenter commands are ignored.
+    pub fn with_correction(mut self, correction: OrientationCorrection) -> Self {
+        self.correction = Some(correction);
+        self
+    }
+
+    /// Only accept clients running as one of these users. By default, everyone who can
+    /// open the socket file is accepted.
+    ///
+    /// As the users are checked on every connection, this makes the socket file
+    /// accessible by everyone (mode 0666), so that it works for users other than the one
+    /// running the server.
+    pub fn with_allowed_uids(mut self, uids: Vec<u32>) -> Self {
+        self.allowed_uids = Some(uids);
+        // If this fails, the socket stays private, so only the clients are affected
+        let _ = std::fs::set_permissions(&self.path, Permissions::from_mode(0o666));
+        self
+    }
+
+    /// Number of connected clients
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Read an event from the glasses, publish it, and execute the pending commands
+    pub fn serve(&mut self, glasses: &mut dyn ARGlasses) -> Result<GlassesEvent> {
+        let event = glasses.read_event()?;
+        self.publish(&event)?;
+        self.handle_commands(glasses)?;
+        Ok(event)
+    }
+
+    /// Accept new clients, and send the event to every client
+    pub fn publish(&mut self, event: &GlassesEvent) -> Result<()> {
+        self.accept_clients()?;
+        self.record.clear();
+        self.record.write_u8(MSG_EVENT)?;
+        // The host time is not meaningful to other processes
+        write_binary_record(&mut self.record, 0, event)?;
+        let record = &self.record;
+        self.clients
+            .retain_mut(|client| client.send(record).is_ok());
+        Ok(())
+    }
+
+    /// Execute the commands received from the clients so far. Commands failing on the
+    /// glasses (e.g. a display mode they don't support) are ignored, so that clients
+    /// can't stop the server.
+    pub fn handle_commands(&mut self, glasses: &mut dyn ARGlasses) -> Result<()> {
+        let mut commands = Vec::new();
+        self.clients
+            .retain_mut(|client| client.receive(&mut commands).is_ok());
+        for command in commands {
+            match command.split_first() {
+                Some((&CMD_SET_DISPLAY_MODE, &[mode])) => {
+                    if let Some(display_mode) = display_mode_from_u8(mode) {
+                        if glasses.set_display_mode(display_mode).is_ok() {
+                            self.hello.display_mode = Some(display_mode);
+                        }
+                    }
+                }
+                Some((&CMD_RECENTER, [])) => {
+                    if let Some(correction) = &self.correction {
+                        correction.recenter_yaw();
+                    }
+                }
+                // Unknown commands are ignored, so that newer clients can still
+                // use the rest of the protocol
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn accept_clients(&mut self) -> Result<()> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                // Other errors (e.g. running out of file descriptors, or a client
+                // closing the connection before it was accepted) only affect the new
+                // clients, who are retried at the next event
+                Err(_) => return Ok(()),
+            };
+            if let Some(allowed_uids) = &self.allowed_uids {
+                // Clients are dropped (closing the connection) if they are not allowed
+                if !peer_uid(&stream).is_ok_and(|uid| allowed_uids.contains(&uid)) {
+                    continue;
+                }
+            }
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let mut client = Client {
+                stream,
+                outgoing: Vec::new(),
+                incoming: Vec::new(),
+            };
+            client.outgoing.extend_from_slice(MAGIC);
+            client.outgoing.write_u16::<LittleEndian>(VERSION)?;
+            if client.send(&self.hello.encode()?).is_ok() {
+                self.clients.push(client);
+            }
+        }
+    }
+}
+
+/// Create a socket at `path` only accessible by the current user. It is created at a
+/// temporary name in the same directory first, so that it is never accessible to others,
+/// not even between creating it and changing its permissions.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let file_name = path
+        .file_name()
+        .ok_or(Error::Other("Event server socket path has no file name"))?;
+    let mut temporary_name = std::ffi::OsString::from(".");
+    temporary_name.push(file_name);
+    temporary_name.push(format!(".{}.tmp", std::process::id()));
+    let temporary_path = path.with_file_name(temporary_name);
+    let listener = UnixListener::bind(&temporary_path)?;
+    // Hard linking (unlike renaming) fails if the file already exists
+    let result = std::fs::set_permissions(&temporary_path, Permissions::from_mode(0o600))
+        .and_then(|_| std::fs::hard_link(&temporary_path, path));
+    let _ = std::fs::remove_file(&temporary_path);
+    result?;
+    Ok(listener)
+}
+
+/// User ID of the process on the other end of the socket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// User ID of the process on the other end of the socket
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+impl Drop for EventServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Client {
+    /// Queue a message and write as much of the queue as possible without blocking
+    fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        self.outgoing
+            .write_u32::<LittleEndian>(message.len() as u32)?;
+        self.outgoing.extend_from_slice(message);
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.outgoing.len() > MAX_CLIENT_BACKLOG {
+            return Err(ErrorKind::OutOfMemory.into());
+        }
+        Ok(())
+    }
+
+    /// Read everything available without blocking, and collect the complete messages
+    fn receive(&mut self, messages: &mut Vec<Vec<u8>>) -> std::io::Result<()> {
+        let mut buf = [0u8; 256];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.incoming.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        while self.incoming.len() >= 4 {
+            let length = (&self.incoming[..4]).read_u32::<LittleEndian>()? as usize;
+            if length > MAX_MESSAGE_SIZE {
+                return Err(ErrorKind::InvalidData.into());
+            }
+            if self.incoming.len() < 4 + length {
+                break;
+            }
+            messages.push(self.incoming[4..4 + length].to_vec());
+            self.incoming.drain(..4 + length);
+        }
+        Ok(())
+    }
+}
+
+/// Everything a [`RemoteGlasses`] needs to know about the glasses, except events
+#[derive(Debug, Clone)]
+struct Hello {
+    serial: String,
+    name: String,
+    display_fov: f32,
+    display_tilt: f32,
+    display_delay: u64,
+    capabilities: Capabilities,
+    display_mode: Option<DisplayMode>,
+    supported_display_modes: Vec<DisplayMode>,
+    /// Rotation, eye offset at 0m IPD, eye offset at 1m IPD; for the left and right side
+    displays: [(UnitQuaternion<f64>, Vector3<f64>, Vector3<f64>); 2],
+}
+
+impl Hello {
+    fn from_glasses(glasses: &mut dyn ARGlasses) -> Result<Self> {
+        let display = |side| {
+            (
+                glasses.imu_to_display_rotation(side),
+                glasses.eye_offset(side, 0.0).vector,
+                glasses.eye_offset(side, 1.0).vector,
+            )
+        };
+        let displays = [display(Side::Left), display(Side::Right)];
+        Ok(Self {
+            serial: glasses.serial()?,
+            name: glasses.name().to_string(),
+            display_fov: glasses.display_fov(),
+            display_tilt: glasses.display_tilt(),
+            display_delay: glasses.display_delay(),
+            capabilities: glasses.capabilities(),
+            display_mode: glasses.get_display_mode().ok(),
+            supported_display_modes: glasses.supported_display_modes().unwrap_or_default(),
+            displays,
+        })
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = vec![MSG_HELLO];
+        for text in [&self.serial, &self.name] {
+            out.write_u16::<LittleEndian>(text.len() as u16)?;
+            out.extend_from_slice(text.as_bytes());
+        }
+        out.write_f32::<LittleEndian>(self.display_fov)?;
+        out.write_f32::<LittleEndian>(self.display_tilt)?;
+        out.write_u64::<LittleEndian>(self.display_delay)?;
+        out.write_u32::<LittleEndian>(self.capabilities.0)?;
+        out.write_u8(display_mode_to_u8(self.display_mode))?;
+        out.write_u8(self.supported_display_modes.len() as u8)?;
+        for mode in &self.supported_display_modes {
+            out.write_u8(display_mode_to_u8(Some(*mode)))?;
+        }
+        for (rotation, offset_0, offset_1) in &self.displays {
+            let values = rotation
+                .coords
+                .iter()
+                .chain(offset_0.iter())
+                .chain(offset_1);
+            for value in values {
+                out.write_f64::<LittleEndian>(*value)?;
+            }
+        }
+        Ok(out)
+    }
+
+    fn decode(reader: &mut impl Read) -> Result<Self> {
+        let mut read_text = || -> Result<String> {
+            let mut text = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+            reader.read_exact(&mut text)?;
+            String::from_utf8(text).map_err(|e| Error::InvalidUtf8 {
+                context: "Event server description",
+                bytes: e.into_bytes(),
+            })
+        };
+        let serial = read_text()?;
+        let name = read_text()?;
+        let display_fov = reader.read_f32::<LittleEndian>()?;
+        let display_tilt = reader.read_f32::<LittleEndian>()?;
+        let display_delay = reader.read_u64::<LittleEndian>()?;
+        let capabilities = Capabilities(reader.read_u32::<LittleEndian>()?);
+        let display_mode = display_mode_from_u8(reader.read_u8()?);
+        let mut supported_display_modes = Vec::new();
+        for _ in 0..reader.read_u8()? {
+            supported_display_modes.extend(display_mode_from_u8(reader.read_u8()?));
+        }
+        let mut read_f64s = |values: &mut [f64]| -> Result<()> {
+            for value in values {
+                *value = reader.read_f64::<LittleEndian>()?;
+            }
+            Ok(())
+        };
+        let mut display = || -> Result<_> {
+            let mut values = [0.0; 10];
+            read_f64s(&mut values)?;
+            Ok((
+                UnitQuaternion::from_quaternion(Quaternion::new(
+                    values[3], values[0], values[1], values[2],
+                )),
+                Vector3::new(values[4], values[5], values[6]),
+                Vector3::new(values[7], values[8], values[9]),
+            ))
+        };
+        let displays = [display()?, display()?];
+        Ok(Self {
+            serial,
+            name,
+            display_fov,
+            display_tilt,
+            display_delay,
+            capabilities,
+            display_mode,
+            supported_display_modes,
+            displays,
+        })
+    }
+}
+
+/// Glasses connected through an [`EventServer`], possibly in another process.
+///
+/// It implements [`ARGlasses`], so it can be used in place of a local connection.
+/// The calibration related methods return what the server's glasses returned when the
+/// server was started. Every event is forwarded, including the sample flags of
+/// [`GlassesEvent::AccGyro`]. [`ARGlasses::set_axis_config`] is ignored, the axes are
+/// remapped by the server.
+pub struct RemoteGlasses {
+    stream: UnixStream,
+    hello: Hello,
+}
+
+impl RemoteGlasses {
+    /// Connect to the [`EventServer`] listening at `path`
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let mut stream = UnixStream::connect(path)?;
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Other("Not an event server"));
+        }
+        if stream.read_u16::<LittleEndian>()? != VERSION {
+            return Err(Error::Other("Unsupported event server protocol version"));
+        }
+        let message = read_message(&mut stream)?;
+        let mut reader = Cursor::new(message);
+        if reader.read_u8()? != MSG_HELLO {
+            return Err(Error::Other("Event server did not describe the glasses"));
+        }
+        let hello = Hello::decode(&mut reader)?;
+        Ok(Self { stream, hello })
+    }
+
+    /// Name of the glasses connected to the server, as returned by its [`ARGlasses::name`]
+    pub fn remote_name(&self) -> &str {
+        &self.hello.name
+    }
+
+    /// Ask the server to recenter, see [`EventServer::with_correction`]
+    pub fn recenter(&mut self) -> Result<()> {
+        self.send_command(&[CMD_RECENTER])
+    }
+
+    fn send_command(&mut self, command: &[u8]) -> Result<()> {
+        let mut message = Vec::with_capacity(command.len() + 4);
+        message.write_u32::<LittleEndian>(command.len() as u32)?;
+        message.extend_from_slice(command);
+        Ok(self.stream.write_all(&message)?)
+    }
+}
+
+fn read_message(stream: &mut UnixStream) -> Result<Vec<u8>> {
+    let length = match stream.read_u32::<LittleEndian>() {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            return Err(Error::disconnected_by("Event server", e))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::Other("Event server message too long"));
+    }
+    let mut message = vec![0; length];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Recover the events forwarded as markers, see [`RemoteGlasses`]
+fn event_from_marker(json_fields: &str) -> Option<GlassesEvent> {
+    let event = match json_fields.strip_prefix("\"type\":")? {
+        "\"ProximityNear\"" => GlassesEvent::ProximityNear,
+        "\"ProximityFar\"" => GlassesEvent::ProximityFar,
+        "\"VSync\"" => GlassesEvent::VSync,
+        "\"HeartbeatDue\"" => GlassesEvent::HeartbeatDue,
+        "\"ProtocolResync\"" => GlassesEvent::ProtocolResync,
+        "\"Still\"" => GlassesEvent::Still,
+        "\"Moving\"" => GlassesEvent::Moving,
+        "\"MotionWake\"" => GlassesEvent::MotionWake,
+        "\"SessionRestored\"" => GlassesEvent::SessionRestored,
+        fields => {
+            if let Some(key) = fields.strip_prefix("\"KeyPress\",\"key\":") {
+                GlassesEvent::KeyPress(key.parse().ok()?)
+            } else if let Some(level) = fields.strip_prefix("\"AmbientLight\",\"level\":") {
+                GlassesEvent::AmbientLight(level.parse().ok()?)
+            } else if let Some(level) = fields.strip_prefix("\"BrightnessChanged\",\"level\":") {
+                GlassesEvent::BrightnessChanged(level.parse().ok()?)
+            } else {
+                return None;
+            }
+        }
+    };
+    Some(event)
+}
+
+impl ARGlasses for RemoteGlasses {
+    fn serial(&mut self) -> Result<String> {
+        Ok(self.hello.serial.clone())
+    }
+
+    fn read_event(&mut self) -> Result<GlassesEvent> {
+        loop {
+            let message = read_message(&mut self.stream)?;
+            let Some((&MSG_EVENT, mut record)) = message.split_first() else {
+                continue;
+            };
+            match read_binary_record(&mut record, BINARY_VERSION)? {
+                Some(LogRecord::Event { event, .. }) => return Ok(event),
+                Some(LogRecord::Marker { json_fields, .. }) => {
+                    if let Some(event) = event_from_marker(&json_fields) {
+                        return Ok(event);
+                    }
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// The display mode when the server was started, or the last one set through
+    /// this connection
+    fn get_display_mode(&mut self) -> Result<DisplayMode> {
+        Capabilities::DISPLAY_CONTROL.require(self)?;
+        self.hello.display_mode.ok_or(Error::Other(
+            "Display mode of the remote glasses is unknown",
+        ))
+    }
+
+    /// Asks the server to set the display mode. Errors on the server side are not reported.
+    fn set_display_mode(&mut self, display_mode: DisplayMode) -> Result<()> {
+        Capabilities::DISPLAY_CONTROL.require(self)?;
+        self.send_command(&[CMD_SET_DISPLAY_MODE, display_mode_to_u8(Some(display_mode))])?;
+        self.hello.display_mode = Some(display_mode);
+        Ok(())
+    }
+
+    fn supported_display_modes(&mut self) -> Result<Vec<DisplayMode>> {
+        Ok(self.hello.supported_display_modes.clone())
+    }
+
+    fn display_fov(&self) -> f32 {
+        self.hello.display_fov
+    }
+
+    fn display_tilt(&self) -> f32 {
+        self.hello.display_tilt
+    }
+
+    fn imu_to_display_matrix(&self, side: Side, ipd: f32) -> Isometry3<f64> {
+        let (rotation, offset_0, offset_1) = &self.hello.displays[side as usize];
+        // The eye offset is linear in the IPD for all glasses
+        let offset = offset_0 + (offset_1 - offset_0) * ipd as f64;
+        Translation3::from(offset) * rotation
+    }
+
+    fn name(&self) -> &'static str {
+        "Remote glasses"
+    }
+
+    /// The capabilities of the remote glasses, limited to the events and the display
+    /// mode, as the other methods are not forwarded. Ambient light events are forwarded
+    /// too, but [`Capabilities::AMBIENT_LIGHT`] is left out, as they can't be turned on
+    /// or off through the connection.
+    fn capabilities(&self) -> Capabilities {
+        let forwarded = Capabilities::IMU
+            | Capabilities::MAGNETOMETER
+            | Capabilities::KEYS
+            | Capabilities::PROXIMITY
+            | Capabilities::VSYNC
+            | Capabilities::DISPLAY_CONTROL;
+        Capabilities(self.hello.capabilities.0 & forwarded.0)
+    }
+
+    fn display_delay(&self) -> u64 {
+        self.hello.display_delay
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Detecting when the head is not moving. See [`StillnessDetector`], [`MotionWakeDetector`]
+//! and [`CalibrationDriftDetector`]
+
+use std::time::Duration;
+
+use nalgebra::Vector3;
+
+use crate::{GlassesEvent, RadiansPerSec};
+
+/// Detects if the glasses are (almost) completely still, based on the gyroscope data,
+/// e.g. to save power or to suppress jitter.
+///
+/// Feed it every event with [`StillnessDetector::update`], and it returns
+/// [`GlassesEvent::Still`] and [`GlassesEvent::Moving`] events on transitions.
+/// The glasses are considered still if the angular speed stayed below the threshold
+/// for the whole window, and moving as soon as it goes above it.
+#[derive(Debug, Clone)]
+pub struct StillnessDetector {
+    threshold: f32,
+    window: u64,
+    still_since: Option<u64>,
+    still: bool,
+}
+
+impl Default for StillnessDetector {
+    fn default() -> Self {
+        Self {
+            threshold: 0.05,
+            window: 500_000,
+            still_since: None,
+            still: false,
+        }
+    }
+}
+
+impl StillnessDetector {
+    /// Create a detector with a threshold of 0.05 rad/s and a window of 500ms
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the angular speed threshold
+    pub fn with_threshold(mut self, threshold: impl Into<RadiansPerSec>) -> Self {
+        self.threshold = threshold.into().value();
+        self
+    }
+
+    /// Set how long the angular speed has to stay below the threshold
+    /// (in device time) to be considered still
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window.as_micros() as u64;
+        self
+    }
+
+    /// Whether the glasses are currently considered still
+    pub fn is_still(&self) -> bool {
+        self.still
+    }
+
+    /// Process an event. Returns [`GlassesEvent::Still`] or [`GlassesEvent::Moving`]
+    /// if the state changed. Events without gyroscope data are ignored.
+    pub fn update(&mut self, event: &GlassesEvent) -> Option<GlassesEvent> {
+        let (gyroscope, timestamp) = gyroscope_sample(event)?;
+        if gyroscope.norm() > self.threshold {
+            self.still_since = None;
+            if self.still {
+                self.still = false;
+                return Some(GlassesEvent::Moving);
+            }
+            return None;
+        }
+        let still_since = *self.still_since.get_or_insert(timestamp);
+        if !self.still && timestamp.saturating_sub(still_since) >= self.window {
+            self.still = true;
+            return Some(GlassesEvent::Still);
+        }
+        None
+    }
+}
+
+/// Detects when the glasses are picked up (e.g. off the desk) after resting, so that the
+/// display can be woken up before the proximity sensor triggers.
+///
+/// Feed it every event with [`MotionWakeDetector::update`]. Once the angular speed stayed
+/// below the rest threshold for the rest duration, the detector is armed. When armed, the
+/// first motion above the wake threshold that lasts for the sustain duration returns
+/// [`GlassesEvent::MotionWake`], and disarms the detector until the glasses rest again,
+/// so it fires only once per pickup.
+///
+/// All durations are in device time, and each sample is considered to cover the time since
+/// the previous one, so it works the same with reduced IMU rates.
+#[derive(Debug, Clone)]
+pub struct MotionWakeDetector {
+    rest_threshold: f32,
+    rest_duration: u64,
+    wake_threshold: f32,
+    sustain: u64,
+    resting_since: Option<u64>,
+    moving_since: Option<u64>,
+    last_timestamp: Option<u64>,
+    armed: bool,
+}
+
+impl Default for MotionWakeDetector {
+    fn default() -> Self {
+        Self {
+            rest_threshold: 0.05,
+            rest_duration: 5_000_000,
+            wake_threshold: 0.3,
+            sustain: 100_000,
+            resting_since: None,
+            moving_since: None,
+            last_timestamp: None,
+            armed: false,
+        }
+    }
+}
+
+impl MotionWakeDetector {
+    /// Create a detector with a rest threshold of 0.05 rad/s for 5s,
+    /// and a wake threshold of 0.3 rad/s for 100ms
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the angular speed below which the glasses are considered resting,
+    /// and how long they have to rest to arm the detector
+    pub fn with_rest(mut self, threshold: impl Into<RadiansPerSec>, duration: Duration) -> Self {
+        self.rest_threshold = threshold.into().value();
+        self.rest_duration = duration.as_micros() as u64;
+        self
+    }
+
+    /// Set the angular speed above which the glasses are considered picked up,
+    /// and how long the motion has to last
+    pub fn with_wake(mut self, threshold: impl Into<RadiansPerSec>, sustain: Duration) -> Self {
+        self.wake_threshold = threshold.into().value();
+        self.sustain = sustain.as_micros() as u64;
+        self
+    }
+
+    /// Whether the glasses rested long enough, and a pickup would be reported
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Process an event. Returns [`GlassesEvent::MotionWake`] if the glasses were picked up.
+    /// Events without gyroscope data are ignored.
+    pub fn update(&mut self, event: &GlassesEvent) -> Option<GlassesEvent> {
+        let (gyroscope, timestamp) = gyroscope_sample(event)?;
+        let previous_timestamp = self.last_timestamp.replace(timestamp).unwrap_or(timestamp);
+        let speed = gyroscope.norm();
+        if speed <= self.rest_threshold {
+            self.moving_since = None;
+            let resting_since = *self.resting_since.get_or_insert(timestamp);
+            if timestamp.saturating_sub(resting_since) >= self.rest_duration {
+                self.armed = true;
+            }
+            return None;
+        }
+        self.resting_since = None;
+        // Small motions (e.g. a vibrating desk) don't disarm the detector
+        if !self.armed || speed < self.wake_threshold {
+            self.moving_since = None;
+            return None;
+        }
+        let moving_since = *self.moving_since.get_or_insert(previous_timestamp);
+        if timestamp.saturating_sub(moving_since) < self.sustain {
+            return None;
+        }
+        self.armed = false;
+        self.moving_since = None;
+        Some(GlassesEvent::MotionWake)
+    }
+}
+
+/// Detects a stale gyroscope calibration (e.g. shifted by temperature), which makes the
+/// tracking slowly drift, and suggests a corrected bias.
+///
+/// Feed it every event with [`CalibrationDriftDetector::update`]. The gyroscope data is
+/// averaged over windows (in device time). A window where the readings barely vary is
+/// considered to be at rest, and its average is the remaining bias. If the remaining bias
+/// is above the threshold in several consecutive rest windows, the detector returns
+/// [`GlassesEvent::CalibrationDrift`]. It is not reported again until the suggestion
+/// changes by more than the threshold.
+///
+/// The suggested bias is to be subtracted from the gyroscope data of the events.
+/// If the application does that, it should tell the detector with
+/// [`CalibrationDriftDetector::set_applied_bias`], but keep feeding it the original events.
+#[derive(Debug, Clone)]
+pub struct CalibrationDriftDetector {
+    threshold: f32,
+    max_noise: f32,
+    window: u64,
+    required_windows: u32,
+    applied_bias: Vector3<f32>,
+    window_start: Option<u64>,
+    sum: Vector3<f64>,
+    sum_squares: Vector3<f64>,
+    samples: u32,
+    rest_windows: u32,
+    rest_bias_sum: Vector3<f32>,
+    reported: Option<Vector3<f32>>,
+}
+
+impl Default for CalibrationDriftDetector {
+    fn default() -> Self {
+        Self {
+            threshold: 0.005,
+            max_noise: 0.01,
+            window: 2_000_000,
+            required_windows: 3,
+            applied_bias: Vector3::zeros(),
+            window_start: None,
+            sum: Vector3::zeros(),
+            sum_squares: Vector3::zeros(),
+            samples: 0,
+            rest_windows: 0,
+            rest_bias_sum: Vector3::zeros(),
+            reported: None,
+        }
+    }
+}
+
+impl CalibrationDriftDetector {
+    /// Create a detector reporting a remaining bias above 0.005 rad/s, seen in 3
+    /// consecutive 2s windows with a noise (standard deviation) below 0.01 rad/s
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the remaining bias above which the calibration is considered stale
+    pub fn with_threshold(mut self, threshold: impl Into<RadiansPerSec>) -> Self {
+        self.threshold = threshold.into().value();
+        self
+    }
+
+    /// Set the largest standard deviation of the gyroscope data (on any axis) in a window
+    /// that is still considered to be at rest
+    pub fn with_max_noise(mut self, max_noise: impl Into<RadiansPerSec>) -> Self {
+        self.max_noise = max_noise.into().value();
+        self
+    }
+
+    /// Set the length of a window (in device time), and how many consecutive rest
+    /// windows have to agree before the drift is reported
+    pub fn with_windows(mut self, window: Duration, required_windows: u32) -> Self {
+        self.window = window.as_micros() as u64;
+        self.required_windows = required_windows.max(1);
+        self
+    }
+
+    /// Set the bias the application already subtracts from the gyroscope data,
+    /// e.g. after applying a suggestion. Restarts the detection.
+    pub fn set_applied_bias(&mut self, bias: Vector3<f32>) {
+        self.applied_bias = bias;
+        self.window_start = None;
+        self.rest_windows = 0;
+        self.rest_bias_sum = Vector3::zeros();
+    }
+
+    /// Process an event. Returns [`GlassesEvent::CalibrationDrift`] if the calibration
+    /// was found to be stale. Events without gyroscope data are ignored.
+    pub fn update(&mut self, event: &GlassesEvent) -> Option<GlassesEvent> {
+        let (gyroscope, timestamp) = gyroscope_sample(event)?;
+        let window_start = match self.window_start {
+            // A restarted device clock also restarts the window
+            Some(start) if start <= timestamp => start,
+            _ => {
+                self.start_window(timestamp);
+                timestamp
+            }
+        };
+        let gyroscope = (gyroscope - self.applied_bias).cast::<f64>();
+        self.sum += gyroscope;
+        self.sum_squares += gyroscope.component_mul(&gyroscope);
+        self.samples += 1;
+        if timestamp - window_start < self.window {
+            return None;
+        }
+
+        let mean = self.sum / self.samples as f64;
+        let variance = self.sum_squares / self.samples as f64 - mean.component_mul(&mean);
+        self.start_window(timestamp);
+        let mean = mean.cast::<f32>();
+        if variance.max().max(0.0).sqrt() as f32 > self.max_noise || mean.norm() < self.threshold {
+            self.rest_windows = 0;
+            self.rest_bias_sum = Vector3::zeros();
+            return None;
+        }
+        self.rest_windows += 1;
+        self.rest_bias_sum += mean;
+        if self.rest_windows < self.required_windows {
+            return None;
+        }
+        let suggested_bias = self.applied_bias + self.rest_bias_sum / self.rest_windows as f32;
+        self.rest_windows = 0;
+        self.rest_bias_sum = Vector3::zeros();
+        if self
+            .reported
+            .is_some_and(|reported| (reported - suggested_bias).norm() < self.threshold)
+        {
+            return None;
+        }
+        self.reported = Some(suggested_bias);
+        Some(GlassesEvent::CalibrationDrift { suggested_bias })
+    }
+
+    fn start_window(&mut self, timestamp: u64) {
+        self.window_start = Some(timestamp);
+        self.sum = Vector3::zeros();
+        self.sum_squares = Vector3::zeros();
+        self.samples = 0;
+    }
+}
+
+fn gyroscope_sample(event: &GlassesEvent) -> Option<(&Vector3<f32>, u64)> {
+    match event {
+        GlassesEvent::AccGyro {
+            gyroscope,
+            timestamp,
+            ..
+        }
+        | GlassesEvent::Gyroscope {
+            gyroscope,
+            timestamp,
+        } => Some((gyroscope, timestamp.as_micros())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceTimestamp;
+
+    /// Gyroscope sample at `millis` turning at `speed` rad/s
+    fn sample(millis: u64, speed: f32) -> GlassesEvent {
+        GlassesEvent::Gyroscope {
+            gyroscope: Vector3::new(0.0, speed, 0.0),
+            timestamp: DeviceTimestamp::from_micros(millis * 1000),
+        }
+    }
+
+    #[test]
+    fn still_and_moving() {
+        let mut detector = StillnessDetector::new();
+        assert!(detector.update(&GlassesEvent::KeyPress(1)).is_none());
+
+        // Still only after the whole window, the threshold itself counts as still
+        for millis in (0..500).step_by(10) {
+            assert!(detector.update(&sample(millis, 0.05)).is_none());
+        }
+        assert!(!detector.is_still());
+        assert!(matches!(
+            detector.update(&sample(500, 0.01)),
+            Some(GlassesEvent::Still)
+        ));
+        assert!(detector.update(&sample(510, 0.01)).is_none());
+        assert!(detector.is_still());
+
+        // Moving as soon as the threshold is exceeded, reported once
+        assert!(matches!(
+            detector.update(&sample(520, 0.051)),
+            Some(GlassesEvent::Moving)
+        ));
+        assert!(detector.update(&sample(530, 1.0)).is_none());
+        assert!(!detector.is_still());
+
+        // A single fast sample restarts the window
+        assert!(detector.update(&sample(540, 0.0)).is_none());
+        assert!(detector.update(&sample(1030, 0.0)).is_none());
+        assert!(detector.update(&sample(1035, 0.1)).is_none());
+        assert!(detector.update(&sample(1040, 0.0)).is_none());
+        assert!(detector.update(&sample(1530, 0.0)).is_none());
+        assert!(matches!(
+            detector.update(&sample(1540, 0.0)),
+            Some(GlassesEvent::Still)
+        ));
+    }
+
+    #[test]
+    fn motion_wake() {
+        let mut detector = MotionWakeDetector::new()
+            .with_rest(0.05, Duration::from_secs(1))
+            .with_wake(0.3, Duration::from_millis(100));
+        let mut millis = 0;
+        let mut run = |detector: &mut MotionWakeDetector, duration: u64, speed: f32| {
+            let start = millis;
+            let mut wakes = Vec::new();
+            for _ in 0..duration / 10 {
+                millis += 10;
+                if detector.update(&sample(millis, speed)).is_some() {
+                    wakes.push(millis - start);
+                }
+            }
+            wakes
+        };
+
+        // Not armed before resting, measured from the first sample at rest
+        assert_eq!(run(&mut detector, 500, 1.0), []);
+        assert_eq!(run(&mut detector, 1000, 0.0), []);
+        assert!(!detector.is_armed());
+        assert_eq!(run(&mut detector, 10, 0.0), []);
+        assert!(detector.is_armed());
+
+        // Small motions neither wake nor disarm, and a short one doesn't wake
+        assert_eq!(run(&mut detector, 500, 0.2), []);
+        assert_eq!(run(&mut detector, 90, 1.0), []);
+        assert_eq!(run(&mut detector, 10, 0.2), []);
+        assert!(detector.is_armed());
+
+        // The first sample covers the time since the previous one
+        assert_eq!(run(&mut detector, 500, 1.0), [100]);
+        assert!(!detector.is_armed());
+
+        // Re-armed after resting again
+        assert_eq!(run(&mut detector, 1000, 0.0), []);
+        assert!(!detector.is_armed());
+        assert_eq!(run(&mut detector, 10, 0.0), []);
+        assert!(detector.is_armed());
+        assert_eq!(run(&mut detector, 100, 1.0), [100]);
+    }
+
+    #[test]
+    fn motion_wake_at_low_rate() {
+        let mut detector = MotionWakeDetector::new()
+            .with_rest(0.05, Duration::from_secs(1))
+            .with_wake(0.3, Duration::from_millis(100));
+        for millis in (0..=1000).step_by(100) {
+            assert!(detector.update(&sample(millis, 0.0)).is_none());
+        }
+        assert!(matches!(
+            detector.update(&sample(1100, 1.0)),
+            Some(GlassesEvent::MotionWake)
+        ));
+    }
+}
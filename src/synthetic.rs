@@ -0,0 +1,417 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Generated sensor data with a known trajectory. See [`SyntheticImu`]
+
+use std::{collections::VecDeque, time::Duration};
+
+use nalgebra::{Isometry3, Unit, UnitQuaternion, Vector3};
+
+use crate::{
+    ARGlasses, AxisConfig, Capabilities, DeviceTimestamp, DisplayMode, Error, GlassesEvent,
+    MetersPerSec2, Radians, RadiansPerSec, Result, SampleFlags, Side,
+};
+
+/// Specific force felt by a stationary, upright device, in the world frame
+const GRAVITY: Vector3<f32> = Vector3::new(0.0, 9.81, 0.0);
+/// Earth's magnetic field in the world frame, in uT: pointing forward (north) and down
+const MAGNETIC_FIELD: Vector3<f32> = Vector3::new(0.0, -40.0, -20.0);
+
+/// Canned motion of a [`SyntheticImu`]. All rotations are around the IMU's origin,
+/// so there is no linear acceleration.
+#[derive(Debug, Clone, Copy)]
+pub enum MotionProfile {
+    /// Upright, not moving at all
+    Stationary,
+    /// Rotating around a fixed axis at a constant rate
+    ConstantRotation {
+        /// Axis of the rotation, in the device frame
+        axis: Unit<Vector3<f32>>,
+        /// Angular speed around the axis. Positive is right handed rotation.
+        rate: RadiansPerSec,
+    },
+    /// Swaying back and forth around a fixed axis: the angle is
+    /// `amplitude * sin(2 * pi * frequency * t)`
+    Sway {
+        /// Axis of the rotation, in the device frame
+        axis: Unit<Vector3<f32>>,
+        /// Largest angle of the sway
+        amplitude: Radians,
+        /// Frequency of the sway, in Hz
+        frequency: f32,
+    },
+}
+
+/// Glasses without hardware, generating physically consistent sensor data for a
+/// [`MotionProfile`], e.g. to validate processing against the true trajectory.
+///
+/// Samples are generated as fast as they are read, with timestamps as if they were
+/// sampled at the sample rate. Noise is Gaussian, and the gyroscope bias does a
+/// random walk. Noise is deterministic for a given seed.
+#[derive(Debug, Clone)]
+pub struct SyntheticImu {
+    profile: MotionProfile,
+    sample_rate: f32,
+    gyroscope_noise: f32,
+    accelerometer_noise: f32,
+    bias_drift: f32,
+    magnetometer: bool,
+    rng: u64,
+    /// Timestamp of the next sample
+    timestamp: DeviceTimestamp,
+    gyroscope_bias: Vector3<f32>,
+    axis_config: AxisConfig,
+    pending_events: VecDeque<GlassesEvent>,
+}
+
+impl SyntheticImu {
+    /// Create a noiseless source with 1000Hz sample rate and no magnetometer
+    pub fn new(profile: MotionProfile) -> Self {
+        Self {
+            profile,
+            sample_rate: 1000.0,
+            gyroscope_noise: 0.0,
+            accelerometer_noise: 0.0,
+            bias_drift: 0.0,
+            magnetometer: false,
+            rng: 0x853c_49e6_748f_ea9b,
+            timestamp: DeviceTimestamp::default(),
+            gyroscope_bias: Vector3::zeros(),
+            axis_config: AxisConfig::default(),
+            pending_events: VecDeque::new(),
+        }
+    }
+
+    /// Set the sample rate, in Hz. Limited to 1Hz..1MHz, as timestamps are in
+    /// microseconds.
+    pub fn with_sample_rate(mut self, sample_rate: f32) -> Self {
+        self.sample_rate = if sample_rate.is_nan() {
+            1.0
+        } else {
+            sample_rate.clamp(1.0, 1_000_000.0)
+        };
+        self
+    }
+
+    /// Set the standard deviation of the gyroscope (rad/s) and
+    /// accelerometer (m/s²) noise
+    pub fn with_noise(
+        mut self,
+        gyroscope: impl Into<RadiansPerSec>,
+        accelerometer: impl Into<MetersPerSec2>,
+    ) -> Self {
+        self.gyroscope_noise = gyroscope.into().value();
+        self.accelerometer_noise = accelerometer.into().value();
+        self
+    }
+
+    /// Set the initial gyroscope bias
+    pub fn with_gyroscope_bias(mut self, bias: Vector3<f32>) -> Self {
+        self.gyroscope_bias = bias;
+        self
+    }
+
+    /// Set the gyroscope bias drift, in rad/s/sqrt(s). Each second, the bias changes
+    /// by this much (standard deviation) on each axis.
+    pub fn with_bias_drift(mut self, bias_drift: f32) -> Self {
+        self.bias_drift = bias_drift;
+        self
+    }
+
+    /// Also generate [`GlassesEvent::Magnetometer`] events after each sample
+    pub fn with_magnetometer(mut self, magnetometer: bool) -> Self {
+        self.magnetometer = magnetometer;
+        self
+    }
+
+    /// Set the seed of the noise generator
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        // Zero would stay zero forever
+        self.rng = seed.max(1);
+        self
+    }
+
+    /// True orientation (world from device) at a timestamp, in device time
+    pub fn orientation_at(&self, timestamp: DeviceTimestamp) -> UnitQuaternion<f32> {
+        let t = timestamp.as_duration_since_boot().as_secs_f32();
+        match self.profile {
+            MotionProfile::Stationary => UnitQuaternion::identity(),
+            MotionProfile::ConstantRotation { axis, rate } => {
+                UnitQuaternion::from_axis_angle(&axis, rate.value() * t)
+            }
+            MotionProfile::Sway {
+                axis,
+                amplitude,
+                frequency,
+            } => UnitQuaternion::from_axis_angle(
+                &axis,
+                amplitude.value() * (std::f32::consts::TAU * frequency * t).sin(),
+            ),
+        }
+    }
+
+    /// True angular velocity at a timestamp, in device time, without noise or bias
+    pub fn angular_velocity_at(&self, timestamp: DeviceTimestamp) -> Vector3<f32> {
+        let t = timestamp.as_duration_since_boot().as_secs_f32();
+        match self.profile {
+            MotionProfile::Stationary => Vector3::zeros(),
+            // Rotation around a fixed axis, so this is the same in the world and device frame
+            MotionProfile::ConstantRotation { axis, rate } => axis.into_inner() * rate.value(),
+            MotionProfile::Sway {
+                axis,
+                amplitude,
+                frequency,
+            } => {
+                let omega = std::f32::consts::TAU * frequency;
+                axis.into_inner() * amplitude.value() * omega * (omega * t).cos()
+            }
+        }
+    }
+
+    /// Current gyroscope bias, including the drift so far
+    pub fn gyroscope_bias(&self) -> Vector3<f32> {
+        self.gyroscope_bias
+    }
+
+    fn generate_sample(&mut self) {
+        let timestamp = self.timestamp;
+        let dt = 1.0 / self.sample_rate;
+        self.timestamp += Duration::from_micros((1_000_000.0 / self.sample_rate) as u64);
+
+        let drift = self.gaussian_vector(self.bias_drift * dt.sqrt());
+        self.gyroscope_bias += drift;
+        let device_from_world = self.orientation_at(timestamp).inverse();
+        let gyroscope = self.angular_velocity_at(timestamp)
+            + self.gyroscope_bias
+            + self.gaussian_vector(self.gyroscope_noise);
+        let accelerometer =
+            device_from_world * GRAVITY + self.gaussian_vector(self.accelerometer_noise);
+        self.pending_events.push_back(GlassesEvent::AccGyro {
+            accelerometer,
+            gyroscope,
+            timestamp,
+            flags: SampleFlags::empty(),
+        });
+        if self.magnetometer {
+            self.pending_events.push_back(GlassesEvent::Magnetometer {
+                magnetometer: device_from_world * MAGNETIC_FIELD,
+                timestamp,
+            });
+        }
+    }
+
+    fn gaussian_vector(&mut self, std_dev: f32) -> Vector3<f32> {
+        if std_dev == 0.0 {
+            return Vector3::zeros();
+        }
+        Vector3::new(self.gaussian(), self.gaussian(), self.gaussian()) * std_dev
+    }
+
+    /// Standard normal sample, with Box-Muller on xorshift64
+    fn gaussian(&mut self) -> f32 {
+        let u1 = self.uniform().max(f64::MIN_POSITIVE);
+        let u2 = self.uniform();
+        ((-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()) as f32
+    }
+
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl ARGlasses for SyntheticImu {
+    fn serial(&mut self) -> Result<String> {
+        Ok("SYNTHETIC".into())
+    }
+
+    fn read_event(&mut self) -> Result<GlassesEvent> {
+        if self.pending_events.is_empty() {
+            self.generate_sample();
+        }
+        let mut event = self
+            .pending_events
+            .pop_front()
+            .ok_or(Error::Other("No synthetic sample was generated"))?;
+        self.axis_config.apply(&mut event);
+        Ok(event)
+    }
+
+    fn get_display_mode(&mut self) -> Result<DisplayMode> {
+        Err(Error::Unsupported {
+            capability: Capabilities::DISPLAY_CONTROL,
+            device: self.name(),
+        })
+    }
+
+    fn set_display_mode(&mut self, _display_mode: DisplayMode) -> Result<()> {
+        Err(Error::Unsupported {
+            capability: Capabilities::DISPLAY_CONTROL,
+            device: self.name(),
+        })
+    }
+
+    fn set_axis_config(&mut self, axis_config: AxisConfig) {
+        self.axis_config = axis_config;
+    }
+
+    fn display_fov(&self) -> f32 {
+        0.0
+    }
+
+    fn display_tilt(&self) -> f32 {
+        0.0
+    }
+
+    fn imu_to_display_matrix(&self, _side: Side, _ipd: f32) -> Isometry3<f64> {
+        Isometry3::identity()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        if self.magnetometer {
+            Capabilities::IMU | Capabilities::MAGNETOMETER
+        } else {
+            Capabilities::IMU
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "Synthetic IMU"
+    }
+
+    fn display_delay(&self) -> u64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tilt_from_accelerometer, CalibrationDriftDetector};
+
+    fn samples(
+        imu: &mut SyntheticImu,
+        count: usize,
+    ) -> Vec<(DeviceTimestamp, Vector3<f32>, Vector3<f32>)> {
+        (0..count)
+            .map(|_| match imu.read_event().unwrap() {
+                GlassesEvent::AccGyro {
+                    accelerometer,
+                    gyroscope,
+                    timestamp,
+                    ..
+                } => (timestamp, accelerometer, gyroscope),
+                event => panic!("Unexpected {event:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sample_rates() {
+        for (sample_rate, step) in [(500.0, 2000), (0.0, 1_000_000), (f32::NAN, 1_000_000)] {
+            let mut imu =
+                SyntheticImu::new(MotionProfile::Stationary).with_sample_rate(sample_rate);
+            let timestamps: Vec<u64> = samples(&mut imu, 3)
+                .iter()
+                .map(|(timestamp, _, _)| timestamp.as_micros())
+                .collect();
+            assert_eq!(timestamps, [0, step, 2 * step]);
+        }
+    }
+
+    #[test]
+    fn no_display() {
+        let mut imu = SyntheticImu::new(MotionProfile::Stationary);
+        assert!(!imu.capabilities().contains(Capabilities::DISPLAY_CONTROL));
+        assert!(matches!(
+            imu.get_display_mode(),
+            Err(Error::Unsupported { .. })
+        ));
+        assert!(matches!(
+            imu.set_display_mode(DisplayMode::SameOnBoth),
+            Err(Error::Unsupported { .. })
+        ));
+        assert!(imu.supported_display_modes().unwrap().is_empty());
+    }
+
+    /// Integrating the noiseless gyroscope data follows the true orientation
+    #[test]
+    fn gyroscope_matches_trajectory() {
+        let axis = Vector3::new(1.0, 2.0, 0.5);
+        for profile in [
+            MotionProfile::ConstantRotation {
+                axis: Unit::new_normalize(axis),
+                rate: RadiansPerSec(1.5),
+            },
+            MotionProfile::Sway {
+                axis: Unit::new_normalize(axis),
+                amplitude: Radians(0.3),
+                frequency: 1.0,
+            },
+        ] {
+            let mut imu = SyntheticImu::new(profile);
+            let mut orientation = UnitQuaternion::identity();
+            for (_, _, gyroscope) in samples(&mut imu, 2000) {
+                orientation *= UnitQuaternion::from_scaled_axis(gyroscope * 0.001);
+            }
+            let truth = imu.orientation_at(DeviceTimestamp::from_micros(2_000_000));
+            assert!(orientation.angle_to(&truth) < 0.005, "{profile:?}");
+        }
+    }
+
+    /// The accelerometer gives the true tilt, within the noise
+    #[test]
+    fn accelerometer_matches_trajectory() {
+        let mut imu = SyntheticImu::new(MotionProfile::Sway {
+            axis: Vector3::x_axis(),
+            amplitude: Radians(0.5),
+            frequency: 0.5,
+        })
+        .with_noise(0.0, 0.02);
+        for (timestamp, accelerometer, _) in samples(&mut imu, 2000) {
+            let truth = imu.orientation_at(timestamp).cast::<f64>();
+            assert!(tilt_from_accelerometer(&accelerometer).angle_to(&truth) < 0.01);
+        }
+    }
+
+    /// The generated noise has the configured standard deviation
+    #[test]
+    fn noise_statistics() {
+        let mut imu = SyntheticImu::new(MotionProfile::Stationary)
+            .with_noise(0.01, 0.1)
+            .with_seed(42);
+        let gyroscope: Vec<f32> = samples(&mut imu, 20000)
+            .iter()
+            .flat_map(|(_, _, gyroscope)| gyroscope.iter().copied().collect::<Vec<_>>())
+            .collect();
+        let mean = gyroscope.iter().sum::<f32>() / gyroscope.len() as f32;
+        let variance =
+            gyroscope.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / gyroscope.len() as f32;
+        assert!(mean.abs() < 0.0005, "{mean}");
+        assert!(
+            (variance.sqrt() - 0.01).abs() < 0.0005,
+            "{}",
+            variance.sqrt()
+        );
+    }
+
+    /// The drift detector finds the true gyroscope bias
+    #[test]
+    fn drift_detector_finds_bias() {
+        let bias = Vector3::new(0.02, -0.01, 0.005);
+        let mut imu = SyntheticImu::new(MotionProfile::Stationary)
+            .with_noise(0.005, 0.05)
+            .with_gyroscope_bias(bias);
+        let mut detector = CalibrationDriftDetector::new();
+        let suggested_bias = (0..10_000)
+            .find_map(|_| match detector.update(&imu.read_event().unwrap()) {
+                Some(GlassesEvent::CalibrationDrift { suggested_bias }) => Some(suggested_bias),
+                _ => None,
+            })
+            .unwrap();
+        assert!((suggested_bias - bias).norm() < 0.001, "{suggested_bias}");
+    }
+}
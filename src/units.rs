@@ -0,0 +1,299 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! Thin wrappers around bare numbers, so that the unit is part of the type.
+//!
+//! All of them convert from and to the bare number with [`From`], and the number
+//! can also be accessed with `.value()`. APIs taking `impl Into<...>` accept both.
+//!
+//! They are used for the thresholds and settings of the event processors (e.g.
+//! [`crate::GestureDetector`], [`crate::SyntheticImu`]), and [`DeviceTimestamp`] for
+//! all device time. Geometry is not wrapped: [`crate::ARGlasses::display_fov`],
+//! [`crate::ARGlasses::display_tilt`] and the IPD keep their bare `f32` radians and
+//! meters, and the pose helpers (e.g. [`crate::heading`]) work in `f64`, like the
+//! nalgebra types they come with. Their docs state the units.
+
+use std::{
+    ops::{Add, AddAssign},
+    time::{Duration, Instant},
+};
+
+use crate::GlassesEvent;
+
+macro_rules! unit {
+    ($(#[$meta:meta])* $name:ident($inner:ty)) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+        pub struct $name(pub $inner);
+
+        impl $name {
+            /// The bare number
+            pub fn value(self) -> $inner {
+                self.0
+            }
+        }
+
+        impl From<$inner> for $name {
+            fn from(value: $inner) -> Self {
+                Self(value)
+            }
+        }
+
+        impl From<$name> for $inner {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+    };
+}
+
+unit!(
+    /// An angle in radians
+    Radians(f32)
+);
+unit!(
+    /// Angular speed in rad/s, e.g. gyroscope data
+    RadiansPerSec(f32)
+);
+unit!(
+    /// Acceleration in m/s², e.g. accelerometer data
+    MetersPerSec2(f32)
+);
+unit!(
+    /// Duration or timestamp in microseconds, e.g. device time
+    Micros(u64)
+);
+
+impl Radians {
+    /// Convert from degrees
+    pub fn from_degrees(degrees: f32) -> Self {
+        Self(degrees.to_radians())
+    }
+
+    /// Convert to degrees
+    pub fn to_degrees(self) -> f32 {
+        self.0.to_degrees()
+    }
+}
+
+impl From<Duration> for Micros {
+    fn from(value: Duration) -> Self {
+        Self(value.as_micros() as u64)
+    }
+}
+
+impl From<Micros> for Duration {
+    fn from(value: Micros) -> Self {
+        Duration::from_micros(value.0)
+    }
+}
+
+/// A timestamp in device time, i.e. the clock of the glasses. Its resolution is one
+/// microsecond.
+///
+/// Unlike the other units, it doesn't convert from and to a bare number with [`From`]:
+/// drivers have to say which unit the raw value is in with [`DeviceTimestamp::from_micros`]
+/// or [`DeviceTimestamp::from_nanos`], so a value in a different unit can't slip through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct DeviceTimestamp(u64);
+
+impl DeviceTimestamp {
+    /// Create a timestamp from microseconds of device time
+    pub const fn from_micros(micros: u64) -> Self {
+        Self(micros)
+    }
+
+    /// Create a timestamp from nanoseconds of device time. Truncated to microseconds.
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos / 1000)
+    }
+
+    /// The timestamp in microseconds of device time
+    pub const fn as_micros(self) -> u64 {
+        self.0
+    }
+
+    /// The timestamp as the time since the device clock started. This is usually when
+    /// the glasses were powered on, but this is not known for every model.
+    pub const fn as_duration_since_boot(self) -> Duration {
+        Duration::from_micros(self.0)
+    }
+
+    /// Time elapsed since an earlier timestamp, or zero if `earlier` is actually later
+    pub fn saturating_duration_since(self, earlier: DeviceTimestamp) -> Duration {
+        Duration::from_micros(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for DeviceTimestamp {
+    type Output = DeviceTimestamp;
+
+    /// Saturates at the largest timestamp instead of overflowing
+    fn add(self, rhs: Duration) -> Self::Output {
+        let micros = u64::try_from(rhs.as_micros()).unwrap_or(u64::MAX);
+        Self(self.0.saturating_add(micros))
+    }
+}
+
+impl AddAssign<Duration> for DeviceTimestamp {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+/// Check that the gyroscope timestamps of a driver advance at roughly the same speed as
+/// the host clock, to catch unit mistakes (e.g. milliseconds or nanoseconds passed as
+/// microseconds) when adding a new device. Failures are counted in
+/// [`crate::Diagnostics::implausible_timestamps`].
+///
+/// The time between consecutive samples is summed up over windows of a few seconds, and
+/// each window is compared separately. Gaps, i.e. a long device or host time between two
+/// samples (the reader stopped, or reports were dropped), or the device clock going
+/// backwards (e.g. after a reconnect) are left out of the sums.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TimestampCheck {
+    previous: Option<(Instant, DeviceTimestamp)>,
+    host_elapsed: Duration,
+    device_elapsed: Duration,
+}
+
+impl TimestampCheck {
+    /// Length of a window, in host time
+    const WINDOW: Duration = Duration::from_secs(5);
+    /// Longest time between two samples that is not considered a gap
+    const MAX_GAP: Duration = Duration::from_millis(500);
+
+    /// Check the timestamp of a gyroscope sample received at `now`, other events are
+    /// ignored. Returns true if the window ending with this sample failed the check.
+    pub(crate) fn check(&mut self, event: &GlassesEvent, now: Instant) -> bool {
+        let timestamp = match event {
+            GlassesEvent::AccGyro { timestamp, .. } | GlassesEvent::Gyroscope { timestamp, .. } => {
+                *timestamp
+            }
+            _ => return false,
+        };
+        let Some((previous_instant, previous_timestamp)) = self.previous.replace((now, timestamp))
+        else {
+            return false;
+        };
+        let host_step = now.saturating_duration_since(previous_instant);
+        let device_step = timestamp.saturating_duration_since(previous_timestamp);
+        if host_step > Self::MAX_GAP || timestamp < previous_timestamp {
+            return false;
+        }
+        self.host_elapsed += host_step;
+        if device_step <= Self::MAX_GAP {
+            self.device_elapsed += device_step;
+        }
+        if self.host_elapsed < Self::WINDOW {
+            return false;
+        }
+        // Samples may arrive late and in bursts, so only a large difference is a mistake.
+        // A unit mistake is off by a factor of 1000. Such a mistake may also make every
+        // step look like a gap, so device gaps are only left out of the device time.
+        let ratio = self.device_elapsed.as_secs_f64() / self.host_elapsed.as_secs_f64();
+        self.host_elapsed = Duration::ZERO;
+        self.device_elapsed = Duration::ZERO;
+        !(0.5..2.0).contains(&ratio)
+    }
+
+    /// Forget the previous samples, e.g. when the stream was paused
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::*;
+    use crate::SampleFlags;
+
+    fn gyro(micros: u64) -> GlassesEvent {
+        GlassesEvent::Gyroscope {
+            gyroscope: Vector3::zeros(),
+            timestamp: DeviceTimestamp::from_micros(micros),
+        }
+    }
+
+    /// Feed samples every 10ms of host time for `duration`, with device timestamps
+    /// scaled by `speed`. Returns the number of failed windows.
+    fn run(check: &mut TimestampCheck, start: Instant, duration: Duration, speed: u64) -> usize {
+        (0..duration.as_millis() as u64 / 10)
+            .filter(|step| {
+                let now = start + Duration::from_millis(step * 10);
+                check.check(&gyro(step * 10_000 * speed), now)
+            })
+            .count()
+    }
+
+    #[test]
+    fn timestamp_check() {
+        let start = Instant::now();
+        assert_eq!(
+            run(
+                &mut TimestampCheck::default(),
+                start,
+                Duration::from_secs(12),
+                1
+            ),
+            0
+        );
+        // Nanoseconds mistaken for microseconds
+        assert!(
+            run(
+                &mut TimestampCheck::default(),
+                start,
+                Duration::from_secs(12),
+                1000
+            ) > 0
+        );
+        // Other events are ignored
+        let event = GlassesEvent::AccGyro {
+            accelerometer: Vector3::zeros(),
+            gyroscope: Vector3::zeros(),
+            timestamp: DeviceTimestamp::from_micros(0),
+            flags: SampleFlags::default(),
+        };
+        let mut check = TimestampCheck::default();
+        assert!(!check.check(&event, start));
+        assert!(!check.check(&GlassesEvent::VSync, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn timestamp_check_skips_gaps() {
+        let start = Instant::now();
+        let mut check = TimestampCheck::default();
+        // The reader stops for 10 seconds, then reads the buffered old reports quickly,
+        // and continues with the live ones, which are 10 seconds later in device time.
+        for step in 0..100 {
+            assert!(!check.check(
+                &gyro(step * 10_000),
+                start + Duration::from_millis(step * 10)
+            ));
+        }
+        let resumed = start + Duration::from_secs(11);
+        for step in 100..110 {
+            assert!(!check.check(&gyro(step * 10_000), resumed));
+        }
+        for step in 0..1000 {
+            let now = resumed + Duration::from_millis(step * 10);
+            assert!(!check.check(&gyro(11_000_000 + step * 10_000), now));
+        }
+    }
+
+    #[test]
+    fn saturating_add() {
+        let timestamp = DeviceTimestamp::from_micros(u64::MAX - 1);
+        assert_eq!((timestamp + Duration::from_secs(1)).as_micros(), u64::MAX);
+        assert_eq!(
+            (DeviceTimestamp::from_micros(1) + Duration::MAX).as_micros(),
+            u64::MAX
+        );
+        let mut timestamp = DeviceTimestamp::from_micros(1);
+        timestamp += Duration::from_millis(1);
+        assert_eq!(timestamp.as_micros(), 1001);
+    }
+}
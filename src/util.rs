@@ -4,15 +4,36 @@
 
 #[cfg(feature = "rusb")]
 #[allow(unused_imports)]
-use rusb::{Device, DeviceHandle, DeviceList, GlobalContext};
+use rusb::{Device, DeviceHandle, GlobalContext};
+#[cfg(feature = "nreal")]
+use std::time::SystemTime;
+#[cfg(any(feature = "rusb", feature = "nreal"))]
+use std::time::{Duration, Instant};
 
+#[cfg(any(feature = "rusb", feature = "nreal"))]
+use crate::Diagnostics;
 #[allow(unused_imports)]
-use crate::{Error, Result};
+use crate::{DeviceErrorKind, Error, Result};
+#[cfg(feature = "rusb")]
+use crate::{GlassesEvent, TransportErrorKind, UsbLocation};
+
+#[cfg(feature = "tinyjson")]
+use tinyjson::JsonValue;
 
 #[cfg(feature = "rusb")]
 #[cfg(not(target_os = "android"))]
 pub fn get_device_vid_pid(vid: u16, pid: u16) -> Result<Device<GlobalContext>> {
-    for device in DeviceList::new()?.iter() {
+    get_device_vid_pid_in(&GlobalContext::default(), vid, pid)
+}
+
+#[cfg(feature = "rusb")]
+#[cfg(not(target_os = "android"))]
+pub fn get_device_vid_pid_in<T: rusb::UsbContext>(
+    context: &T,
+    vid: u16,
+    pid: u16,
+) -> Result<Device<T>> {
+    for device in context.devices()?.iter() {
         if let Ok(desc) = device.device_descriptor() {
             if desc.vendor_id() == vid && desc.product_id() == pid {
                 return Ok(device);
@@ -22,6 +43,59 @@ pub fn get_device_vid_pid(vid: u16, pid: u16) -> Result<Device<GlobalContext>> {
     Err(Error::NotFound)
 }
 
+#[cfg(feature = "rusb")]
+pub fn usb_location<T: rusb::UsbContext>(device: &Device<T>) -> UsbLocation {
+    UsbLocation {
+        bus: device.bus_number(),
+        address: device.address(),
+        ports: device.port_numbers().unwrap_or_default(),
+    }
+}
+
+/// Find a device with the specified VID and PID whose location satisfies `predicate`.
+/// Waits for it to appear for at most `timeout`.
+#[cfg(feature = "rusb")]
+#[cfg(not(target_os = "android"))]
+pub fn wait_for_device_at(
+    vid: u16,
+    pid: u16,
+    timeout: Duration,
+    predicate: impl Fn(&UsbLocation) -> bool,
+) -> Result<Device<GlobalContext>> {
+    let started = Instant::now();
+    loop {
+        for device in rusb::DeviceList::new()?.iter() {
+            if let Ok(desc) = device.device_descriptor() {
+                if desc.vendor_id() == vid
+                    && desc.product_id() == pid
+                    && predicate(&usb_location(&device))
+                {
+                    return Ok(device);
+                }
+            }
+        }
+        if started.elapsed() >= timeout {
+            return Err(Error::NotFound);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Locations of all devices with the specified VID and PID
+#[cfg(feature = "rusb")]
+#[cfg(not(target_os = "android"))]
+pub fn usb_locations(vid: u16, pid: u16) -> Result<Vec<UsbLocation>> {
+    Ok(rusb::DeviceList::new()?
+        .iter()
+        .filter(|device| {
+            device
+                .device_descriptor()
+                .is_ok_and(|desc| desc.vendor_id() == vid && desc.product_id() == pid)
+        })
+        .map(|device| usb_location(&device))
+        .collect())
+}
+
 #[cfg(feature = "rusb")]
 pub fn get_interface_for_endpoint(
     device: &Device<GlobalContext>,
@@ -40,6 +114,871 @@ pub fn get_interface_for_endpoint(
     None
 }
 
+/// USB layout of a connected device, see [`describe_device`]
+#[cfg(feature = "rusb")]
+#[derive(Debug, Clone)]
+pub struct DeviceDescription {
+    /// USB Vendor ID
+    pub vid: u16,
+    /// USB Product ID
+    pub pid: u16,
+    /// Bus number the device is connected to
+    pub bus: u8,
+    /// Address of the device on the bus
+    pub address: u8,
+    /// All interfaces (and alternate settings) of the active configuration
+    pub interfaces: Vec<InterfaceDescription>,
+}
+
+/// A single interface (alternate setting) of a [`DeviceDescription`]
+#[cfg(feature = "rusb")]
+#[derive(Debug, Clone)]
+pub struct InterfaceDescription {
+    /// Interface number
+    pub number: u8,
+    /// Alternate setting number
+    pub alternate_setting: u8,
+    /// USB interface class code (3 is HID)
+    pub class: u8,
+    /// USB interface subclass code
+    pub subclass: u8,
+    /// USB interface protocol code
+    pub protocol: u8,
+    /// Interface string, if present and readable
+    pub name: Option<String>,
+    /// Endpoints of the interface
+    pub endpoints: Vec<EndpointDescription>,
+    /// Raw HID report descriptor, for HID interfaces, if it could be read
+    pub hid_report_descriptor: Option<Vec<u8>>,
+}
+
+/// A single endpoint of an [`InterfaceDescription`]
+#[cfg(feature = "rusb")]
+#[derive(Debug, Clone)]
+pub struct EndpointDescription {
+    /// Endpoint address, including the direction bit (0x80 is IN)
+    pub address: u8,
+    /// Transfer type of the endpoint
+    pub transfer_type: rusb::TransferType,
+    /// Maximum packet size
+    pub max_packet_size: u16,
+}
+
+/// Describe the USB interface and endpoint layout of a connected device.
+/// Useful for debugging device detection problems, as manufacturers sometimes change
+/// the layout between revisions. Only the first matching device is described.
+///
+/// Strings and HID report descriptors need the device to be opened, so they
+/// are only filled in if the current user has access to it.
+#[cfg(feature = "rusb")]
+#[cfg(not(target_os = "android"))]
+pub fn describe_device(vid: u16, pid: u16) -> Result<DeviceDescription> {
+    let device = get_device_vid_pid(vid, pid)?;
+    let config_desc = device.active_config_descriptor()?;
+    let handle = device.open().ok();
+    let mut interfaces = Vec::new();
+    for interface in config_desc.interfaces() {
+        for desc in interface.descriptors() {
+            let name = handle.as_ref().and_then(|handle| {
+                handle
+                    .read_string_descriptor_ascii(desc.description_string_index()?)
+                    .ok()
+            });
+            let hid_report_descriptor = match (&handle, desc.class_code()) {
+                (Some(handle), 3) => read_hid_report_descriptor(handle, desc.interface_number()),
+                _ => None,
+            };
+            interfaces.push(InterfaceDescription {
+                number: desc.interface_number(),
+                alternate_setting: desc.setting_number(),
+                class: desc.class_code(),
+                subclass: desc.sub_class_code(),
+                protocol: desc.protocol_code(),
+                name,
+                endpoints: desc
+                    .endpoint_descriptors()
+                    .map(|endpoint| EndpointDescription {
+                        address: endpoint.address(),
+                        transfer_type: endpoint.transfer_type(),
+                        max_packet_size: endpoint.max_packet_size(),
+                    })
+                    .collect(),
+                hid_report_descriptor,
+            });
+        }
+    }
+    Ok(DeviceDescription {
+        vid,
+        pid,
+        bus: device.bus_number(),
+        address: device.address(),
+        interfaces,
+    })
+}
+
+#[cfg(feature = "rusb")]
+#[cfg(not(target_os = "android"))]
+fn read_hid_report_descriptor(
+    handle: &DeviceHandle<GlobalContext>,
+    interface: u8,
+) -> Option<Vec<u8>> {
+    let mut result = vec![0u8; 4096];
+    let size = handle
+        .read_control(
+            rusb::request_type(
+                rusb::Direction::In,
+                rusb::RequestType::Standard,
+                rusb::Recipient::Interface,
+            ),
+            rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR,
+            (rusb::constants::LIBUSB_DT_REPORT as u16) << 8,
+            interface as u16,
+            &mut result,
+            Duration::from_millis(250),
+        )
+        .ok()?;
+    result.truncate(size);
+    Some(result)
+}
+
+/// Retries transient libusb errors on interrupt reads, and rate limits the
+/// [`GlassesEvent::TransportWarning`]s about them
+#[cfg(feature = "rusb")]
+#[derive(Default)]
+pub(crate) struct TransportRecovery {
+    /// Recovered errors since the last warning, per [`TransportErrorKind`]
+    counts: [u32; 3],
+    last_warnings: [Option<Instant>; 3],
+}
+
+#[cfg(feature = "rusb")]
+impl TransportRecovery {
+    const MAX_RETRIES: usize = 3;
+    const WARNING_INTERVAL: Duration = Duration::from_secs(1);
+    const KINDS: [TransportErrorKind; 3] = [
+        TransportErrorKind::Pipe,
+        TransportErrorKind::Overflow,
+        TransportErrorKind::Interrupted,
+    ];
+
+    /// Read a frame, retrying after transient errors. A timeout is returned as
+    /// [`rusb::Error::Timeout`], like a plain interrupt read would.
+    pub fn read_interrupt(
+        &mut self,
+        transport: &dyn FrameTransport,
+        buf: &mut [u8],
+        timeout: Duration,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<usize> {
+        let mut recovered = Vec::new();
+        loop {
+            let error = match transport.read_timeout(buf, timeout.as_millis() as i32) {
+                Ok(0) => return Err(rusb::Error::Timeout.into()),
+                Ok(size) => {
+                    for kind in recovered {
+                        self.counts[kind as usize] += 1;
+                        diagnostics.transport_recoveries += 1;
+                    }
+                    return Ok(size);
+                }
+                Err(e) => e,
+            };
+            let kind = match error {
+                Error::UsbError(rusb::Error::Pipe) => TransportErrorKind::Pipe,
+                Error::UsbError(rusb::Error::Overflow) => TransportErrorKind::Overflow,
+                Error::UsbError(rusb::Error::Interrupted) => TransportErrorKind::Interrupted,
+                _ => return Err(error),
+            };
+            if recovered.len() == Self::MAX_RETRIES {
+                return Err(error);
+            }
+            if kind == TransportErrorKind::Pipe {
+                transport.clear_stall()?;
+            }
+            recovered.push(kind);
+        }
+    }
+
+    /// Get a pending warning about recovered errors, if one is due at `now`
+    pub fn take_warning(&mut self, now: Instant) -> Option<GlassesEvent> {
+        for kind in Self::KINDS {
+            let i = kind as usize;
+            let due = match self.last_warnings[i] {
+                Some(last) => now.saturating_duration_since(last) >= Self::WARNING_INTERVAL,
+                None => true,
+            };
+            if self.counts[i] > 0 && due {
+                self.last_warnings[i] = Some(now);
+                let count = std::mem::take(&mut self.counts[i]);
+                return Some(GlassesEvent::TransportWarning { kind, count });
+            }
+        }
+        None
+    }
+}
+
+/// Advisory lock on a physical device, shared between processes, so that a second
+/// instance fails cleanly instead of half-opening the device.
+/// Released on drop, or by the OS when the process dies.
+///
+/// The lock files are in the per-user runtime directory (`XDG_RUNTIME_DIR`), or in the
+/// temporary directory if it is not set. When running with elevated privileges (e.g.
+/// setuid root), they are in [`PRIVILEGED_LOCK_DIR`], as the environment is controlled
+/// by the less privileged user then. The files are left behind, but a file is only
+/// locked while a process holds it open.
+#[cfg_attr(target_os = "android", allow(dead_code))]
+pub(crate) struct DeviceLock {
+    _file: std::fs::File,
+}
+
+#[cfg(not(target_os = "android"))]
+impl DeviceLock {
+    /// `key` identifies the physical device, and must be usable in a file name
+    pub fn acquire(key: &str) -> Result<Self> {
+        use std::io::{Read, Write};
+
+        let path = lock_dir()?.join(format!("ar-drivers-{key}.lock"));
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            // A symlink planted at the path would make us truncate its target
+            options.custom_flags(libc::O_NOFOLLOW);
+            // Other users only need to read it to lock it
+            options.mode(0o644);
+        }
+        // Nobody else can open the file while we have it open, that is the lock
+        #[cfg(windows)]
+        std::os::windows::fs::OpenOptionsExt::share_mode(&mut options, 0);
+        let (mut file, writable) = match options.open(&path) {
+            Ok(file) => (file, true),
+            // Left behind by another user in a shared directory
+            #[cfg(unix)]
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                (options.write(false).create(false).open(&path)?, false)
+            }
+            // ERROR_SHARING_VIOLATION
+            #[cfg(windows)]
+            Err(e) if e.raw_os_error() == Some(32) => {
+                return Err(Error::AlreadyInUse { holder_pid: None })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !try_lock(&file)? {
+            let mut holder = String::new();
+            // The pid is optional, don't fail on it
+            let _ = file.read_to_string(&mut holder);
+            return Err(Error::AlreadyInUse {
+                holder_pid: holder.trim().parse().ok(),
+            });
+        }
+        if writable {
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+        }
+        Ok(Self { _file: file })
+    }
+
+    /// Lock keyed by the path of a HID device, i.e. by the port it is connected to
+    #[cfg(feature = "hidapi")]
+    pub fn for_hid_path(path: &std::ffi::CStr) -> Result<Self> {
+        let path: String = path
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Self::acquire(&format!("hid-{path}"))
+    }
+
+    /// Lock keyed by the physical USB port the device is connected to
+    #[cfg(feature = "rusb")]
+    pub fn for_usb_device(device: &Device<GlobalContext>) -> Result<Self> {
+        let location = usb_location(device);
+        let ports: Vec<String> = location.ports.iter().map(|port| port.to_string()).collect();
+        Self::acquire(&format!("usb-{}-{}", location.bus, ports.join(".")))
+    }
+}
+
+/// Take an exclusive lock on `file`, without waiting. `false` if another process holds it.
+#[cfg(unix)]
+#[cfg(not(target_os = "android"))]
+fn try_lock(file: &std::fs::File) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    if error.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(error.into())
+    }
+}
+
+/// On Windows, the share mode locks the file when opening it. Elsewhere, there is no lock.
+#[cfg(not(unix))]
+fn try_lock(_file: &std::fs::File) -> Result<bool> {
+    Ok(true)
+}
+
+/// Directory of the lock files when running with elevated privileges, see [`DeviceLock`]
+#[cfg(unix)]
+#[cfg(not(target_os = "android"))]
+pub(crate) const PRIVILEGED_LOCK_DIR: &str = "/run/ar-drivers";
+
+/// Directory of the lock files, see [`DeviceLock`]
+#[cfg(unix)]
+#[cfg(not(target_os = "android"))]
+fn lock_dir() -> Result<std::path::PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    let effective_uid = unsafe { libc::geteuid() };
+    if effective_uid != 0 && effective_uid == unsafe { libc::getuid() } {
+        return Ok(std::env::var_os("XDG_RUNTIME_DIR")
+            .map(std::path::PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .unwrap_or_else(std::env::temp_dir));
+    }
+    match std::fs::DirBuilder::new()
+        .mode(0o755)
+        .create(PRIVILEGED_LOCK_DIR)
+    {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => (),
+    }
+    let metadata = std::fs::symlink_metadata(PRIVILEGED_LOCK_DIR)?;
+    if !metadata.is_dir()
+        || metadata.uid() != effective_uid
+        || metadata.permissions().mode() & 0o022 != 0
+    {
+        return Err(Error::Other(
+            "Lock directory is not a directory owned by and only writable by this user",
+        ));
+    }
+    Ok(PRIVILEGED_LOCK_DIR.into())
+}
+
+/// Directory of the lock files, see [`DeviceLock`]
+#[cfg(not(unix))]
+fn lock_dir() -> Result<std::path::PathBuf> {
+    Ok(std::env::temp_dir())
+}
+
+/// Interface numbers of the HID devices with the specified VID and PID
+#[cfg(feature = "hidapi")]
+#[cfg(not(target_os = "android"))]
+pub(crate) fn hid_interfaces(vid: u16, pid: u16) -> Result<Vec<i32>> {
+    Ok(hidapi::HidApi::new()?
+        .device_list()
+        .filter(|device| device.vendor_id() == vid && device.product_id() == pid)
+        .map(|device| device.interface_number())
+        .collect())
+}
+
+/// Paths of the HID devices with the specified VID and PID, and interface number if given
+#[cfg(feature = "hidapi")]
+#[cfg(not(target_os = "android"))]
+pub(crate) fn hid_paths(vid: u16, pid: u16, interface: Option<i32>) -> Result<Vec<String>> {
+    Ok(hidapi::HidApi::new()?
+        .device_list()
+        .filter(|device| {
+            device.vendor_id() == vid
+                && device.product_id() == pid
+                && !matches!(interface, Some(interface) if device.interface_number() != interface)
+        })
+        .map(|device| device.path().to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Blocking connection to a device that transfers whole frames (e.g. HID reports).
+/// The protocol logic of the drivers only talks to the device through this, so that
+/// it can also run against a scripted fake instead of real hardware.
+#[cfg(any(feature = "hidapi", feature = "rusb"))]
+pub(crate) trait FrameTransport: Send {
+    /// Read a single frame into `buf`, waiting at most `timeout` milliseconds (not at all
+    /// if 0, forever if negative). Returns the size of the frame, or 0 on timeout.
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize>;
+    /// Write a single frame. Returns the number of bytes written.
+    fn write(&self, data: &[u8]) -> Result<usize>;
+
+    /// Replace `error` with [`Error::Disconnected`] if the device turned out to be gone.
+    /// Read and write errors are already checked, this is for timeouts and protocol errors.
+    fn check_disconnect(&self, error: Error) -> Error {
+        error
+    }
+
+    /// Recover the endpoint after a stall ([`rusb::Error::Pipe`]), if the transport has to
+    #[cfg(feature = "rusb")]
+    fn clear_stall(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discard the frames that are already waiting to be read
+    fn drain(&self) -> Result<()> {
+        let mut buf = [0u8; 0x100];
+        // Bounded, as a streaming endpoint may keep producing frames while being drained
+        for _ in 0..1024 {
+            if self.read_timeout(&mut buf, 0)? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A HID interface of the glasses. Errors are checked with [`hid_disconnect_check`].
+#[cfg(feature = "hidapi")]
+pub(crate) struct HidTransport {
+    device: hidapi::HidDevice,
+    vid: u16,
+    pid: u16,
+    interface: Option<i32>,
+    name: &'static str,
+}
+
+#[cfg(feature = "hidapi")]
+impl HidTransport {
+    /// See [`hid_disconnect_check`] for the parameters
+    pub fn new(
+        device: hidapi::HidDevice,
+        vid: u16,
+        pid: u16,
+        interface: Option<i32>,
+        name: &'static str,
+    ) -> Self {
+        Self {
+            device,
+            vid,
+            pid,
+            interface,
+            name,
+        }
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl FrameTransport for HidTransport {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        self.device
+            .read_timeout(buf, timeout)
+            .map_err(|e| self.check_disconnect(e.into()))
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        self.device
+            .write(data)
+            .map_err(|e| self.check_disconnect(e.into()))
+    }
+
+    fn check_disconnect(&self, error: Error) -> Error {
+        hid_disconnect_check(error, self.vid, self.pid, self.interface, self.name)
+    }
+}
+
+/// Interrupt endpoints of a USB device, borrowed for a single transfer
+#[cfg(feature = "rusb")]
+pub(crate) struct UsbInterrupt<'a> {
+    pub handle: &'a DeviceHandle<GlobalContext>,
+    pub endpoint_in: u8,
+    /// `None` if the device has no interrupt OUT endpoint (or it is not known)
+    pub endpoint_out: Option<u8>,
+}
+
+#[cfg(feature = "rusb")]
+impl FrameTransport for UsbInterrupt<'_> {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        match self
+            .handle
+            .read_interrupt(self.endpoint_in, buf, usb_timeout(timeout))
+        {
+            Err(rusb::Error::Timeout) => Ok(0),
+            result => Ok(result?),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        let endpoint = self
+            .endpoint_out
+            .ok_or(Error::Other("Device has no interrupt OUT endpoint"))?;
+        Ok(self
+            .handle
+            .write_interrupt(endpoint, data, Duration::from_millis(250))?)
+    }
+
+    fn clear_stall(&self) -> Result<()> {
+        Ok(self.handle.clear_halt(self.endpoint_in)?)
+    }
+}
+
+/// Convert a [`FrameTransport::read_timeout`] timeout for libusb, which waits forever
+/// with a zero timeout. Like hidapi, zero means not waiting (as much as libusb allows),
+/// and a negative timeout means waiting forever.
+#[cfg(feature = "rusb")]
+fn usb_timeout(timeout: i32) -> Duration {
+    match timeout {
+        0 => Duration::from_millis(1),
+        timeout => Duration::from_millis(timeout.max(0) as u64),
+    }
+}
+
+/// Fake [`FrameTransport`] for unit tests. Cloning it gives a handle to the same script,
+/// so the test can keep one while the driver owns the other.
+///
+/// Reads return the queued frames (or errors) in order, and time out when there are
+/// none. Written frames are checked against the expected ones, if any are queued, and
+/// the responder can queue frames in answer to each write.
+#[cfg(all(test, any(feature = "hidapi", feature = "rusb")))]
+#[derive(Clone, Default)]
+pub(crate) struct ScriptedTransport {
+    script: std::sync::Arc<std::sync::Mutex<Script>>,
+}
+
+#[cfg(all(test, any(feature = "hidapi", feature = "rusb")))]
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<Vec<u8>> + Send>;
+
+#[cfg(all(test, any(feature = "hidapi", feature = "rusb")))]
+#[derive(Default)]
+struct Script {
+    reads: std::collections::VecDeque<Result<Vec<u8>>>,
+    expected_writes: std::collections::VecDeque<Vec<u8>>,
+    written: Vec<Vec<u8>>,
+    responder: Option<Responder>,
+    /// Device name, once unplugged
+    unplugged: Option<&'static str>,
+    /// Advanced by the timeout of the reads that time out
+    clock: Option<std::sync::Arc<crate::clock::MockClock>>,
+}
+
+#[cfg(all(test, any(feature = "hidapi", feature = "rusb")))]
+impl ScriptedTransport {
+    fn script(&self) -> std::sync::MutexGuard<'_, Script> {
+        self.script.lock().unwrap()
+    }
+
+    /// Queue a frame to be read
+    pub fn push_read(&self, frame: impl Into<Vec<u8>>) {
+        self.script().reads.push_back(Ok(frame.into()));
+    }
+
+    /// Queue a read that times out, even if there are frames queued after it
+    pub fn push_timeout(&self) {
+        self.script().reads.push_back(Ok(Vec::new()));
+    }
+
+    /// Queue a read that fails
+    pub fn push_error(&self, error: Error) {
+        self.script().reads.push_back(Err(error));
+    }
+
+    /// The next write that is not expected yet must be exactly `frame`
+    pub fn expect_write(&self, frame: impl Into<Vec<u8>>) {
+        self.script().expected_writes.push_back(frame.into());
+    }
+
+    /// Queue the frames returned by `responder` for reading after every write
+    pub fn set_responder(&self, responder: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static) {
+        self.script().responder = Some(Box::new(responder));
+    }
+
+    /// Advance `clock` by the timeout of each read that times out, like a real
+    /// device blocking for that long
+    pub fn set_clock(&self, clock: std::sync::Arc<crate::clock::MockClock>) {
+        self.script().clock = Some(clock);
+    }
+
+    /// Simulate unplugging: from now on, reads are empty (like on Linux, before hidapi
+    /// reports an error), and the presence check reports the device as gone
+    pub fn unplug(&self, name: &'static str) {
+        let mut script = self.script();
+        script.reads.clear();
+        script.responder = None;
+        script.unplugged = Some(name);
+    }
+
+    /// All frames written so far
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.script().written.clone()
+    }
+
+    /// Forget the frames written so far
+    pub fn clear_written(&self) {
+        self.script().written.clear();
+    }
+
+    /// Whether all queued reads and expected writes were used up
+    pub fn is_finished(&self) -> bool {
+        let script = self.script();
+        script.reads.is_empty() && script.expected_writes.is_empty()
+    }
+
+    pub fn boxed(&self) -> Box<dyn FrameTransport> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(all(test, any(feature = "hidapi", feature = "rusb")))]
+impl FrameTransport for ScriptedTransport {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        let mut script = self.script();
+        let frame = script.reads.pop_front().unwrap_or_else(|| Ok(Vec::new()))?;
+        if frame.is_empty() {
+            if let Some(clock) = &script.clock {
+                clock.advance(Duration::from_millis(timeout.max(0) as u64));
+            }
+            return Ok(0);
+        }
+        let size = frame.len().min(buf.len());
+        buf[..size].copy_from_slice(&frame[..size]);
+        // Like HID reports, the rest of the buffer is not touched
+        Ok(size)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        let mut script = self.script();
+        if let Some(expected) = script.expected_writes.pop_front() {
+            assert_eq!(
+                crate::protocol::hex(data),
+                crate::protocol::hex(&expected),
+                "unexpected frame written"
+            );
+        }
+        script.written.push(data.to_vec());
+        if let Some(responder) = &mut script.responder {
+            let answers = responder(data);
+            script.reads.extend(answers.into_iter().map(Ok));
+        }
+        Ok(data.len())
+    }
+
+    fn check_disconnect(&self, error: Error) -> Error {
+        match self.script().unplugged {
+            Some(name) => Error::disconnected_by(name, error),
+            None => error,
+        }
+    }
+}
+
+/// Convert a libusb error got on an open device, turning the ones that mean the device
+/// is gone into [`Error::Disconnected`]. `NotFound` can only mean that for an already
+/// opened device, so this must not be used while opening.
+#[cfg(feature = "rusb")]
+pub(crate) fn usb_disconnect_check(error: rusb::Error, name: &'static str) -> Error {
+    match error {
+        rusb::Error::NoDevice | rusb::Error::NotFound => Error::disconnected_by(name, error),
+        error => error.into(),
+    }
+}
+
+/// Replace `error` (got while reading or writing a HID device) with [`Error::Disconnected`]
+/// if the device (or the specified interface of it) is not connected anymore.
+/// hidapi reports unplugs with generic errors or empty reads, so the only way to tell
+/// is looking up the device again. Devices can't be enumerated on Android, so
+/// `error` is always kept there.
+#[cfg(feature = "hidapi")]
+#[cfg_attr(target_os = "android", allow(unused_variables))]
+pub(crate) fn hid_disconnect_check(
+    error: impl Into<Error>,
+    vid: u16,
+    pid: u16,
+    interface: Option<i32>,
+    name: &'static str,
+) -> Error {
+    #[cfg(not(target_os = "android"))]
+    if let Ok(interfaces) = hid_interfaces(vid, pid) {
+        let gone = match interface {
+            Some(interface) => !interfaces.contains(&interface),
+            None => interfaces.is_empty(),
+        };
+        if gone {
+            return Error::disconnected_by(name, error);
+        }
+    }
+    error.into()
+}
+
+/// Whether a transport error means that the device handle stopped working (e.g. the
+/// device was reset or unplugged), as opposed to e.g. a timeout or a rejected request
+#[cfg(feature = "hidapi")]
+pub(crate) fn is_io_failure(error: &Error) -> bool {
+    match error {
+        Error::IoError(_) | Error::Disconnected { .. } => true,
+        // hidapi reports failed reads and writes as HidApiError with the OS's message
+        Error::HidError(e) => matches!(
+            e,
+            hidapi::HidError::IoError { .. } | hidapi::HidError::HidApiError { .. }
+        ),
+        #[cfg(feature = "rusb")]
+        Error::UsbError(e) => matches!(
+            e,
+            rusb::Error::NoDevice | rusb::Error::Io | rusb::Error::NotFound
+        ),
+        _ => false,
+    }
+}
+
+/// Drops repeated presses of the same key arriving within a short window of each other,
+/// caused by contact bounce of worn buttons. The window is measured from the previous
+/// (raw) press of the same key, so a long bounce train is collapsed into one press.
+#[cfg(any(feature = "rokid", feature = "nreal"))]
+pub(crate) struct KeyDebouncer {
+    window: Duration,
+    last_presses: Vec<(u8, Instant)>,
+}
+
+#[cfg(any(feature = "rokid", feature = "nreal"))]
+impl Default for KeyDebouncer {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(80),
+            last_presses: Vec::new(),
+        }
+    }
+}
+
+#[cfg(any(feature = "rokid", feature = "nreal"))]
+impl KeyDebouncer {
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Whether a press of `key` at `now` is a real press (and not a bounce).
+    /// Dropped presses are counted in `diagnostics`.
+    pub fn accept(&mut self, key: u8, now: Instant, diagnostics: &mut Diagnostics) -> bool {
+        let last_press = match self.last_presses.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, last_press)) => std::mem::replace(last_press, now),
+            None => {
+                self.last_presses.push((key, now));
+                return true;
+            }
+        };
+        if now.saturating_duration_since(last_press) < self.window {
+            diagnostics.debounced_key_presses += 1;
+            return false;
+        }
+        true
+    }
+}
+
+/// Detects that the host was suspended between two calls: the monotonic clock ([`Instant`])
+/// stops during suspend on Linux and macOS, while the wall clock keeps going. A long pause
+/// between calls advances both clocks, so it is not mistaken for a suspend.
+///
+/// A wall clock jump alone may also be a clock adjustment (e.g. by NTP), so if the IMU
+/// is streaming, it has to confirm the suspend: either the next sample's device time
+/// doesn't follow the host's monotonic clock (the glasses kept running, or rebooted),
+/// or no sample arrives for `IMU_CONFIRM_TIMEOUT` (the stream died).
+#[cfg(feature = "nreal")]
+#[derive(Default)]
+pub(crate) struct SleepDetector {
+    min_sleep: Option<Duration>,
+    last_check: Option<(Instant, SystemTime)>,
+    last_imu_sample: Option<(Instant, crate::DeviceTimestamp)>,
+    /// When a wall clock jump was seen that the IMU didn't confirm yet
+    suspected_at: Option<Instant>,
+}
+
+#[cfg(feature = "nreal")]
+impl SleepDetector {
+    const IMU_CONFIRM_TIMEOUT: Duration = Duration::from_millis(500);
+
+    pub fn set_min_sleep(&mut self, min_sleep: Option<Duration>) {
+        self.min_sleep = min_sleep;
+        self.suspected_at = None;
+    }
+
+    /// Whether the host slept at least `min_sleep` since the previous call. Call before
+    /// every read.
+    pub fn host_slept(&mut self, now: Instant, wall_now: SystemTime) -> bool {
+        let last_check = self.last_check.replace((now, wall_now));
+        let Some(min_sleep) = self.min_sleep else {
+            return false;
+        };
+        if let Some(suspected_at) = self.suspected_at {
+            if now.saturating_duration_since(suspected_at) < Self::IMU_CONFIRM_TIMEOUT {
+                return false;
+            }
+            self.suspected_at = None;
+            self.last_imu_sample = None;
+            return true;
+        }
+        let Some((last, last_wall)) = last_check else {
+            return false;
+        };
+        let wall_elapsed = wall_now.duration_since(last_wall).unwrap_or_default();
+        if wall_elapsed.saturating_sub(now.saturating_duration_since(last)) < min_sleep {
+            return false;
+        }
+        if self.last_imu_sample.is_none() {
+            // Nothing to confirm it with
+            return true;
+        }
+        self.suspected_at = Some(now);
+        false
+    }
+
+    /// Whether the IMU sample received at `now` confirms a suspected sleep. Call for
+    /// every sample.
+    pub fn imu_sample(&mut self, now: Instant, timestamp: crate::DeviceTimestamp) -> bool {
+        let last_sample = self.last_imu_sample.replace((now, timestamp));
+        let (Some(min_sleep), Some(_)) = (self.min_sleep, self.suspected_at.take()) else {
+            return false;
+        };
+        let Some((last, last_timestamp)) = last_sample else {
+            return false;
+        };
+        if timestamp < last_timestamp {
+            return true;
+        }
+        let device_elapsed = timestamp.saturating_duration_since(last_timestamp);
+        device_elapsed.abs_diff(now.saturating_duration_since(last)) >= min_sleep
+    }
+}
+
+/// Look up an error message in a device specific table of (keyword, kind) pairs.
+/// Keywords are matched case-insensitively as substrings, the first match wins.
+#[cfg(feature = "nreal")]
+pub(crate) fn classify_error_message(
+    registry: &[(&str, DeviceErrorKind)],
+    message: &str,
+) -> DeviceErrorKind {
+    let message = message.to_lowercase();
+    registry
+        .iter()
+        .find(|(keyword, _)| message.contains(keyword))
+        .map_or(DeviceErrorKind::Unknown, |(_, kind)| *kind)
+}
+
+/// Get a fixed size float array from a nested JSON object, following the `keys` path
+#[cfg(feature = "tinyjson")]
+pub(crate) fn json_float_array<const N: usize>(
+    json: &JsonValue,
+    keys: &[&str],
+) -> Result<nalgebra::ArrayStorage<f64, N, 1>> {
+    let mut result = [0.0; N];
+    let mut json_val = json;
+    for key in keys {
+        json_val = json_val
+            .get::<std::collections::HashMap<String, JsonValue>>()
+            .ok_or(Error::Other("Json value is not an object"))?
+            .get(*key)
+            .ok_or(Error::Other("Json key not found"))?;
+    }
+    let json_val = json_val
+        .get::<Vec<JsonValue>>()
+        .ok_or(Error::Other("Json value not an array"))?;
+    if json_val.len() != N {
+        return Err(Error::Other("Json array is the wrong length"));
+    }
+    for i in 0..N {
+        result[i] = *json_val[i]
+            .get::<f64>()
+            .ok_or(Error::Other("Json value is not a float"))?;
+    }
+    Ok(nalgebra::ArrayStorage([result]))
+}
+
 #[cfg(feature = "nreal")]
 pub(crate) fn crc32_adler(buf: &[u8]) -> u32 {
     // Code copied from rust-zip, but a similar code is also present in the
@@ -91,3 +1030,286 @@ pub(crate) fn crc32_adler(buf: &[u8]) -> u32 {
 
     r ^ 0xffffffffu32
 }
+
+#[cfg(all(test, any(feature = "rusb", feature = "nreal")))]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+
+    #[cfg(feature = "rusb")]
+    #[test]
+    fn transport_recovery() {
+        let transport = ScriptedTransport::default();
+        transport.push_error(Error::UsbError(rusb::Error::Pipe));
+        transport.push_error(Error::UsbError(rusb::Error::Overflow));
+        transport.push_read([4, 2]);
+        transport.push_timeout();
+        transport.push_error(Error::UsbError(rusb::Error::NoDevice));
+        let mut recovery = TransportRecovery::default();
+        let mut diagnostics = Diagnostics::default();
+        let mut buf = [0; 0x40];
+        let timeout = Duration::from_millis(250);
+
+        assert_eq!(
+            recovery
+                .read_interrupt(&transport, &mut buf, timeout, &mut diagnostics)
+                .unwrap(),
+            2
+        );
+        assert_eq!(diagnostics.transport_recoveries, 2);
+        assert!(matches!(
+            recovery.read_interrupt(&transport, &mut buf, timeout, &mut diagnostics),
+            Err(Error::UsbError(rusb::Error::Timeout))
+        ));
+        assert!(matches!(
+            recovery.read_interrupt(&transport, &mut buf, timeout, &mut diagnostics),
+            Err(Error::UsbError(rusb::Error::NoDevice))
+        ));
+
+        let now = Instant::now();
+        assert!(matches!(
+            recovery.take_warning(now),
+            Some(GlassesEvent::TransportWarning {
+                kind: TransportErrorKind::Pipe,
+                count: 1
+            })
+        ));
+        assert!(matches!(
+            recovery.take_warning(now),
+            Some(GlassesEvent::TransportWarning {
+                kind: TransportErrorKind::Overflow,
+                count: 1
+            })
+        ));
+        assert!(recovery.take_warning(now).is_none());
+    }
+
+    #[cfg(feature = "rusb")]
+    #[test]
+    fn transport_warning_rate_limit() {
+        let transport = ScriptedTransport::default();
+        let mut recovery = TransportRecovery::default();
+        let mut diagnostics = Diagnostics::default();
+        let mut recover_once = |recovery: &mut TransportRecovery| {
+            transport.push_error(Error::UsbError(rusb::Error::Pipe));
+            transport.push_read([1]);
+            recovery
+                .read_interrupt(
+                    &transport,
+                    &mut [0; 0x40],
+                    Duration::from_millis(250),
+                    &mut diagnostics,
+                )
+                .unwrap();
+        };
+        let start = Instant::now();
+        recover_once(&mut recovery);
+        assert!(recovery.take_warning(start).is_some());
+
+        // Errors within the interval are summed up in the next warning
+        recover_once(&mut recovery);
+        recover_once(&mut recovery);
+        let almost = start + TransportRecovery::WARNING_INTERVAL - Duration::from_millis(1);
+        assert!(recovery.take_warning(almost).is_none());
+        assert!(matches!(
+            recovery.take_warning(start + TransportRecovery::WARNING_INTERVAL),
+            Some(GlassesEvent::TransportWarning {
+                kind: TransportErrorKind::Pipe,
+                count: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn drain() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let transport = ScriptedTransport::default();
+        transport.set_clock(clock.clone());
+        transport.push_read([1]);
+        transport.push_read([2]);
+        transport.push_timeout();
+        transport.push_read([3]);
+
+        // Reads without waiting until the first timeout
+        transport.drain().unwrap();
+        assert_eq!(clock.now(), start);
+        let mut buf = [0u8; 0x10];
+        assert_eq!(transport.read_timeout(&mut buf, 100).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+
+        // A device that keeps streaming is not drained forever
+        for _ in 0..2000 {
+            transport.push_read([4]);
+        }
+        transport.drain().unwrap();
+        assert!(!transport.is_finished());
+    }
+
+    #[cfg(feature = "rusb")]
+    #[test]
+    fn usb_timeouts() {
+        // libusb would wait forever with 0
+        assert_eq!(usb_timeout(0), Duration::from_millis(1));
+        assert_eq!(usb_timeout(250), Duration::from_millis(250));
+        assert_eq!(usb_timeout(-1), Duration::ZERO);
+    }
+
+    #[cfg(feature = "rusb")]
+    #[test]
+    fn usb_disconnect_errors() {
+        // The errors libusb reports for a removed device, depending on the backend
+        for error in [rusb::Error::NoDevice, rusb::Error::NotFound] {
+            let Error::Disconnected {
+                device: "Test",
+                source: Some(source),
+            } = usb_disconnect_check(error, "Test")
+            else {
+                panic!("{error:?} is a disconnection");
+            };
+            assert!(matches!(*source, Error::UsbError(e) if e == error));
+        }
+        for error in [
+            rusb::Error::Io,
+            rusb::Error::Timeout,
+            rusb::Error::Pipe,
+            rusb::Error::Access,
+        ] {
+            assert!(matches!(
+                usb_disconnect_check(error, "Test"),
+                Error::UsbError(e) if e == error
+            ));
+        }
+    }
+
+    #[cfg(feature = "rusb")]
+    #[test]
+    fn transport_recovery_gives_up() {
+        let transport = ScriptedTransport::default();
+        for _ in 0..=TransportRecovery::MAX_RETRIES {
+            transport.push_error(Error::UsbError(rusb::Error::Interrupted));
+        }
+        transport.push_read([1]);
+        let mut recovery = TransportRecovery::default();
+        let mut diagnostics = Diagnostics::default();
+        assert!(matches!(
+            recovery.read_interrupt(
+                &transport,
+                &mut [0; 0x40],
+                Duration::from_millis(250),
+                &mut diagnostics
+            ),
+            Err(Error::UsbError(rusb::Error::Interrupted))
+        ));
+        assert_eq!(diagnostics.transport_recoveries, 0);
+        assert!(recovery.take_warning(Instant::now()).is_none());
+    }
+
+    #[cfg(any(feature = "rokid", feature = "nreal"))]
+    #[cfg(unix)]
+    #[cfg(not(target_os = "android"))]
+    #[test]
+    fn device_lock() {
+        let key = format!("test-{}", std::process::id());
+        let lock = DeviceLock::acquire(&key).unwrap();
+        // Also taken through another open file of the same process
+        assert!(matches!(
+            DeviceLock::acquire(&key),
+            Err(Error::AlreadyInUse { holder_pid: Some(pid) }) if pid == std::process::id()
+        ));
+        drop(lock);
+        // The file is left behind, but unlocked
+        drop(DeviceLock::acquire(&key).unwrap());
+        std::fs::remove_file(lock_dir().unwrap().join(format!("ar-drivers-{key}.lock"))).unwrap();
+    }
+
+    #[cfg(any(feature = "rokid", feature = "nreal"))]
+    #[test]
+    fn key_debouncing() {
+        let mut debouncer = KeyDebouncer::default();
+        let mut diagnostics = Diagnostics::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // Press-press-press bounce: one press
+        assert!(debouncer.accept(1, at(0), &mut diagnostics));
+        assert!(!debouncer.accept(1, at(20), &mut diagnostics));
+        assert!(!debouncer.accept(1, at(40), &mut diagnostics));
+        // Every bounce extends the window
+        assert!(!debouncer.accept(1, at(110), &mut diagnostics));
+        assert_eq!(diagnostics.debounced_key_presses, 3);
+        // Keys are tracked separately
+        assert!(debouncer.accept(2, at(120), &mut diagnostics));
+        assert!(debouncer.accept(1, at(200), &mut diagnostics));
+        assert!(!debouncer.accept(2, at(150), &mut diagnostics));
+        assert_eq!(diagnostics.debounced_key_presses, 4);
+
+        debouncer.set_window(Duration::ZERO);
+        assert!(debouncer.accept(1, at(200), &mut diagnostics));
+        assert!(debouncer.accept(1, at(200), &mut diagnostics));
+        assert_eq!(diagnostics.debounced_key_presses, 4);
+    }
+
+    #[cfg(feature = "nreal")]
+    #[test]
+    fn sleep_detection() {
+        use crate::DeviceTimestamp;
+
+        let start = Instant::now();
+        let wall_start = SystemTime::now();
+        let at = |secs: f32, wall_secs: f32| {
+            (
+                start + Duration::from_secs_f32(secs),
+                wall_start + Duration::from_secs_f32(wall_secs),
+            )
+        };
+        let device_time = |secs: f32| DeviceTimestamp::from_micros((secs * 1e6) as u64);
+        let min_sleep = Some(Duration::from_secs(5));
+
+        // Off by default
+        let mut detector = SleepDetector::default();
+        assert!(!detector.host_slept(at(0.0, 0.0).0, at(0.0, 0.0).1));
+        let (now, wall_now) = at(1.0, 60.0);
+        assert!(!detector.host_slept(now, wall_now));
+
+        // Without IMU data, the wall clock jump is enough. A pause is not a sleep.
+        let mut detector = SleepDetector::default();
+        detector.set_min_sleep(min_sleep);
+        for (secs, wall_secs, slept) in [(0.0, 0.0, false), (60.0, 60.0, false), (61.0, 70.0, true)]
+        {
+            let (now, wall_now) = at(secs, wall_secs);
+            assert_eq!(detector.host_slept(now, wall_now), slept);
+        }
+
+        // A clock adjustment while the IMU keeps streaming
+        let mut detector = SleepDetector::default();
+        detector.set_min_sleep(min_sleep);
+        let (now, wall_now) = at(0.0, 0.0);
+        assert!(!detector.host_slept(now, wall_now));
+        assert!(!detector.imu_sample(now, device_time(100.0)));
+        let (now, wall_now) = at(0.001, 60.0);
+        assert!(!detector.host_slept(now, wall_now));
+        assert!(!detector.imu_sample(now, device_time(100.001)));
+
+        // Suspend, glasses rebooted
+        let (now, wall_now) = at(0.002, 120.0);
+        assert!(!detector.host_slept(now, wall_now));
+        assert!(detector.imu_sample(now, device_time(1.0)));
+
+        // Another clock adjustment, then a suspend while the glasses kept running
+        let (now, wall_now) = at(0.003, 180.0);
+        assert!(!detector.host_slept(now, wall_now));
+        assert!(!detector.imu_sample(now, device_time(1.001)));
+        let (now, wall_now) = at(0.004, 240.0);
+        assert!(!detector.host_slept(now, wall_now));
+        assert!(detector.imu_sample(now, device_time(61.0)));
+
+        // Suspend, IMU stream died
+        let (now, wall_now) = at(0.005, 300.0);
+        assert!(!detector.host_slept(now, wall_now));
+        let (now, wall_now) = at(0.3, 300.3);
+        assert!(!detector.host_slept(now, wall_now));
+        let (now, wall_now) = at(0.6, 300.6);
+        assert!(detector.host_slept(now, wall_now));
+    }
+}
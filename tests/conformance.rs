@@ -0,0 +1,86 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+#![cfg(feature = "nreal")]
+
+use ar_drivers::protocol::{verify_vectors, CODECS};
+
+const VECTORS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors");
+
+#[test]
+fn codecs_match_vectors() {
+    let report = verify_vectors(VECTORS).unwrap();
+    assert!(report.checked > 0);
+    assert!(report.mismatches.is_empty(), "{:#?}", report.mismatches);
+}
+
+/// Bitwise CRC-32 (IEEE), independent of the table based one used by the drivers
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn hex_decode(text: &str) -> Vec<u8> {
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Check the framing (header, length and checksum) of a valid frame
+fn check_framing(codec: &str, mut frame: Vec<u8>) {
+    match codec {
+        "nreal_air_mcu" | "nreal_air_imu" => {
+            let header = if codec == "nreal_air_mcu" { 0xfd } else { 0xaa };
+            assert_eq!(frame[0], header);
+            // The length counts from the length field, the rest is zero padding
+            let length = u16::from_le_bytes([frame[5], frame[6]]) as usize;
+            frame.resize(frame.len().max(5 + length), 0);
+            let checksum = u32::from_le_bytes(frame[1..5].try_into().unwrap());
+            assert_eq!(checksum, crc32(&frame[5..5 + length]));
+        }
+        "nreal_light_mcu" => {
+            // STX, colon separated fields ending with the checksum in hex text, ETX
+            assert_eq!(frame[0], 0x02);
+            assert!(frame.ends_with(b":\x03"));
+            let checksum_start = frame.len() - 10;
+            let checksum = std::str::from_utf8(&frame[checksum_start..frame.len() - 2]).unwrap();
+            assert_eq!(
+                u32::from_str_radix(checksum.trim(), 16).unwrap(),
+                crc32(&frame[..checksum_start])
+            );
+        }
+        _ => panic!("No framing check for {codec}"),
+    }
+}
+
+/// The vectors were written with the help of the codecs under test, so check their
+/// checksums and lengths independently, so that a codec bug can't hide in the vectors
+#[test]
+fn vector_framing() {
+    for (codec, _) in CODECS {
+        let vectors = std::fs::read_to_string(format!("{VECTORS}/{codec}.txt")).unwrap();
+        for line in vectors.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let frame = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["encode", _, frame] | ["decode", frame, _] if !line.ends_with(" -") => frame,
+                _ => continue,
+            };
+            check_framing(codec, hex_decode(frame));
+        }
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use ar_drivers::{read_all, ARGlasses, GlassesEvent, MotionProfile, SyntheticImu};
+
+#[test]
+fn events_of_two_glasses_are_tagged_and_ordered() {
+    let fast: Box<dyn ARGlasses> = Box::new(SyntheticImu::new(MotionProfile::Stationary));
+    let slow: Box<dyn ARGlasses> =
+        Box::new(SyntheticImu::new(MotionProfile::Stationary).with_sample_rate(500.0));
+    let events = read_all([("fast", fast), ("slow", slow)]).unwrap();
+
+    let mut last = [None, None];
+    let mut counts = [0, 0];
+    while counts.iter().any(|&count| count < 200) {
+        let (id, event) = events.recv().unwrap();
+        let (index, step) = match id {
+            "fast" => (0, 1000),
+            "slow" => (1, 2000),
+            _ => panic!("Unknown id {id}"),
+        };
+        let GlassesEvent::AccGyro { timestamp, .. } = event.unwrap() else {
+            panic!("Unexpected event");
+        };
+        if let Some(last) = last[index] {
+            assert_eq!(timestamp.as_micros(), last + step, "{id}");
+        }
+        last[index] = Some(timestamp.as_micros());
+        counts[index] += 1;
+    }
+}
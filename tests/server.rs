@@ -0,0 +1,186 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of ar-drivers-rs
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+#![cfg(all(feature = "server", unix))]
+
+use std::{
+    io::{Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+use ar_drivers::{
+    server::{EventServer, RemoteGlasses},
+    ARGlasses, DeviceErrorKind, DeviceTimestamp, Gesture, GlassesEvent, MotionProfile, SampleFlags,
+    SyntheticImu, TransportErrorKind,
+};
+use nalgebra::Vector3;
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ar-drivers-{name}-{}.sock", std::process::id()))
+}
+
+/// Publish `event` until a client is accepted. The accepting call sends it to the client.
+fn wait_for_client(server: &mut EventServer, event: &GlassesEvent) {
+    while server.client_count() == 0 {
+        server.publish(event).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn events_are_forwarded() {
+    let path = socket_path("events");
+    let mut glasses = SyntheticImu::new(MotionProfile::Stationary);
+    let mut server = EventServer::bind(&path, &mut glasses).unwrap();
+    let events = vec![
+        GlassesEvent::AccGyro {
+            accelerometer: Vector3::new(0.5, -9.81, 1.0),
+            gyroscope: Vector3::new(0.25, 0.0, -3.5),
+            timestamp: DeviceTimestamp::from_micros(123_456),
+            flags: SampleFlags::CLIPPED_GYROSCOPE | SampleFlags::BIAS_APPLIED,
+        },
+        GlassesEvent::Magnetometer {
+            magnetometer: Vector3::new(1.0, 2.0, 3.0),
+            timestamp: DeviceTimestamp::from_micros(7),
+        },
+        GlassesEvent::DisplayLink {
+            up: false,
+            detail: Some("DP link lost".into()),
+            inferred: true,
+        },
+        GlassesEvent::DisplayLink {
+            up: true,
+            detail: None,
+            inferred: false,
+        },
+        GlassesEvent::TransportWarning {
+            kind: TransportErrorKind::Reopened,
+            count: 3,
+        },
+        GlassesEvent::DeviceError {
+            kind: DeviceErrorKind::ThermalThrottle,
+            raw: vec![0x12, 0x00, 0xff],
+            message: None,
+        },
+        GlassesEvent::Gesture(Gesture::Shake),
+        GlassesEvent::Resumed {
+            gap: Duration::from_micros(2_500_000),
+        },
+        GlassesEvent::CalibrationDrift {
+            suggested_bias: Vector3::new(0.001, -0.002, 0.0),
+        },
+        GlassesEvent::Overflow { dropped: 42 },
+        GlassesEvent::KeyPress(2),
+        GlassesEvent::ProximityNear,
+        GlassesEvent::BrightnessChanged(5),
+    ];
+
+    let client_path = path.clone();
+    let count = events.len();
+    let client = std::thread::spawn(move || {
+        let mut remote = RemoteGlasses::connect(client_path).unwrap();
+        assert_eq!(remote.remote_name(), "Synthetic IMU");
+        (0..=count)
+            .map(|_| format!("{:?}", remote.read_event().unwrap()))
+            .collect::<Vec<_>>()
+    });
+    wait_for_client(&mut server, &GlassesEvent::VSync);
+    for event in &events {
+        server.publish(event).unwrap();
+    }
+
+    let expected: Vec<_> = std::iter::once(&GlassesEvent::VSync)
+        .chain(&events)
+        .map(|event| format!("{event:?}"))
+        .collect();
+    assert_eq!(client.join().unwrap(), expected);
+}
+
+#[test]
+fn rejected_commands_are_ignored() {
+    let path = socket_path("commands");
+    let mut glasses = SyntheticImu::new(MotionProfile::Stationary);
+    let mut server = EventServer::bind(&path, &mut glasses).unwrap();
+    let mut client = UnixStream::connect(&path).unwrap();
+    wait_for_client(&mut server, &GlassesEvent::VSync);
+
+    let mut header = [0u8; 6];
+    client.read_exact(&mut header).unwrap();
+    assert_eq!(&header, b"ARDS\x02\x00");
+    let commands: [&[u8]; 3] = [
+        // Unknown display mode
+        &[1, 0x7f],
+        // Known display mode, but the synthetic glasses don't support setting it
+        &[1, 0],
+        // Unknown command
+        &[0x42, 1, 2, 3],
+    ];
+    for command in commands {
+        client
+            .write_all(&(command.len() as u32).to_le_bytes())
+            .unwrap();
+        client.write_all(command).unwrap();
+    }
+    // Wait for the commands to arrive
+    std::thread::sleep(Duration::from_millis(50));
+    server.handle_commands(&mut glasses).unwrap();
+    assert_eq!(server.client_count(), 1);
+
+    // The server keeps serving the client
+    let event = server.serve(&mut glasses).unwrap();
+    assert!(matches!(event, GlassesEvent::AccGyro { .. }));
+    assert_eq!(server.client_count(), 1);
+    client
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    // Hello, the VSync and the sample
+    for _ in 0..3 {
+        let mut length = [0u8; 4];
+        client.read_exact(&mut length).unwrap();
+        let mut message = vec![0; u32::from_le_bytes(length) as usize];
+        client.read_exact(&mut message).unwrap();
+    }
+}
+
+#[test]
+fn socket_permissions() {
+    let path = socket_path("permissions");
+    let mut glasses = SyntheticImu::new(MotionProfile::Stationary);
+    let server = EventServer::bind(&path, &mut glasses).unwrap();
+    let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(), 0o600);
+    assert!(EventServer::bind(&path, &mut glasses).is_err());
+
+    let server = server.with_allowed_uids(vec![0]);
+    assert_eq!(mode(), 0o666);
+    drop(server);
+    assert!(!path.exists());
+}
+
+/// Number of open file descriptors of this process
+#[cfg(target_os = "linux")]
+fn open_files() -> usize {
+    std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn connect_disconnect_soak() {
+    let path = socket_path("soak");
+    let mut glasses = SyntheticImu::new(MotionProfile::Stationary);
+    let mut server = EventServer::bind(&path, &mut glasses).unwrap();
+    let files_before = open_files();
+    for _ in 0..500 {
+        let client = UnixStream::connect(&path).unwrap();
+        server.serve(&mut glasses).unwrap();
+        assert_eq!(server.client_count(), 1);
+        drop(client);
+        server.serve(&mut glasses).unwrap();
+        assert_eq!(server.client_count(), 0);
+    }
+    // Other tests running in parallel may have a few files open
+    assert!(open_files() < files_before + 50);
+}